@@ -0,0 +1,130 @@
+/// Advice on which columns of tables to index, from the queries run so far.
+///
+/// Each scan of a table binding some of its columns to atoms, e.g. for the
+/// term `edge(a, Y)`, counts as a lookup on those columns; see `observe`.
+/// Lookups are counted per thread, like profiles and limits. `advise` then
+/// weighs the lookups on each column against statistics on the column: an
+/// index pays for itself on a column looked up often, in a table large enough
+/// that scanning it is slow, with enough distinct atoms that each lookup
+/// matches few rows.
+
+use ast;
+use eval::AstView;
+use storage::{Relation, StorageEngine};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+
+type Storage = StorageEngine<AstView>;
+
+/// The fewest lookups on a column worth indexing it for.
+pub const MIN_LOOKUPS: usize = 10;
+
+/// The fewest facts in a table worth indexing it for.
+pub const MIN_ROWS: usize = 1000;
+
+/// The fewest distinct atoms in a column worth indexing it for. With fewer,
+/// each lookup matches more than a tenth of the table, on average, and is
+/// little faster than scanning it.
+pub const MIN_DISTINCT: usize = 10;
+
+/// What the advisor makes of the lookups on one column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// The column is indexed already.
+    Indexed,
+    /// The column should be indexed.
+    Index,
+    /// The column isn't looked up often enough to index.
+    Rare,
+    /// The table has too few facts to be worth indexing.
+    Small,
+    /// Lookups on the column match too many facts to be worth indexing.
+    Unselective
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Decision::Indexed => "indexed",
+            Decision::Index => "index",
+            Decision::Rare => "too few lookups",
+            Decision::Small => "too few rows",
+            Decision::Unselective => "not selective"
+        })
+    }
+}
+
+/// The lookups on one column of a table, and what to do about them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advice {
+    pub relation: String,
+    /// The column, numbered from 0.
+    pub column: usize,
+    pub lookups: usize,
+    /// The number of facts in the table.
+    pub rows: usize,
+    /// The number of distinct atoms in the column, if they were counted.
+    /// They aren't for columns looked up too rarely, or in tables too small,
+    /// to index anyway.
+    pub distinct: Option<usize>,
+    pub decision: Decision
+}
+
+thread_local! {
+    static LOOKUPS: RefCell<BTreeMap<(String, usize), usize>> =
+        RefCell::new(BTreeMap::new());
+}
+
+/// Count a scan of a table with the given pattern as a lookup on each column
+/// it binds to an atom.
+pub fn observe(relation: &str, pattern: &[ast::AtomicTerm]) {
+    LOOKUPS.with(|l| {
+        let mut lookups = l.borrow_mut();
+        for (column, param) in pattern.iter().enumerate() {
+            if let ast::AtomicTerm::Atom(_) = param {
+                *lookups.entry((relation.to_string(), column))
+                        .or_insert(0) += 1;
+            }
+        }
+    });
+}
+
+/// Forget the lookups counted so far on this thread.
+pub fn reset() {
+    LOOKUPS.with(|l| l.borrow_mut().clear());
+}
+
+/// Advice on each column of a table looked up so far on this thread, by
+/// relation and column. Columns of relations no longer tables are skipped.
+pub fn advise(engine: &Storage) -> Vec<Advice> {
+    let lookups = LOOKUPS.with(|l| l.borrow().clone());
+    lookups.into_iter().filter_map(|((relation, column), lookups)| {
+        let table = match engine.get_relation(&relation) {
+            Some(Relation::Extension(table)) => table,
+            _ => return None
+        };
+        if column >= table.arity() {
+            return None;
+        }
+        let rows = table.len();
+        let mut distinct = None;
+        let decision = if table.is_indexed(column) {
+            Decision::Indexed
+        } else if lookups < MIN_LOOKUPS {
+            Decision::Rare
+        } else if rows < MIN_ROWS {
+            Decision::Small
+        } else {
+            let count = table.distinct_in(column);
+            distinct = Some(count);
+            if count < MIN_DISTINCT {
+                Decision::Unselective
+            } else {
+                Decision::Index
+            }
+        };
+        Some(Advice { relation, column, lookups, rows, distinct, decision })
+    }).collect()
+}
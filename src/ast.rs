@@ -1,6 +1,26 @@
+use value::Value;
+
+use std;
+use std::fmt;
+use std::time::Duration;
+
+/// Words with a meaning of their own in the language, or set aside for
+/// features to come. They can only be used as relation names when quoted, as
+/// in `"is"(a, b)`, so that giving them a meaning later won't change what
+/// existing programs mean.
+pub const RESERVED: &'static [&'static str] = &[
+    "count", "count_derivations", "instantiate", "is", "not", "on", "redefine",
+    "ref", "select", "template", "with"
+];
+
+/// Is the given word reserved?
+pub fn is_reserved(word: &str) -> bool {
+    RESERVED.contains(&word)
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum AtomicTerm {
-    Atom(String),
+    Atom(Value),
     Variable(String)
 }
 
@@ -13,17 +33,128 @@ pub struct CompoundTerm {
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum Term {
     Atomic(AtomicTerm),
-    Compound(CompoundTerm)
+    Compound(CompoundTerm),
+    /// `not` followed by a term, in the body of a rule. Holds when the term
+    /// has no matches.
+    Not(Box<Term>)
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl Term {
+    /// The distinct variables in this term, in order of first appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        let params = match self {
+            Term::Atomic(a) => std::slice::from_ref(a),
+            Term::Compound(c) => c.params.as_slice(),
+            Term::Not(t) => return t.variables()
+        };
+        for param in params {
+            if let AtomicTerm::Variable(v) = param {
+                if !result.contains(v) {
+                    result.push(v.clone());
+                }
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Rule {
     pub head: Term,
     pub body: Vec<Term>
 }
 
+/// How to evaluate a query, overriding the defaults, as given by the hints
+/// after `using`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct QueryOptions {
+    /// Evaluate recursive views with the semi-naive algorithm (`seminaive`)
+    /// rather than the naive one (`naive`).
+    pub semi_naive: bool,
+    /// Neither read cached views nor cache the views evaluated (`no_cache`).
+    pub no_cache: bool
+}
+
+/// A REPL meta-command, e.g. `.export_query "parent(X, Y)?" out.parquet`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    pub name: String,
+    pub args: Vec<String>
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Line {
     Query(Term),
-    Rule(Rule)
+    /// A query followed by `select` and some of its variables, whose answers
+    /// only include those variables.
+    Select(Term, Vec<String>),
+    /// A query followed by `using` and hints on how to evaluate it.
+    Using(Term, QueryOptions),
+    /// A query followed by `within` and a length of time, after which it is
+    /// answered from what recursive views have derived so far.
+    Within(Term, Duration),
+    /// A query followed by `->` and a relation name, whose answers are stored
+    /// in a temporary relation of that name.
+    Capture(Term, String),
+    Rule(Rule),
+    /// `redefine` followed by a rule, replacing all existing rules for a view.
+    Redefine(Rule),
+    /// `count` followed by a query, counting its distinct answers.
+    Count(Term),
+    /// `count_derivations` followed by a query, counting the derivations of
+    /// each answer.
+    CountDerivations(Term),
+    /// `not` followed by a query, which holds if the query has no answers.
+    Not(Term),
+    /// `with` followed by rules and then a query, which is answered as if the
+    /// rules were in the database.
+    With(Vec<Rule>, Term),
+    /// `ref` followed by two terms listing columns (numbered from 1) of a
+    /// table and of another relation, separated by `->`: every fact of the
+    /// table must agree with a tuple of the other relation on those columns.
+    Ref(Term, Term),
+    /// `on` followed by a term, `->` and facts separated by commas: whenever a
+    /// fact matching the term is asserted, the facts are asserted too.
+    Trigger(Term, Vec<Term>),
+    Command(Command)
+}
+
+impl fmt::Display for AtomicTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtomicTerm::Atom(a) => write!(f, "{}", a),
+            AtomicTerm::Variable(v) => write!(f, "{}", v)
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::Atomic(a) => write!(f, "{}", a),
+            Term::Compound(c) => {
+                let params: Vec<String> =
+                    c.params.iter().map(|p| p.to_string()).collect();
+                if is_reserved(&c.relation) {
+                    write!(f, "\"{}\"({})", c.relation, params.join(", "))
+                } else {
+                    write!(f, "{}({})", c.relation, params.join(", "))
+                }
+            },
+            Term::Not(t) => write!(f, "not {}", t)
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.head)?;
+        if !self.body.is_empty() {
+            let body: Vec<String> =
+                self.body.iter().map(|t| t.to_string()).collect();
+            write!(f, " :- {}", body.join(", "))?;
+        }
+        write!(f, ".")
+    }
 }
@@ -1,5 +1,10 @@
-use std::cell::{RefCell};
-use std::collections::{HashMap, HashSet};
+use strata::Strata;
+use value::Value;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
 
 struct DependencyGraph {
     /// Maps relations to the relations *that depend on them*.
@@ -17,21 +22,158 @@ impl DependencyGraph {
                        .push(dependent)
     }
 
+    /// Forget everything the given relation depends on.
+    fn remove_dependent(&mut self, dependent: &str) {
+        for dependents in self.dependents.values_mut() {
+            dependents.retain(|d| d != dependent);
+        }
+    }
+
     fn get_dependents(&self, relation: &str) -> &[String] {
         self.dependents.get(relation).map(|v| v.as_slice()).unwrap_or(&[])
     }
 }
 
+/// Which columns of a query against a view were bound, and to what: `None`
+/// for a free column. A pattern of all `None`s stands for the whole view.
+pub type BindingPattern = Vec<Option<Value>>;
+
+// The cached results of a single relation, by binding pattern.
+type Entries = HashMap<BindingPattern, HashSet<Vec<Value>>>;
+
+fn matches(pattern: &[Option<Value>], tuple: &[Value]) -> bool {
+    pattern.iter().zip(tuple).all(|(bound, v)| {
+        bound.as_ref().map_or(true, |b| b == v)
+    })
+}
+
+// Whether every tuple matching `specific` also matches `general`.
+fn subsumes(general: &[Option<Value>], specific: &[Option<Value>]) -> bool {
+    general.len() == specific.len() &&
+        general.iter().zip(specific).all(|(g, s)| g.is_none() || g == s)
+}
+
+/// A relation standing for the whole database: views that depend on it, like
+/// those using the built-in `holds`, may change whenever any relation does.
+pub const EVERY_RELATION: &'static str = "holds";
+
+/// A fact added to a table since the views depending on it were cached.
+pub type Delta = (String, Vec<Value>);
+
+// The most deltas kept for a view before its cached results are discarded
+// instead: past this, recomputing it is likely to be cheaper.
+const MAX_DELTAS: usize = 1024;
+
 pub struct ViewCache {
     dependencies: DependencyGraph,
-    contents: RefCell<HashMap<String, HashSet<Vec<String>>>>
+    contents: RefCell<HashMap<String, Entries>>,
+    // Views whose cached results are missing the facts added since, with those
+    // facts.
+    stale: RefCell<HashMap<String, Vec<Delta>>>,
+    // While a query is being planned, the strata of the views it may use and
+    // the results of each recursive view evaluated for it, so that neither is
+    // worked out more than once; and how deeply planning is nested.
+    strata: RefCell<Option<Rc<Strata>>>,
+    fixpoints: RefCell<HashMap<String, Rc<Vec<Vec<Value>>>>>,
+    planning: Cell<usize>,
+    // Views invalidated since they were last refreshed, whose
+    // materializations, if any, may no longer match their rules.
+    outdated: RefCell<BTreeSet<String>>,
+    // Values read back from joins spilled to disk, each kept once, for the
+    // frames of those joins to borrow; see `keep_spilled`.
+    spilled: RefCell<HashSet<Box<Value>>>
 }
 
 impl ViewCache {
     pub fn new() -> Self {
         ViewCache {
             dependencies: DependencyGraph::new(),
-            contents: RefCell::new(HashMap::new())
+            contents: RefCell::new(HashMap::new()),
+            stale: RefCell::new(HashMap::new()),
+            strata: RefCell::new(None),
+            fixpoints: RefCell::new(HashMap::new()),
+            planning: Cell::new(0),
+            outdated: RefCell::new(BTreeSet::new()),
+            spilled: RefCell::new(HashSet::new())
+        }
+    }
+
+    /// Keep a value read back from a join spilled to disk (see `spill`) until
+    /// `free_spilled`, so that frames can borrow it for as long as they
+    /// borrow the cache, however long the join itself lasts.
+    ///
+    /// The values outlive the join, so the memory they take isn't bounded by
+    /// its budget; but each is kept only once, however many frames hold it,
+    /// so it is bounded by the distinct values spilled between calls to
+    /// `free_spilled`. The driver frees them after each line.
+    pub fn keep_spilled(&self, value: Value) -> &Value {
+        let mut spilled = self.spilled.borrow_mut();
+        if !spilled.contains(&value) {
+            spilled.insert(Box::new(value.clone()));
+        }
+        let kept: &Value = spilled.get(&value).unwrap();
+        // The values are boxed, so stay put however the set grows, and are
+        // only dropped by `free_spilled`, once nothing borrows the cache.
+        unsafe { mem::transmute(kept) }
+    }
+
+    /// The number of values kept by `keep_spilled`.
+    pub fn spilled_len(&self) -> usize {
+        self.spilled.borrow().len()
+    }
+
+    /// Drop the values kept by `keep_spilled`. Since this takes `&mut self`,
+    /// no frame can still be borrowing them.
+    pub fn free_spilled(&mut self) {
+        self.spilled.get_mut().clear();
+    }
+
+    /// Note that planning a query has begun. Queries planned while planning
+    /// another, like those for the terms in a view's rules, are part of it.
+    ///
+    /// Must be matched by a call to `end_planning`.
+    pub fn begin_planning(&self) {
+        self.planning.set(self.planning.get() + 1);
+    }
+
+    /// Note that planning a query is done, forgetting the results remembered
+    /// for it by `remember_strata` and `remember_fixpoint` if it was the
+    /// outermost.
+    pub fn end_planning(&self) {
+        self.planning.set(self.planning.get() - 1);
+        if self.planning.get() == 0 {
+            self.strata.borrow_mut().take();
+            self.fixpoints.borrow_mut().clear();
+        }
+    }
+
+    /// The strata worked out while planning the current query, if any.
+    pub fn strata(&self) -> Option<Rc<Strata>> {
+        self.strata.borrow().clone()
+    }
+
+    /// Remember the strata of the views until the current query is planned.
+    /// The rules can't change while it is.
+    pub fn remember_strata(&self, strata: Rc<Strata>) {
+        if self.planning.get() > 0 {
+            *self.strata.borrow_mut() = Some(strata);
+        }
+    }
+
+    /// The results of a recursive view already evaluated while planning the
+    /// current query, if any.
+    pub fn fixpoint(&self, view: &str) -> Option<Rc<Vec<Vec<Value>>>> {
+        self.fixpoints.borrow().get(view).cloned()
+    }
+
+    /// Remember the results of a recursive view until the current query is
+    /// planned. Unlike `publish`, these may be used even if the scan they
+    /// were for is abandoned, since they were complete when evaluated.
+    pub fn remember_fixpoint(&self,
+                             view: String,
+                             tuples: Rc<Vec<Vec<Value>>>) {
+        if self.planning.get() > 0 {
+            self.fixpoints.borrow_mut().insert(view, tuples);
         }
     }
 
@@ -39,8 +181,79 @@ impl ViewCache {
         self.dependencies.add_dependency(relation, dependent);
     }
 
+    /// Forget everything `dependent` depends on, e.g. because its rules have
+    /// changed.
+    pub fn remove_dependencies(&mut self, dependent: &str) {
+        self.dependencies.remove_dependent(dependent);
+    }
+
+    /// The views that depend on `relation`, directly or through other views,
+    /// in alphabetical order: those whose contents may change when it does.
+    /// A recursive view depends on itself.
+    pub fn dependents(&self, relation: &str) -> Vec<String> {
+        let mut found: BTreeSet<&str> = BTreeSet::new();
+        let mut stack = vec!(relation, EVERY_RELATION);
+        while let Some(relation) = stack.pop() {
+            for dependent in self.dependencies.get_dependents(relation) {
+                if found.insert(dependent) {
+                    stack.push(dependent);
+                }
+            }
+        }
+        found.into_iter().map(str::to_string).collect()
+    }
+
+    /// Map each relation used in the rules of a view to the views that use
+    /// it directly, all in alphabetical order.
+    pub fn dependency_graph(&self) -> BTreeMap<String, Vec<String>> {
+        self.dependencies.dependents.iter().filter(|(_, d)| !d.is_empty())
+            .map(|(relation, dependents)| {
+                let dependents: BTreeSet<&String> = dependents.iter().collect();
+                (relation.clone(), dependents.into_iter().cloned().collect())
+            }).collect()
+    }
+
+    /// The recursive groups of views: the strongly connected components of
+    /// the dependency graph with a cycle in them. Each group is in
+    /// alphabetical order, and the groups are in order of their first view.
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let graph = &self.dependencies;
+        let reachable = |from: &str| -> BTreeSet<&str> {
+            let mut found = BTreeSet::new();
+            let mut stack = vec!(from);
+            while let Some(relation) = stack.pop() {
+                for dependent in graph.get_dependents(relation) {
+                    if found.insert(dependent.as_str()) {
+                        stack.push(dependent);
+                    }
+                }
+            }
+            found
+        };
+
+        // Only views can be in a cycle, and every view with rules has some
+        // relation it depends on.
+        let views: BTreeSet<&str> = graph.dependents.values()
+            .flat_map(|d| d.iter().map(String::as_str)).collect();
+        let reaches: HashMap<&str, BTreeSet<&str>> = views.iter()
+            .map(|view| (*view, reachable(view))).collect();
+
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+        for view in &views {
+            if seen.contains(view) || !reaches[view].contains(view) {
+                continue;
+            }
+            let cycle: Vec<&str> = reaches[view].iter().cloned()
+                .filter(|other| reaches[other].contains(view)).collect();
+            seen.extend(cycle.iter().cloned());
+            result.push(cycle.into_iter().map(str::to_string).collect());
+        }
+        result
+    }
+
     fn invalidate_helper<'a>(
-            contents: &mut HashMap<String, HashSet<Vec<String>>>,
+            contents: &mut HashMap<String, Entries>,
             dependencies: &'a DependencyGraph,
             relation: &str,
             visited: &mut HashSet<&'a str>) {
@@ -57,24 +270,166 @@ impl ViewCache {
     }
 
     pub fn invalidate(&mut self, relation: &str) {
+        debug!("invalidating views depending on {}", relation);
         let mut visited: HashSet<&'_ str> = HashSet::new();
+        let mut contents = self.contents.borrow_mut();
+
+        for relation in &[relation, EVERY_RELATION] {
+            Self::invalidate_helper(&mut contents,
+                                    &self.dependencies,
+                                    relation,
+                                    &mut visited);
+        }
+        self.stale.borrow_mut().retain(|view, _| contents.contains_key(view));
+        let mut outdated = self.outdated.borrow_mut();
+        outdated.insert(relation.to_string());
+        outdated.extend(visited.into_iter().map(str::to_string));
+    }
+
+    /// Mark `view` as outdated, as `invalidate` does for every view depending
+    /// on the relation it is given: its materialization, if any, may no
+    /// longer match its rules, and must not be read until refreshed.
+    pub fn outdate(&self, view: &str) {
+        self.outdated.borrow_mut().insert(view.to_string());
+    }
+
+    /// Has `view` been outdated since it was last refreshed?
+    pub fn is_outdated(&self, view: &str) -> bool {
+        self.outdated.borrow().contains(view)
+    }
+
+    /// The views outdated since they were last refreshed, in alphabetical
+    /// order.
+    pub fn outdated(&self) -> Vec<String> {
+        self.outdated.borrow().iter().cloned().collect()
+    }
+
+    /// Note that the materialization of `view`, if any, matches its rules
+    /// again.
+    pub fn refreshed(&self, view: &str) {
+        self.outdated.borrow_mut().remove(view);
+    }
+
+    /// Record that `fact` was added to the table `relation`, without
+    /// discarding the cached results of the views depending on it. Instead,
+    /// they are marked stale, to be brought up to date with `take_deltas` the
+    /// next time they are read.
+    pub fn defer(&mut self, relation: &str, fact: Vec<Value>) {
+        let contents = self.contents.borrow();
+        let mut stale = self.stale.borrow_mut();
+        let mut discarded = Vec::new();
+        for view in self.dependents(relation) {
+            if !contents.contains_key(&view) {
+                continue;
+            }
+            let deltas = stale.entry(view.clone()).or_insert(Vec::new());
+            if deltas.len() < MAX_DELTAS {
+                deltas.push((relation.to_string(), fact.clone()));
+            } else {
+                discarded.push(view);
+            }
+        }
+        drop(contents);
+        drop(stale);
+
+        for view in discarded {
+            debug!("too many new facts for {} to bring up to date", view);
+            self.discard(&view);
+        }
+    }
 
-        Self::invalidate_helper(&mut self.contents.borrow_mut(),
-                                &self.dependencies,
-                                relation,
-                                &mut visited);
+    /// Is `view` cached, but missing facts added since?
+    pub fn is_stale(&self, view: &str) -> bool {
+        self.stale.borrow().contains_key(view)
+    }
+
+    /// The facts added since `view` was cached, if it is stale; it is no
+    /// longer considered stale afterwards, so the caller must either `extend`
+    /// its cached results with what follows from them or `discard` them.
+    pub fn take_deltas(&self, view: &str) -> Option<Vec<Delta>> {
+        self.stale.borrow_mut().remove(view)
+    }
+
+    /// Add `tuples` to the cached results of `view`, to every binding pattern
+    /// they match.
+    pub fn extend(&self, view: &str, tuples: &[Vec<Value>]) {
+        if let Some(entries) = self.contents.borrow_mut().get_mut(view) {
+            for (pattern, set) in entries.iter_mut() {
+                set.extend(tuples.iter().filter(|t| matches(pattern, t))
+                                        .cloned());
+            }
+        }
     }
 
-    pub fn add_tuple(&self, relation: String, tuple: Vec<String>) {
+    /// Forget the cached results of `view` alone.
+    pub fn discard(&self, view: &str) {
+        self.contents.borrow_mut().remove(view);
+        self.stale.borrow_mut().remove(view);
+    }
+
+    /// Record `tuples` as the complete results of querying `relation` with
+    /// `pattern`.
+    ///
+    /// Only publish a result set once it has been enumerated in full: later
+    /// queries treat cache entries as complete.
+    pub fn publish(&self,
+                   relation: String,
+                   pattern: &[Option<Value>],
+                   tuples: Vec<Vec<Value>>) {
+        trace!("caching {} tuples of {} for {:?}", tuples.len(), relation,
+               pattern);
         let mut lock = self.contents.borrow_mut();
-        let set = lock.entry(relation).or_insert(HashSet::new());
-        set.insert(tuple);
+        let set = lock.entry(relation)
+                      .or_insert(HashMap::new())
+                      .entry(pattern.to_vec())
+                      .or_insert(HashSet::new());
+        set.extend(tuples);
+    }
+
+    /// Can querying `relation` with `pattern` be answered by `read_cache`?
+    pub fn is_cached(&self, relation: &str, pattern: &[Option<Value>]) -> bool {
+        !self.is_stale(relation) &&
+            self.contents.borrow().get(relation).map_or(false, |entries| {
+                entries.keys().any(|cached| subsumes(cached, pattern))
+            })
+    }
+
+    /// The number of cached results of querying `relation` with exactly
+    /// `pattern`, if they are cached and up to date.
+    pub fn cached_len(&self, relation: &str, pattern: &[Option<Value>])
+            -> Option<usize> {
+        if self.is_stale(relation) {
+            return None;
+        }
+        self.contents.borrow().get(relation)
+                              .and_then(|entries| entries.get(pattern))
+                              .map(|set| set.len())
     }
 
-    pub fn read_cache<'s>(&'s self, relation: &str)
-            -> Option<Vec<Vec<String>>> {
-        self.contents.borrow().get(relation).map(|set| {
-            set.iter().map(Vec::clone).collect()
-        })
+    /// The cached results of querying `relation` with `pattern`, if any.
+    ///
+    /// Falls back to filtering the results of a more general query, e.g. the
+    /// whole view, if one of those is cached. Stale views have no results
+    /// until they are brought up to date.
+    pub fn read_cache<'s>(&'s self,
+                          relation: &str,
+                          pattern: &[Option<Value>])
+            -> Option<Vec<Vec<Value>>> {
+        if self.is_stale(relation) {
+            return None;
+        }
+        let contents = self.contents.borrow();
+        let entries = contents.get(relation)?;
+        if let Some(set) = entries.get(pattern) {
+            return Some(set.iter().map(Vec::clone).collect());
+        }
+
+        entries.iter().find(|(cached, _)| subsumes(cached, pattern))
+               .map(|(_, set)| {
+                   set.iter()
+                      .filter(|t| matches(pattern, t))
+                      .map(Vec::clone)
+                      .collect()
+               })
     }
 }
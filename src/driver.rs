@@ -1,15 +1,26 @@
 use error::*;
 
+use advisor;
 use ast;
-use cache::ViewCache;
+use cache;
+use cache::{BindingPattern, ViewCache};
 use eval;
+use export;
+use graph;
 use lexer::Lexer;
+use limits;
 use storage;
+use value::Value;
+use parser;
 use parser::Parser;
+use sandbox::Sandbox;
+use spill;
 
 use colored::Colorize;
+use serde_json;
 
 use std;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Display;
 use std::io;
 use std::io::stdout;
@@ -19,7 +30,7 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 use std::sync::TryLockError::WouldBlock;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 fn abort<T: Display>(e: T) -> ! {
     eprintln!("Error: {}", e);
@@ -31,20 +42,313 @@ fn unwrap_or_abort<T, E: Display>(res: std::result::Result<T, E>) -> T {
 }
 
 #[derive(Copy, Clone)]
-#[allow(dead_code)]
 enum DriverMode {
     Interactive,
     Quiet
 }
 
+type Storage = storage::StorageEngine<eval::AstView>;
+
 static PROMPT: &'static str = "data-goblin> ";
 
+/// The temporary relation holding the answers to the most recent query.
+pub const LAST_RESULT: &'static str = "last_result";
+
+/// The most assertions `.undo` can reverse.
+const UNDO_LIMIT: usize = 100;
+
+/// Options the user can change with `.set <name> <on|off>`.
+#[derive(Default)]
+struct Settings {
+    /// After each query, index the columns of tables `advisor::advise` says
+    /// to.
+    auto_index: bool,
+    /// Skip asserting facts that are already in the database.
+    dedup: bool,
+    /// Sort query results, so that they are the same from run to run.
+    deterministic: bool,
+    /// Check facts and rules, and say what asserting them would do, without
+    /// asserting them.
+    dryrun: bool,
+    /// Print only the answers added or removed since a query last ran.
+    diff: bool,
+    /// Warn about rules whose bodies are probably slow or mistaken as they
+    /// are asserted; see `eval::lint`.
+    lint: bool,
+    /// After each query or assertion, print how long it took, and for queries
+    /// how many answers they had and whether they were answered from the
+    /// cache.
+    time: bool
+}
+
+impl Settings {
+    fn set(&mut self, name: &str, value: bool) -> Result<()> {
+        match name {
+            "auto_index" => self.auto_index = value,
+            "dedup" => self.dedup = value,
+            "deterministic" => self.deterministic = value,
+            "dryrun" => self.dryrun = value,
+            "diff" => self.diff = value,
+            "lint" => self.lint = value,
+            "time" => self.time = value,
+            other => return Err(Error::Command(
+                    format!("unknown setting: {}", other)))
+        }
+        Ok(())
+    }
+
+    fn show(&self) -> Vec<(&'static str, bool)> {
+        vec!(("auto_index", self.auto_index), ("dedup", self.dedup),
+             ("deterministic", self.deterministic),
+             ("dryrun", self.dryrun), ("diff", self.diff),
+             ("lint", self.lint), ("time", self.time))
+    }
+}
+
+/// Where query results are printed; see `.output`.
+enum Output {
+    Stdout,
+    File(io::BufWriter<std::fs::File>)
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout => stdout().write(buf),
+            Output::File(f) => f.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => stdout().flush(),
+            Output::File(f) => f.flush()
+        }
+    }
+}
+
+// Write one line of query results.
+fn write_line<W: Write>(out: &mut W, line: &str) -> Result<()> {
+    writeln!(out, "{}", line).map_err(write_error)
+}
+
+fn write_error(e: io::Error) -> Error {
+    Error::Command(format!("cannot write results: {}", e))
+}
+
+// Keep only the given variables of the frames of a query with the given
+// variables, in a canonical order if `deterministic` is set.
+fn arrange<'s>(frames: eval::Frames<'s, 's>,
+               variables: &[String],
+               deterministic: bool,
+               columns: &[String]) -> eval::Frames<'s, 's> {
+    let frames = if variables != columns {
+        eval::project(frames, columns)
+    } else {
+        frames
+    };
+    if deterministic {
+        eval::sorted(frames, columns)
+    } else {
+        frames
+    }
+}
+
+// Parse the value of a boolean setting.
+fn parse_switch(s: &str) -> Result<bool> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(Error::Command(
+                format!("expected \"on\" or \"off\", got {}", other)))
+    }
+}
+
+/// Run a single query against the database in `data_dir` and print the
+/// results, one tab-separated line per result after a header line of variable
+/// names.
+///
+/// Any parameters in the query (`$1`, `$2`, ...) are replaced by the
+/// corresponding entry of `params`; see `parser::parse_query_with_params`.
+pub fn execute(data_dir: String, query: &str, params: &[String])
+        -> Result<()> {
+    let query = parser::parse_query_with_params(query, params)?;
+    let columns = query.variables();
+    let engine = storage::StorageEngine::new(data_dir)?;
+    let mut cache = ViewCache::new();
+    eval::initialize_view_cache(&engine, &mut cache);
+
+    let frames = eval::query(&engine, &cache, query)?;
+    print_frames(&mut stdout(), &columns, frames)?;
+    spill::check()
+}
+
+// Print a header line of the given variables, then one tab-separated line of
+// their values per frame, returning the number of frames.
+fn print_frames<W: Write>(out: &mut W, columns: &[String], frames: eval::Frames)
+        -> Result<usize> {
+    write_line(out, &columns.join("\t"))?;
+    let mut count = 0;
+    for frame in frames {
+        write_values(out, columns.iter().map(|c| frame[c]))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Write values separated by tabs, as one line of query results. Each value is
+// written as it is formatted, rather than collected into a string first.
+fn write_values<'v, W, I>(out: &mut W, values: I) -> Result<()>
+        where W: Write, I: IntoIterator<Item = &'v Value> {
+    for (i, value) in values.into_iter().enumerate() {
+        let separator = if i == 0 { "" } else { "\t" };
+        write!(out, "{}{}", separator, value).map_err(write_error)?;
+    }
+    writeln!(out).map_err(write_error)
+}
+
+// Show answers one at a time in the terminal, moving on to the next when the
+// user enters ";", and return how many were shown.
+fn page_answers(columns: &[String], answers: &[Vec<Value>]) -> usize {
+    let mut shown = 0;
+    for answer in answers {
+        shown += 1;
+        for (i, (var, val)) in columns.iter().zip(answer).enumerate() {
+            print!("{}{:} {}", var.bright_black(), ":".bright_black(), val);
+            unwrap_or_abort(stdout().flush());
+            if i != columns.len() - 1 {
+                println!("");
+            }
+        }
+
+        let mut buf = String::new();
+        unwrap_or_abort(io::stdin().read_line(&mut buf));
+        println!("");
+        match buf.as_str() {
+            ";\n" => continue,
+            _ => break
+        }
+    }
+    shown
+}
+
+// The lines of `old` and `new` in order, marked "-" if only in `old`, "+" if
+// only in `new` and " " if in both, pairing up as many as possible.
+fn diff_lines<'t>(old: &'t str, new: &'t str) -> Vec<(char, &'t str)> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j] is the most lines `old[i..]` and `new[j..]` share, in
+    // order.
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(common[i + 1][j], common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len()
+                && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            lines.push(('+', new[j]));
+            j += 1;
+        } else {
+            lines.push(('-', old[i]));
+            i += 1;
+        }
+    }
+    lines
+}
+
+// The name of the relation a fact or rule with the given head is about.
+fn head_relation(head: &ast::Term) -> String {
+    match head {
+        ast::Term::Compound(c) => c.relation.clone(),
+        other => other.to_string()
+    }
+}
+
+// Record the rules of a view after a change to them in its history, as made
+// now by the user running data-goblin. A view that's gone has no rules.
+fn record_history(engine: &mut Storage, view: &str, change: String) {
+    let rules = eval::rules(engine, view).unwrap_or_default().iter()
+                                         .map(|r| r.to_string()).collect();
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs()).unwrap_or(0);
+    let user = std::env::var("USER").unwrap_or("unknown".to_string());
+    engine.record_version(view, storage::Version { time, user, change, rules });
+}
+
+// The given version, counting from 1, of a view's rules.
+fn history_version<'e>(engine: &'e Storage, view: &str, n: usize)
+        -> Result<&'e storage::Version> {
+    n.checked_sub(1).and_then(|i| engine.history(view).get(i)).ok_or_else(|| {
+        Error::Command(format!("{} has no version {}", view, n))
+    })
+}
+
+// A virtual table read from a file of tab-separated values, one fact per
+// line, like the output of a query. The file is read again for every scan.
+struct FileTable {
+    path: String,
+    arity: usize
+}
+
+impl FileTable {
+    fn read(path: &str) -> Result<Vec<Vec<Value>>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Command(format!("{}: {}", path, e)))?;
+        Ok(text.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+            line.split('\t').map(|v| parser::param_value(v.trim())).collect()
+        }).collect())
+    }
+}
+
+impl storage::VirtualTable for FileTable {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn facts(&self) -> Result<Vec<Vec<Value>>> {
+        FileTable::read(&self.path)
+    }
+}
+
+/// Reads lines of Datalog and meta-commands, and runs them one at a time.
+///
+/// Each line sees the effects of every line before it, and nothing else
+/// changes the database while a line runs. A background thread writes changes
+/// to disk, but never changes what is in memory; facts expired from tables
+/// with a limit (see `.retain`) are removed between lines, by the driver.
 pub struct Driver {
     lines: Box<Iterator<Item = ast::Line>>,
     storage: Arc<RwLock<storage::StorageEngine<eval::AstView>>>,
+    cache: ViewCache,
+    settings: Settings,
+    /// The most work each query may do; see `.limit`.
+    limits: limits::Limits,
+    output: Output,
     writer: std::thread::JoinHandle<()>,
     done: Arc<AtomicBool>,
-    mode: DriverMode
+    mode: DriverMode,
+    /// The number of queries run so far, to label script output.
+    queries: usize,
+    /// Saved queries run so far, so that each is only parsed once.
+    saved: HashMap<String, ast::Term>,
+    /// The most recent facts and rules asserted, newest last, for `.undo`.
+    undo: Vec<ast::Rule>,
+    /// The answers to each query the last time it ran with `.set diff on`, by
+    /// the text of the query.
+    answers: HashMap<String, BTreeSet<Vec<String>>>
 }
 
 impl Driver {
@@ -52,17 +356,39 @@ impl Driver {
         Self::from_reader(io::stdin(), data_dir, DriverMode::Interactive)
     }
 
-    pub fn run(self) {
-        print!("{}", PROMPT.bright_blue());
+    /// Run the script at `path` without prompting.
+    ///
+    /// The results of each query are printed after a line `#query <n>\t<query>`
+    /// (counting from 1), followed by a header line of variable names and one
+    /// tab-separated line per result.
+    pub fn from_script(path: &str, data_dir: String) -> Result<Driver> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| Error::Command(format!("{}: {}", path, e)))?;
+        Ok(Self::from_reader(file, data_dir, DriverMode::Quiet))
+    }
 
-        // TODO: Initially populate cache.
-        let mut cache = ViewCache::new();
+    /// Start with `.set auto_index on`.
+    pub fn auto_index(mut self) -> Driver {
+        self.settings.auto_index = true;
+        self
+    }
 
-        eval::initialize_view_cache(&self.storage.read().unwrap(), &mut cache);
+    pub fn run(mut self) {
+        // TODO: Initially populate cache.
+        eval::initialize_view_cache(&self.storage.read().unwrap(),
+                                    &mut self.cache);
 
-        stdout().flush().unwrap();
-        for line in self.lines {
-            Self::handle_line(self.storage.clone(), &mut cache, self.mode, line)
+        if let DriverMode::Interactive = self.mode {
+            print!("{}", PROMPT.bright_blue());
+            stdout().flush().unwrap();
+        }
+        while let Some(line) = self.lines.next() {
+            let handled = self.handle_line(line);
+            // Even a line that failed may have changed the database first.
+            let refreshed = self.refresh_materialized();
+            // No frame from the line is left to borrow spilled values.
+            self.cache.free_spilled();
+            handled.and(refreshed)
                 .unwrap_or_else(|e| {
                     eprintln!("{} {}", "Error:".bright_red(), e)
                 });
@@ -79,9 +405,17 @@ impl Driver {
 
         self.writer.join().unwrap();
 
-        self.storage.write().unwrap().write_back();
+        let mut engine = self.storage.write().unwrap();
+        if engine.is_frozen() {
+            warn!("thawing the database to write back changes before exiting");
+            engine.thaw();
+        }
+        engine.write_back();
     }
 
+    // Start a thread periodically writing changes back to disk. It only
+    // reads the database, so that the contents of relations (and so of the
+    // view cache) only change when the driver changes them.
     fn make_writer(engine: Arc<RwLock<storage::StorageEngine<eval::AstView>>>,
                    done: Arc<AtomicBool>)
             -> std::thread::JoinHandle<()> {
@@ -89,7 +423,8 @@ impl Driver {
             while !done.load(Ordering::Relaxed) {
                 match engine.try_read() {
                     Ok(guard) => guard.write_back(),
-                    Err(WouldBlock) => (),
+                    Err(WouldBlock) =>
+                        trace!("database busy; skipping write-back"),
                     Err(_) => panic!("poisoned engine lock")
                 };
                 std::thread::sleep(Duration::from_millis(250));
@@ -117,45 +452,1595 @@ impl Driver {
 
         let writer = Self::make_writer(storage.clone(), done.clone());
 
-        Driver { lines, storage, writer, done, mode }
+        let cache = ViewCache::new();
+
+        let settings = Settings::default();
+
+        Driver { lines, storage, cache, settings,
+                 limits: limits::Limits::default(), output: Output::Stdout,
+                 writer, done, mode, queries: 0, saved: HashMap::new(),
+                 undo: Vec::new(), answers: HashMap::new() }
     }
 
-    fn handle_line(storage: Arc<RwLock<storage::StorageEngine<eval::AstView>>>,
-                   cache: &mut ViewCache,
-                   mode: DriverMode,
-                   line: ast::Line) -> Result<()> {
-        Ok(match line {
+    fn handle_line(&mut self, line: ast::Line) -> Result<()> {
+        // Expire facts from tables over their limit before the line runs, so
+        // that it sees them gone from both the tables and the views cached
+        // from them.
+        {
+            let mut engine = self.storage.write().unwrap();
+            engine.expire();
+            for name in engine.take_expired() {
+                self.cache.invalidate(&name);
+            }
+            // Virtual tables may have changed since the last line, too.
+            for name in engine.virtual_tables() {
+                self.cache.invalidate(name);
+            }
+            eval::refresh_materialized(&mut engine, &self.cache)?;
+        }
+
+        let start = Instant::now();
+        let query = match line {
+            ast::Line::Query(_)
+                | ast::Line::Select(..)
+                | ast::Line::Using(..)
+                | ast::Line::Within(..) => true,
+            _ => false
+        };
+        match line {
             ast::Line::Query(t) => {
-                match mode {
-                    DriverMode::Quiet => (),
-                    DriverMode::Interactive => {
-                        let engine = &storage.read().unwrap();
-                        for frame in eval::query(engine, cache, t)? {
-                            let l = frame.len();
-                            for (i, (var, val)) in frame.iter().enumerate() {
-                                print!("{}{:} {}", var.bright_black(),
-                                                   ":".bright_black(),
-                                                   val);
-                                unwrap_or_abort(stdout().flush());
-                                if i != l - 1 {
-                                    println!("");
-                                }
-                            }
-
-                            let mut buf = String::new();
-                            unwrap_or_abort(io::stdin().read_line(&mut buf));
-                            println!("");
-                            match buf.as_str() {
-                                ";\n" => continue,
-                                _ => break
-                            }
-                        }
-                    }
+                let columns = t.variables();
+                let hit = self.cache_hit(&t);
+                let rows = self.print_query(format!("{}?", t), t, columns,
+                                            &Default::default())?;
+                self.report_time(start, Some((rows, hit)));
+            },
+            ast::Line::Select(t, columns) => {
+                let text = format!("{}? select {}", t, columns.join(", "));
+                let hit = self.cache_hit(&t);
+                let rows = self.print_query(text, t, columns,
+                                            &Default::default())?;
+                self.report_time(start, Some((rows, hit)));
+            },
+            ast::Line::Using(t, options) => {
+                let mut hints = vec!(if options.semi_naive {
+                    "seminaive"
+                } else {
+                    "naive"
+                });
+                if options.no_cache {
+                    hints.push("no_cache");
                 }
+                let text = format!("{}? using {}", t, hints.join(", "));
+                let columns = t.variables();
+                let hit = self.cache_hit(&t)
+                              .map(|hit| hit && !options.no_cache);
+                let rows = self.print_query(text, t, columns, &options)?;
+                self.report_time(start, Some((rows, hit)));
+            },
+            ast::Line::Within(t, budget) => {
+                let text = format!("{}? within {}ms", t,
+                                   limits::millis(budget));
+                let columns = t.variables();
+                let hit = self.cache_hit(&t);
+                let limits = self.limits;
+                self.limits.budget = Some(budget);
+                let rows = self.print_query(text, t, columns,
+                                            &Default::default());
+                self.limits = limits;
+                self.report_time(start, Some((rows?, hit)));
+            },
+            ast::Line::Rule(ref r) | ast::Line::Redefine(ref r)
+                    if self.settings.dryrun => {
+                let replace = match line {
+                    ast::Line::Redefine(_) => true,
+                    _ => false
+                };
+                let engine = self.storage.read().unwrap();
+                println!("{}", eval::check_assert(&engine, &self.cache, r,
+                                                   replace)?);
             },
             ast::Line::Rule(r) => {
-                eval::assert(&mut storage.write().unwrap(), cache, r)?
+                let mut engine = self.storage.write().unwrap();
+                if self.settings.dedup && r.body.is_empty()
+                        && eval::is_known(&engine, &self.cache, &r.head)? {
+                    self.note(&format!("already known: {}", r));
+                    return Ok(());
+                }
+                eval::assert(&mut engine, &mut self.cache, r.clone())?;
+                self.warn_lint(&engine, &r);
+                if !r.body.is_empty() {
+                    record_history(&mut engine, &head_relation(&r.head),
+                                   r.to_string());
+                }
+                if self.undo.len() == UNDO_LIMIT {
+                    self.undo.remove(0);
+                }
+                self.undo.push(r);
+                self.report_time(start, None);
+            },
+            ast::Line::Redefine(r) => {
+                let mut engine = self.storage.write().unwrap();
+                eval::redefine(&mut engine, &mut self.cache, r.clone())?;
+                self.warn_lint(&engine, &r);
+                record_history(&mut engine, &head_relation(&r.head),
+                               format!("redefine {}", r));
+                self.report_time(start, None);
+            },
+            ast::Line::Count(t) => {
+                self.label(&format!("count {}?", t))?;
+                let engine = self.storage.read().unwrap();
+                let count = eval::count(&engine, &self.cache, t)?;
+                write_line(&mut self.output, &count.to_string())?;
+                self.report_time(start, Some((1, None)));
+            },
+            ast::Line::CountDerivations(t) => {
+                self.label(&format!("count_derivations {}?", t))?;
+                let mut columns = t.variables();
+                let engine = self.storage.read().unwrap();
+                let answers = eval::count_derivations(&engine, t)?;
+                columns.push("derivations".to_string());
+                write_line(&mut self.output, &columns.join("\t"))?;
+                for (answer, count) in answers {
+                    let mut values: Vec<String> =
+                        answer.iter().map(|v| v.to_string()).collect();
+                    values.push(count.to_string());
+                    write_line(&mut self.output, &values.join("\t"))?;
+                }
+            },
+            ast::Line::Not(t) => {
+                self.label(&format!("not {}?", t))?;
+                let engine = self.storage.read().unwrap();
+                let absent = eval::absent(&engine, &self.cache, t)?;
+                write_line(&mut self.output, &absent.to_string())?;
+            },
+            ast::Line::With(rules, t) => {
+                let rules_text: Vec<String> =
+                    rules.iter().map(|r| r.to_string()).collect();
+                self.label(&format!("with {} {}?", rules_text.join(" "), t))?;
+                let columns = t.variables();
+                let deterministic = self.settings.deterministic;
+                let output = &mut self.output;
+                let mut engine = self.storage.write().unwrap();
+                let rows = eval::query_with(&mut engine, rules, t, |frames| {
+                    if deterministic {
+                        print_frames(output, &columns,
+                                     eval::sorted(frames, &columns))
+                    } else {
+                        print_frames(output, &columns, frames)
+                    }
+                })??;
+                self.report_time(start, Some((rows, None)));
+            },
+            ast::Line::Capture(t, name) => {
+                let mut engine = self.storage.write().unwrap();
+                let count = eval::capture(&mut engine, &mut self.cache, t,
+                                          name.clone())?;
+                self.note(&format!("stored {} answers in {}", count, name));
+            },
+            ast::Line::Ref(from, to) => {
+                let mut engine = self.storage.write().unwrap();
+                eval::add_reference(&mut engine, &self.cache, from, to)?
+            },
+            ast::Line::Trigger(pattern, actions) => {
+                let mut engine = self.storage.write().unwrap();
+                eval::add_trigger(&mut engine, pattern, actions)?
+            },
+            ast::Line::Command(c) => self.handle_command(c)?
+        };
+        if query && self.settings.auto_index {
+            self.apply_advice()?;
+        }
+        self.output.flush().map_err(write_error)
+    }
+
+    // Evaluate the materialized views outdated since they were last
+    // refreshed, so that what is written back matches their rules.
+    fn refresh_materialized(&mut self) -> Result<()> {
+        let mut engine = self.storage.write().unwrap();
+        eval::refresh_materialized(&mut engine, &self.cache)
+    }
+
+    // Index each column `advisor::advise` says to, noting each one.
+    fn apply_advice(&mut self) -> Result<()> {
+        let advice: Vec<advisor::Advice> =
+            advisor::advise(&self.storage.read().unwrap()).into_iter()
+                .filter(|a| a.decision == advisor::Decision::Index)
+                .collect();
+        if advice.is_empty() {
+            return Ok(());
+        }
+        let mut engine = self.storage.write().unwrap();
+        for a in advice {
+            eval::set_index(&mut engine, &a.relation, a.column, true)?;
+            self.note(&format!("indexed {} column {} after {} lookups",
+                               a.relation, a.column + 1, a.lookups));
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: ast::Command) -> Result<()> {
+        debug!("running .{} {}", command.name, command.args.join(" "));
+        match command.name.as_str() {
+            "export_query" => self.export_query(&command.args),
+            "set" => self.set(&command.args),
+            "limit" => self.limit(&command.args),
+            "join" => self.join(&command.args),
+            "graph" => self.graph(&command.args),
+            "output" => self.output(&command.args),
+            "dryrun" => self.dryrun(&command.args),
+            "diff" => self.diff(&command.args),
+            "time" => self.time(&command.args),
+            "rules" => self.rules(&command.args),
+            "lint" => self.lint(&command.args),
+            "fixpoint" => self.fixpoint(&command.args),
+            "tables" => self.tables(&command.args),
+            "namespaces" => self.namespaces(&command.args),
+            "dependents" => self.dependents(&command.args),
+            "dependency_graph" => self.dependency_graph(&command.args),
+            "cycles" => self.cycles(&command.args),
+            "strata" => self.strata(&command.args),
+            "describe" => self.describe(&command.args),
+            "infer_schema" => self.infer_schema(&command.args),
+            "check_refs" => self.check_refs(&command.args),
+            "explain_analyze" => self.explain_analyze(&command.args),
+            "subsume" => self.subsume(&command.args),
+            "rules_file" => self.rules_file(&command.args),
+            "retract_rule" => self.retract_rule(&command.args),
+            "undo" => self.undo(&command.args),
+            "history" => self.history(&command.args),
+            "revert" => self.revert(&command.args),
+            "convert" => self.convert(&command.args),
+            "vacuum" => self.vacuum(&command.args),
+            "flush" => self.flush(&command.args),
+            "freeze" => self.freeze(&command.args),
+            "thaw" => self.thaw(&command.args),
+            "verify" => self.verify(&command.args),
+            "retain" => self.retain(&command.args),
+            "alter" => self.alter(&command.args),
+            "load" => self.load(&command.args),
+            "attach" => self.attach(&command.args),
+            "detach" => self.detach(&command.args),
+            "create_table" => self.create_table(&command.args),
+            "save_query" => self.save_query(&command.args),
+            "run" => self.run_saved(&command.args),
+            "queries" => self.queries(&command.args),
+            "plan_baseline" => self.plan_baseline(&command.args),
+            "sandbox" => self.sandbox(&command.args),
+            "index" => self.index(&command.args),
+            "advisor" => self.advisor(&command.args),
+            "materialize" => self.materialize(&command.args),
+            "copy" => self.copy(&command.args),
+            other => Err(Error::Command(format!("unknown command: .{}",
+                                                other)))
+        }
+    }
+
+    // Run a query, printing the values of the given variables for each
+    // answer, and return how many were printed. In the terminal, answers are
+    // shown one at a time; otherwise all are printed after a header line,
+    // labelled in scripts by `text`. The answers are then stored in
+    // `LAST_RESULT`.
+    fn print_query(&mut self, text: String, t: ast::Term, columns: Vec<String>,
+                   options: &ast::QueryOptions) -> Result<usize> {
+        if self.settings.diff {
+            return self.print_diff(text, t, columns, options);
+        }
+        let interactive = self.interactive();
+        if !interactive {
+            self.label(&text)?;
+        }
+        // Answers are shown at the user's pace, so collect them all first
+        // rather than holding the lock while waiting on them.
+        let (answers, partial) =
+            self.answers_to(t, &columns, self.settings.deterministic,
+                            options)?;
+        let shown = if interactive {
+            page_answers(&columns, &answers)
+        } else {
+            write_line(&mut self.output, &columns.join("\t"))?;
+            for answer in &answers {
+                write_values(&mut self.output, answer)?;
             }
+            answers.len()
+        };
+        if let Some(partial) = partial {
+            self.report_partial(&partial)?;
+        }
+        self.store_last_result(&columns, answers)?;
+        Ok(shown)
+    }
+
+    // Are query results being shown to a user at the terminal?
+    fn interactive(&self) -> bool {
+        match (self.mode, &self.output) {
+            (DriverMode::Interactive, Output::Stdout) => true,
+            _ => false
+        }
+    }
+
+    // The answers to a query, as the values of the given variables in order,
+    // and how the query was cut short if its budget ran out.
+    fn answers_to(&self, t: ast::Term, columns: &[String],
+                  deterministic: bool, options: &ast::QueryOptions)
+            -> Result<(Vec<Vec<Value>>, Option<limits::Partial>)> {
+        let engine = &self.storage.read().unwrap();
+        let variables = t.variables();
+        eval::query_partial(engine, &self.cache, t, options, &self.limits,
+                            |frames| {
+            arrange(frames, &variables, deterministic, columns).map(|frame| {
+                columns.iter().map(|c| frame[c].clone()).collect()
+            }).collect()
         })
     }
+
+    // Say that the answers just shown are only some of a query's: at the
+    // terminal as a warning, and otherwise in a line after them starting with
+    // `#`, like the label before them, so that tools reading them can tell.
+    fn report_partial(&mut self, partial: &limits::Partial) -> Result<()> {
+        if self.interactive() {
+            eprintln!("{} {}", "warning:".yellow(), partial);
+            Ok(())
+        } else {
+            write_line(&mut self.output, &format!("#{}", partial))
+        }
+    }
+
+    // Replace the answers in `LAST_RESULT` with the given answers to a query,
+    // unless some relation in the database already has that name. Queries
+    // without variables leave it as it was.
+    fn store_last_result(&mut self, columns: &[String],
+                         answers: Vec<Vec<Value>>) -> Result<()> {
+        if columns.is_empty() {
+            return Ok(());
+        }
+        let mut engine = self.storage.write().unwrap();
+        if engine.get_relation(LAST_RESULT).is_some()
+                && !engine.is_scratch(LAST_RESULT) {
+            trace!("{} is a relation; not storing query results", LAST_RESULT);
+            return Ok(());
+        }
+        eval::store(&mut engine, &mut self.cache, LAST_RESULT.to_string(),
+                    columns.len(), answers).map(|_| ())
+    }
+
+    // Print the answers to a query with `.set diff on`: all of them the first
+    // time it runs, in order, and after that only those added (marked "+") or
+    // removed ("-") since it last ran. Returns the number of answers.
+    fn print_diff(&mut self, text: String, t: ast::Term, columns: Vec<String>,
+                  options: &ast::QueryOptions) -> Result<usize> {
+        self.label(&text)?;
+        let (values, partial) = self.answers_to(t, &columns, false, options)?;
+        let answers: BTreeSet<Vec<String>> = values.iter().map(|answer| {
+            answer.iter().map(|v| v.to_string()).collect()
+        }).collect();
+        self.store_last_result(&columns, values)?;
+
+        match self.answers.insert(text, answers.clone()) {
+            Some(previous) => {
+                write_line(&mut self.output,
+                           &format!("diff\t{}", columns.join("\t")))?;
+                for answer in previous.difference(&answers) {
+                    write_line(&mut self.output,
+                               &format!("-\t{}", answer.join("\t")))?;
+                }
+                for answer in answers.difference(&previous) {
+                    write_line(&mut self.output,
+                               &format!("+\t{}", answer.join("\t")))?;
+                }
+            },
+            None => {
+                write_line(&mut self.output, &columns.join("\t"))?;
+                for answer in &answers {
+                    write_line(&mut self.output, &answer.join("\t"))?;
+                }
+            }
+        }
+        if let Some(partial) = partial {
+            self.report_partial(&partial)?;
+        }
+        Ok(answers.len())
+    }
+
+    // Whether a query will be answered from the view cache, or `None` if it
+    // isn't a query against a view.
+    fn cache_hit(&self, t: &ast::Term) -> Option<bool> {
+        let c = match t {
+            ast::Term::Compound(c) => c,
+            _ => return None
+        };
+        match self.storage.read().unwrap().get_relation(&c.relation) {
+            Some(storage::Relation::Intension(_)) => (),
+            _ => return None
+        }
+        let pattern: BindingPattern = c.params.iter().map(|p| match p {
+            ast::AtomicTerm::Atom(a) => Some(a.clone()),
+            ast::AtomicTerm::Variable(_) => None
+        }).collect();
+        Some(self.cache.is_cached(&c.relation, &pattern))
+    }
+
+    // With `.set time on`, print how long a line took since `start`, and for
+    // a query, how many answers it had and whether it hit the cache. Timings
+    // go to standard error, so they don't end up in saved results.
+    fn report_time(&self,
+                   start: Instant,
+                   query: Option<(usize, Option<bool>)>) {
+        if !self.settings.time {
+            return;
+        }
+        let elapsed = start.elapsed();
+        let millis = elapsed.as_secs() as f64 * 1e3
+                   + elapsed.subsec_nanos() as f64 / 1e6;
+        let mut report = format!("time={:.3}ms", millis);
+        if let Some((rows, hit)) = query {
+            report.push_str(&format!(" rows={}", rows));
+            if let Some(hit) = hit {
+                report.push_str(if hit { " cache=hit" } else { " cache=miss" });
+            }
+        }
+        eprintln!("{}", report.bright_black());
+    }
+
+    // In a script, mark the start of a query's results with its number and
+    // text.
+    fn label(&mut self, query: &str) -> Result<()> {
+        if let DriverMode::Quiet = self.mode {
+            self.queries += 1;
+            let label = format!("#query {}\t{}", self.queries, query);
+            write_line(&mut self.output, &label)?;
+        }
+        Ok(())
+    }
+
+    // Warn about a rule just asserted that can never hold, and with `.set lint
+    // on`, about anything else `eval::lint` finds.
+    fn warn_lint(&self, engine: &Storage, rule: &ast::Rule) {
+        if let Some(reason) = eval::contradiction(engine, &rule.body) {
+            eprintln!("{} {}: {}", "warning:".yellow(), rule, reason);
+        }
+        if !self.settings.lint {
+            return;
+        }
+        for warning in eval::lint(rule) {
+            eprintln!("{} {}: {}", "warning:".yellow(), rule, warning);
+        }
+    }
+
+    // Tell an interactive user something about the line they just entered.
+    fn note(&self, message: &str) {
+        match self.mode {
+            DriverMode::Quiet => (),
+            DriverMode::Interactive => println!("{}", message.bright_black())
+        }
+    }
+
+    /// `.output <file>`: print query results to a file, replacing its contents,
+    /// instead of to the terminal. `.output -` goes back to the terminal.
+    fn output(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command("usage: .output <file|->".to_string()));
+        }
+
+        self.output.flush().map_err(write_error)?;
+        self.output = if args[0] == "-" {
+            Output::Stdout
+        } else {
+            let file = std::fs::File::create(&args[0])
+                .map_err(|e| Error::Command(format!("{}: {}", args[0], e)))?;
+            Output::File(io::BufWriter::new(file))
+        };
+        Ok(())
+    }
+
+    /// `.set [<name> <on|off>]`: change a setting, or with no arguments, show
+    /// all of them.
+    fn set(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [] => {
+                for (name, value) in self.settings.show() {
+                    println!("{}: {}", name, if value { "on" } else { "off" });
+                }
+                Ok(())
+            },
+            [name, value] => self.settings.set(name, parse_switch(value)?),
+            _ => Err(Error::Command(
+                    "usage: .set [<name> <on|off>]".to_string()))
+        }
+    }
+
+    /// `.limit [<rows|derived|time> <n|off>]`: limit the work each query may
+    /// do, with times in milliseconds, or show the limits; see `limits`.
+    fn limit(&mut self, args: &[String]) -> Result<()> {
+        let show = |max: Option<u64>| {
+            max.map_or("off".to_string(), |n| n.to_string())
+        };
+        match args {
+            [] => {
+                let limits = &self.limits;
+                println!("rows: {}", show(limits.max_rows.map(|n| n as u64)));
+                println!("derived: {}",
+                         show(limits.max_derived.map(|n| n as u64)));
+                println!("time: {}", show(limits.timeout.map(limits::millis)));
+                Ok(())
+            },
+            [name, value] => {
+                let max = match value.as_str() {
+                    "off" => None,
+                    n => Some(n.parse::<u64>().map_err(|_| {
+                        Error::Command(format!(
+                            "expected a number or \"off\", got {}", n))
+                    })?)
+                };
+                match name.as_str() {
+                    "rows" => self.limits.max_rows = max.map(|n| n as usize),
+                    "derived" =>
+                        self.limits.max_derived = max.map(|n| n as usize),
+                    "time" =>
+                        self.limits.timeout = max.map(Duration::from_millis),
+                    other => return Err(Error::Command(
+                            format!("unknown limit: {}", other)))
+                }
+                Ok(())
+            },
+            _ => Err(Error::Command(
+                    "usage: .limit [<rows|derived|time> <n|off>]".to_string()))
+        }
+    }
+
+    /// `.join [<nested_loop|budget|partitions> <n>]`: change when joins
+    /// switch strategies, or show the thresholds; see `spill`. `nested_loop`
+    /// and `budget` are numbers of frames, not bytes.
+    fn join(&mut self, args: &[String]) -> Result<()> {
+        let mut config = spill::config();
+        match args {
+            [] => {
+                println!("nested_loop: {} frames", config.nested_loop_max);
+                println!("budget: {} frames", config.memory_budget);
+                println!("partitions: {}", config.partitions);
+                Ok(())
+            },
+            [name, value] => {
+                let n = value.parse::<usize>().map_err(|_| {
+                    Error::Command(format!("expected a number, got {}", value))
+                })?;
+                match name.as_str() {
+                    "nested_loop" => config.nested_loop_max = n,
+                    "budget" => config.memory_budget = n,
+                    "partitions" if n > 0 => config.partitions = n,
+                    "partitions" => return Err(Error::Command(
+                            "joins need at least one partition".to_string())),
+                    other => return Err(Error::Command(
+                            format!("unknown join threshold: {}", other)))
+                }
+                spill::configure(config);
+                Ok(())
+            },
+            _ => Err(Error::Command(
+                    "usage: .join [<nested_loop|budget|partitions> <n>] \
+                     (nested_loop and budget count frames, not bytes)"
+                        .to_string()))
+        }
+    }
+
+    /// `.graph [<table>]`: make the path built-ins read a table of edges, or
+    /// show the one they read; see `graph`.
+    fn graph(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [] => {
+                println!("{}", graph::edges());
+                Ok(())
+            },
+            [name] => {
+                graph::designate(name);
+                // Views using the path built-ins depend on every relation.
+                self.cache.invalidate(cache::EVERY_RELATION);
+                Ok(())
+            },
+            _ => Err(Error::Command("usage: .graph [<table>]".to_string()))
+        }
+    }
+
+    /// `.dryrun <on|off>`: the same as `.set dryrun <on|off>`.
+    fn dryrun(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [value] => self.settings.set("dryrun", parse_switch(value)?),
+            _ => Err(Error::Command("usage: .dryrun <on|off>".to_string()))
+        }
+    }
+
+    /// `.diff <on|off>`: the same as `.set diff <on|off>`.
+    fn diff(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [value] => self.settings.set("diff", parse_switch(value)?),
+            _ => Err(Error::Command("usage: .diff <on|off>".to_string()))
+        }
+    }
+
+    /// `.time <on|off>`: the same as `.set time <on|off>`.
+    fn time(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [value] => self.settings.set("time", parse_switch(value)?),
+            _ => Err(Error::Command("usage: .time <on|off>".to_string()))
+        }
+    }
+
+    /// `.rules <view>`: list the rules defining a view, numbered from 1.
+    fn rules(&self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command("usage: .rules <view>".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        for (i, rule) in eval::rules(&engine, &args[0])?.iter().enumerate() {
+            println!("{}: {}", i + 1, rule);
+        }
+        Ok(())
+    }
+
+    /// `.lint [<view>]`: warn about the rules of a view, or of every view,
+    /// whose bodies can never hold or are probably slow or mistaken; see
+    /// `eval::contradiction` and `eval::lint`.
+    fn lint(&self, args: &[String]) -> Result<()> {
+        let engine = self.storage.read().unwrap();
+        let mut views: Vec<&str> = match args {
+            [] => engine.get_relations().into_iter().filter(|name| {
+                match engine.get_relation(name) {
+                    Some(storage::Relation::Intension(_)) => true,
+                    _ => false
+                }
+            }).collect(),
+            [view] => vec!(view.as_str()),
+            _ => return Err(Error::Command(
+                    "usage: .lint [<view>]".to_string()))
+        };
+        views.sort();
+
+        let mut warned = false;
+        for view in views {
+            for rule in eval::rules(&engine, view)? {
+                let contradiction = eval::contradiction(&engine, &rule.body);
+                for warning in contradiction.into_iter()
+                                            .chain(eval::lint(&rule)) {
+                    println!("{}\t{}", rule, warning);
+                    warned = true;
+                }
+            }
+        }
+        if !warned {
+            self.note("no warnings");
+        }
+        Ok(())
+    }
+
+    /// `.fixpoint <view> [naive|semi_naive]`: evaluate a view, showing how
+    /// many tuples each iteration derived, how many recursive rules it applied
+    /// and how many tuples of the view those rules joined against.
+    fn fixpoint(&self, args: &[String]) -> Result<()> {
+        let semi_naive = match args.get(1).map(String::as_str) {
+            _ if args.is_empty() || args.len() > 2 => None,
+            None | Some("naive") => Some(false),
+            Some("semi_naive") => Some(true),
+            Some(_) => None
+        }.ok_or(Error::Command(
+                "usage: .fixpoint <view> [naive|semi_naive]".to_string()))?;
+
+        let engine = self.storage.read().unwrap();
+        let stats = eval::fixpoint_stats(&engine, &self.cache, &args[0],
+                                         semi_naive)?;
+        println!("iteration\tdelta\trules\tjoin_inputs");
+        for (i, iteration) in stats.iter().enumerate() {
+            println!("{}\t{}\t{}\t{}", i, iteration.delta,
+                     iteration.rules_applied, iteration.join_inputs);
+        }
+        Ok(())
+    }
+
+    /// `.tables [<namespace>]`: list the relations in the database, or just
+    /// those directly in a namespace, with their arity, whether each is a
+    /// table or a view, the facts in each table and cached view, and any
+    /// description.
+    fn tables(&self, args: &[String]) -> Result<()> {
+        if args.len() > 1 {
+            return Err(Error::Command(
+                "usage: .tables [<namespace>]".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        let mut names = match args.first() {
+            Some(namespace) => {
+                let mut names = engine.relations_in(namespace);
+                names.extend(engine.virtual_tables().into_iter().filter(|n| {
+                    storage::namespace_of(n) == namespace.as_str()
+                }));
+                names
+            },
+            None => {
+                let mut names = engine.get_relations();
+                names.extend(engine.virtual_tables());
+                names
+            }
+        };
+        names.sort();
+        println!("relation\tkind\trows\tdescription");
+        for name in names {
+            if let Some(table) = engine.virtual_table(name) {
+                println!("{}/{}\tvirtual\t-\t-", name, table.arity());
+                continue;
+            }
+            let (kind, arity, rows) = match engine.get_relation(name) {
+                Some(storage::Relation::Extension(table)) => {
+                    let kind = if engine.is_scratch(name) {
+                        "temporary"
+                    } else {
+                        "table"
+                    };
+                    (kind, Some(table.arity()), Some(table.len()))
+                },
+                Some(storage::Relation::Intension(view)) => {
+                    if let Some(table) = engine.materialization(name) {
+                        ("materialized", view.arity(), Some(table.len()))
+                    } else {
+                        let rows = view.arity().and_then(|arity| {
+                            self.cache.cached_len(name, &vec![None; arity])
+                        });
+                        ("view", view.arity(), rows)
+                    }
+                },
+                None => continue
+            };
+            let arity = arity.map_or("?".to_string(), |a| a.to_string());
+            let rows = rows.map_or("-".to_string(), |r| r.to_string());
+            println!("{}/{}\t{}\t{}\t{}", name, arity, kind, rows,
+                     engine.description(name).unwrap_or("-"));
+        }
+        Ok(())
+    }
+
+    /// `.namespaces`: list the namespaces with relations directly in them.
+    fn namespaces(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .namespaces".to_string()));
+        }
+
+        for namespace in self.storage.read().unwrap().namespaces() {
+            println!("{}", namespace);
+        }
+        Ok(())
+    }
+
+    /// `.dependents <relation>`: list the views whose contents depend on a
+    /// relation, directly or through other views.
+    fn dependents(&self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command(
+                "usage: .dependents <relation>".to_string()));
+        }
+
+        for dependent in self.cache.dependents(&args[0]) {
+            println!("{}", dependent);
+        }
+        Ok(())
+    }
+
+    /// `.dependency_graph [<file>]`: write which views use each relation
+    /// directly, as a JSON object mapping relation names to lists of views, to
+    /// a file or the terminal.
+    fn dependency_graph(&self, args: &[String]) -> Result<()> {
+        let graph = self.cache.dependency_graph();
+        let result = match args {
+            [] => serde_json::to_writer_pretty(stdout(), &graph)
+                .map(|()| println!("")),
+            [path] => {
+                let file = std::fs::File::create(path)
+                    .map_err(|e| Error::Command(format!("{}: {}", path, e)))?;
+                serde_json::to_writer_pretty(file, &graph)
+            },
+            _ => return Err(Error::Command(
+                    "usage: .dependency_graph [<file>]".to_string()))
+        };
+        result.map_err(|e| Error::Command(e.to_string()))
+    }
+
+    /// `.strata [<relation>]`: list the strata of the views, or of those a
+    /// relation depends on, in the order they are evaluated, one line of view
+    /// names per stratum.
+    fn strata(&self, args: &[String]) -> Result<()> {
+        let engine = self.storage.read().unwrap();
+        let strata = eval::evaluation_order(&engine)?;
+        let strata = match args {
+            [] => strata.strata().iter().collect(),
+            [relation] => strata.needed_by(relation),
+            _ => return Err(Error::Command(
+                    "usage: .strata [<relation>]".to_string()))
+        };
+        if strata.is_empty() {
+            self.note("no views");
+        }
+        for stratum in strata {
+            let recursive = if stratum.recursive { " (recursive)" } else { "" };
+            println!("{}{}", stratum.views.join(", "), recursive);
+        }
+        Ok(())
+    }
+
+    /// `.cycles`: list each group of mutually recursive views, with the rules
+    /// that make it recursive: those using a view in the same group.
+    fn cycles(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .cycles".to_string()));
+        }
+
+        let cycles = self.cache.cycles();
+        if cycles.is_empty() {
+            self.note("no recursive views");
+        }
+        let engine = self.storage.read().unwrap();
+        for cycle in cycles {
+            println!("{}", cycle.join(", "));
+            for view in &cycle {
+                for rule in eval::rules(&engine, view)? {
+                    let recursive = rule.body.iter().any(|term| {
+                        let term = match term {
+                            ast::Term::Not(t) => &**t,
+                            t => t
+                        };
+                        match term {
+                            ast::Term::Compound(c) =>
+                                cycle.contains(&c.relation),
+                            _ => false
+                        }
+                    });
+                    if recursive {
+                        println!("  {}", rule);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `.describe <relation> ["<description>"]`: attach a description to a
+    /// relation, stored with it, or with no description, show its current one.
+    fn describe(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [name] => {
+                let engine = self.storage.read().unwrap();
+                match engine.description(name) {
+                    Some(description) => println!("{}", description),
+                    None => self.note(&format!("{} has no description", name))
+                }
+                Ok(())
+            },
+            [name, description] => self.storage.write().unwrap()
+                .describe(name, description.clone()),
+            _ => Err(Error::Command(
+                "usage: .describe <relation> [\"<description>\"]".to_string()))
+        }
+    }
+
+    /// `.infer_schema <table>`: guess the type of each column of a table,
+    /// showing one line per column with its type, number of distinct values,
+    /// common prefix and number of null markers.
+    fn infer_schema(&self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command(
+                "usage: .infer_schema <table>".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        let columns = eval::infer_schema(&engine, &args[0])?;
+        println!("column\ttype\tdistinct\tprefix\tnulls");
+        for (i, column) in columns.iter().enumerate() {
+            println!("{}\t{}\t{}\t{}\t{}", i + 1, column.kind, column.distinct,
+                     column.prefix.as_ref().map_or("-", String::as_str),
+                     column.nulls);
+        }
+        Ok(())
+    }
+
+    /// `.check_refs`: check every fact against the references declared with
+    /// `ref`, showing each fact that breaks one.
+    fn check_refs(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .check_refs".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        let broken = eval::check_references(&engine, &self.cache)?;
+        if broken.is_empty() {
+            self.note("all references hold");
+        }
+        for fact in broken {
+            println!("{}", fact);
+        }
+        Ok(())
+    }
+
+    /// `.explain_analyze <query>`: run a query, showing the frames produced by
+    /// and time spent in the scan of each term planned along the way, and
+    /// whether views were answered from the cache.
+    fn explain_analyze(&self, args: &[String]) -> Result<()> {
+        if args.is_empty() {
+            return Err(Error::Command(
+                "usage: .explain_analyze <query>".to_string()));
+        }
+
+        let query = parser::parse_query(&args.join(" "))?;
+        let engine = self.storage.read().unwrap();
+        for node in eval::explain_analyze(&engine, &self.cache, query)? {
+            print!("{}", node.borrow());
+        }
+        Ok(())
+    }
+
+    /// `.subsume <view> <column> <min|max>` or `.subsume <view> off`: while
+    /// evaluating a recursive view, keep only the tuple with the smallest or
+    /// largest value in a column (numbered from 1) among those agreeing on
+    /// every other column.
+    fn subsume(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .subsume <view> <column> <min|max> | .subsume <view> off"
+                .to_string());
+        let subsumption = match args {
+            [_, off] if off == "off" => None,
+            [_, column, keep] => Some(
+                eval::Subsumption::parse(column, keep).ok_or_else(usage)?),
+            _ => return Err(usage())
+        };
+
+        let mut engine = self.storage.write().unwrap();
+        eval::set_subsumption(&mut engine, &mut self.cache, &args[0],
+                              subsumption)
+    }
+
+    /// `.rules_file [<on|off>]`: keep every view in the database's rules file,
+    /// or each in a file of its own, or with no argument, say which; see
+    /// `StorageEngine::set_rules_file`.
+    fn rules_file(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [] => {
+                let on = self.storage.read().unwrap().rules_file();
+                println!("{}", if on { "on" } else { "off" });
+                Ok(())
+            },
+            [value] => self.storage.write().unwrap()
+                .set_rules_file(parse_switch(value)?),
+            _ => Err(Error::Command(
+                "usage: .rules_file [<on|off>]".to_string()))
+        }
+    }
+
+    /// `.retract_rule <view> <n>`: remove the nth rule (as numbered by
+    /// `.rules`) from a view.
+    fn retract_rule(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .retract_rule <view> <n>".to_string());
+        if args.len() != 2 {
+            return Err(usage());
+        }
+        let n: usize = args[1].parse().map_err(|_| usage())?;
+        if n == 0 {
+            return Err(usage());
+        }
+
+        let mut engine = self.storage.write().unwrap();
+        let rule = eval::retract_rule(&mut engine, &mut self.cache,
+                                      &args[0], n - 1)?;
+        record_history(&mut engine, &args[0],
+                       format!("retract rule {}: {}", n, rule));
+        self.note(&format!("retracted: {}", rule));
+        Ok(())
+    }
+
+    /// `.undo`: reverse the most recent assertion not yet undone, removing the
+    /// fact or rule it added.
+    fn undo(&mut self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .undo".to_string()));
+        }
+
+        let rule = self.undo.pop()
+            .ok_or(Error::Command("nothing to undo".to_string()))?;
+        let text = rule.to_string();
+        let view = head_relation(&rule.head);
+        let is_rule = !rule.body.is_empty();
+        let mut engine = self.storage.write().unwrap();
+        eval::retract(&mut engine, &mut self.cache, rule)?;
+        if is_rule {
+            record_history(&mut engine, &view, format!("undo {}", text));
+        }
+        self.note(&format!("undid: {}", text));
+        Ok(())
+    }
+
+    /// `.history <view> [<version>]`: list the recorded versions of a view's
+    /// rules, or show the rules of one version.
+    fn history(&self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .history <view> [<version>]".to_string());
+        let engine = self.storage.read().unwrap();
+        match args {
+            [view] => {
+                println!("version\ttime\tuser\tchange");
+                for (i, version) in engine.history(view).iter().enumerate() {
+                    println!("{}\t{}\t{}\t{}", i + 1, version.time,
+                             version.user, version.change);
+                }
+            },
+            [view, n] => {
+                let n: usize = n.parse().map_err(|_| usage())?;
+                for rule in &history_version(&engine, view, n)?.rules {
+                    println!("{}", rule);
+                }
+            },
+            _ => return Err(usage())
+        }
+        Ok(())
+    }
+
+    /// `.revert <view> <version>`: replace the rules of a view with those of
+    /// an earlier version, listed by `.history`.
+    fn revert(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .revert <view> <version>".to_string());
+        let (view, n) = match args {
+            [view, n] => (view, n.parse().map_err(|_| usage())?),
+            _ => return Err(usage())
+        };
+
+        let mut engine = self.storage.write().unwrap();
+        let mut rules = Vec::new();
+        for text in &history_version(&engine, view, n)?.rules {
+            let toks = Lexer::new(text.chars()).collect::<Result<Vec<_>>>()?;
+            for line in Parser::new(toks.into_iter()) {
+                match line? {
+                    ast::Line::Rule(rule) => rules.push(rule),
+                    _ => return Err(Error::Command(
+                            format!("not a rule in history: {}", text)))
+                }
+            }
+        }
+        eval::replace_rules(&mut engine, &mut self.cache, view, rules)?;
+        record_history(&mut engine, view, format!("revert to version {}", n));
+        Ok(())
+    }
+
+    /// `.convert <view>`: replace a view with a table of its current contents.
+    fn convert(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command("usage: .convert <view>".to_string()));
+        }
+
+        let mut engine = self.storage.write().unwrap();
+        eval::convert(&mut engine, &mut self.cache, &args[0])
+    }
+
+    /// `.create_table <name> as <query>`: store the results of a query as a new
+    /// table.
+    fn create_table(&mut self, args: &[String]) -> Result<()> {
+        if args.len() < 3 || args[1] != "as" {
+            return Err(Error::Command(
+                "usage: .create_table <name> as <query>".to_string()));
+        }
+
+        let query = parser::parse_query(&args[2..].join(" "))?;
+        let mut engine = self.storage.write().unwrap();
+        let len = eval::create_table(&mut engine, &mut self.cache, &args[0],
+                                     query)?;
+        self.note(&format!("created {} with {} facts", args[0], len));
+        Ok(())
+    }
+
+    /// `.save_query <name> <query>`: save a query in the database, to be run
+    /// later with `.run <name>`. The query may take parameters `$1`, `$2`, ...,
+    /// and must be one that could be answered now.
+    fn save_query(&mut self, args: &[String]) -> Result<()> {
+        if args.len() < 2 {
+            return Err(Error::Command(
+                "usage: .save_query <name> <query>".to_string()));
+        }
+
+        let query = parser::parse_query(&args[1..].join(" "))?;
+        parser::parameter_count(&query)?;
+        let mut engine = self.storage.write().unwrap();
+        eval::check_query(&engine, &query)?;
+        let text = format!("{}?", query);
+        engine.save_query(args[0].clone(), text)?;
+        self.saved.insert(args[0].clone(), query);
+        Ok(())
+    }
+
+    /// `.run <name> [<param>...]`: run a query saved with `.save_query`,
+    /// giving its parameters in order.
+    fn run_saved(&mut self, args: &[String]) -> Result<()> {
+        let (name, params) = match args.split_first() {
+            Some(parts) => parts,
+            None => return Err(Error::Command(
+                    "usage: .run <name> [<param>...]".to_string()))
+        };
+
+        let query = self.saved_query(name)?;
+        let expected = parser::parameter_count(&query)?;
+        if params.len() != expected {
+            return Err(Error::Command(format!(
+                "{} takes {} parameters, but was given {}", name, expected,
+                params.len())));
+        }
+        let query = parser::bind_params(query, params)?;
+        self.handle_line(ast::Line::Query(query))
+    }
+
+    // The query saved under `name`, parsed only the first time it's used.
+    fn saved_query(&mut self, name: &str) -> Result<ast::Term> {
+        if !self.saved.contains_key(name) {
+            let text = self.storage.read().unwrap().saved_query(name)
+                .map(str::to_string)
+                .ok_or(Error::Command(
+                        format!("no saved query named {}", name)))?;
+            self.saved.insert(name.to_string(), parser::parse_query(&text)?);
+        }
+        Ok(self.saved[name].clone())
+    }
+
+    /// `.plan_baseline save <name>`: record the shape of the plan for a saved
+    /// query, as `eval::plan_shape` gives it. `.plan_baseline check [<name>]`:
+    /// compare the plan for that query, or for every query with a baseline,
+    /// with its baseline, showing how any plan changed.
+    fn plan_baseline(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .plan_baseline <save <name>|check [<name>]>".to_string());
+        let (action, name) = match args {
+            [action] => (action.as_str(), None),
+            [action, name] => (action.as_str(), Some(name)),
+            _ => return Err(usage())
+        };
+        match (action, name) {
+            ("save", Some(name)) => {
+                let query = self.saved_query(name)?;
+                if parser::parameter_count(&query)? > 0 {
+                    return Err(Error::Command(format!(
+                        "{} takes parameters, so has no single plan", name)));
+                }
+                let mut engine = self.storage.write().unwrap();
+                let shape = eval::plan_shape(&engine, query)?;
+                engine.save_plan(name.clone(), shape)
+            },
+            ("check", name) => {
+                let names: Vec<String> = match name {
+                    Some(name) => vec!(name.clone()),
+                    None => self.storage.read().unwrap().saved_plans()
+                                .into_iter().map(str::to_string).collect()
+                };
+                let mut changed = 0;
+                for name in &names {
+                    let query = self.saved_query(name)?;
+                    let engine = self.storage.read().unwrap();
+                    let baseline = engine.saved_plan(name).ok_or_else(|| {
+                        Error::Command(format!("no baseline plan for {}", name))
+                    })?;
+                    let shape = eval::plan_shape(&engine, query)?;
+                    if shape == baseline {
+                        println!("{}\tunchanged", name);
+                        continue;
+                    }
+                    changed += 1;
+                    println!("{}\tchanged", name);
+                    for (mark, line) in diff_lines(baseline, &shape) {
+                        println!("{}\t{}", mark, line);
+                    }
+                }
+                if changed > 0 {
+                    return Err(Error::Command(format!(
+                        "{} of {} plans changed", changed, names.len())));
+                }
+                Ok(())
+            },
+            _ => Err(usage())
+        }
+    }
+
+    /// `.queries`: list the saved queries, with the parameters each takes.
+    fn queries(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .queries".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        println!("name\tparameters\tquery");
+        for (name, text) in engine.saved_queries() {
+            let parameters = parser::parse_query(text)
+                .and_then(|q| parser::parameter_count(&q))
+                .map_or("?".to_string(), |n| n.to_string());
+            println!("{}\t{}\t{}", name, parameters, text);
+        }
+        Ok(())
+    }
+
+    /// `.copy <relation> from <data dir>`: copy a relation from another
+    /// database.
+    fn copy(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 3 || args[1] != "from" {
+            return Err(Error::Command(
+                "usage: .copy <relation> from <data dir>".to_string()));
+        }
+
+        let source = Storage::open_read_only(args[2].clone())?;
+        let mut engine = self.storage.write().unwrap();
+        let copied = eval::copy_relation(&mut engine, &mut self.cache,
+                                         &source, &args[0])?;
+        self.note(&format!("copied {} facts or rules", copied));
+        Ok(())
+    }
+
+    /// `.load <file>`: add all of the facts in a file at once, which is much
+    /// faster than asserting them one at a time.
+    fn load(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command("usage: .load <file>".to_string()));
+        }
+
+        let text = std::fs::read_to_string(&args[0])
+            .map_err(|e| Error::Command(format!("{}: {}", args[0], e)))?;
+        let toks = Lexer::new(text.chars()).collect::<Result<Vec<_>>>()?;
+        let mut facts = Vec::new();
+        for line in Parser::new(toks.into_iter()) {
+            let rule = match line? {
+                ast::Line::Rule(r) => r,
+                _ => return Err(Error::Command(
+                        "can only load facts".to_string()))
+            };
+            if !rule.body.is_empty() {
+                return Err(Error::Command(
+                        format!("can only load facts, not rules: {}", rule)));
+            }
+            facts.push(rule.head);
+        }
+
+        let mut engine = self.storage.write().unwrap();
+        let count = eval::bulk_assert(&mut engine, &mut self.cache, facts)?;
+        self.note(&format!("loaded {} facts", count));
+        Ok(())
+    }
+
+    /// `.sandbox <file> [<relation>...]`: run the facts, rules and queries in
+    /// a file as an untrusted program, which may only read the relations
+    /// listed and those it creates, and may only add to the latter; see
+    /// `sandbox`. Queries are held to the limits set with `.limit`, or to
+    /// `sandbox::default_limits`. The program stops at its first error.
+    fn sandbox(&mut self, args: &[String]) -> Result<()> {
+        let (path, allowed) = match args.split_first() {
+            Some(parts) => parts,
+            None => return Err(Error::Command(
+                    "usage: .sandbox <file> [<relation>...]".to_string()))
+        };
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Command(format!("{}: {}", path, e)))?;
+        let toks = Lexer::new(text.chars()).collect::<Result<Vec<_>>>()?;
+        let mut sandbox = Sandbox::new(allowed.iter().cloned(), self.limits);
+        for line in Parser::new(toks.into_iter()) {
+            match line? {
+                ast::Line::Rule(rule) => {
+                    let mut engine = self.storage.write().unwrap();
+                    sandbox.assert(&mut engine, &mut self.cache, rule)?;
+                },
+                ast::Line::Query(query) => {
+                    self.label(&format!("{}?", query))?;
+                    let columns = query.variables();
+                    let answers: Vec<Vec<Value>> = {
+                        let engine = self.storage.read().unwrap();
+                        sandbox.query(&engine, &self.cache, query, |frames| {
+                            frames.map(|frame| {
+                                columns.iter().map(|c| frame[c].clone())
+                                              .collect()
+                            }).collect()
+                        })?
+                    };
+                    write_line(&mut self.output, &columns.join("\t"))?;
+                    for answer in &answers {
+                        write_values(&mut self.output, answer)?;
+                    }
+                },
+                _ => return Err(Error::Sandbox(
+                        "only facts, rules and queries can be run".to_string()))
+            }
+        }
+        let mut created: Vec<&str> = sandbox.created().collect();
+        created.sort();
+        self.note(&format!("sandboxed program created: {}",
+                           created.join(", ")));
+        Ok(())
+    }
+
+    /// `.attach <relation> <file>`: make a relation whose facts are read from
+    /// a file of tab-separated values each time it is used, and are never
+    /// stored in the database.
+    fn attach(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 2 {
+            return Err(Error::Command(
+                "usage: .attach <relation> <file>".to_string()));
+        }
+
+        let facts = FileTable::read(&args[1])?;
+        let arity = facts.first().map(Vec::len).ok_or_else(|| {
+            Error::Command(format!("{} has no facts", args[1]))
+        })?;
+        let table = FileTable { path: args[1].clone(), arity };
+        let mut engine = self.storage.write().unwrap();
+        engine.attach(args[0].clone(), Box::new(table))?;
+        self.cache.invalidate(&args[0]);
+        self.note(&format!("attached {}/{}", args[0], arity));
+        Ok(())
+    }
+
+    /// `.detach <relation>`: remove a relation made by `.attach`.
+    fn detach(&mut self, args: &[String]) -> Result<()> {
+        if args.len() != 1 {
+            return Err(Error::Command("usage: .detach <relation>".to_string()));
+        }
+
+        let mut engine = self.storage.write().unwrap();
+        if !engine.detach(&args[0]) {
+            return Err(Error::Command(
+                format!("{} is not a virtual table", args[0])));
+        }
+        self.cache.invalidate(&args[0]);
+        Ok(())
+    }
+
+    /// `.retain <table> <n|off>`: keep only the newest `n` facts in a table,
+    /// or with `off`, all of them.
+    fn retain(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .retain <table> <n|off>".to_string());
+        let max_len = match args {
+            [_, n] if n == "off" => None,
+            [_, n] => Some(n.parse().map_err(|_| usage())?),
+            _ => return Err(usage())
+        };
+
+        let mut engine = self.storage.write().unwrap();
+        eval::set_retention(&mut engine, &args[0], max_len)
+    }
+
+    /// `.index <table> [<column> [off]]`: index a column (numbered from 1) of
+    /// a table, or with `off`, remove its index; with no column, list the
+    /// indexed columns.
+    fn index(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .index <table> [<column> [off]]".to_string());
+        let column = |arg: &str| match arg.parse::<usize>() {
+            Ok(column) if column > 0 => Ok(column - 1),
+            _ => Err(usage())
+        };
+        let (name, column, on) = match args {
+            [name] => {
+                let engine = self.storage.read().unwrap();
+                return match engine.get_relation(name) {
+                    Some(storage::Relation::Extension(table)) => {
+                        for column in table.indexes() {
+                            println!("{}", column + 1);
+                        }
+                        Ok(())
+                    },
+                    Some(_) => Err(Error::NotExtensional(name.to_string())),
+                    None => Err(Error::MalformedLine(
+                            format!("No relation \"{}\" found.", name)))
+                };
+            },
+            [name, n] => (name, column(n)?, true),
+            [name, n, off] if off == "off" => (name, column(n)?, false),
+            _ => return Err(usage())
+        };
+
+        let mut engine = self.storage.write().unwrap();
+        if !eval::set_index(&mut engine, name, column, on)? {
+            self.note(&format!("{} column {} {}", name, column + 1,
+                               if on { "is already indexed" }
+                               else { "has no index" }));
+        }
+        Ok(())
+    }
+
+    /// `.materialize [<view> [off]]`: materialize a recursive view, storing
+    /// its contents and keeping them up to date as facts change (see
+    /// `eval::materialize`), or with `off`, stop; with no view, list the
+    /// materialized views and the number of tuples in each.
+    fn materialize(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [] => {
+                let engine = self.storage.read().unwrap();
+                println!("view\ttuples");
+                for name in engine.materialized() {
+                    let rows = engine.materialization(name)
+                                     .map_or(0, |table| table.len());
+                    println!("{}\t{}", name, rows);
+                }
+                Ok(())
+            },
+            [name] => {
+                let mut engine = self.storage.write().unwrap();
+                let count = eval::materialize(&mut engine, &mut self.cache,
+                                              name)?;
+                self.note(&format!("stored {} tuples of {}", count, name));
+                Ok(())
+            },
+            [name, off] if off == "off" => {
+                let mut engine = self.storage.write().unwrap();
+                if !eval::dematerialize(&mut engine, &self.cache, name) {
+                    self.note(&format!("{} is not materialized", name));
+                }
+                Ok(())
+            },
+            _ => Err(Error::Command(
+                    "usage: .materialize [<view> [off]]".to_string()))
+        }
+    }
+
+    /// `.advisor [apply|reset]`: list the columns of tables looked up so far
+    /// this session, with what `advisor::advise` makes of each; `apply`
+    /// indexes those it says to, and `reset` forgets the lookups.
+    fn advisor(&mut self, args: &[String]) -> Result<()> {
+        match args {
+            [] => {
+                let engine = self.storage.read().unwrap();
+                println!("relation\tcolumn\tlookups\trows\tdistinct\tadvice");
+                for a in advisor::advise(&engine) {
+                    let distinct =
+                        a.distinct.map_or("?".to_string(), |n| n.to_string());
+                    println!("{}\t{}\t{}\t{}\t{}\t{}", a.relation, a.column + 1,
+                             a.lookups, a.rows, distinct, a.decision);
+                }
+                Ok(())
+            },
+            [action] if action == "apply" => self.apply_advice(),
+            [action] if action == "reset" => Ok(advisor::reset()),
+            _ => Err(Error::Command(
+                    "usage: .advisor [apply|reset]".to_string()))
+        }
+    }
+
+    /// `.alter <table> <arity> [<value>]`: change the arity of a table,
+    /// dropping columns past the new arity, or adding columns holding `value`
+    /// in every fact.
+    fn alter(&mut self, args: &[String]) -> Result<()> {
+        let usage = || Error::Command(
+            "usage: .alter <table> <arity> [<value>]".to_string());
+        let (name, arity, fill) = match args {
+            [name, arity] => (name, arity, None),
+            [name, arity, fill] =>
+                (name, arity, Some(parser::param_value(fill))),
+            _ => return Err(usage())
+        };
+        let arity = arity.parse().map_err(|_| usage())?;
+
+        let mut engine = self.storage.write().unwrap();
+        eval::alter(&mut engine, &mut self.cache, name, arity, fill)
+    }
+
+    /// `.verify`: check every relation's file for corruption.
+    fn verify(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .verify".to_string()));
+        }
+
+        let errors = self.storage.read().unwrap().verify();
+        if errors.is_empty() {
+            self.note("all relations ok");
+            return Ok(());
+        }
+        for (relation, error) in &errors {
+            eprintln!("{}: {}", relation, error);
+        }
+        Err(Error::Command(format!("{} corrupt relation(s)", errors.len())))
+    }
+
+    /// `.flush`: write every change to disk now, rather than waiting for the
+    /// background writer.
+    fn flush(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .flush".to_string()));
+        }
+
+        let engine = self.storage.read().unwrap();
+        if engine.is_frozen() {
+            return Err(Error::Command(
+                "the database is frozen; .thaw it first".to_string()));
+        }
+        engine.write_back();
+        Ok(())
+    }
+
+    /// `.freeze`: write every change to disk, then stop writing to the data
+    /// directory until `.thaw`, so that it can be copied safely. Says when
+    /// the directory is safe to copy.
+    fn freeze(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .freeze".to_string()));
+        }
+
+        // Holding the write lock waits out any write-back under way.
+        let mut engine = self.storage.write().unwrap();
+        engine.write_back();
+        engine.freeze();
+        println!("frozen: {} is safe to copy until .thaw", engine.data_dir());
+        Ok(())
+    }
+
+    /// `.thaw`: go back to writing changes to disk after `.freeze`.
+    fn thaw(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .thaw".to_string()));
+        }
+
+        let mut engine = self.storage.write().unwrap();
+        engine.thaw();
+        engine.write_back();
+        println!("thawed");
+        Ok(())
+    }
+
+    /// `.vacuum`: compact every relation's journal into its main file.
+    fn vacuum(&self, args: &[String]) -> Result<()> {
+        if !args.is_empty() {
+            return Err(Error::Command("usage: .vacuum".to_string()));
+        }
+
+        self.storage.read().unwrap().vacuum();
+        Ok(())
+    }
+
+    /// `.export_query "<query>" <file>`: write the results of a query to a
+    /// Parquet file, with one column per variable in the query.
+    fn export_query(&self, args: &[String]) -> Result<()> {
+        if args.len() != 2 {
+            return Err(Error::Command(
+                "usage: .export_query \"<query>\" <file>".to_string()));
+        }
+
+        let query = parser::parse_query(&args[0])?;
+        let columns = query.variables();
+        let engine = &self.storage.read().unwrap();
+        let frames = arrange(eval::query(engine, &self.cache, query)?,
+                             &columns, self.settings.deterministic, &columns);
+        export::write_parquet(&args[1], &columns, frames)?;
+        spill::check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use driver::*;
+
+    #[test]
+    fn output() {
+        let dir = "_test_output_dir";
+        if std::fs::read_dir(dir).is_ok() {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+        std::fs::create_dir(dir).unwrap();
+        let data_dir = format!("{}/data", dir);
+        let output = format!("{}/out.tsv", dir);
+
+        // At the terminal, only the answers go to the file: no prompts, no
+        // colours and no labels.
+        let lines = format!(".set deterministic on\np(a).\np(b).\n\
+                             .output {}\np(X)?\n", output);
+        Driver::from_reader(io::Cursor::new(lines.into_bytes()),
+                            data_dir.clone(), DriverMode::Interactive).run();
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(written, "X\na\nb\n");
+        assert!(!written.contains(PROMPT) && !written.contains('\x1b'));
+
+        // Answers go back to standard output after `.output -`.
+        let script = format!("{}/script.dl", dir);
+        std::fs::write(&script, format!(".set deterministic on\n\
+                                         .output {}\np(X)?\n.output -\n\
+                                         p(Y)?\n", output)).unwrap();
+        Driver::from_script(&script, data_dir).unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tp(X)?\nX\na\nb\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
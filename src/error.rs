@@ -1,3 +1,5 @@
+use limits::Limit;
+
 use std;
 use std::error;
 use std::fmt;
@@ -16,12 +18,37 @@ pub enum Error {
     /// An operation could not be performed because the named relation is not
     /// intensional.
     NotIntensional(String),
+    /// An operation could not be performed because the named relation is built
+    /// in.
+    Builtin(String),
+    /// An operation could not be performed because the named relation is a
+    /// virtual table.
+    Virtual(String),
     /// A query or assertion was malformed for the given reason.
     MalformedLine(String),
-    StorageError(Box<std::error::Error>),
+    StorageError(Box<std::error::Error + Send + Sync>),
     BadFilename(std::ffi::OsString),
     /// The arity of some fact did not match the arity of the table.
-    ArityMismatch{ expected: usize, got: usize }
+    ArityMismatch{ expected: usize, got: usize },
+    /// The named relation was used with a different arity than it has.
+    RelationArity { relation: String, arity: usize, got: usize },
+    /// A meta-command was unknown or used incorrectly, for the given reason.
+    Command(String),
+    /// Query results could not be exported for the given reason.
+    Export(String),
+    /// A test suite could not be run for the given reason.
+    Suite(String),
+    /// The file storing a relation is damaged, for the given reason.
+    Corrupt { path: String, reason: String },
+    /// The name of a new relation does not follow the naming rules.
+    BadRelationName { name: String, reason: String },
+    /// A query result could not be converted for the given reason.
+    Row(String),
+    /// A query was stopped for exceeding one of its limits.
+    LimitExceeded(Limit),
+    /// A sandboxed program tried something it isn't allowed to, for the given
+    /// reason.
+    Sandbox(String)
 }
 
 /// Custom result type for data-goblin.
@@ -35,10 +62,22 @@ impl error::Error for Error {
             Error::NotExtensional(_)
                 | Error::MalformedLine(_)
                 | Error::NotIntensional(_)
+                | Error::Builtin(_)
+                | Error::Virtual(_)
                 => "evaluation error",
             Error::StorageError(_) => "storage error",
             Error::BadFilename(_) => "bad filename for table file",
-            Error::ArityMismatch { expected: _, got: _ } => "arity mismatch"
+            Error::ArityMismatch { expected: _, got: _ } => "arity mismatch",
+            Error::RelationArity { .. } => "arity mismatch",
+            Error::Command(_) => "command error",
+            Error::Export(_) => "export error",
+            Error::Suite(_) => "test suite error",
+            Error::Corrupt { path: _, reason: _ } => "corrupt relation file",
+            Error::BadRelationName { name: _, reason: _ } =>
+                "bad relation name",
+            Error::Row(_) => "row error",
+            Error::LimitExceeded(_) => "limit exceeded",
+            Error::Sandbox(_) => "not allowed in sandbox"
         }
     }
 
@@ -48,10 +87,21 @@ impl error::Error for Error {
             Error::Parser(_) => None,
             Error::NotExtensional(_) => None,
             Error::NotIntensional(_) => None,
+            Error::Builtin(_) => None,
+            Error::Virtual(_) => None,
             Error::MalformedLine(_) => None,
             Error::StorageError(e) => e.cause(),
             Error::BadFilename(_) => None,
-            Error::ArityMismatch { expected: _, got: _ } => None
+            Error::ArityMismatch { expected: _, got: _ } => None,
+            Error::RelationArity { .. } => None,
+            Error::Command(_) => None,
+            Error::Export(_) => None,
+            Error::Suite(_) => None,
+            Error::Corrupt { path: _, reason: _ } => None,
+            Error::BadRelationName { name: _, reason: _ } => None,
+            Error::Row(_) => None,
+            Error::LimitExceeded(_) => None,
+            Error::Sandbox(_) => None
         }
     }
 }
@@ -65,6 +115,10 @@ impl fmt::Display for Error {
                 write!(f, "not an extensional relation: {}", s),
             Error::NotIntensional(s) =>
                 write!(f, "not an intensional relation: {}", s),
+            Error::Builtin(s) =>
+                write!(f, "cannot modify built-in relation: {}", s),
+            Error::Virtual(s) =>
+                write!(f, "cannot modify virtual table: {}", s),
             Error::MalformedLine(s) =>
                 write!(f, "malformed query/assertion: {}", s),
             Error::StorageError(e) => write!(f, "storage error: {}", e),
@@ -72,7 +126,21 @@ impl fmt::Display for Error {
                 write!(f, "bad filename for table file: {:?}", s),
             Error::ArityMismatch { expected, got } =>
                 write!(f, "arity mismatch: expected arity {} but got {}",
-                          expected, got)
+                          expected, got),
+            Error::RelationArity { relation, arity, got } =>
+                write!(f, "arity mismatch: {} has arity {}, but was used with \
+                           arity {}", relation, arity, got),
+            Error::Command(s) => write!(f, "command error: {}", s),
+            Error::Export(s) => write!(f, "export error: {}", s),
+            Error::Suite(s) => write!(f, "test suite error: {}", s),
+            Error::Corrupt { path, reason } =>
+                write!(f, "corrupt relation file {}: {}", path, reason),
+            Error::BadRelationName { name, reason } =>
+                write!(f, "bad relation name {:?}: {}", name, reason),
+            Error::Row(s) => write!(f, "row error: {}", s),
+            Error::LimitExceeded(limit) =>
+                write!(f, "limit exceeded: {}", limit),
+            Error::Sandbox(s) => write!(f, "not allowed in sandbox: {}", s)
         }
     }
 }
@@ -1,19 +1,39 @@
 /// The evaluator.
 
+use advisor;
 use ast;
-use cache::ViewCache;
+use cache::{BindingPattern, Delta, ViewCache, EVERY_RELATION};
 use error::*;
+use graph;
+use lexer::Lexer;
+use limits;
+use parser::Parser;
+use profile;
+use spill;
+use spill::Strategy;
 use storage;
 use storage::Relation::*;
 use storage::Tuple;
+use strata::{Dependence, References, Strata, Stratum};
+use value::Value;
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::hash_set;
 use std::collections::LinkedList;
-use std::cell::Cell;
+use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
 use std::marker::PhantomData;
 use std::mem;
+use std::rc::Rc;
+use std::time::Instant;
+use std::vec;
+
+/// The number of items plans try to produce per batch.
+pub const BATCH_SIZE: usize = 1024;
 
 /// Plans are simply iterators that can be reset to the beginning.
 pub trait Plan: Iterator {
@@ -22,28 +42,202 @@ pub trait Plan: Iterator {
     /// I.e., the next call to `next` should return the same thing as when the
     /// plan was first created.
     fn reset(&mut self);
+
+    /// Get up to `max` items from the plan at once.
+    ///
+    /// Returns an empty batch only once the plan is exhausted. Plans that can
+    /// do their work a batch at a time should override this; consuming plans
+    /// in batches amortizes the cost of dynamic dispatch on boxed plans.
+    fn next_batch(&mut self, max: usize) -> Vec<Self::Item> {
+        let mut batch = Vec::new();
+        while batch.len() < max {
+            match self.next() {
+                Some(item) => batch.push(item),
+                None => break
+            }
+        }
+        batch
+    }
+}
+
+impl<P: Plan + ?Sized> Plan for Box<P> {
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Self::Item> {
+        (**self).next_batch(max)
+    }
+}
+
+/// Consume all remaining items of a plan a batch at a time.
+fn for_each_batched<P, F>(plan: &mut P, mut f: F)
+        where P: Plan + ?Sized, F: FnMut(P::Item) {
+    loop {
+        let batch = plan.next_batch(BATCH_SIZE);
+        if batch.is_empty() {
+            return;
+        }
+        for item in batch {
+            f(item);
+        }
+    }
 }
 
 //
 // Views.
 //
 
+/// Which of two values in a column is better, for views with a subsumption
+/// order.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Extremum {
+    Min,
+    Max
+}
+
+/// A subsumption order on a view: of two tuples agreeing everywhere except in
+/// `column`, only the one with the better value there is kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Subsumption {
+    pub column: usize,
+    pub keep: Extremum
+}
+
+impl Subsumption {
+    /// The order keeping the `min` or `max` value in a column numbered from 1,
+    /// as given to `.subsume`.
+    pub fn parse(column: &str, keep: &str) -> Option<Subsumption> {
+        let column: usize = column.parse().ok().filter(|c| *c > 0)?;
+        let keep = match keep {
+            "min" => Extremum::Min,
+            "max" => Extremum::Max,
+            _ => return None
+        };
+        Some(Subsumption { column: column - 1, keep })
+    }
+}
+
 /// An `AstView` represents a view simply as the AST of each of its rules.
 #[derive(Serialize, Deserialize)]
 pub struct AstView {
-    rules: Vec<(Vec<String>, Vec<ast::Term>)>
+    rules: Vec<(Vec<String>, Vec<ast::Term>)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subsumption: Option<Subsumption>
 }
 
 impl AstView {
     fn new() -> AstView {
         AstView {
-            rules: Vec::new()
+            rules: Vec::new(),
+            subsumption: None
         }
     }
 
+    /// The subsumption order used when evaluating this view, if any.
+    pub fn subsumption(&self) -> Option<&Subsumption> {
+        self.subsumption.as_ref()
+    }
+
+    // A subsumer for evaluating this view, if it has a subsumption order that
+    // still fits its arity.
+    fn subsumer<'s>(&self) -> Option<Subsumer<'s>> {
+        let arity = self.arity()?;
+        self.subsumption.as_ref()
+                        .filter(|s| s.column < arity)
+                        .map(|s| Subsumer::new(s.clone()))
+    }
+
     fn add_rule(&mut self, formals: Vec<String>, body: Vec<ast::Term>) {
         self.rules.push((formals, body));
     }
+
+    /// The number of columns in this view, if it has any rules.
+    pub fn arity(&self) -> Option<usize> {
+        self.rules.first().map(|(formals, _)| formals.len())
+    }
+
+    /// Reconstruct the rules defining this view, which is named `name`.
+    pub fn to_rules(&self, name: &str) -> Vec<ast::Rule> {
+        self.rules.iter().map(|(formals, body)| {
+            let params = formals.iter().map(|f| {
+                ast::AtomicTerm::Variable(f.clone())
+            }).collect();
+            ast::Rule {
+                head: ast::Term::Compound(ast::CompoundTerm {
+                    relation: name.to_string(),
+                    params
+                }),
+                body: body.clone()
+            }
+        }).collect()
+    }
+}
+
+// Views are written to the rules file as their rules, followed by the
+// meta-commands that would set their subsumption order and description.
+impl storage::RulesText for AstView {
+    fn to_text(&self, name: &str, description: Option<&str>) -> String {
+        let mut text = String::new();
+        for rule in self.to_rules(name) {
+            text.push_str(&format!("{}\n", rule));
+        }
+        if let Some(ref s) = self.subsumption {
+            let keep = match s.keep {
+                Extremum::Min => "min",
+                Extremum::Max => "max"
+            };
+            text.push_str(&format!(".subsume {} {} {}\n",
+                                   name, s.column + 1, keep));
+        }
+        if let Some(description) = description {
+            text.push_str(&format!(".describe {} \"{}\"\n",
+                                   name, description));
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Result<Vec<(String, Self, Option<String>)>> {
+        let tokens = Lexer::new(text.chars()).collect::<Result<Vec<_>>>()?;
+        let mut views: BTreeMap<String, (AstView, Option<String>)> =
+            BTreeMap::new();
+        for line in Parser::new(tokens.into_iter()) {
+            match line? {
+                ast::Line::Rule(ref rule) if !rule.body.is_empty() => {
+                    let (name, params) = deconstruct_term(rule.head.clone())?;
+                    let formals = params.into_iter().map(|p| match p {
+                        ast::AtomicTerm::Variable(v) => Ok(v),
+                        ast::AtomicTerm::Atom(_) => Err(Error::MalformedLine(
+                            format!("expected only variables in {}",
+                                    rule.head)))
+                    }).collect::<Result<Vec<_>>>()?;
+                    views.entry(name).or_insert((AstView::new(), None)).0
+                         .add_rule(formals, rule.body.clone());
+                },
+                ast::Line::Command(ref command) => {
+                    let view = command.args.first()
+                        .and_then(|name| views.get_mut(name));
+                    match (command.name.as_str(), &command.args[..], view) {
+                        ("subsume", [_, column, keep], Some(view)) => {
+                            view.0.subsumption =
+                                Subsumption::parse(column, keep);
+                        },
+                        ("describe", [_, description], Some(view)) => {
+                            view.1 = Some(description.clone());
+                        },
+                        _ => return Err(Error::MalformedLine(format!(
+                            "unexpected command .{} {}", command.name,
+                            command.args.join(" "))))
+                    }
+                },
+                other => return Err(Error::MalformedLine(format!(
+                    "expected only rules, not {:?}", other)))
+            }
+        }
+        Ok(views.into_iter().map(|(name, (view, description))| {
+            (name, view, description)
+        }).collect())
+    }
 }
 
 type Storage = storage::StorageEngine<AstView>;
@@ -61,17 +255,50 @@ pub type Tuples<'s, 'a> = Box<TuplePlan<'s, Item = Tuple<'s>> + 'a>;
 /// A (resetable) scan over an extensional relation.
 struct ExtensionalScan<'a> {
     table: &'a storage::Table,
+    /// Columns known to contain a particular atom, which the scan can skip
+    /// straight past.
+    constants: Vec<(usize, Value)>,
+    /// Pairs of columns known to contain the same atom, because the same
+    /// variable is in both.
+    equal: Vec<(usize, usize)>,
     scan: storage::TableScan<'a>
 }
 
 impl<'a> ExtensionalScan<'a> {
-    /// Create a new ExtensionalScan staring at the beginning of this table.
-    fn new(table: &'a storage::Table) -> Self {
+    /// Create a new ExtensionalScan staring at the beginning of this table,
+    /// yielding only tuples that agree with any atoms in `pattern`, and with
+    /// any variable repeated in it.
+    fn new(table: &'a storage::Table, pattern: &[ast::AtomicTerm]) -> Self {
+        let mut constants = Vec::new();
+        let mut equal = Vec::new();
+        for (i, param) in pattern.iter().enumerate() {
+            match param {
+                ast::AtomicTerm::Atom(a) => constants.push((i, a.clone())),
+                ast::AtomicTerm::Variable(_) => {
+                    if let Some(first) = pattern.iter().position(|p| p == param)
+                                                .filter(|first| *first != i) {
+                        equal.push((first, i));
+                    }
+                }
+            }
+        }
+        let scan = Self::select(table, &constants, &equal);
+
         ExtensionalScan {
             table,
-            scan: table.into_iter()
+            constants,
+            equal,
+            scan
         }
     }
+
+    fn select(table: &'a storage::Table,
+              constants: &[(usize, Value)],
+              equal: &[(usize, usize)]) -> storage::TableScan<'a> {
+        let constants: Vec<(usize, &Value)> =
+            constants.iter().map(|(i, a)| (*i, a)).collect();
+        table.select_equal(&constants, equal)
+    }
 }
 
 impl<'a> Iterator for ExtensionalScan<'a> {
@@ -84,10 +311,14 @@ impl<'a> Iterator for ExtensionalScan<'a> {
 
 impl<'a> Plan for ExtensionalScan<'a> {
     fn reset(&mut self) {
-        self.scan = self.table.into_iter();
+        self.scan = Self::select(self.table, &self.constants, &self.equal);
     }
 }
 
+// The rules of a view: scans over its non-recursive rules, and the formal
+// parameters and bodies of its recursive ones.
+type ViewParts<'s> = (Vec<Tuples<'s, 's>>, Vec<(Vec<String>, Vec<ast::Term>)>);
+
 /// A (resetable) scan over an intensional relation.
 struct IntensionalScan<'s: 'a, 'a> {
     column_names: Vec<String>,
@@ -97,49 +328,89 @@ struct IntensionalScan<'s: 'a, 'a> {
 impl<'s: 'a, 'a> IntensionalScan<'s, 'a> {
     /// Create a new scan based on the given view definition, running against
     /// the given storage engine.
+    ///
+    /// Only tuples agreeing with the constants in `pattern` are returned, and
+    /// they are cached under that pattern.
     fn from_view(name: &str,
                  engine: &'s Storage,
                  cache: &'s ViewCache,
                  view: &'s AstView,
+                 pattern: BindingPattern,
                  semi_naive: bool) -> Result<Tuples<'s, 's>> {
-        let mut recursive = false;
+        // A recursive view is evaluated in full as it is planned, along with
+        // the rest of its stratum, so once per query is enough, however many
+        // terms refer to it or the others.
+        let scan: Tuples<'s, 's> = match cache.fixpoint(name) {
+            Some(tuples) => Box::new(VecPlan::shared(tuples)),
+            None => {
+                let strata = planned_strata(engine, cache)?;
+                match strata.stratum(name).filter(|s| s.recursive) {
+                    None => {
+                        let (base_scans, _) =
+                            Self::split_view(name, engine, cache, view, &[])?;
+                        Box::new(Chain::new(base_scans))
+                    },
+                    Some(stratum) => {
+                        let (fixpoint, _) = evaluate_stratum(
+                            name, engine, cache, stratum, semi_naive)?;
+                        let mut tuples = None;
+                        for (view, found) in fixpoint {
+                            let found = Rc::new(found);
+                            if view == name {
+                                tuples = Some(Rc::clone(&found));
+                            }
+                            cache.remember_fixpoint(view, found);
+                        }
+                        Box::new(VecPlan::shared(tuples.expect(
+                            "view missing from its own stratum")))
+                    }
+                }
+            }
+        };
+
+        let scan: Tuples<'s, 's> = if pattern.iter().any(Option::is_some) {
+            Box::new(Selection::new(&pattern, scan))
+        } else {
+            scan
+        };
+
+        Ok(Box::new(CachingWrapper::new(name.to_string(),
+                                        pattern,
+                                        cache,
+                                        scan)))
+    }
+
+    /// Plan the non-recursive rules of a view, and separate out its recursive
+    /// ones: those using a view in `stratum`, the views it is evaluated with.
+    fn split_view(name: &str,
+                  engine: &'s Storage,
+                  cache: &'s ViewCache,
+                  view: &'s AstView,
+                  stratum: &[String]) -> Result<ViewParts<'s>> {
         let mut base_scans: Vec<Tuples<'s, 's>> = Vec::new();
         let mut recursive_rules = Vec::new();
         for (params, rule) in &view.rules {
-            if is_recursive(name, rule.to_vec())? {
-                recursive = true;
+            // A rule that can never hold is planned as nothing at all, so
+            // none of its relations are read.
+            if let Some(term) = rule.iter().find(|t| never_holds(t)) {
+                debug!("skipping rule of {}: {} never holds", name, term);
+                continue;
+            }
+            if refers_to(rule, stratum)? {
                 recursive_rules.push((params.clone(), rule.clone()));
             } else {
+                let (positive, negated) = split_body(rule);
                 let mut joins = LinkedList::new();
-                for term in rule {
-                    joins.push_back(query(engine, cache, term.clone())?);
+                for term in positive.iter().filter(|t| !is_comparison(t)) {
+                    joins.push_back(query(engine, cache, (*term).clone())?);
                 }
-                let join = plan_joins(joins);
+                let join =
+                    plan_body(engine, cache, joins, &positive, &negated)?;
                 base_scans.push(Box::new(IntensionalScan::new(params.to_vec(),
                                                               join)));
             }
         }
-
-        Ok(if recursive {
-            if semi_naive {
-                let semi_naive = SemiNaive::new(name,
-                                          cache,
-                                          base_scans,
-                                          recursive_rules,
-                                          engine)?;
-                Box::new(CachingWrapper::new(name.to_string(), cache, semi_naive))
-            } else {
-                let bottom_up = BottomUp::new(name,
-                                          cache,
-                                          base_scans,
-                                          recursive_rules,
-                                          engine)?;
-                Box::new(CachingWrapper::new(name.to_string(), cache, bottom_up))
-            }
-        } else {
-            let chain = Chain::new(base_scans);
-            Box::new(CachingWrapper::new(name.to_string(), cache, chain))
-        })
+        Ok((base_scans, recursive_rules))
     }
 
     fn new(column_names: Vec<String>,
@@ -152,13 +423,18 @@ impl<'s: 'a, 'a> Iterator for IntensionalScan<'s, 'a> {
     type Item = Tuple<'s>;
 
     fn next(&mut self) -> Option<Tuple<'s>> {
-        self.scan.next().map(|frame| {
-            (&self.column_names).into_iter().map(|v| {
-                *frame.get(v).unwrap_or_else(|| {
-                    panic!("frame in view plan missing a column")
-                })
-            }).collect()
-        })
+        let frame = self.scan.next()?;
+        Some(self.to_tuple(frame))
+    }
+}
+
+impl<'s: 'a, 'a> IntensionalScan<'s, 'a> {
+    fn to_tuple(&self, frame: Frame<'s>) -> Tuple<'s> {
+        (&self.column_names).into_iter().map(|v| {
+            *frame.get(v).unwrap_or_else(|| {
+                panic!("frame in view plan missing a column")
+            })
+        }).collect()
     }
 }
 
@@ -166,151 +442,367 @@ impl<'s: 'a, 'a> Plan for IntensionalScan<'s, 'a> {
     fn reset(&mut self) {
         self.scan.reset()
     }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Tuple<'s>> {
+        let frames = self.scan.next_batch(max);
+        frames.into_iter().map(|f| self.to_tuple(f)).collect()
+    }
 }
 
-struct BottomUp<'s> {
-    all_tuples: Vec<Tuple<'s>>,
-    index: usize
+/// What one iteration of evaluating a recursive view to a fixpoint did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IterationStats {
+    /// Tuples first derived in this iteration.
+    pub delta: usize,
+    /// Recursive rules applied.
+    pub rules_applied: usize,
+    /// Tuples of the view itself fed into the recursive rules' joins, summed
+    /// over the rules.
+    pub join_inputs: usize
 }
 
-impl<'s> BottomUp<'s> {
-    fn new(name: &str,
-           cache: &'s ViewCache,
-           base_scans: Vec<Tuples<'s, 's>>,
-           recursive_rules: Vec<(Vec<String>, Vec<ast::Term>)>,
-           engine: &'s Storage) -> Result<BottomUp<'s>> {
-        let mut all_tuples = HashSet::new();
+/// Tracks the best tuple derived so far in each group of tuples that a
+/// subsumption order compares.
+struct Subsumer<'s> {
+    order: Subsumption,
+    best: HashMap<Vec<&'s Value>, Tuple<'s>>
+}
+
+impl<'s> Subsumer<'s> {
+    fn new(order: Subsumption) -> Self {
+        Subsumer { order, best: HashMap::new() }
+    }
 
-        for scan in base_scans {
-            for tuple in scan {
-                all_tuples.insert(tuple);
+    fn group(&self, tuple: &Tuple<'s>) -> Vec<&'s Value> {
+        let mut group = tuple.clone();
+        group.remove(self.order.column);
+        group
+    }
+
+    /// Is `tuple` strictly better than any tuple seen in its group? If so, it
+    /// becomes the best.
+    fn admit(&mut self, tuple: &Tuple<'s>) -> bool {
+        let column = self.order.column;
+        let group = self.group(tuple);
+        let better = match self.best.get(&group) {
+            None => true,
+            Some(best) => match self.order.keep {
+                Extremum::Min => tuple[column] < best[column],
+                Extremum::Max => tuple[column] > best[column]
             }
+        };
+        if better {
+            self.best.insert(group, tuple.clone());
+        }
+        better
+    }
+
+    fn is_best(&self, tuple: &Tuple<'s>) -> bool {
+        self.best.get(&self.group(tuple)) == Some(tuple)
+    }
+}
+
+// Filter derived tuples through an optional subsumer.
+fn admit<'s>(subsumer: &mut Option<Subsumer<'s>>, tuple: &Tuple<'s>) -> bool {
+    subsumer.as_mut().map_or(true, |s| s.admit(tuple))
+}
+
+// Copies of the tuples a fixpoint has derived. Tuples from the plans for a
+// view's rules may refer to values owned by those plans, like the facts of a
+// virtual table, and the plans are dropped long before the fixpoint is done.
+#[derive(Default)]
+struct Derived(Vec<Box<Vec<Value>>>);
+
+impl Derived {
+    fn keep<'s>(&mut self, tuple: Tuple) -> Tuple<'s> {
+        limits::derived();
+        let owned: Box<Vec<Value>> =
+            Box::new(tuple.into_iter().cloned().collect());
+        // The values live as long as this, and don't move as more are added.
+        let kept = owned.iter().map(|v| unsafe { mem::transmute(v) })
+                               .collect();
+        self.0.push(owned);
+        kept
+    }
+}
+
+// The values of a fixpoint's tuples, which outlive the copies they refer to.
+fn owned_tuples(tuples: HashSet<Tuple>) -> Vec<Vec<Value>> {
+    tuples.into_iter().map(|t| t.into_iter().cloned().collect()).collect()
+}
+
+// Drop tuples that have since been subsumed.
+fn prune<'s>(subsumer: &Option<Subsumer<'s>>, tuples: &mut HashSet<Tuple<'s>>) {
+    if let Some(s) = subsumer {
+        tuples.retain(|t| s.is_best(t));
+    }
+}
+
+// A view evaluated to a fixpoint with the rest of its stratum: scans over its
+// non-recursive rules, its recursive rules, and its subsumer, if any.
+struct Member<'s> {
+    name: String,
+    base_scans: Vec<Tuples<'s, 's>>,
+    recursive_rules: Vec<(Vec<String>, Vec<ast::Term>)>,
+    subsumer: Option<Subsumer<'s>>
+}
+
+// The tuples derived for each view of a stratum.
+type Relations<'s> = HashMap<String, HashSet<Tuple<'s>>>;
+
+// The recursive rules of a stratum, each with the name of its view.
+type RecursiveRules = Vec<(String, Vec<String>, Vec<ast::Term>)>;
+
+// Apply the non-recursive rules of each view of a stratum, returning the
+// tuples they derive, the stratum's recursive rules, and each view's subsumer.
+fn apply_base_rules<'s>(members: Vec<Member<'s>>, derived: &mut Derived)
+        -> Result<(Relations<'s>,
+                   RecursiveRules,
+                   HashMap<String, Option<Subsumer<'s>>>)> {
+    let mut tuples = Relations::new();
+    let mut rules = Vec::new();
+    let mut subsumers = HashMap::new();
+    for member in members {
+        let mut subsumer = member.subsumer;
+        let mut found = HashSet::new();
+        for scan in member.base_scans {
+            for_each_batched(&mut Guarded::new(scan), |tuple| {
+                let tuple = derived.keep(tuple);
+                if admit(&mut subsumer, &tuple) {
+                    found.insert(tuple);
+                }
+            });
+        }
+        limits::check()?;
+        spill::check()?;
+        prune(&subsumer, &mut found);
+        for (formals, rule) in member.recursive_rules {
+            rules.push((member.name.clone(), formals, rule));
+        }
+        tuples.insert(member.name.clone(), found);
+        subsumers.insert(member.name, subsumer);
+    }
+    Ok((tuples, rules, subsumers))
+}
+
+// The number of tuples of the views of a stratum that a rule reads.
+fn join_inputs(rule: &[ast::Term], tuples: &Relations) -> usize {
+    let read: HashSet<&str> = split_body(rule).0.into_iter().filter_map(|t| {
+        match t {
+            ast::Term::Compound(c) => Some(c.relation.as_str()),
+            _ => None
         }
+    }).collect();
+    read.into_iter().filter_map(|r| tuples.get(r)).map(HashSet::len).sum()
+}
+
+fn count_tuples(tuples: &Relations) -> usize {
+    tuples.values().map(HashSet::len).sum()
+}
+
+struct BottomUp {
+    all_tuples: HashMap<String, Vec<Vec<Value>>>,
+    stats: Vec<IterationStats>
+}
+
+impl BottomUp {
+    fn new<'s>(name: &str,
+               cache: &'s ViewCache,
+               members: Vec<Member<'s>>,
+               engine: &'s Storage) -> Result<BottomUp> {
+        let mut derived = Derived::default();
+        let (mut all_tuples, rules, mut subsumers) =
+            apply_base_rules(members, &mut derived)?;
+        let mut stats = vec![IterationStats {
+            delta: count_tuples(&all_tuples),
+            .. IterationStats::default()
+        }];
 
         // Now, repeatedly apply recursive rules.
         let mut new_tuple = true;
-        while new_tuple {
+        while new_tuple && limits::within_budget() {
             new_tuple = false;
-            for (formals, rule) in &recursive_rules {
+            let mut iteration = IterationStats::default();
+            for (view, formals, rule) in &rules {
                 let mut new_tuples = Vec::new();
+                iteration.rules_applied += 1;
+                iteration.join_inputs += join_inputs(rule, &all_tuples);
                 {
                     // Apply the given rule and see if we get any new tuples
+                    let subsumer = subsumers.get_mut(view).unwrap();
                     let scan = plan_recursive_rule(engine,
                                                    cache,
-                                                   name,
                                                    &rule,
                                                    &formals,
                                                    &all_tuples)?;
-                    for tuple in scan {
-                        if !all_tuples.contains(&tuple) {
+                    let known = &all_tuples[view];
+                    for_each_batched(&mut Guarded::new(scan), |tuple| {
+                        if known.contains(&tuple) {
+                            return;
+                        }
+                        let tuple = derived.keep(tuple);
+                        if admit(subsumer, &tuple) {
                             new_tuple = true;
                             new_tuples.push(tuple);
                         }
-                    }
+                    });
+                    limits::check()?;
+                    spill::check()?;
                 }
+                let tuples = all_tuples.get_mut(view).unwrap();
                 for tuple in new_tuples {
-                    all_tuples.insert(tuple);
+                    if tuples.insert(tuple) {
+                        iteration.delta += 1;
+                    }
                 }
+                prune(&subsumers[view], tuples);
             }
+            trace!("{}: iteration {} derived {} tuples", name, stats.len(),
+                   iteration.delta);
+            stats.push(iteration);
+        }
+        if limits::cut_short(name, stats.len() - 1) {
+            debug!("{}: out of time after {} iterations", name, stats.len());
+        } else {
+            debug!("{}: fixpoint after {} iterations", name, stats.len());
         }
 
-        println!("tuples: {}", all_tuples.len());
-        Ok(BottomUp { all_tuples: all_tuples.into_iter().collect(), index: 0 })
-    }
-}
-
-impl<'s> Iterator for BottomUp<'s> {
-    type Item = Tuple<'s>;
-
-    fn next(&mut self) -> Option<Tuple<'s>> {
-        let result = self.all_tuples.get(self.index);
-        self.index += 1;
-        return result.map(|t| t.clone());
-    }
-}
-
-impl<'s> Plan for BottomUp<'s> {
-    fn reset(&mut self) {
-        self.index = 0;
+        Ok(BottomUp {
+            all_tuples: all_tuples.into_iter().map(|(view, tuples)| {
+                (view, owned_tuples(tuples))
+            }).collect(),
+            stats
+        })
     }
 }
 
-struct SemiNaive<'s> {
-    all_tuples: Vec<Tuple<'s>>,
-    index: usize
+struct SemiNaive {
+    all_tuples: HashMap<String, Vec<Vec<Value>>>,
+    stats: Vec<IterationStats>
 }
 
-impl<'s> SemiNaive<'s> {
-    fn new(name: &str,
-           cache: &'s ViewCache,
-           base_scans: Vec<Tuples<'s, 's>>,
-           recursive_rules: Vec<(Vec<String>, Vec<ast::Term>)>,
-           engine: &'s Storage) -> Result<SemiNaive<'s>> {
-        let mut all_tuples = HashSet::new();
-
-        let mut last_tuples = HashSet::new();
-        let mut new_tuples = HashSet::new();
-        println!("base scans: {}", base_scans.len());
-        for scan in base_scans {
-            for tuple in scan {
-                last_tuples.insert(tuple);
-            }
-        }
-        println!("recursive rules: {}", recursive_rules.len());
+impl SemiNaive {
+    fn new<'s>(name: &str,
+               cache: &'s ViewCache,
+               members: Vec<Member<'s>>,
+               engine: &'s Storage) -> Result<SemiNaive> {
+        let mut derived = Derived::default();
+        let (mut last_tuples, rules, mut subsumers) =
+            apply_base_rules(members, &mut derived)?;
+        let empty: Relations = last_tuples.keys().map(|view| {
+            (view.clone(), HashSet::new())
+        }).collect();
+        let mut all_tuples = empty.clone();
+        let mut new_tuples = empty;
+        let mut stats = vec![IterationStats {
+            delta: count_tuples(&last_tuples),
+            .. IterationStats::default()
+        }];
 
-        // Now, repeatedly apply recursive rules.
-        while !last_tuples.is_empty() {
-            assert!(new_tuples.is_empty());
-            for (formals, rule) in &recursive_rules {
+        // Now, repeatedly apply recursive rules, each to the tuples of the
+        // stratum's views derived in the last round.
+        while count_tuples(&last_tuples) > 0 && limits::within_budget() {
+            assert_eq!(count_tuples(&new_tuples), 0);
+            let mut iteration = IterationStats::default();
+            for (view, formals, rule) in &rules {
+                iteration.rules_applied += 1;
+                iteration.join_inputs += join_inputs(rule, &last_tuples);
                 {
                     // Apply the given rule and see if we get any new tuples
+                    let subsumer = subsumers.get_mut(view).unwrap();
                     let scan = plan_recursive_rule(engine,
                                                    cache,
-                                                   name,
                                                    &rule,
                                                    &formals,
                                                    &last_tuples)?;
-                    for tuple in scan {
-                        if (!all_tuples.contains(&tuple))
-                        && (!last_tuples.contains(&tuple))
-                        && (!new_tuples.contains(&tuple)) {
-                            new_tuples.insert(tuple);
+                    let all = &all_tuples[view];
+                    let last = &last_tuples[view];
+                    let new = new_tuples.get_mut(view).unwrap();
+                    for_each_batched(&mut Guarded::new(scan), |tuple| {
+                        if all.contains(&tuple)
+                        || last.contains(&tuple)
+                        || new.contains(&tuple) {
+                            return;
                         }
-                    }
+                        let tuple = derived.keep(tuple);
+                        if admit(subsumer, &tuple) {
+                            new.insert(tuple);
+                        }
+                    });
+                    limits::check()?;
+                    spill::check()?;
                 }
             }
-            for tuple in last_tuples.drain() {
-                assert!(!new_tuples.contains(&tuple));
-                all_tuples.insert(tuple);
-            }
-            assert!(last_tuples.is_empty());
+            for (view, last) in last_tuples.iter_mut() {
+                let all = all_tuples.get_mut(view).unwrap();
+                for tuple in last.drain() {
+                    all.insert(tuple);
+                }
 
-            for tuple in new_tuples.drain() {
-                assert!(!all_tuples.contains(&tuple));
-                last_tuples.insert(tuple);
+                let new = new_tuples.get_mut(view).unwrap();
+                prune(&subsumers[view], new);
+                iteration.delta += new.len();
+                for tuple in new.drain() {
+                    assert!(!all.contains(&tuple));
+                    last.insert(tuple);
+                }
             }
-            assert!(new_tuples.is_empty());
+            trace!("{}: iteration {} derived {} tuples", name, stats.len(),
+                   iteration.delta);
+            stats.push(iteration);
+        }
+        // Stopped early, the last tuples derived are still tuples of the views.
+        for (view, last) in last_tuples.iter_mut() {
+            let all = all_tuples.get_mut(view).unwrap();
+            all.extend(last.drain());
+            prune(&subsumers[view], all);
+        }
+        if limits::cut_short(name, stats.len() - 1) {
+            debug!("{}: out of time after {} iterations", name, stats.len());
+        } else {
+            debug!("{}: fixpoint after {} iterations", name, stats.len());
         }
 
-        println!("total tuples: {}", all_tuples.len());
-        Ok(SemiNaive { all_tuples: all_tuples.into_iter().collect(), index: 0 })
-    }
-}
-
-impl<'s> Iterator for SemiNaive<'s> {
-    type Item = Tuple<'s>;
-
-    fn next(&mut self) -> Option<Tuple<'s>> {
-        let result = self.all_tuples.get(self.index);
-        self.index += 1;
-        return result.map(|t| t.clone());
+        Ok(SemiNaive {
+            all_tuples: all_tuples.into_iter().map(|(view, tuples)| {
+                (view, owned_tuples(tuples))
+            }).collect(),
+            stats
+        })
     }
 }
 
-impl<'s> Plan for SemiNaive<'s> {
-    fn reset(&mut self) {
-        self.index = 0;
+// Evaluate the views of a recursive stratum together to a fixpoint, on behalf
+// of a query of the named one, returning the tuples of each view and what each
+// iteration did.
+fn evaluate_stratum<'s>(name: &str,
+                        engine: &'s Storage,
+                        cache: &'s ViewCache,
+                        stratum: &Stratum,
+                        semi_naive: bool)
+        -> Result<(HashMap<String, Vec<Vec<Value>>>, Vec<IterationStats>)> {
+    let mut members = Vec::new();
+    for view_name in &stratum.views {
+        let view = match engine.get_relation(view_name) {
+            Some(Intension(view)) => view,
+            _ => return Err(Error::NotIntensional(view_name.clone()))
+        };
+        let (base_scans, recursive_rules) = IntensionalScan::split_view(
+            view_name, engine, cache, view, &stratum.views)?;
+        members.push(Member {
+            name: view_name.clone(),
+            base_scans,
+            recursive_rules,
+            subsumer: view.subsumer()
+        });
     }
+    Ok(if semi_naive {
+        let fixpoint = SemiNaive::new(name, cache, members, engine)?;
+        (fixpoint.all_tuples, fixpoint.stats)
+    } else {
+        let fixpoint = BottomUp::new(name, cache, members, engine)?;
+        (fixpoint.all_tuples, fixpoint.stats)
+    })
 }
 
 struct SetNode<'s: 'a, 'a> {
@@ -372,23 +864,112 @@ impl<'s: 'a, 'a> Plan for Chain<'s, 'a> {
         }
         self.current = 0;
     }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Tuple<'s>> {
+        while self.current < self.parts.len() {
+            let batch = self.parts[self.current].next_batch(max);
+            if !batch.is_empty() {
+                return batch;
+            }
+            self.current += 1;
+        }
+        Vec::new()
+    }
+}
+
+/// Filters a scan down to the tuples agreeing with some constants.
+struct Selection<'s: 'a, 'a> {
+    constants: Vec<(usize, Value)>,
+    child: Tuples<'s, 'a>
+}
+
+impl<'s: 'a, 'a> Selection<'s, 'a> {
+    fn new(pattern: &[Option<Value>], child: Tuples<'s, 'a>) -> Self {
+        let constants = pattern.iter().enumerate().filter_map(|(i, bound)| {
+            bound.as_ref().map(|v| (i, v.clone()))
+        }).collect();
+        Selection { constants, child }
+    }
+
+    fn matches(&self, tuple: &Tuple<'s>) -> bool {
+        self.constants.iter().all(|(i, v)| tuple[*i] == v)
+    }
+}
+
+impl<'s: 'a, 'a> Iterator for Selection<'s, 'a> {
+    type Item = Tuple<'s>;
+
+    fn next(&mut self) -> Option<Tuple<'s>> {
+        while let Some(t) = self.child.next() {
+            if self.matches(&t) {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Selection<'s, 'a> {
+    fn reset(&mut self) {
+        self.child.reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Tuple<'s>> {
+        loop {
+            let batch = self.child.next_batch(max);
+            if batch.is_empty() {
+                return batch;
+            }
+            let selected: Vec<Tuple<'s>> =
+                batch.into_iter().filter(|t| self.matches(t)).collect();
+            if !selected.is_empty() {
+                return selected;
+            }
+        }
+    }
 }
 
+/// Adds the tuples passing through it to the view cache, under the binding
+/// pattern they were queried with.
+///
+/// Tuples are held back until the child plan is exhausted, so that a scan
+/// abandoned part way through never leaves a partial extent in the cache.
 struct CachingWrapper<'s, P> {
     name: String,
+    pattern: BindingPattern,
     cache: &'s ViewCache,
+    pending: Vec<Vec<Value>>,
     child: P
 }
 
 impl<'s, P> CachingWrapper<'s, P> {
-    fn new(name: String, cache: &'s ViewCache, child: P)
-            -> CachingWrapper<'s, P> {
+    fn new(name: String,
+           pattern: BindingPattern,
+           cache: &'s ViewCache,
+           child: P) -> CachingWrapper<'s, P> {
         CachingWrapper {
             name,
+            pattern,
             cache,
+            pending: Vec::new(),
             child
         }
     }
+
+    fn record(&mut self, tuple: &Tuple) {
+        self.pending.push(tuple.iter().map(|v| (*v).clone()).collect());
+    }
+
+    // The child is exhausted, so everything it returned is the full result,
+    // unless the query's budget ran out or a join failed, cutting short what
+    // it was built on.
+    fn publish(&mut self) {
+        let tuples = mem::replace(&mut self.pending, Vec::new());
+        if limits::spent() || spill::failed() {
+            return;
+        }
+        self.cache.publish(self.name.clone(), &self.pattern, tuples);
+    }
 }
 
 impl<'s, 'a, P: Iterator<Item = Tuple<'a>>> Iterator for CachingWrapper<'s, P> {
@@ -397,11 +978,9 @@ impl<'s, 'a, P: Iterator<Item = Tuple<'a>>> Iterator for CachingWrapper<'s, P> {
     fn next(&mut self) -> Option<Tuple<'a>> {
         let result = self.child.next();
 
-        if let Some(ref t) = &result {
-            let owned_tuple = (&t).into_iter()
-                                  .map(|s| s.to_string())
-                                  .collect();
-            self.cache.add_tuple(self.name.clone(), owned_tuple);
+        match result {
+            Some(ref t) => self.record(t),
+            None => self.publish()
         }
 
         result
@@ -410,18 +989,37 @@ impl<'s, 'a, P: Iterator<Item = Tuple<'a>>> Iterator for CachingWrapper<'s, P> {
 
 impl<'s, 'a, P: Plan<Item = Tuple<'a>>> Plan for CachingWrapper<'s, P> {
     fn reset(&mut self) {
+        self.pending.clear();
         self.child.reset();
     }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Tuple<'a>> {
+        let batch = self.child.next_batch(max);
+
+        if batch.is_empty() {
+            self.publish();
+        }
+        for t in &batch {
+            self.record(t);
+        }
+
+        batch
+    }
 }
 
 struct VecPlan<'a> {
-    contents: Vec<Vec<String>>,
+    contents: Rc<Vec<Vec<Value>>>,
     index: Cell<usize>,
     phantom: PhantomData<&'a ()>
 }
 
 impl<'a> VecPlan<'a> {
-    fn new(contents: Vec<Vec<String>>) -> Self {
+    fn new(contents: Vec<Vec<Value>>) -> Self {
+        Self::shared(Rc::new(contents))
+    }
+
+    // A plan over contents that something else may also be holding on to.
+    fn shared(contents: Rc<Vec<Vec<Value>>>) -> Self {
         VecPlan {
             contents,
             index: Cell::new(0),
@@ -442,7 +1040,7 @@ impl<'a> Iterator for VecPlan<'a> {
 
         for atom in &self.contents[self.index.get()] {
             unsafe {
-                result.push(mem::transmute(atom.as_str()))
+                result.push(mem::transmute(atom))
             }
         }
 
@@ -506,291 +1104,4071 @@ impl<'s: 'a, 'a> Plan for PatternMatch<'s, 'a> {
     fn reset(&mut self) {
         self.child.reset();
     }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        loop {
+            let tuples = self.child.next_batch(max);
+            if tuples.is_empty() {
+                return Vec::new();
+            }
+
+            let pattern = &mut self.pattern;
+            let frames: Vec<Frame<'s>> =
+                tuples.into_iter().filter_map(|t| pattern.match_tuple(t))
+                                  .collect();
+            if !frames.is_empty() {
+                return frames;
+            }
+        }
+    }
 }
 
-/// Represents a cross join between two FramePlans.
-struct Join<'s: 'a, 'a> {
-    left: Frames<'s, 'a>,
-    right: Frames<'s, 'a>,
-    /// Where are we currently in the left scan? `None` if we haven't started.
-    current_left: Option<Frame<'s>>
+/// Keeps the frames of its child for which a comparison like `before(X, Y)`
+/// holds (or, if `expected` is false, doesn't hold).
+struct Comparison<'s: 'a, 'a> {
+    child: Frames<'s, 'a>,
+    term: ast::Term,
+    expected: bool
 }
 
-impl<'s: 'a, 'a> Join<'s, 'a> {
-    fn new(left: Frames<'s, 'a>, right: Frames<'s, 'a>) -> Join<'s, 'a> {
-        Join {
-            left,
-            right,
-            current_left: None
-        }
+impl<'s: 'a, 'a> Comparison<'s, 'a> {
+    fn new(child: Frames<'s, 'a>, term: ast::Term, expected: bool) -> Self {
+        Comparison { child, term, expected }
+    }
+
+    fn keep(&self, frame: &Frame<'s>) -> bool {
+        comparison_holds(&self.term, |v| {
+            *frame.get(v).unwrap_or_else(|| {
+                panic!("frame in comparison missing a variable")
+            })
+        }) == self.expected
     }
 }
 
-impl<'s: 'a, 'a> Iterator for Join<'s, 'a> {
+impl<'s: 'a, 'a> Iterator for Comparison<'s, 'a> {
     type Item = Frame<'s>;
 
     fn next(&mut self) -> Option<Frame<'s>> {
-        loop {
-            match self.right.next() {
-                None => {
-                    self.right.reset();
-                    let r = self.right.next()?;
-                    let l = self.left.next()?;
-                    self.current_left = Some(l.clone());
-                    if let Some(result) = merge_frames(&l, &r) {
-                        return Some(result);
-                    };
-                },
-                Some(r) => {
-                    if let Some(ref l) = self.current_left {
-                        if let Some(result) = merge_frames(&l, &r) {
-                            return Some(result);
-                        }
-                    } else {
-                        // Left iterator hasn't been advanced
-                        let l = self.left.next()?;
-                        self.current_left = Some(l.clone());
-                        if let Some(result) = merge_frames(&l, &r) {
-                            return Some(result);
-                        }
-                    }
-                }
+        while let Some(frame) = self.child.next() {
+            if self.keep(&frame) {
+                return Some(frame);
             }
         }
+        None
     }
 }
 
-impl<'s: 'a, 'a> Plan for Join<'s, 'a> {
+impl<'s: 'a, 'a> Plan for Comparison<'s, 'a> {
     fn reset(&mut self) {
-        self.left.reset();
-        self.right.reset();
-        self.current_left = None;
+        self.child.reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        loop {
+            let batch = self.child.next_batch(max);
+            if batch.is_empty() {
+                return batch;
+            }
+            let kept: Vec<Frame<'s>> =
+                batch.into_iter().filter(|f| self.keep(f)).collect();
+            if !kept.is_empty() {
+                return kept;
+            }
+        }
     }
 }
 
-//
-// Frames and pattern matching.
-//
+/// Passes on the items of its child until the query exceeds one of its limits,
+/// counting them as rows returned if `rows` is set; see `limits`.
+struct Guarded<P> {
+    child: P,
+    rows: bool
+}
 
-pub type Frame<'a> = BTreeMap<String, &'a str>;
+impl<P: Plan> Guarded<P> {
+    fn new(child: P) -> Self {
+        Guarded { child, rows: false }
+    }
 
-#[derive(Debug)]
-struct Pattern {
-    params: Vec<ast::AtomicTerm>
-}
+    fn rows(child: P) -> Self {
+        Guarded { child, rows: true }
+    }
+
+    fn within(&self) -> bool {
+        if self.rows {
+            limits::row()
+        } else {
+            limits::within()
+        }
+    }
+}
+
+impl<P: Plan> Iterator for Guarded<P> {
+    type Item = P::Item;
+
+    fn next(&mut self) -> Option<P::Item> {
+        let item = self.child.next()?;
+        if self.within() {
+            Some(item)
+        } else {
+            None
+        }
+    }
+}
+
+impl<P: Plan> Plan for Guarded<P> {
+    fn reset(&mut self) {
+        self.child.reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<P::Item> {
+        let mut batch = self.child.next_batch(max);
+        if self.rows {
+            let allowed = batch.iter().take_while(|_| limits::row()).count();
+            batch.truncate(allowed);
+        } else if !limits::within() {
+            batch.clear();
+        }
+        batch
+    }
+}
+
+/// Extends each frame of its child with the tuples of a registered built-in
+/// relation for the values bound in the frame, producing a frame for each.
+struct Call<'s: 'a, 'a> {
+    child: Frames<'s, 'a>,
+    name: String,
+    params: Vec<ast::AtomicTerm>,
+    builtin: Rc<Registered>,
+    // Every tuple the built-in has returned, which the frames produced refer
+    // to. Boxed, so the values don't move as more are added.
+    results: Vec<Box<Vec<Value>>>,
+    pending: vec::IntoIter<Frame<'s>>
+}
+
+impl<'s: 'a, 'a> Call<'s, 'a> {
+    fn new(child: Frames<'s, 'a>, term: &ast::Term, builtin: Rc<Registered>)
+            -> Self {
+        let (name, params) = match term {
+            ast::Term::Compound(c) => (c.relation.clone(), c.params.clone()),
+            _ => panic!("not a built-in call: {}", term)
+        };
+        Call {
+            child,
+            name,
+            params,
+            builtin,
+            results: Vec::new(),
+            pending: Vec::new().into_iter()
+        }
+    }
+
+    fn extend(&mut self, frame: Frame<'s>) -> Vec<Frame<'s>> {
+        let tuples = self.builtin.call(&self.name, &self.params, |v| {
+            *frame.get(v).unwrap_or_else(|| {
+                panic!("frame in built-in call missing a variable")
+            })
+        });
+        let mut frames = Vec::new();
+        for tuple in tuples {
+            let tuple = Box::new(tuple);
+            // The values live as long as this plan, like those of a VecPlan.
+            let values: &'s Vec<Value> = unsafe { mem::transmute(&*tuple) };
+            self.results.push(tuple);
+            let mut extended = frame.clone();
+            let matches = self.params.iter().zip(values).all(|(param, value)| {
+                match param {
+                    ast::AtomicTerm::Atom(a) => a == value,
+                    ast::AtomicTerm::Variable(v) =>
+                        *extended.entry(v.clone()).or_insert(value) == value
+                }
+            });
+            if matches {
+                frames.push(extended);
+            }
+        }
+        frames
+    }
+}
+
+impl<'s: 'a, 'a> Iterator for Call<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        loop {
+            if let Some(frame) = self.pending.next() {
+                return Some(frame);
+            }
+            let frame = self.child.next()?;
+            self.pending = self.extend(frame).into_iter();
+        }
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Call<'s, 'a> {
+    fn reset(&mut self) {
+        self.child.reset();
+        self.pending = Vec::new().into_iter();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        let mut batch: Vec<Frame<'s>> = self.pending.by_ref().collect();
+        while batch.is_empty() {
+            let frames = self.child.next_batch(max);
+            if frames.is_empty() {
+                break;
+            }
+            for frame in frames {
+                let extended = self.extend(frame);
+                batch.extend(extended);
+            }
+        }
+        batch
+    }
+}
+
+/// Keeps one frame of its child for each group of frames agreeing on all but
+/// the last parameter of a term like `min_by(K, V)`: the one with the least
+/// (or for `max_by`, the greatest) value of the last parameter.
+///
+/// The child is read in full the first time a frame is asked for, since any
+/// frame could turn out to be the best in its group.
+struct Extremes<'s: 'a, 'a> {
+    child: Frames<'s, 'a>,
+    term: ast::Term,
+    kept: Option<vec::IntoIter<Frame<'s>>>
+}
+
+impl<'s: 'a, 'a> Extremes<'s, 'a> {
+    fn new(child: Frames<'s, 'a>, term: ast::Term) -> Self {
+        Extremes { child, term, kept: None }
+    }
+
+    fn read_child(&mut self) -> &mut vec::IntoIter<Frame<'s>> {
+        if self.kept.is_none() {
+            let mut frames = Vec::new();
+            for_each_batched(&mut *self.child, |frame| frames.push(frame));
+            let kept = extremes(&self.term, &frames);
+            let mut frames: Vec<Option<Frame<'s>>> =
+                frames.into_iter().map(Some).collect();
+            let kept: Vec<Frame<'s>> = kept.into_iter().map(|i| {
+                frames[i].take().unwrap()
+            }).collect();
+            self.kept = Some(kept.into_iter());
+        }
+        self.kept.as_mut().unwrap()
+    }
+}
+
+impl<'s: 'a, 'a> Iterator for Extremes<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        self.read_child().next()
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Extremes<'s, 'a> {
+    fn reset(&mut self) {
+        self.child.reset();
+        self.kept = None;
+    }
+}
+
+/// Streams the frames of its left child, dropping those that agree with some
+/// frame of its right child on all of `variables`.
+///
+/// The right child is read into a hash set the first time a frame is asked
+/// for, so each left frame is checked in constant time. With no variables in
+/// common, the right child need only be checked for a single frame.
+struct AntiJoin<'s: 'a, 'a> {
+    left: Frames<'s, 'a>,
+    // Kept after it has been read, since the excluded keys may borrow from
+    // it, e.g. if it scans a `VecPlan`.
+    right: Frames<'s, 'a>,
+    read: bool,
+    variables: Vec<String>,
+    excluded: HashSet<Vec<&'s Value>>
+}
+
+impl<'s: 'a, 'a> AntiJoin<'s, 'a> {
+    fn new(left: Frames<'s, 'a>,
+           right: Frames<'s, 'a>,
+           variables: Vec<String>) -> Self {
+        AntiJoin {
+            left,
+            right,
+            read: false,
+            variables,
+            excluded: HashSet::new()
+        }
+    }
+
+    fn key(variables: &[String], frame: &Frame<'s>) -> Vec<&'s Value> {
+        variables.iter().map(|v| {
+            *frame.get(v).unwrap_or_else(|| {
+                panic!("frame in anti-join missing a variable")
+            })
+        }).collect()
+    }
+
+    fn read_right(&mut self) {
+        if self.read {
+            return;
+        }
+        self.read = true;
+        if self.variables.is_empty() {
+            if self.right.next().is_some() {
+                self.excluded.insert(Vec::new());
+            }
+            return;
+        }
+
+        let mut excluded = HashSet::new();
+        let variables = &self.variables;
+        for_each_batched(&mut *self.right, |frame| {
+            excluded.insert(Self::key(variables, &frame));
+        });
+        self.excluded = excluded;
+    }
+
+    fn keep(&self, frame: &Frame<'s>) -> bool {
+        !self.excluded.contains(&Self::key(&self.variables, frame))
+    }
+}
+
+impl<'s: 'a, 'a> Iterator for AntiJoin<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        self.read_right();
+        while let Some(frame) = self.left.next() {
+            if self.keep(&frame) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+impl<'s: 'a, 'a> Plan for AntiJoin<'s, 'a> {
+    fn reset(&mut self) {
+        self.left.reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        self.read_right();
+        loop {
+            let batch = self.left.next_batch(max);
+            if batch.is_empty() {
+                return batch;
+            }
+            let kept: Vec<Frame<'s>> =
+                batch.into_iter().filter(|f| self.keep(f)).collect();
+            if !kept.is_empty() {
+                return kept;
+            }
+        }
+    }
+}
+
+/// Represents a cross join between two FramePlans.
+///
+/// Once it has seen how many frames the right (build) side produces, a join on
+/// shared variables picks a strategy as `spill` describes: a nested loop, a
+/// hash join, or a Grace hash join spilling both sides to disk. Joins without
+/// shared variables always use a nested loop.
+///
+/// The nested loop is a block nested loop: each block of `BATCH_SIZE` frames
+/// from the left side is joined against a complete pass over the right side,
+/// so the right side is rescanned once per block rather than once per frame.
+struct Join<'s: 'a, 'a> {
+    left: Frames<'s, 'a>,
+    right: Frames<'s, 'a>,
+    /// The variables bound by both sides, which must agree in merged frames.
+    shared: Vec<String>,
+    left_block: Vec<Frame<'s>>,
+    right_block: Vec<Frame<'s>>,
+    /// The pair of frames (one from each block) most recently merged.
+    left_index: usize,
+    right_index: usize,
+    /// Whether the right block holds the whole right side, which is then
+    /// never rescanned.
+    right_held: bool,
+    /// How the join is evaluated, once the right side has been counted.
+    strategy: Option<Strategy>,
+    /// For hash joins, frames from the right side by their key; for Grace
+    /// hash joins, those of the block of the partition being joined.
+    table: HashMap<spill::Key<'s>, Vec<Frame<'s>>>,
+    /// Merged frames not yet returned.
+    pending: VecDeque<Frame<'s>>,
+    spilled: Option<Spilled>,
+    /// Where values read back from spilled frames are kept, for merged frames
+    /// to borrow; see `ViewCache::keep_spilled`.
+    cache: &'s ViewCache,
+    /// The scan whose rules the join is in, to record its strategy in.
+    node: Option<Rc<RefCell<profile::Node>>>
+}
+
+// The state of a Grace hash join.
+struct Spilled {
+    left: spill::Partitions,
+    right: spill::Partitions,
+    /// The next partition to join.
+    partition: usize,
+    /// The rest of the right side of the partition being joined, if it didn't
+    /// fit in one block.
+    build: Option<spill::PartitionReader>,
+    /// The left side of the partition being joined, to probe the current
+    /// block with.
+    probe: Option<spill::PartitionReader>
+}
+
+impl<'s: 'a, 'a> Join<'s, 'a> {
+    fn new(left: Frames<'s, 'a>,
+           right: Frames<'s, 'a>,
+           shared: Vec<String>,
+           cache: &'s ViewCache) -> Join<'s, 'a> {
+        Join {
+            left,
+            right,
+            shared,
+            left_block: Vec::new(),
+            right_block: Vec::new(),
+            left_index: 0,
+            right_index: 0,
+            right_held: false,
+            strategy: None,
+            table: HashMap::new(),
+            pending: VecDeque::new(),
+            spilled: None,
+            cache,
+            node: profile::current()
+        }
+    }
+
+    // Count the right side, up to the memory budget, and pick a strategy
+    // accordingly.
+    fn choose(&mut self) {
+        let config = spill::config();
+        let mut build = Vec::new();
+        let mut fits = true;
+        if !self.shared.is_empty() {
+            loop {
+                let batch = self.right.next_batch(BATCH_SIZE);
+                if batch.is_empty() {
+                    break;
+                }
+                build.extend(batch);
+                if build.len() > config.memory_budget {
+                    fits = false;
+                    break;
+                }
+            }
+        }
+
+        let strategy = if fits && build.len() <= config.nested_loop_max {
+            // Counting the right side read all of it, so keep it.
+            self.right_held = !self.shared.is_empty();
+            self.right_block = build;
+            Strategy::NestedLoop
+        } else if fits {
+            for frame in build {
+                let key = spill::key(&self.shared, &frame);
+                self.table.entry(key).or_insert_with(Vec::new).push(frame);
+            }
+            Strategy::Hash
+        } else {
+            match self.spill(build, config.partitions) {
+                Ok(spilled) => {
+                    self.spilled = Some(spilled);
+                    Strategy::Grace
+                },
+                Err(e) => {
+                    warn!("cannot spill join to disk, so using a nested \
+                           loop: {}", e);
+                    self.left.reset();
+                    self.right.reset();
+                    Strategy::NestedLoop
+                }
+            }
+        };
+        debug!("joining on {:?} with strategy {}", self.shared, strategy);
+        if let Some(ref node) = self.node {
+            *node.borrow_mut().joins.entry(strategy.to_string())
+                                    .or_insert(0) += 1;
+        }
+        self.strategy = Some(strategy);
+    }
+
+    // Partition the frames already read from the right side, the rest of the
+    // right side and the whole left side.
+    fn spill(&mut self, build: Vec<Frame<'s>>, partitions: usize)
+            -> Result<Spilled> {
+        let mut right = spill::Partitions::create(partitions)?;
+        for frame in &build {
+            right.write(&spill::key(&self.shared, frame), frame)?;
+        }
+        drop(build);
+        loop {
+            let batch = self.right.next_batch(BATCH_SIZE);
+            if batch.is_empty() {
+                break;
+            }
+            for frame in &batch {
+                right.write(&spill::key(&self.shared, frame), frame)?;
+            }
+        }
+
+        let mut left = spill::Partitions::create(partitions)?;
+        loop {
+            let batch = self.left.next_batch(BATCH_SIZE);
+            if batch.is_empty() {
+                break;
+            }
+            for frame in &batch {
+                left.write(&spill::key(&self.shared, frame), frame)?;
+            }
+        }
+        Ok(Spilled {
+            left,
+            right,
+            partition: 0,
+            build: None,
+            probe: None
+        })
+    }
+
+    /// Move on to the next pair of frames to merge, fetching new blocks from
+    /// the children as needed. Returns `false` once the join is exhausted.
+    ///
+    /// Maintains the invariant that the right block is nonempty whenever the
+    /// left block is.
+    fn advance(&mut self) -> bool {
+        self.left_index += 1;
+        if self.left_index < self.left_block.len() {
+            return true;
+        }
+
+        self.left_index = 0;
+        self.right_index += 1;
+        if self.right_index < self.right_block.len()
+                && !self.left_block.is_empty() {
+            return true;
+        }
+
+        self.right_index = 0;
+        if !self.left_block.is_empty() && !self.right_held {
+            self.right_block = self.right.next_batch(BATCH_SIZE);
+            if !self.right_block.is_empty() {
+                return true;
+            }
+        }
+
+        // We've made a full pass over the right side for this block, so start
+        // on the next block from the left.
+        self.left_block = self.left.next_batch(BATCH_SIZE);
+        if self.left_block.is_empty() {
+            return false;
+        }
+        if !self.right_held {
+            self.right.reset();
+            self.right_block = self.right.next_batch(BATCH_SIZE);
+        }
+        if self.right_block.is_empty() {
+            self.left_block.clear();
+            return false;
+        }
+        true
+    }
+
+    fn merge_current(&self) -> Option<Frame<'s>> {
+        let left = &self.left_block[self.left_index];
+        let right = &self.right_block[self.right_index];
+        let agree = self.shared.iter().all(|v| {
+            match (left.get(v), right.get(v)) {
+                (Some(l), Some(r)) => l == r,
+                _ => true
+            }
+        });
+        if !agree {
+            return None;
+        }
+        Some(merge(left, right))
+    }
+
+    // The next merged frame of a nested loop join.
+    fn next_nested(&mut self) -> Option<Frame<'s>> {
+        while self.advance() {
+            if let Some(result) = self.merge_current() {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    // The next merged frame of a hash join, probing the table with frames
+    // from the left side until one matches.
+    fn next_hashed(&mut self) -> Option<Frame<'s>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Some(frame);
+            }
+            if self.left_index >= self.left_block.len() {
+                self.left_block = self.left.next_batch(BATCH_SIZE);
+                self.left_index = 0;
+                if self.left_block.is_empty() {
+                    return None;
+                }
+            }
+            let left = &self.left_block[self.left_index];
+            self.left_index += 1;
+            let key = spill::key(&self.shared, left);
+            if let Some(matches) = self.table.get(&key) {
+                self.pending.extend(matches.iter().map(|r| merge(left, r)));
+            }
+        }
+    }
+
+    // The next merged frame of a Grace hash join. If the spilled frames can't
+    // be read back, the join stops, noting why for `spill::check`.
+    fn next_spilled(&mut self) -> Option<Frame<'s>> {
+        match self.try_next_spilled() {
+            Ok(frame) => frame,
+            Err(e) => {
+                spill::fail(e);
+                self.spilled = None;
+                self.table.clear();
+                None
+            }
+        }
+    }
+
+    // Probe the table with spilled frames from the left side until one
+    // matches.
+    fn try_next_spilled(&mut self) -> Result<Option<Frame<'s>>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(Some(frame));
+            }
+            let probe = self.spilled.as_mut()
+                                    .and_then(|s| s.probe.as_mut()?.next());
+            match probe {
+                Some(left) => {
+                    let left = restore(self.cache, left?);
+                    let key = spill::key(&self.shared, &left);
+                    if let Some(matches) = self.table.get(&key) {
+                        self.pending.extend(
+                            matches.iter().map(|r| merge(&left, r)));
+                    }
+                },
+                None => if !self.next_spilled_block()? {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    // Hash the next block of the right side of a Grace hash join, moving on to
+    // the next partition once the current one is used up. Returns `false`
+    // once every partition has been joined.
+    fn next_spilled_block(&mut self) -> Result<bool> {
+        let budget = spill::config().memory_budget.max(1);
+        let spilled = match self.spilled {
+            Some(ref mut spilled) => spilled,
+            None => return Ok(false)
+        };
+        self.table.clear();
+        loop {
+            if let Some(ref mut build) = spilled.build {
+                for frame in build.by_ref().take(budget) {
+                    let frame = restore(self.cache, frame?);
+                    let key = spill::key(&self.shared, &frame);
+                    self.table.entry(key).or_insert_with(Vec::new)
+                              .push(frame);
+                }
+            }
+            if !self.table.is_empty() {
+                let partition = spilled.partition - 1;
+                spilled.probe = Some(spilled.left.read(partition)?);
+                return Ok(true);
+            }
+            if spilled.partition == spilled.right.len() {
+                return Ok(false);
+            }
+            spilled.build = Some(spilled.right.read(spilled.partition)?);
+            spilled.partition += 1;
+        }
+    }
+}
+
+// Merge two frames agreeing on their shared variables.
+fn merge<'s>(left: &Frame<'s>, right: &Frame<'s>) -> Frame<'s> {
+    let mut merged = left.clone();
+    merged.extend(right.iter().filter(|(v, _)| !left.contains_key(*v))
+                              .map(|(v, value)| (v.clone(), *value)));
+    merged
+}
+
+// Turn a frame read back from disk into one borrowing its values from the
+// cache.
+fn restore<'s>(cache: &'s ViewCache,
+               frame: BTreeMap<String, Value>) -> Frame<'s> {
+    frame.into_iter().map(|(variable, value)| {
+        (variable, cache.keep_spilled(value))
+    }).collect()
+}
+
+impl<'s: 'a, 'a> Iterator for Join<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        if self.strategy.is_none() {
+            self.choose();
+        }
+        match self.strategy {
+            Some(Strategy::Hash) => self.next_hashed(),
+            Some(Strategy::Grace) => self.next_spilled(),
+            _ => self.next_nested()
+        }
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Join<'s, 'a> {
+    // The right side gives the same frames each time, so a join holding all
+    // of them, or a hash join, keeps them, and only rescans the left side. A
+    // Grace hash join starts over.
+    fn reset(&mut self) {
+        self.left.reset();
+        self.left_block.clear();
+        self.left_index = 0;
+        self.right_index = 0;
+        self.pending.clear();
+        match self.strategy {
+            Some(Strategy::Hash) => (),
+            Some(Strategy::NestedLoop) if self.right_held => (),
+            _ => {
+                self.right.reset();
+                self.right_block.clear();
+                self.strategy = None;
+                self.spilled = None;
+                self.table.clear();
+            }
+        }
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        let mut batch = Vec::new();
+        while batch.len() < max {
+            match self.next() {
+                Some(result) => batch.push(result),
+                None => break
+            }
+        }
+        batch
+    }
+}
+
+/// Frames collected up front and returned in a canonical order.
+struct SortedFrames<'s: 'a, 'a> {
+    frames: Vec<Frame<'s>>,
+    index: usize,
+    // The plan the frames came from, which may own the values they borrow.
+    _source: Frames<'s, 'a>
+}
+
+impl<'s: 'a, 'a> Iterator for SortedFrames<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        let frame = self.frames.get(self.index).cloned();
+        self.index += 1;
+        frame
+    }
+}
+
+impl<'s: 'a, 'a> Plan for SortedFrames<'s, 'a> {
+    fn reset(&mut self) {
+        self.index = 0;
+    }
+}
+
+/// Sort frames by the values of the given variables, in order, so that the
+/// results of a query are the same from run to run.
+///
+/// Consumes all of `frames` before returning.
+pub fn sorted<'s: 'a, 'a>(mut frames: Frames<'s, 'a>, columns: &[String])
+        -> Frames<'s, 'a> {
+    let mut all = Vec::new();
+    for_each_batched(&mut *frames, |frame| all.push(frame));
+    all.sort_by(|a, b| {
+        let key = |f: &Frame<'s>| -> Vec<Option<&'s Value>> {
+            columns.iter().map(|c| f.get(c).cloned()).collect()
+        };
+        key(a).cmp(&key(b)).then_with(|| a.cmp(b))
+    });
+    Box::new(SortedFrames { frames: all, index: 0, _source: frames })
+}
+
+/// Keeps only some of the variables of each frame, skipping frames that are
+/// then the same as earlier ones.
+struct Projected<'s: 'a, 'a> {
+    child: Frames<'s, 'a>,
+    columns: Vec<String>,
+    seen: HashSet<Frame<'s>>
+}
+
+impl<'s: 'a, 'a> Iterator for Projected<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        loop {
+            let frame = self.child.next()?;
+            let frame: Frame<'s> = frame.into_iter().filter(|(k, _)| {
+                self.columns.contains(k)
+            }).collect();
+            if self.seen.insert(frame.clone()) {
+                return Some(frame);
+            }
+        }
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Projected<'s, 'a> {
+    fn reset(&mut self) {
+        self.child.reset();
+        self.seen.clear();
+    }
+}
+
+/// Keep only the given variables of each frame, dropping duplicates, for
+/// queries that `select` some of their variables.
+pub fn project<'s: 'a, 'a>(frames: Frames<'s, 'a>, columns: &[String])
+        -> Frames<'s, 'a> {
+    Box::new(Projected {
+        child: frames,
+        columns: columns.to_vec(),
+        seen: HashSet::new()
+    })
+}
+
+/// Counts the frames a plan produces and the time spent producing them, for
+/// `explain_analyze`.
+struct Instrumented<'s: 'a, 'a> {
+    child: Frames<'s, 'a>,
+    node: Rc<RefCell<profile::Node>>
+}
+
+impl<'s: 'a, 'a> Iterator for Instrumented<'s, 'a> {
+    type Item = Frame<'s>;
+
+    fn next(&mut self) -> Option<Frame<'s>> {
+        let start = Instant::now();
+        let frame = self.child.next();
+        let mut node = self.node.borrow_mut();
+        node.time += start.elapsed();
+        node.rows += frame.is_some() as usize;
+        frame
+    }
+}
+
+impl<'s: 'a, 'a> Plan for Instrumented<'s, 'a> {
+    fn reset(&mut self) {
+        self.child.reset();
+    }
+
+    fn next_batch(&mut self, max: usize) -> Vec<Frame<'s>> {
+        let start = Instant::now();
+        let batch = self.child.next_batch(max);
+        let mut node = self.node.borrow_mut();
+        node.time += start.elapsed();
+        node.rows += batch.len();
+        batch
+    }
+}
+
+//
+// Frames and pattern matching.
+//
+
+pub type Frame<'a> = BTreeMap<String, &'a Value>;
+
+#[derive(Debug)]
+struct Pattern {
+    params: Vec<ast::AtomicTerm>
+}
 
 impl Pattern {
     fn new(params: Vec<ast::AtomicTerm>) -> Self {
         Pattern { params }
     }
 
-    /// Match the tuple against this pattern, returning the variable bindings
-    /// that make the match.
-    /// 
-    /// Return `None` if the given tuple does not match this pattern.
-    fn match_tuple<'a>(&mut self, t: storage::Tuple<'a>) -> Option<Frame<'a>> {
-        // Ensure each variable is bound to exactly one atom
-        let mut variable_bindings = BTreeMap::new();
+    /// Match the tuple against this pattern, returning the variable bindings
+    /// that make the match.
+    /// 
+    /// Return `None` if the given tuple does not match this pattern.
+    fn match_tuple<'a>(&mut self, t: storage::Tuple<'a>) -> Option<Frame<'a>> {
+        // Ensure each variable is bound to exactly one atom
+        let mut variable_bindings = BTreeMap::new();
+
+        for i in 0..self.params.len() {
+            match self.params[i] {
+                ast::AtomicTerm::Atom(ref s) => {
+                    if s != t[i] {
+                        return None;
+                    }
+                },
+                ast::AtomicTerm::Variable(ref s) => {
+                    let binding = variable_bindings.entry(s.to_string())
+                        .or_insert(t[i]);
+                    if *binding != t[i] {
+                        return None;
+                    }
+                }
+            }
+        }
+        return Some(variable_bindings);
+    }
+}
+
+
+//
+// Query planning.
+//
+
+/// Plan a cross join over arbitrarily many terms.
+// Join plans, each given with the variables it binds, returning the joined
+// plan and all of the variables it binds.
+fn plan_joins<'s: 'a, 'a>(
+        cache: &'s ViewCache,
+        mut joins: LinkedList<(Frames<'s, 'a>, HashSet<String>)>)
+        -> (Frames<'s, 'a>, HashSet<String>) {
+    let head = joins.pop_front();
+    match head {
+        None => panic!("Empty Join list"),
+        Some((term, variables)) => {
+            if joins.len() == 0 {
+                (term, variables)
+            } else {
+                let (rest, rest_variables) = plan_joins(cache, joins);
+                let shared = variables.intersection(&rest_variables)
+                                      .cloned().collect();
+                let join = Box::new(Join::new(term, rest, shared, cache));
+                (join, variables.union(&rest_variables).cloned().collect())
+            }
+        }
+    }
+}
+
+// Plan a recursive rule, reading the views of its stratum from `tuples`.
+fn plan_recursive_rule<'s: 'a, 'a>(
+        engine: &'s Storage,
+        cache: &'s ViewCache,
+        rule: &[ast::Term],
+        formals: &[String],
+        tuples: &'a Relations<'s>) -> Result<Tuples<'s, 'a>> {
+    let (positive, negated) = split_body(rule);
+    let mut joins: LinkedList<Frames<'s, 'a>> = LinkedList::new();
+    for term in positive.iter().filter(|t| !is_comparison(t)) {
+        let (relation_name, params) = deconstruct_term((*term).clone())?;
+        if let Some(tuples) = tuples.get(&relation_name) {
+            let tuples = Box::new(SetNode::new(tuples));
+            let scan = PatternMatch::new(Pattern::new(params), tuples);
+            joins.push_back(Box::new(scan));
+        } else {
+            joins.push_back(query(engine, cache, (*term).clone())?);
+        }
+    }
+
+    let join = plan_body(engine, cache, joins, &positive, &negated)?;
+    Ok(Box::new(IntensionalScan::new(formals.to_vec(), join)))
+}
+
+// Plan a rule body: join the plans for its positive terms, then keep only the
+// frames passing its comparisons and matching none of its negated terms, and
+// finally only the best of those for each extremum. Variables only appearing
+// in a negated term stand for any value at all.
+fn plan_body<'s: 'a, 'a>(engine: &'s Storage,
+                         cache: &'s ViewCache,
+                         mut joins: LinkedList<Frames<'s, 'a>>,
+                         positive: &[&ast::Term],
+                         negated: &[&ast::Term]) -> Result<Frames<'s, 'a>> {
+    if joins.is_empty() {
+        joins.push_back(unit(engine, cache)?);
+    }
+    let bound: HashSet<String> =
+        positive.iter().flat_map(|t| t.variables()).collect();
+    // The plans in `joins` are for the terms that aren't comparisons, in
+    // order, or for `unit` if there are none.
+    let variables = positive.iter().filter(|t| !is_comparison(t))
+        .map(|t| t.variables().into_iter().collect())
+        .chain(Some(HashSet::new()));
+    let (mut plan, mut variables) =
+        plan_joins(cache, joins.into_iter().zip(variables).collect());
+    let (extrema, comparisons): (Vec<&ast::Term>, Vec<&ast::Term>) =
+        positive.iter().filter(|t| is_comparison(t)).partition(|t| {
+            is_extremum(t)
+        });
+    // Registered built-ins come first, in order, since they may bind
+    // variables for the rest.
+    let (calls, comparisons): (Vec<&ast::Term>, Vec<&ast::Term>) =
+        comparisons.into_iter().partition(|t| is_registered(t));
+    for term in calls {
+        let (name, params) = deconstruct_term(term.clone())?;
+        let builtin = registered(&name).expect("call to unregistered built-in");
+        check_registered_arity(&name, &builtin, &params)?;
+        let unbound = params.iter().zip(&builtin.bound).find(|(p, bound)| {
+            match p {
+                ast::AtomicTerm::Variable(v) =>
+                    **bound && !variables.contains(v),
+                ast::AtomicTerm::Atom(_) => false
+            }
+        });
+        if let Some((param, _)) = unbound {
+            return Err(unbound_registered(&name, param));
+        }
+        variables.extend(term.variables());
+        plan = Box::new(Call::new(plan, term, builtin));
+    }
+    for term in comparisons {
+        plan = Box::new(Comparison::new(plan, term.clone(), true));
+    }
+    for term in negated {
+        if is_comparison(term) {
+            plan = Box::new(Comparison::new(plan, (*term).clone(), false));
+            continue;
+        }
+        let matches = query(engine, cache, (*term).clone())?;
+        let shared = term.variables().into_iter()
+                                     .filter(|v| bound.contains(v))
+                                     .collect();
+        plan = Box::new(AntiJoin::new(plan, matches, shared));
+    }
+    for term in extrema {
+        plan = Box::new(Extremes::new(plan, term.clone()));
+    }
+    Ok(plan)
+}
+
+// A plan returning a single frame with nothing bound.
+fn unit<'s>(engine: &'s Storage, cache: &'s ViewCache)
+        -> Result<Frames<'s, 's>> {
+    let truth = ast::AtomicTerm::Atom(Value::Symbol("true".to_string()));
+    query(engine, cache, ast::Term::Atomic(truth))
+}
+
+/// Plan a scan over the named relation, for a term with the given parameters.
+fn scan_relation<'s>(engine: &'s Storage,
+                     cache: &'s ViewCache,
+                     name: &str,
+                     params: &[ast::AtomicTerm],
+                     semi_naive: bool) -> Result<Tuples<'s, 's>> {
+    if let Some(scan) = builtin_scan(engine, name, params) {
+        profile::set_kind("builtin", None);
+        return scan;
+    }
+    if let Some(table) = engine.virtual_table(name) {
+        profile::set_kind("virtual", None);
+        return virtual_scan(name, table, params);
+    }
+    if let Some(table) = materialization(engine, cache, name) {
+        if table.arity() == params.len() {
+            profile::set_kind("materialized", None);
+            return Ok(Box::new(ExtensionalScan::new(table, params)));
+        }
+    }
+
+    let pattern: BindingPattern = params.iter().map(|p| match p {
+        ast::AtomicTerm::Atom(a) => Some(a.clone()),
+        ast::AtomicTerm::Variable(_) => None
+    }).collect();
+    revalidate(engine, cache, name)?;
+    if let Some(cached) = cache.read_cache(name, &pattern) {
+        profile::set_kind("view", Some(true));
+        return Ok(Box::new(VecPlan::new(cached)));
+    }
+
+    let relation =
+        engine.get_relation(name)
+              .ok_or(Error::MalformedLine(
+                      format!("No relation \"{}\" found.", name)))?;
+    Ok(match relation {
+        Extension(ref table) if table.arity() != params.len() =>
+            return Err(Error::RelationArity {
+                relation: name.to_string(),
+                arity: table.arity(),
+                got: params.len()
+            }),
+        Extension(ref table) => {
+            advisor::observe(name, params);
+            let indexed = params.iter().enumerate().any(|(i, p)| match p {
+                ast::AtomicTerm::Atom(_) => table.is_indexed(i),
+                ast::AtomicTerm::Variable(_) => false
+            });
+            profile::set_kind(if indexed { "index" } else { "table" }, None);
+            Box::new(ExtensionalScan::new(table, params))
+        },
+        Intension(view) => match read_through_view(name, engine, cache, view,
+                                                   &pattern)? {
+            Some(tuples) => {
+                profile::set_kind("view", Some(true));
+                Box::new(VecPlan::new(tuples))
+            },
+            None => {
+                profile::set_kind("view", Some(false));
+                IntensionalScan::from_view(name, engine, cache, view, pattern,
+                                           semi_naive)?
+            }
+        }
+    })
+}
+
+// Answer a query against a view that just selects from another view, like
+// `v(X) :- w(X, a).`, from the cached results of that other view, if any.
+fn read_through_view(name: &str,
+                     engine: &Storage,
+                     cache: &ViewCache,
+                     view: &AstView,
+                     pattern: &[Option<Value>])
+        -> Result<Option<Vec<Vec<Value>>>> {
+    if view.rules.len() != 1 || view.subsumption.is_some() {
+        return Ok(None);
+    }
+    let (formals, body) = &view.rules[0];
+    if body.iter().any(never_holds) {
+        return Ok(None);
+    }
+    let inner = match body.as_slice() {
+        [ast::Term::Compound(inner)] if inner.relation != name => inner,
+        _ => return Ok(None)
+    };
+
+    // Bind as many columns of the inner view as possible, so the smallest
+    // cached result set that contains the answers can be used.
+    let inner_pattern: BindingPattern = inner.params.iter().map(|p| match p {
+        ast::AtomicTerm::Atom(a) => Some(a.clone()),
+        ast::AtomicTerm::Variable(v) => formals.iter()
+                                               .zip(pattern)
+                                               .find(|(f, _)| *f == v)
+                                               .and_then(|(_, b)| b.clone())
+    }).collect();
+    revalidate(engine, cache, &inner.relation)?;
+    let cached = match cache.read_cache(&inner.relation, &inner_pattern) {
+        Some(cached) => cached,
+        None => return Ok(None)
+    };
+
+    let mut answers = BTreeSet::new();
+    'tuples: for tuple in cached {
+        let mut bindings: HashMap<&str, &Value> = HashMap::new();
+        for (param, value) in inner.params.iter().zip(&tuple) {
+            if let ast::AtomicTerm::Variable(v) = param {
+                if *bindings.entry(v).or_insert(value) != value {
+                    continue 'tuples;
+                }
+            }
+        }
+        let answer: Option<Vec<Value>> = formals.iter().map(|f| {
+            bindings.get(f.as_str()).map(|v| (*v).clone())
+        }).collect();
+        let answer = match answer {
+            Some(answer) => answer,
+            None => return Ok(None)
+        };
+        let selected = answer.iter().zip(pattern).all(|(v, b)| {
+            b.as_ref().map_or(true, |b| b == v)
+        });
+        if selected {
+            answers.insert(answer);
+        }
+    }
+    Ok(Some(answers.into_iter().collect()))
+}
+
+// Bring the cached results of a stale view up to date with the facts added
+// since, if it has any.
+fn revalidate(engine: &Storage, cache: &ViewCache, name: &str) -> Result<()> {
+    let deltas = match cache.take_deltas(name) {
+        Some(deltas) => deltas,
+        None => return Ok(())
+    };
+    match derive_from_deltas(engine, cache, name, &deltas) {
+        Ok(Some(tuples)) => {
+            debug!("{} new facts gave {} tuples of {}", deltas.len(),
+                   tuples.len(), name);
+            cache.extend(name, &tuples)
+        },
+        Ok(None) => {
+            debug!("recomputing {} after {} new facts", name, deltas.len());
+            cache.discard(name)
+        },
+        Err(e) => {
+            cache.discard(name);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// The tuples a view gains from facts added to tables it uses, or `None` if
+// they can't be found from those facts alone.
+//
+// Only views whose rules just join relations and compare values can gain
+// tuples but never lose them, and only those using the changed tables
+// directly, rather than through other views, can be brought up to date
+// without bringing those views up to date first. For those, it suffices to
+// evaluate each rule once for each new fact matching each of its terms, with
+// that term replaced by the fact.
+fn derive_from_deltas(engine: &Storage,
+                      cache: &ViewCache,
+                      name: &str,
+                      deltas: &[Delta])
+        -> Result<Option<Vec<Vec<Value>>>> {
+    let affected: HashSet<String> =
+        deltas.iter().flat_map(|(table, _)| cache.dependents(table)).collect();
+    match engine.get_relation(name) {
+        Some(Intension(view)) if follows_changes(name, view, &affected,
+                                                 false) => (),
+        _ => return Ok(None)
+    }
+
+    let mut derived = Vec::new();
+    for (table, fact) in deltas {
+        derived.extend(derive_from_fact(engine, cache, name, table, fact)?);
+    }
+    Ok(Some(derived))
+}
+
+// Can the changes to a view be found by following changes to the relations in
+// `affected` through its rules, a fact at a time? Only if its rules just join
+// relations and compare values, so that it gains tuples but never loses them
+// as facts are added, and none of the relations they use is in `affected`:
+// all changes come from the changed tables themselves, or with `recursive`,
+// from the view itself.
+fn follows_changes(name: &str,
+                   view: &AstView,
+                   affected: &HashSet<String>,
+                   recursive: bool) -> bool {
+    view.subsumption.is_none() &&
+        view.rules.iter().flat_map(|(_, body)| body).all(|t| match t {
+            ast::Term::Compound(c) if c.relation == "before" => true,
+            ast::Term::Compound(c) if c.relation == name => recursive,
+            ast::Term::Compound(c) =>
+                !is_builtin(&c.relation) && !affected.contains(&c.relation),
+            _ => false
+        })
+}
+
+// The tuples the rules of the named view derive with a term for `relation`
+// replaced by `fact`, once for each such term. Only for views whose rules
+// `follows_changes` accepts.
+fn derive_from_fact(engine: &Storage,
+                    cache: &ViewCache,
+                    name: &str,
+                    relation: &str,
+                    fact: &[Value]) -> Result<Vec<Vec<Value>>> {
+    let view = match engine.get_relation(name) {
+        Some(Intension(view)) => view,
+        _ => return Ok(Vec::new())
+    };
+    let mut derived = Vec::new();
+    for (formals, body) in &view.rules {
+        if body.iter().any(never_holds) {
+            continue;
+        }
+        for (i, term) in body.iter().enumerate() {
+            let changed = match term {
+                ast::Term::Compound(c) if c.relation == relation => c,
+                _ => continue
+            };
+            let bindings = match fact_bindings(&changed.params, fact) {
+                Some(bindings) => bindings,
+                None => continue
+            };
+            let rest: Vec<ast::Term> = body.iter().enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, t)| bind_variables(t, &bindings))
+                .collect();
+            for frame in plan_positive_body(engine, cache, &rest)? {
+                derived.push(formals.iter().map(|f| {
+                    bindings.get(f.as_str()).cloned()
+                            .or_else(|| frame.get(f).cloned())
+                            .expect("view column bound by neither")
+                            .clone()
+                }).collect());
+            }
+        }
+    }
+    Ok(derived)
+}
+
+// Plan a rule body with no negated terms.
+fn plan_positive_body<'s>(engine: &'s Storage,
+                          cache: &'s ViewCache,
+                          body: &[ast::Term]) -> Result<Frames<'s, 's>> {
+    let positive: Vec<&ast::Term> = body.iter().collect();
+    let mut joins = LinkedList::new();
+    for term in positive.iter().filter(|t| !is_comparison(t)) {
+        joins.push_back(query(engine, cache, (*term).clone())?);
+    }
+    plan_body(engine, cache, joins, &positive, &[])
+}
+
+// The materialization of the named view, unless it is outdated.
+fn materialization<'s>(engine: &'s Storage, cache: &ViewCache, name: &str)
+        -> Option<&'s storage::Table> {
+    match engine.get_relation(name) {
+        Some(Intension(_)) if !cache.is_outdated(name) =>
+            engine.materialization(name),
+        _ => None
+    }
+}
+
+// The materialized views depending on `relation` whose materializations can
+// follow changes to its facts; see `materialize`. The rest of those depending
+// on it are marked outdated.
+fn maintained_views(engine: &Storage, cache: &ViewCache, relation: &str)
+        -> Vec<String> {
+    let affected: HashSet<String> =
+        cache.dependents(relation).into_iter().collect();
+    let mut views: Vec<String> = affected.iter().filter(|view| {
+        materialization(engine, cache, view).is_some()
+    }).cloned().collect();
+    views.sort();
+    views.retain(|view| match engine.get_relation(view) {
+        Some(Intension(v)) if follows_changes(view, v, &affected, true) => true,
+        _ => {
+            debug!("{} can't follow changes to {}", view, relation);
+            cache.outdate(view);
+            false
+        }
+    });
+    views
+}
+
+// Bring the materialized views depending on the table `relation` up to date
+// with `fact` having been added to it. Views that can't be are marked
+// outdated instead.
+fn materialize_insert(engine: &mut Storage,
+                      cache: &ViewCache,
+                      relation: &str,
+                      fact: &[Value]) {
+    for name in maintained_views(engine, cache, relation) {
+        let derived = derive_from_fact(engine, cache, &name, relation, fact);
+        let added = derived.and_then(|derived| {
+            propagate(engine, cache, &name, derived)
+        });
+        match added {
+            Ok(added) => debug!("{} gained {} tuples", name, added),
+            Err(e) => {
+                warn!("can't bring {} up to date: {}", name, e);
+                cache.outdate(&name);
+            }
+        }
+    }
+}
+
+// Add the tuples derived for a materialized view that it doesn't have yet,
+// then those its rules derive from them, and so on until nothing new is
+// derived. Returns the number of tuples added.
+fn propagate(engine: &mut Storage,
+             cache: &ViewCache,
+             name: &str,
+             mut derived: Vec<Vec<Value>>) -> Result<usize> {
+    let mut added = 0;
+    loop {
+        let new: Vec<Vec<Value>> = match engine.materialization(name) {
+            Some(table) => {
+                let mut seen = HashSet::new();
+                derived.into_iter().filter(|tuple| {
+                    !table.contains(tuple) && seen.insert(tuple.clone())
+                }).collect()
+            },
+            None => return Ok(added)
+        };
+        if new.is_empty() {
+            return Ok(added);
+        }
+        added += new.len();
+        engine.extend_materialization(name, new.clone())?;
+        derived = Vec::new();
+        for tuple in &new {
+            derived.extend(derive_from_fact(engine, cache, name, name, tuple)?);
+        }
+    }
+}
+
+// The tuples of each materialized view depending on the table `relation`
+// that may be lost when `fact` is retracted from it: everything derived from
+// the fact, and everything derived from those, and so on. Must be found
+// before the fact is retracted. Views that can't follow the change are marked
+// outdated instead.
+fn overdelete(engine: &Storage,
+              cache: &ViewCache,
+              relation: &str,
+              fact: &[Value]) -> Vec<(String, HashSet<Vec<Value>>)> {
+    match engine.get_relation(relation) {
+        Some(Extension(table)) if table.contains(fact) => (),
+        _ => return Vec::new()
+    }
+    let mut overdeleted = Vec::new();
+    for name in maintained_views(engine, cache, relation) {
+        let table = engine.materialization(&name)
+                          .expect("maintained view not materialized");
+        let mut deleted = HashSet::new();
+        let mut derived =
+            derive_from_fact(engine, cache, &name, relation, fact);
+        loop {
+            let new: Vec<Vec<Value>> = match derived {
+                Ok(derived) => derived.into_iter().filter(|tuple| {
+                    table.contains(tuple) && deleted.insert(tuple.clone())
+                }).collect(),
+                Err(e) => {
+                    warn!("can't bring {} up to date: {}", name, e);
+                    cache.outdate(&name);
+                    break;
+                }
+            };
+            if new.is_empty() {
+                overdeleted.push((name, deleted));
+                break;
+            }
+            derived = new.iter().map(|tuple| {
+                derive_from_fact(engine, cache, &name, &name, tuple)
+            }).collect::<Result<Vec<_>>>().map(|d| d.concat());
+        }
+    }
+    overdeleted
+}
+
+// Bring materialized views up to date with `fact` having been retracted from
+// the table `relation`, given what `overdelete` found they might lose: remove
+// all of that, then put back what can still be derived without the fact, and
+// what follows from that.
+fn materialize_retract(engine: &mut Storage,
+                       cache: &ViewCache,
+                       relation: &str,
+                       fact: &[Value],
+                       overdeleted: Vec<(String, HashSet<Vec<Value>>)>) {
+    // With another copy of the fact left, nothing is lost.
+    let remains = match engine.get_relation(relation) {
+        Some(Extension(table)) => table.contains(fact),
+        _ => false
+    };
+    for (name, deleted) in overdeleted {
+        cache.refreshed(&name);
+        if remains || deleted.is_empty() {
+            continue;
+        }
+        match rederive(engine, cache, &name, deleted) {
+            Ok((lost, kept)) => debug!("{} lost {} tuples, and kept {}", name,
+                                       lost, kept),
+            Err(e) => {
+                warn!("can't bring {} up to date: {}", name, e);
+                cache.outdate(&name);
+            }
+        }
+    }
+}
+
+// Remove tuples from a materialized view, then put back those its rules still
+// derive, and what follows from them. Returns the number of tuples lost and
+// the number put back.
+fn rederive(engine: &mut Storage,
+            cache: &ViewCache,
+            name: &str,
+            deleted: HashSet<Vec<Value>>) -> Result<(usize, usize)> {
+    engine.retain_materialized(name, |tuple| {
+        let tuple: Vec<Value> = tuple.iter().map(|v| (*v).clone()).collect();
+        !deleted.contains(&tuple)
+    });
+    let mut derivable = Vec::new();
+    for tuple in deleted.iter() {
+        if is_derivable(engine, cache, name, tuple)? {
+            derivable.push(tuple.clone());
+        }
+    }
+    let kept = propagate(engine, cache, name, derivable)?;
+    Ok((deleted.len() - kept, kept))
+}
+
+// Does some rule of the named view derive `tuple` in one step?
+fn is_derivable(engine: &Storage,
+                cache: &ViewCache,
+                name: &str,
+                tuple: &[Value]) -> Result<bool> {
+    let view = match engine.get_relation(name) {
+        Some(Intension(view)) => view,
+        _ => return Ok(false)
+    };
+    for (formals, body) in &view.rules {
+        if body.iter().any(never_holds) {
+            continue;
+        }
+        let head: Vec<ast::AtomicTerm> = formals.iter().map(|f| {
+            ast::AtomicTerm::Variable(f.clone())
+        }).collect();
+        let bindings = match fact_bindings(&head, tuple) {
+            Some(bindings) => bindings,
+            None => continue
+        };
+        let body: Vec<ast::Term> =
+            body.iter().map(|t| bind_variables(t, &bindings)).collect();
+        if plan_positive_body(engine, cache, &body)?.next().is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Replace the variables of a term with their values in `bindings`, if any.
+fn bind_variables(term: &ast::Term, bindings: &HashMap<&str, &Value>)
+        -> ast::Term {
+    let bind = |p: &ast::AtomicTerm| match p {
+        ast::AtomicTerm::Variable(v) => match bindings.get(v.as_str()) {
+            Some(value) => ast::AtomicTerm::Atom((*value).clone()),
+            None => p.clone()
+        },
+        atom => atom.clone()
+    };
+    match term {
+        ast::Term::Atomic(a) => ast::Term::Atomic(bind(a)),
+        ast::Term::Compound(c) => ast::Term::Compound(ast::CompoundTerm {
+            relation: c.relation.clone(),
+            params: c.params.iter().map(bind).collect()
+        }),
+        ast::Term::Not(t) => ast::Term::Not(Box::new(bind_variables(t,
+                                                                   bindings)))
+    }
+}
+
+// Plan a query, recording what its scan does if a profile is being recorded.
+fn plan_query<'s>(engine: &'s Storage,
+                  cache: &'s ViewCache,
+                  query: ast::Term,
+                  semi_naive: bool) -> Result<Frames<'s, 's>> {
+    trace!("planning {}", query);
+    let node = profile::enter(&query);
+    let start = Instant::now();
+    cache.begin_planning();
+    let planned = deconstruct_term(query).and_then(|(head, rest)| {
+        let scan = scan_relation(engine, cache, &head, &rest, semi_naive)?;
+        let plan: Frames<'s, 's> =
+            Box::new(PatternMatch::new(Pattern::new(rest), scan));
+        Ok(plan)
+    });
+    cache.end_planning();
+    profile::exit();
+
+    match node {
+        None => planned,
+        Some(node) => {
+            node.borrow_mut().time += start.elapsed();
+            Ok(Box::new(Instrumented { child: planned?, node }))
+        }
+    }
+}
+
+/// Given a query, return all variable assignments over the database that
+/// satisfy that query.
+///
+/// If a join's frames spilled to disk can't be read back, the frames stop
+/// early; `spill::check` says why.
+pub fn query<'s>(engine: &'s Storage,
+                 cache: &'s ViewCache,
+                 query: ast::Term) -> Result<Frames<'s, 's>> {
+    plan_query(engine, cache, query, false)
+}
+
+/// Given a query, return all variable assignments over the database that
+/// satisfy that query, using a semi-naive algorithm for recursive rules if
+/// needed.
+pub fn query_semi_naive<'s>(engine: &'s Storage,
+                            cache: &'s ViewCache,
+                            query: ast::Term) -> Result<Frames<'s, 's>> {
+    plan_query(engine, cache, query, true)
+}
+
+/// Answer a query as `options` say, passing its frames to `f`.
+///
+/// With `no_cache`, the query is answered as if nothing were cached, and the
+/// views it evaluates are cached only until `f` returns.
+pub fn query_using<T, F>(engine: &Storage,
+                         cache: &ViewCache,
+                         query: ast::Term,
+                         options: &ast::QueryOptions,
+                         f: F) -> Result<T>
+        where F: for<'s> FnOnce(Frames<'s, 's>) -> T {
+    let scratch;
+    let cache = if options.no_cache {
+        scratch = ViewCache::new();
+        &scratch
+    } else {
+        cache
+    };
+    let frames = plan_query(engine, cache, query, options.semi_naive)?;
+    let answer = f(frames);
+    spill::check()?;
+    Ok(answer)
+}
+
+/// Answer a query as `options` say, passing its frames to `f`, but stop it if
+/// it exceeds any of `limits`.
+///
+/// A query stopped early fails with `Error::LimitExceeded`, whatever `f` made
+/// of the frames it was given. See `query_partial` for queries with a budget.
+pub fn query_limited<T, F>(engine: &Storage,
+                           cache: &ViewCache,
+                           query: ast::Term,
+                           options: &ast::QueryOptions,
+                           limits: &limits::Limits,
+                           f: F) -> Result<T>
+        where F: for<'s> FnOnce(Frames<'s, 's>) -> T {
+    query_partial(engine, cache, query, options, limits, f)
+        .map(|(answer, _)| answer)
+}
+
+/// Answer a query as `query_limited` does, also saying how it was cut short if
+/// its budget in `limits` ran out: then `f` was given the answers found from
+/// the tuples recursive views had derived by then, and not every answer.
+///
+/// Views cut short aren't cached.
+pub fn query_partial<T, F>(engine: &Storage,
+                           cache: &ViewCache,
+                           query: ast::Term,
+                           options: &ast::QueryOptions,
+                           limits: &limits::Limits,
+                           f: F) -> Result<(T, Option<limits::Partial>)>
+        where F: for<'s> FnOnce(Frames<'s, 's>) -> T {
+    limits::start(*limits);
+    let answered = query_using(engine, cache, query, options, |frames| {
+        f(Box::new(Guarded::rows(frames)))
+    });
+    let finished = limits::finish();
+    let answer = answered?;
+    finished.map(|partial| (answer, partial))
+}
+
+/// Run a query to completion, returning the value of each variable in each
+/// frame, written as it would be printed.
+///
+/// Unlike the frames from `query`, the results don't borrow from the
+/// database, so e.g. a lock on it can be released before they are used. See
+/// `row::query_rows` to keep the values' types.
+pub fn query_owned(engine: &Storage,
+                   cache: &ViewCache,
+                   query: ast::Term) -> Result<Vec<BTreeMap<String, String>>> {
+    Ok(self::query(engine, cache, query)?.map(|frame| {
+        frame.into_iter().map(|(k, v)| (k, v.to_string())).collect()
+    }).collect())
+}
+
+/// Run a query to completion, returning what the scan for each term planned
+/// along the way did: the frames it produced, the time spent in it and
+/// whether it was answered from the view cache.
+///
+/// The scans for the terms in a view's rules are the children of the scan of
+/// the view.
+pub fn explain_analyze(engine: &Storage,
+                       cache: &ViewCache,
+                       query: ast::Term)
+        -> Result<Vec<Rc<RefCell<profile::Node>>>> {
+    profile::start();
+    let ran = self::query(engine, cache, query).map(|mut frames| {
+        for_each_batched(&mut frames, |_| ());
+    });
+    let roots = profile::finish();
+    ran.map(|_| roots)
+}
+
+/// The shape of the plan for a query (see `profile::Node::shape`), planned as
+/// if nothing were cached, so that it only depends on the database's tables
+/// and rules, and on the planner. The query is run to completion.
+pub fn plan_shape(engine: &Storage, query: ast::Term) -> Result<String> {
+    let cache = ViewCache::new();
+    let roots = explain_analyze(engine, &cache, query)?;
+    Ok(roots.iter().map(|node| node.borrow().shape()).collect())
+}
+
+/// Does the given query have any answers? Evaluation stops at the first one.
+pub fn holds(engine: &Storage,
+             cache: &ViewCache,
+             query: ast::Term) -> Result<bool> {
+    Ok(self::query(engine, cache, query)?.next().is_some())
+}
+
+/// The number of distinct answers to the given query.
+///
+/// A query of a whole relation, with a different variable for each column, is
+/// answered without evaluating it if the table's size is known or the view
+/// is cached.
+pub fn count(engine: &Storage,
+             cache: &ViewCache,
+             query: ast::Term) -> Result<usize> {
+    if let Some(count) = known_count(engine, cache, &query)? {
+        return Ok(count);
+    }
+    let mut frames = self::query(engine, cache, query)?;
+    let mut answers = HashSet::new();
+    for_each_batched(&mut frames, |frame| {
+        answers.insert(frame);
+    });
+    Ok(answers.len())
+}
+
+// The number of answers to a query of a whole relation, if it can be told
+// without evaluating the query.
+fn known_count(engine: &Storage, cache: &ViewCache, query: &ast::Term)
+        -> Result<Option<usize>> {
+    let c = match query {
+        ast::Term::Compound(c) => c,
+        _ => return Ok(None)
+    };
+    let variables: HashSet<&str> = c.params.iter().filter_map(|p| match p {
+        ast::AtomicTerm::Variable(v) => Some(v.as_str()),
+        _ => None
+    }).collect();
+    if variables.len() != c.params.len() {
+        return Ok(None);
+    }
+    Ok(match engine.get_relation(&c.relation) {
+        Some(Extension(table)) if table.arity() == c.params.len() =>
+            Some(table.distinct_len()),
+        Some(Intension(_)) => {
+            revalidate(engine, cache, &c.relation)?;
+            cache.cached_len(&c.relation, &vec![None; c.params.len()])
+        },
+        _ => None
+    })
+}
+
+/// Does the given query have no answers at all?
+///
+/// This is negation as failure: anything that can't be derived from the
+/// database is taken to be false. Evaluation stops at the first answer.
+pub fn absent(engine: &Storage,
+              cache: &ViewCache,
+              query: ast::Term) -> Result<bool> {
+    let matches = self::query(engine, cache, query)?;
+    let mut plan = AntiJoin::new(unit(engine, cache)?, matches, Vec::new());
+    Ok(plan.next().is_some())
+}
+
+/// Answer a query as if the given rules were in the database, passing its
+/// frames to `f`.
+///
+/// The rules may only define new views, and are forgotten once `f` returns.
+/// Nothing derived while answering the query is cached.
+pub fn query_with<T, F>(engine: &mut Storage,
+                        rules: Vec<ast::Rule>,
+                        query: ast::Term,
+                        f: F) -> Result<T>
+        where F: for<'s> FnOnce(Frames<'s, 's>) -> T {
+    let mut defined = Vec::new();
+    let result = define_scratch_views(engine, rules, &mut defined)
+        .and_then(|()| {
+            let engine = &*engine;
+            let cache = ViewCache::new();
+            let frames = self::query(engine, &cache, query)?;
+            Ok(f(frames))
+        });
+    for name in defined {
+        engine.remove_scratch(&name);
+    }
+    result
+}
+
+// Define views for a single query, adding their names to `defined`; see
+// `query_with`.
+fn define_scratch_views(engine: &mut Storage,
+                        rules: Vec<ast::Rule>,
+                        defined: &mut Vec<String>) -> Result<()> {
+    for rule in rules {
+        if rule.body.is_empty() {
+            return Err(Error::MalformedLine(format!(
+                "\"with\" can only define rules, not facts like {}", rule)));
+        }
+        let (name, params) = check_rule(engine, &rule, false)?;
+        let relation = Intension(AstView::new());
+        match engine.define_scratch(name.clone(), relation) {
+            Some(Intension(view)) => view.add_rule(params, rule.body),
+            _ => return Err(Error::MalformedLine(format!(
+                "\"{}\" is already in the database, so \"with\" can't \
+                 define it", name)))
+        }
+        if !defined.contains(&name) {
+            defined.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Store the answers to a query in a temporary table `name`, with one column
+/// per variable of the query in order of first appearance, replacing any
+/// answers stored under that name before. The table can be queried like any
+/// other, but not changed, and is never written back.
+///
+/// Returns the number of answers stored.
+pub fn capture(engine: &mut Storage,
+               cache: &mut ViewCache,
+               query: ast::Term,
+               name: String) -> Result<usize> {
+    check_not_builtin(&name)?;
+    if engine.get_relation(&name).is_some() && !engine.is_scratch(&name) {
+        return Err(Error::MalformedLine(format!(
+            "\"{}\" is already in the database, so query results can't be \
+             stored there", name)));
+    }
+    let columns = query.variables();
+    if columns.is_empty() {
+        return Err(Error::MalformedLine(
+                format!("{}? has no variables to store", query)));
+    }
+
+    let mut facts: Vec<Vec<Value>> = Vec::new();
+    {
+        let mut frames = self::query(engine, cache, query)?;
+        for_each_batched(&mut *frames, |frame| {
+            facts.push(columns.iter().map(|c| frame[c].clone()).collect());
+        });
+    }
+    store(engine, cache, name, columns.len(), facts)
+}
+
+/// Store answers already computed in a temporary table `name` with the given
+/// arity, as `capture` does, returning the number of answers stored.
+///
+/// Unlike `capture`, doesn't check that `name` is free to hold them.
+pub fn store(engine: &mut Storage,
+             cache: &mut ViewCache,
+             name: String,
+             arity: usize,
+             facts: Vec<Vec<Value>>) -> Result<usize> {
+    let count = facts.len();
+    let mut table = storage::Table::new(arity);
+    table.assert_batch(facts)?;
+    engine.replace_scratch(name.clone(), Extension(table));
+    cache.invalidate(&name);
+    Ok(count)
+}
+
+/// Evaluate the named view to a fixpoint, returning what each iteration did.
+///
+/// The first entry covers the view's non-recursive rules; each later one is a
+/// round of applying its recursive rules, the last deriving nothing new. A
+/// view is evaluated with the rest of its stratum, so these cover every view
+/// in it.
+pub fn fixpoint_stats(engine: &Storage,
+                      cache: &ViewCache,
+                      name: &str,
+                      semi_naive: bool) -> Result<Vec<IterationStats>> {
+    let view = match engine.get_relation(name) {
+        Some(Intension(view)) => view,
+        Some(Extension(_)) => return Err(Error::NotIntensional(
+                name.to_string())),
+        None => return Err(Error::MalformedLine(
+                format!("No relation \"{}\" found.", name)))
+    };
+
+    let strata = evaluation_order(engine)?;
+    Ok(match strata.stratum(name).filter(|s| s.recursive) {
+        None => {
+            let (base_scans, _) =
+                IntensionalScan::split_view(name, engine, cache, view, &[])?;
+            let mut tuples = HashSet::new();
+            for mut scan in base_scans {
+                for_each_batched(&mut *scan, |tuple| {
+                    tuples.insert(tuple);
+                });
+            }
+            vec![IterationStats {
+                delta: tuples.len(),
+                .. IterationStats::default()
+            }]
+        },
+        Some(stratum) =>
+            evaluate_stratum(name, engine, cache, stratum, semi_naive)?.1
+    })
+}
+
+//
+// Provenance.
+//
+
+// The number of derivations of each tuple of a relation.
+type Derivations = HashMap<Vec<Value>, u64>;
+
+/// Counts derivations by evaluating relations bottom-up in the counting
+/// semiring: joins multiply counts and the rules of a view add them.
+struct DerivationCounter<'s> {
+    engine: &'s Storage,
+    /// The views counted together: the counts of a stratum's views are
+    /// found at once.
+    strata: Strata,
+    counts: HashMap<String, Derivations>
+}
+
+impl<'s> DerivationCounter<'s> {
+    fn new(engine: &'s Storage) -> Result<Self> {
+        Ok(DerivationCounter {
+            engine,
+            strata: evaluation_order(engine)?,
+            counts: HashMap::new()
+        })
+    }
+
+    /// Make sure the counts for the named relation have been computed.
+    fn count(&mut self, name: &str) -> Result<()> {
+        if self.counts.contains_key(name) {
+            return Ok(());
+        }
+
+        let mut counts = Derivations::new();
+        if is_builtin(name) {
+            if name == "true" {
+                counts.insert(Vec::new(), 1);
+            } else if name == "holds" {
+                for tuple in holds_tuples(self.engine, None) {
+                    counts.insert(tuple, 1);
+                }
+            } else if name == "reachable" || name == "shortest_path_len" {
+                // Each path found counts as one derivation, as each tuple of
+                // a registered built-in does.
+                let params: Vec<_> = ["Src", "Dst", "N"].iter().map(|v| {
+                    ast::AtomicTerm::Variable(v.to_string())
+                }).collect();
+                for tuple in path_tuples(self.engine, name, &params)? {
+                    counts.insert(tuple, 1);
+                }
+            }
+            self.counts.insert(name.to_string(), counts);
+            return Ok(());
+        }
+
+        match self.engine.get_relation(name) {
+            Some(Extension(table)) => {
+                for tuple in table.select(&[]) {
+                    let count = counts.entry(tuple.into_iter()
+                                                  .cloned()
+                                                  .collect())
+                                      .or_insert(0);
+                    *count = count.saturating_add(1);
+                }
+                self.counts.insert(name.to_string(), counts);
+                return Ok(());
+            },
+            Some(Intension(_)) => (),
+            None => match self.engine.virtual_table(name) {
+                Some(table) => {
+                    for fact in table.facts()? {
+                        let count = counts.entry(fact).or_insert(0);
+                        *count = count.saturating_add(1);
+                    }
+                    self.counts.insert(name.to_string(), counts);
+                    return Ok(());
+                },
+                None => return Err(Error::MalformedLine(
+                        format!("No relation \"{}\" found.", name)))
+            }
+        }
+
+        // Count the relations the view's stratum uses outside itself first.
+        let stratum = match self.strata.stratum(name) {
+            Some(stratum) => stratum.views.clone(),
+            None => vec!(name.to_string())
+        };
+        let engine = self.engine;
+        let mut views = Vec::new();
+        for view_name in &stratum {
+            let view = match engine.get_relation(view_name) {
+                Some(Intension(view)) => view,
+                _ => continue
+            };
+            for (_, body) in &view.rules {
+                let (positive, negated) = split_body(body);
+                for term in positive.into_iter().chain(negated) {
+                    let (relation, _) = deconstruct_term(term.clone())?;
+                    if !stratum.contains(&relation) {
+                        self.count(&relation)?;
+                    }
+                }
+            }
+            views.push((view_name, view));
+        }
+
+        // Apply the rules of the whole stratum until the counts stop
+        // changing. Without cycles, no derivation is deeper than the number
+        // of tuples, so counts that are still changing after that many rounds
+        // will never settle.
+        let mut counts: HashMap<String, Derivations> = stratum.iter()
+            .map(|view| (view.clone(), Derivations::new())).collect();
+        let mut rounds = 0;
+        loop {
+            let mut next = HashMap::new();
+            for (view_name, view) in &views {
+                next.insert((*view_name).clone(),
+                            self.apply_rules(view_name, view, &counts)?);
+            }
+            if next == counts {
+                break;
+            }
+            rounds += 1;
+            if rounds > next.values().map(HashMap::len).sum::<usize>() + 1 {
+                return Err(Error::MalformedLine(format!(
+                    "tuples of {} have infinitely many derivations", name)));
+            }
+            counts = next;
+        }
+
+        self.counts.extend(counts);
+        Ok(())
+    }
+
+    // Apply every rule of the named view once, given counts for the views of
+    // its stratum.
+    fn apply_rules(&self,
+                   name: &str,
+                   view: &AstView,
+                   current: &HashMap<String, Derivations>)
+            -> Result<Derivations> {
+        let mut result = Derivations::new();
+        for (formals, body) in &view.rules {
+            if body.iter().any(never_holds) {
+                continue;
+            }
+            let (positive, negated) = split_body(body);
+            let mut frames = vec![(HashMap::new(), 1)];
+            for term in positive.iter().filter(|t| !is_comparison(t)) {
+                let (relation, params) = deconstruct_term((*term).clone())?;
+                let tuples = match current.get(&relation) {
+                    Some(tuples) => tuples,
+                    None => &self.counts[&relation]
+                };
+                frames = Self::join(frames, &params, tuples);
+            }
+            // Each tuple of a registered built-in counts as one derivation.
+            for term in positive.iter().filter(|t| is_registered(t)) {
+                let (relation, params) = deconstruct_term((*term).clone())?;
+                let builtin = registered(&relation)
+                    .expect("registered built-in vanished");
+                let mut extended = Vec::new();
+                for (frame, count) in frames {
+                    let tuples: Derivations = builtin.call(
+                        &relation, &params, |v| &frame[v]
+                    ).into_iter().map(|tuple| (tuple, 1)).collect();
+                    extended.extend(Self::join(vec![(frame, count)],
+                                               &params,
+                                               &tuples));
+                }
+                frames = extended;
+            }
+            // Comparisons and negated terms contribute no derivations of
+            // their own.
+            let comparisons = positive.iter().filter(|t| !is_registered(t))
+                .map(|t| (t, true))
+                .chain(negated.iter().map(|t| (t, false)))
+                .filter(|(t, _)| is_comparison(t) && !is_extremum(t));
+            for (term, expected) in comparisons {
+                frames.retain(|(frame, _)| {
+                    comparison_holds(term, |v| &frame[v]) == expected
+                });
+            }
+            for term in negated.iter().filter(|t| !is_comparison(t)) {
+                let (relation, params) = deconstruct_term((*term).clone())?;
+                let tuples = &self.counts[&relation];
+                frames.retain(|(frame, _)| {
+                    Self::join(vec![(frame.clone(), 1)], &params, tuples)
+                        .is_empty()
+                });
+            }
+            // An extremum keeps the derivations of the frames it keeps.
+            for term in positive.iter().filter(|t| is_extremum(t)) {
+                let kept = {
+                    let keyed: Vec<Frame> = frames.iter().map(|(frame, _)| {
+                        frame.iter().map(|(k, v)| (k.clone(), v)).collect()
+                    }).collect();
+                    extremes(term, &keyed)
+                };
+                let mut all: Vec<_> = frames.into_iter().map(Some).collect();
+                frames = kept.into_iter().map(|i| {
+                    all[i].take().unwrap()
+                }).collect();
+            }
+
+            for (frame, count) in frames {
+                let tuple = formals.iter().map(|f| {
+                    frame.get(f).cloned()
+                }).collect::<Option<Vec<Value>>>().ok_or_else(|| {
+                    Error::MalformedLine(format!(
+                        "a rule for {} has a variable not bound in its body",
+                        name))
+                })?;
+                let total = result.entry(tuple).or_insert(0);
+                *total = total.saturating_add(count);
+            }
+        }
+        Ok(result)
+    }
+
+    // Extend each frame with each tuple matching a term's parameters.
+    fn join(frames: Vec<(HashMap<String, Value>, u64)>,
+            params: &[ast::AtomicTerm],
+            tuples: &Derivations) -> Vec<(HashMap<String, Value>, u64)> {
+        let mut result = Vec::new();
+        for (frame, count) in frames {
+            for (tuple, n) in tuples {
+                if tuple.len() != params.len() {
+                    continue;
+                }
+                let mut extended = frame.clone();
+                let matches = params.iter().zip(tuple).all(|(param, v)| {
+                    match param {
+                        ast::AtomicTerm::Atom(a) => a == v,
+                        ast::AtomicTerm::Variable(x) => {
+                            extended.entry(x.clone())
+                                    .or_insert_with(|| v.clone()) == v
+                        }
+                    }
+                });
+                if matches {
+                    result.push((extended, count.saturating_mul(*n)));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Answer a query, counting the distinct derivations of each answer.
+///
+/// Answers are given as the values of the query's variables, in order of first
+/// appearance, and are sorted. An answer is just true or false in the boolean
+/// semiring that ordinary queries evaluate in; here each is weighted by how
+/// many ways the rules derive it, which helps explain unexpected duplicates.
+pub fn count_derivations(engine: &Storage, query: ast::Term)
+        -> Result<Vec<(Vec<Value>, u64)>> {
+    let variables = query.variables();
+    let (name, params) = deconstruct_term(query)?;
+    let mut counter = DerivationCounter::new(engine)?;
+    counter.count(&name)?;
+
+    let frames = DerivationCounter::join(vec![(HashMap::new(), 1)],
+                                         &params,
+                                         &counter.counts[&name]);
+    let mut answers: BTreeMap<Vec<Value>, u64> = BTreeMap::new();
+    for (frame, count) in frames {
+        let answer = variables.iter().map(|v| frame[v].clone()).collect();
+        let total = answers.entry(answer).or_insert(0);
+        *total = total.saturating_add(count);
+    }
+    Ok(answers.into_iter().collect())
+}
+
+// Read the facts of a virtual table matching the constants of a term.
+fn virtual_scan<'s>(name: &str,
+                    table: &storage::VirtualTable,
+                    params: &[ast::AtomicTerm]) -> Result<Tuples<'s, 's>> {
+    if table.arity() != params.len() {
+        return Err(Error::RelationArity {
+            relation: name.to_string(),
+            arity: table.arity(),
+            got: params.len()
+        });
+    }
+    let mut facts = table.facts()?;
+    if let Some(fact) = facts.iter().find(|f| f.len() != params.len()) {
+        return Err(Error::ArityMismatch {
+            expected: params.len(),
+            got: fact.len()
+        });
+    }
+    facts.retain(|fact| {
+        params.iter().zip(fact).all(|(param, value)| match param {
+            ast::AtomicTerm::Atom(a) => a == value,
+            ast::AtomicTerm::Variable(_) => true
+        })
+    });
+    Ok(Box::new(VecPlan::new(facts)))
+}
+
+//
+// Built-in relations.
+//
+
+/// The names of the relations data-goblin defines itself.
+pub const BUILTINS: &'static [&'static str] =
+    &["true", "fail", "before", "holds", "min_by", "max_by", "reachable",
+      "shortest_path_len"];
+
+/// Is the named relation built in, or registered with `register_builtin`?
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name) || registered(name).is_some()
+}
+
+/// A built-in relation computed by a Rust function, for relations that are
+/// easier to compute than to store, like distances between points.
+///
+/// The function is called with the values of the arguments that must be bound,
+/// in order, and returns the values of the other arguments, in order, for each
+/// tuple of the relation with those bound values.
+struct Registered {
+    bound: Vec<bool>,
+    function: Box<Fn(&[Value]) -> Vec<Vec<Value>>>
+}
+
+impl Registered {
+    // Call the function with the bound parameters of a term, given the values
+    // of its variables, returning whole tuples of the relation.
+    fn call<'v, F>(&self, name: &str, params: &'v [ast::AtomicTerm], value: F)
+            -> Vec<Vec<Value>>
+            where F: Fn(&str) -> &'v Value {
+        let inputs: Vec<Value> = params.iter().zip(&self.bound)
+            .filter(|(_, bound)| **bound)
+            .map(|(param, _)| match param {
+                ast::AtomicTerm::Atom(a) => a.clone(),
+                ast::AtomicTerm::Variable(v) => value(v).clone()
+            }).collect();
+        let free = self.bound.iter().filter(|b| !**b).count();
+        (self.function)(&inputs).into_iter().map(|outputs| {
+            if outputs.len() != free {
+                panic!("built-in {} returned {} values, but has {} unbound \
+                        arguments", name, outputs.len(), free);
+            }
+            let mut inputs = inputs.iter();
+            let mut outputs = outputs.into_iter();
+            self.bound.iter().map(|bound| if *bound {
+                inputs.next().unwrap().clone()
+            } else {
+                outputs.next().unwrap()
+            }).collect()
+        }).collect()
+    }
+}
+
+// Relations are registered per thread, like profiles, since they run on the
+// thread answering queries.
+thread_local! {
+    static REGISTERED: RefCell<HashMap<String, Rc<Registered>>> =
+        RefCell::new(HashMap::new());
+}
+
+// The registered built-in with the given name, if any.
+fn registered(name: &str) -> Option<Rc<Registered>> {
+    REGISTERED.with(|r| r.borrow().get(name).cloned())
+}
+
+/// Define a built-in relation on this thread, computed by `function`.
+///
+/// `bound` has an entry for each argument of the relation, saying whether it
+/// must be bound (by a constant, or by another term of a rule body) wherever
+/// the relation is used. `function` is called with the values of those
+/// arguments, in order, and returns the values of the other arguments for
+/// each matching tuple. For example, a relation `succ(X, Y)` with `X` bound
+/// might return `vec![vec![Value::Int(x + 1)]]`.
+///
+/// Registering a name again replaces its function. Names of relations in the
+/// database, and of data-goblin's own built-in relations, are refused.
+pub fn register_builtin<F>(engine: &Storage,
+                           name: &str,
+                           bound: Vec<bool>,
+                           function: F) -> Result<()>
+        where F: Fn(&[Value]) -> Vec<Vec<Value>> + 'static {
+    if BUILTINS.contains(&name) {
+        return Err(Error::Builtin(name.to_string()));
+    }
+    if engine.get_relation(name).is_some() {
+        return Err(Error::MalformedLine(format!(
+            "can't register {}: a relation of that name already exists",
+            name)));
+    }
+    let builtin = Registered { bound, function: Box::new(function) };
+    REGISTERED.with(|r| {
+        r.borrow_mut().insert(name.to_string(), Rc::new(builtin))
+    });
+    Ok(())
+}
+
+/// Plan a scan over a built-in relation, or return `None` if the named relation
+/// is not built in.
+fn builtin_scan<'s>(engine: &Storage,
+                    name: &str,
+                    params: &[ast::AtomicTerm])
+        -> Option<Result<Tuples<'s, 's>>> {
+    let tuples = match name {
+        // `true` holds exactly once, and `fail` never holds.
+        "true" => vec!(Vec::new()),
+        "fail" => Vec::new(),
+        "before" => return Some(before_scan(params)),
+        "holds" => return Some(holds_scan(engine, params)),
+        "reachable" | "shortest_path_len" =>
+            return Some(path_scan(engine, name, params)),
+        "min_by" | "max_by" => return Some(Err(Error::MalformedLine(format!(
+            "{} can only be used in the body of a rule", name)))),
+        _ => return registered(name).map(|r| registered_scan(name, &r, params))
+    };
+
+    Some(if params.len() != 0 {
+        Err(Error::ArityMismatch { expected: 0, got: params.len() })
+    } else {
+        Ok(Box::new(VecPlan::new(tuples)))
+    })
+}
+
+// `before(X, Y)` holds when X comes before Y in the total order on values. It
+// has infinitely many tuples, so it can only be queried with both arguments
+// given.
+fn before_scan<'s>(params: &[ast::AtomicTerm]) -> Result<Tuples<'s, 's>> {
+    if params.len() != 2 {
+        return Err(Error::ArityMismatch { expected: 2, got: params.len() });
+    }
+    let first = to_atom(params[0].clone()).map_err(|_| unbound_before())?;
+    let second = to_atom(params[1].clone()).map_err(|_| unbound_before())?;
+    let tuples = if first < second {
+        vec!(vec!(first, second))
+    } else {
+        Vec::new()
+    };
+    Ok(Box::new(VecPlan::new(tuples)))
+}
+
+// A registered built-in can only be queried with the arguments it needs
+// bound given.
+fn registered_scan<'s>(name: &str,
+                       builtin: &Registered,
+                       params: &[ast::AtomicTerm]) -> Result<Tuples<'s, 's>> {
+    check_registered_arity(name, builtin, params)?;
+    let unbound = params.iter().zip(&builtin.bound).find(|(param, bound)| {
+        match param {
+            ast::AtomicTerm::Variable(_) => **bound,
+            ast::AtomicTerm::Atom(_) => false
+        }
+    });
+    if let Some((param, _)) = unbound {
+        return Err(unbound_registered(name, param));
+    }
+    let mut tuples = builtin.call(name, params, |_| unreachable!());
+    tuples.retain(|tuple| {
+        params.iter().zip(tuple).all(|(param, value)| match param {
+            ast::AtomicTerm::Atom(a) => a == value,
+            ast::AtomicTerm::Variable(_) => true
+        })
+    });
+    Ok(Box::new(VecPlan::new(tuples)))
+}
+
+fn check_registered_arity(name: &str,
+                          builtin: &Registered,
+                          params: &[ast::AtomicTerm]) -> Result<()> {
+    if params.len() == builtin.bound.len() {
+        Ok(())
+    } else {
+        Err(Error::RelationArity {
+            relation: name.to_string(),
+            arity: builtin.bound.len(),
+            got: params.len()
+        })
+    }
+}
+
+fn unbound_registered(name: &str, param: &ast::AtomicTerm) -> Error {
+    Error::MalformedLine(format!(
+        "argument {} of {} must be bound by a constant or another term",
+        param, name))
+}
+
+// `holds(R, X, Y)` holds when `R(X, Y)` is a fact of a table with two columns,
+// so rules can range over relations as well as values. Views are left out, so
+// that a view using `holds` can't end up depending on itself.
+fn holds_scan<'s>(engine: &Storage, params: &[ast::AtomicTerm])
+        -> Result<Tuples<'s, 's>> {
+    if params.len() != 3 {
+        return Err(Error::ArityMismatch { expected: 3, got: params.len() });
+    }
+    let relation = match params[0] {
+        ast::AtomicTerm::Atom(Value::Symbol(ref name)) => Some(name.as_str()),
+        ast::AtomicTerm::Atom(_) => return Ok(Box::new(VecPlan::new(vec!()))),
+        ast::AtomicTerm::Variable(_) => None
+    };
+    let mut tuples = holds_tuples(engine, relation);
+    tuples.retain(|tuple| {
+        params.iter().zip(tuple).all(|(param, value)| match param {
+            ast::AtomicTerm::Atom(a) => a == value,
+            ast::AtomicTerm::Variable(_) => true
+        })
+    });
+    Ok(Box::new(VecPlan::new(tuples)))
+}
+
+// The tuples of `holds` for the named relation, or for every relation in
+// alphabetical order.
+fn holds_tuples(engine: &Storage, relation: Option<&str>) -> Vec<Vec<Value>> {
+    let mut names = match relation {
+        Some(name) => vec!(name),
+        None => engine.get_relations()
+    };
+    names.sort();
+    let mut tuples = Vec::new();
+    for name in names {
+        match engine.get_relation(name) {
+            Some(Extension(table))
+                    if table.arity() == 2 && !engine.is_scratch(name) => {
+                let name = Value::Symbol(name.to_string());
+                for tuple in table.select(&[]) {
+                    let mut holding = vec!(name.clone());
+                    holding.extend(tuple.into_iter().cloned());
+                    tuples.push(holding);
+                }
+            },
+            _ => ()
+        }
+    }
+    tuples
+}
+
+// `reachable(Src, Dst, Via)` and `shortest_path_len(Src, Dst, N)` hold for
+// paths through the table of edges `graph::edges` names, found by searching
+// from each source: the one given, or with `Src` a variable, every node some
+// edge leaves.
+fn path_scan<'s>(engine: &Storage, name: &str, params: &[ast::AtomicTerm])
+        -> Result<Tuples<'s, 's>> {
+    Ok(Box::new(VecPlan::new(path_tuples(engine, name, params)?)))
+}
+
+// The tuples of `reachable` or `shortest_path_len` matching a term's
+// constants.
+fn path_tuples(engine: &Storage, name: &str, params: &[ast::AtomicTerm])
+        -> Result<Vec<Vec<Value>>> {
+    if params.len() != 3 {
+        return Err(Error::ArityMismatch { expected: 3, got: params.len() });
+    }
+    let edges = graph::edges();
+    let table = match engine.get_relation(&edges) {
+        Some(Extension(table)) => table,
+        _ => return Err(Error::MalformedLine(format!(
+            "{} needs a table of edges named {}; designate another with \
+             .graph", name, edges)))
+    };
+    let graph = graph::Graph::new(table)?;
+    let sources = match params[0] {
+        ast::AtomicTerm::Atom(ref source) => vec!(source),
+        ast::AtomicTerm::Variable(_) => graph.sources()
+    };
+    let mut tuples = Vec::new();
+    for source in sources {
+        if name == "reachable" {
+            for (node, via) in graph.reachable(source) {
+                tuples.push(vec!(source.clone(), node.clone(), via.clone()));
+            }
+        } else {
+            for (node, length) in graph.shortest_paths(source) {
+                tuples.push(vec!(source.clone(), node.clone(), length));
+            }
+        }
+    }
+    tuples.retain(|tuple| {
+        params.iter().zip(tuple).all(|(param, value)| match param {
+            ast::AtomicTerm::Atom(a) => a == value,
+            ast::AtomicTerm::Variable(_) => true
+        })
+    });
+    Ok(tuples)
+}
+
+fn unbound_before() -> Error {
+    Error::MalformedLine(
+        "both arguments of before must be bound by other terms".to_string())
+}
+
+// Is this term a comparison, an extremum or a registered built-in, which
+// filter or extend frames rather than producing them?
+fn is_comparison(term: &ast::Term) -> bool {
+    match term {
+        ast::Term::Compound(c) => c.relation == "before" || is_extremum(term)
+                                  || is_registered(term),
+        _ => false
+    }
+}
+
+// Is this term for a relation registered with `register_builtin`?
+fn is_registered(term: &ast::Term) -> bool {
+    match term {
+        ast::Term::Compound(c) => registered(&c.relation).is_some(),
+        _ => false
+    }
+}
+
+// Is this term an extremum like `min_by(K, V)`, which keeps only the best
+// frames of the rest of a rule body?
+fn is_extremum(term: &ast::Term) -> bool {
+    match term {
+        ast::Term::Compound(c) =>
+            c.relation == "min_by" || c.relation == "max_by",
+        _ => false
+    }
+}
+
+// The indices of the frames an extremum keeps, in order of their groups: the
+// frames agreeing on all but its last parameter form a group, and the one
+// with the least (for `min_by`) or greatest (for `max_by`) value of the last
+// parameter is kept. Ties go to the least frame, so the same frame is kept
+// however the frames are ordered.
+fn extremes(term: &ast::Term, frames: &[Frame]) -> Vec<usize> {
+    fn resolve<'a>(frame: &'a Frame<'a>, param: &'a ast::AtomicTerm)
+            -> &'a Value {
+        match param {
+            ast::AtomicTerm::Atom(a) => a,
+            ast::AtomicTerm::Variable(v) => frame.get(v).unwrap_or_else(|| {
+                panic!("frame in extremum missing a variable")
+            })
+        }
+    }
+
+    let (keep, params) = match term {
+        ast::Term::Compound(c) if c.relation == "min_by" =>
+            (Extremum::Min, &c.params),
+        ast::Term::Compound(c) => (Extremum::Max, &c.params),
+        _ => panic!("not an extremum: {}", term)
+    };
+    let (value, group) = params.split_last()
+                               .expect("extremum with no parameters");
+    let mut best: BTreeMap<Vec<&Value>, usize> = BTreeMap::new();
+    for (i, frame) in frames.iter().enumerate() {
+        let key = group.iter().map(|p| resolve(frame, p)).collect();
+        let current = *best.entry(key).or_insert(i);
+        let ordering = resolve(frame, value).cmp(
+            resolve(&frames[current], value));
+        let ordering = match keep {
+            Extremum::Min => ordering,
+            Extremum::Max => ordering.reverse()
+        }.then_with(|| frame.cmp(&frames[current]));
+        if ordering == Ordering::Less {
+            best.insert(group.iter().map(|p| resolve(frame, p)).collect(), i);
+        }
+    }
+    best.into_iter().map(|(_, i)| i).collect()
+}
+
+// Does a comparison hold, given the values of its variables?
+fn comparison_holds<'v, F>(term: &'v ast::Term, value: F) -> bool
+        where F: Fn(&str) -> &'v Value {
+    let (relation, params) = match term {
+        ast::Term::Compound(c) => (&c.relation, &c.params),
+        _ => panic!("not a comparison: {}", term)
+    };
+    let resolve = |param: &'v ast::AtomicTerm| match param {
+        ast::AtomicTerm::Atom(a) => a,
+        ast::AtomicTerm::Variable(v) => value(v)
+    };
+    match registered(relation) {
+        Some(builtin) => {
+            let tuples = builtin.call(relation, params, &value);
+            tuples.iter().any(|tuple| {
+                params.iter().zip(tuple).all(|(p, v)| resolve(p) == v)
+            })
+        },
+        None => resolve(&params[0]) < resolve(&params[1])
+    }
+}
+
+// Fail if the named relation is built in, and so can't be modified.
+fn check_not_builtin(name: &str) -> Result<()> {
+    if is_builtin(name) {
+        Err(Error::Builtin(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+// Fail if the named relation holds query results stored by `capture`, or is a
+// virtual table, and so can't be modified.
+fn check_not_scratch(engine: &Storage, name: &str) -> Result<()> {
+    if engine.is_scratch(name) {
+        Err(Error::MalformedLine(format!(
+            "{} holds stored query results, which can't be changed", name)))
+    } else if engine.virtual_table(name).is_some() {
+        Err(Error::Virtual(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+//
+// Modifying the database.
+//
+
+/// Add a simple fact (one with no variables) to the database.
+// Check that a fact can be asserted, returning its relation and tuple.
+fn check_fact(engine: &Storage, cache: &ViewCache, fact: ast::Term)
+        -> Result<(String, Vec<Value>)> {
+    let (head, rest) = deconstruct_term(fact)?;
+    check_not_builtin(&head)?;
+    check_not_scratch(engine, &head)?;
+    let tuple = to_atoms(rest)?;
+    match engine.get_relation(&head) {
+        Some(Extension(table)) if table.arity() != tuple.len() =>
+            Err(Error::RelationArity {
+                relation: head,
+                arity: table.arity(),
+                got: tuple.len()
+            }),
+        Some(Extension(table)) => {
+            let fact: Vec<&Value> = tuple.iter().collect();
+            for reference in table.references() {
+                let params = (0..relation_arity(engine, &reference.relation)?)
+                    .map(|i| match reference.referenced.iter()
+                                                       .position(|c| *c == i) {
+                        Some(j) => ast::AtomicTerm::Atom(
+                            fact[reference.columns[j]].clone()),
+                        None => ast::AtomicTerm::Variable(format!("_{}", i))
+                    }).collect();
+                let target = ast::Term::Compound(ast::CompoundTerm {
+                    relation: reference.relation.clone(),
+                    params
+                });
+                if absent(engine, cache, target)? {
+                    return Err(Error::MalformedLine(
+                        broken_reference(engine, &head, reference, &fact)));
+                }
+            }
+            Ok((head, tuple))
+        },
+        Some(Intension(_)) => Err(Error::NotExtensional(head)),
+        None => Ok((head, tuple))
+    }
+}
+
+// Check that a rule can be added to its view, or with `replace`, replace the
+// view's rules. Returns the view's name and the variables of the rule's head.
+fn check_rule(engine: &Storage, rule: &ast::Rule, replace: bool)
+        -> Result<(String, Vec<String>)> {
+    let (name, definition) = deconstruct_term(rule.head.clone())?;
+    check_not_builtin(&name)?;
+    if engine.virtual_table(&name).is_some() {
+        return Err(Error::Virtual(name));
+    }
+    let arity = match engine.get_relation(&name) {
+        Some(Extension(_)) => return Err(Error::NotIntensional(name)),
+        Some(Intension(view)) if !replace => view.arity(),
+        _ => None
+    };
+    if let Some(arity) = arity.filter(|a| *a != definition.len()) {
+        return Err(Error::RelationArity {
+            relation: name,
+            arity,
+            got: definition.len()
+        });
+    }
+    let params = to_variables(definition)?;
+    check_bindings(&params, &rule.body)?;
+    check_stratified(engine, rule, replace)?;
+    Ok((name, params))
+}
+
+fn simple_assert(engine: &mut Storage,
+                 cache: &mut ViewCache,
+                 fact: ast::Term) -> Result<()> {
+    assert_and_fire(engine, cache, fact, 0)
+}
+
+// The most triggers that may fire one after another, each for a fact asserted
+// by the last, before they are taken to be looping forever.
+const MAX_TRIGGER_DEPTH: usize = 100;
+
+// Assert a fact, then the facts asserted by the triggers on its table, and so
+// on. `depth` is the number of triggers that led to the fact.
+fn assert_and_fire(engine: &mut Storage,
+                   cache: &mut ViewCache,
+                   fact: ast::Term,
+                   depth: usize) -> Result<()> {
+    let (head, tuple) = check_fact(engine, cache, fact)?;
+    engine.assert_fact(head.clone(), tuple.clone())?;
+    cache.defer(&head, tuple.clone());
+    materialize_insert(engine, cache, &head, &tuple);
+
+    let triggers = match engine.get_relation(&head) {
+        Some(Extension(table)) => table.triggers().to_vec(),
+        _ => Vec::new()
+    };
+    for trigger in triggers {
+        let bindings = match fact_bindings(&trigger.pattern, &tuple) {
+            Some(bindings) => bindings,
+            None => continue
+        };
+        if depth == MAX_TRIGGER_DEPTH {
+            return Err(Error::MalformedLine(format!(
+                "more than {} triggers fired in a row, the last on {}",
+                MAX_TRIGGER_DEPTH, head)));
+        }
+        debug!("trigger on {} fired at depth {}", head, depth);
+        for action in &trigger.actions {
+            let params = action.params.iter().map(|p| match p {
+                ast::AtomicTerm::Variable(v) =>
+                    ast::AtomicTerm::Atom(bindings[v.as_str()].clone()),
+                atom => atom.clone()
+            }).collect();
+            let fact = ast::Term::Compound(ast::CompoundTerm {
+                relation: action.relation.clone(),
+                params
+            });
+            assert_and_fire(engine, cache, fact, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+// Match a fact against a pattern, like that of a trigger, returning the values
+// of the pattern's variables if it matches.
+fn fact_bindings<'v>(pattern: &'v [ast::AtomicTerm], tuple: &'v [Value])
+        -> Option<HashMap<&'v str, &'v Value>> {
+    let mut bindings = HashMap::new();
+    for (param, value) in pattern.iter().zip(tuple) {
+        let matches = match param {
+            ast::AtomicTerm::Atom(a) => a == value,
+            ast::AtomicTerm::Variable(v) =>
+                *bindings.entry(v.as_str()).or_insert(value) == value
+        };
+        if !matches {
+            return None;
+        }
+    }
+    Some(bindings)
+}
+
+/// Declare a trigger on a table: from now on, whenever a fact matching
+/// `pattern` is asserted into it, each of `actions` is asserted too, with the
+/// variables of the pattern replaced by their values in the fact.
+///
+/// Triggers only fire for facts asserted one at a time, not for those added
+/// by `bulk_assert`.
+pub fn add_trigger(engine: &mut Storage,
+                   pattern: ast::Term,
+                   actions: Vec<ast::Term>) -> Result<()> {
+    let (name, params) = deconstruct_term(pattern)?;
+    check_not_scratch(engine, &name)?;
+    let arity = match engine.get_relation(&name) {
+        Some(Extension(table)) => table.arity(),
+        Some(Intension(_)) => return Err(Error::NotExtensional(name)),
+        None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", name)))
+    };
+    if params.len() != arity {
+        return Err(Error::RelationArity {
+            relation: name,
+            arity,
+            got: params.len()
+        });
+    }
+
+    let variables = |params: &[ast::AtomicTerm]| -> Vec<String> {
+        params.iter().filter_map(|p| match p {
+            ast::AtomicTerm::Variable(v) => Some(v.clone()),
+            ast::AtomicTerm::Atom(_) => None
+        }).collect()
+    };
+    let bound = variables(&params);
+    let mut facts = Vec::new();
+    for action in actions {
+        let action = match action {
+            ast::Term::Compound(c) => c,
+            other => return Err(Error::MalformedLine(format!(
+                    "a trigger can only assert facts, not {}", other)))
+        };
+        check_not_builtin(&action.relation)?;
+        check_not_scratch(engine, &action.relation)?;
+        if let Some(Intension(_)) = engine.get_relation(&action.relation) {
+            return Err(Error::NotExtensional(action.relation));
+        }
+        let unbound = variables(&action.params).into_iter()
+                                               .find(|v| !bound.contains(v));
+        if let Some(v) = unbound {
+            return Err(Error::MalformedLine(format!(
+                "variable {} in {} must appear in the term the trigger \
+                 matches", v, ast::Term::Compound(action))));
+        }
+        facts.push(action);
+    }
+
+    if let Some(mut relation) = engine.get_relation_mut(&name) {
+        if let Extension(ref mut table) = *relation {
+            table.add_trigger(storage::Trigger {
+                pattern: params,
+                actions: facts
+            });
+        }
+    }
+    Ok(())
+}
+
+fn add_rule_to_view(engine: &mut Storage,
+                    cache: &mut ViewCache,
+                    rule: ast::Rule) -> Result<()> {
+    let (name, params) = check_rule(engine, &rule, false)?;
+    let relation = storage::Relation::Intension(AstView::new());
+    let mut rel_view = engine.get_or_create_relation(name.clone(), relation)?;
+
+    match *rel_view {
+        Extension(_) => return Err(Error::NotIntensional(name.clone())),
+        Intension(ref mut view) => view.add_rule(params, rule.body.clone())
+    }
+
+    cache.invalidate(&name);
+    add_dependencies(cache, &name, &rule.body);
+    Ok(())
+}
+
+// Check that every variable a rule needs bound is bound by one of its terms
+// that produces frames: each head variable in a negated term, every variable
+// in a comparison, and the arguments registered built-ins need bound. A
+// registered built-in binds its other arguments for the terms after it.
+fn check_bindings(head: &[String], body: &[ast::Term]) -> Result<()> {
+    let (positive, negated) = split_body(body);
+    let mut bound: HashSet<String> = positive.iter()
+                                             .filter(|t| !is_comparison(t))
+                                             .flat_map(|t| t.variables())
+                                             .collect();
+    for term in positive.iter().chain(&negated).filter(|t| is_registered(t)) {
+        let (name, params) = deconstruct_term((*term).clone())?;
+        let builtin = registered(&name).expect("registered built-in vanished");
+        check_registered_arity(&name, &builtin, &params)?;
+    }
+    for term in positive.iter().filter(|t| is_registered(t)) {
+        let (name, params) = deconstruct_term((*term).clone())?;
+        let builtin = registered(&name).expect("registered built-in vanished");
+        for (param, needed) in params.iter().zip(&builtin.bound) {
+            match param {
+                ast::AtomicTerm::Variable(v) if *needed && !bound.contains(v) =>
+                    return Err(unbound_registered(&name, param)),
+                _ => ()
+            }
+        }
+        bound.extend(term.variables());
+    }
+    let comparisons = positive.iter().filter(|t| !is_registered(t))
+                              .chain(&negated)
+                              .filter(|t| is_comparison(t));
+    for term in comparisons {
+        if let Some(v) = term.variables().iter().find(|v| !bound.contains(*v)) {
+            return Err(Error::MalformedLine(format!(
+                "variable {} in \"{}\" must appear in a term that isn't a \
+                 comparison", v, term)));
+        }
+    }
+    for term in negated {
+        if is_extremum(term) {
+            return Err(Error::MalformedLine(format!(
+                "\"not {}\" is not allowed: min_by and max_by can't be \
+                 negated", term)));
+        }
+        let unbound = term.variables().into_iter().find(|v| {
+            head.contains(v) && !bound.contains(v)
+        });
+        if let Some(v) = unbound {
+            return Err(Error::MalformedLine(format!(
+                "variable {} in \"not {}\" must appear in a term that isn't \
+                 negated", v, term)));
+        }
+    }
+    Ok(())
+}
+
+// The relations each view refers to, and how each is used, were `rule` added
+// to the database. If `replace` is set, the rule replaces the existing rules
+// of its view.
+fn dependency_edges(engine: &Storage, rule: &ast::Rule, replace: bool)
+        -> Result<References> {
+    let mut edges = References::new();
+    let (name, _) = deconstruct_term(rule.head.clone())?;
+    for relation in engine.get_relations() {
+        if replace && relation == name {
+            continue;
+        }
+        if let Some(Intension(view)) = engine.get_relation(relation) {
+            for (_, body) in &view.rules {
+                add_edges(&mut edges, relation, body)?;
+            }
+        }
+    }
+    add_edges(&mut edges, &name, &rule.body)?;
+    Ok(edges)
+}
+
+fn add_edges(edges: &mut References,
+             name: &str,
+             body: &[ast::Term]) -> Result<()> {
+    let (positive, negated) = split_body(body);
+    let extremum = if positive.iter().any(|t| is_extremum(t)) {
+        Dependence::Extremum
+    } else {
+        Dependence::Positive
+    };
+    let targets = edges.entry(name.to_string()).or_insert(Vec::new());
+    for (terms, dependence) in vec![(positive, extremum),
+                                    (negated, Dependence::Negated)] {
+        for term in terms {
+            let (relation, _) = deconstruct_term(term.clone())?;
+            targets.push((relation, dependence));
+        }
+    }
+    Ok(())
+}
+
+// A chain of references from one relation to another, as a list of steps
+// each marked with how it is used.
+fn dependency_path(edges: &References,
+                   from: &str,
+                   to: &str) -> Option<Vec<(String, Dependence)>> {
+    let mut previous: HashMap<&str, (&str, Dependence)> = HashMap::new();
+    let mut frontier = vec![from];
+    while !frontier.is_empty() && from != to && !previous.contains_key(to) {
+        let mut next = Vec::new();
+        for relation in frontier {
+            for (target, dependence) in edges.get(relation).into_iter()
+                                                           .flatten() {
+                if target != from && !previous.contains_key(target.as_str()) {
+                    previous.insert(target, (relation, *dependence));
+                    next.push(target.as_str());
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    if from == to {
+        return Some(Vec::new());
+    }
+    let mut path = Vec::new();
+    let mut current = to;
+    while current != from {
+        let (source, dependence) = previous.get(current)?;
+        path.push((current.to_string(), *dependence));
+        current = source;
+    }
+    path.reverse();
+    Some(path)
+}
+
+// Check that adding `rule` wouldn't make any view negate a view in its own
+// stratum, or take an extremum over one: either way, answers it derives could
+// take away others, which has no well-defined fixpoint. The error names the
+// rule and the cycle.
+fn check_stratified(engine: &Storage, rule: &ast::Rule, replace: bool)
+        -> Result<()> {
+    fn step(relation: &str, dependence: Dependence) -> String {
+        match dependence {
+            Dependence::Positive => relation.to_string(),
+            Dependence::Negated => format!("not {}", relation),
+            Dependence::Extremum => format!("extremum of {}", relation)
+        }
+    }
+
+    let strata = Strata::new(dependency_edges(engine, rule, replace)?);
+    if let Some((view, target)) = strata.non_monotone_within() {
+        let edges = strata.references();
+        let dependence = edges[view].iter()
+            .filter(|(relation, _)| relation == target)
+            .map(|(_, dependence)| *dependence)
+            .find(|dependence| *dependence != Dependence::Positive)
+            .expect("no non-monotone reference between the views");
+        let path = dependency_path(edges, target, view)
+            .expect("no path between views in the same stratum");
+        let mut cycle = format!("{} -> {}", view, step(target, dependence));
+        for (relation, dependence) in path {
+            cycle.push_str(&format!(" -> {}", step(&relation, dependence)));
+        }
+        let problem = match dependence {
+            Dependence::Extremum => "take an extremum over itself",
+            _ => "depend on its own negation"
+        };
+        return Err(Error::MalformedLine(format!(
+            "the rule \"{}\" would make {} {} ({})", rule, view, problem,
+            cycle)));
+    }
+    Ok(())
+}
+
+/// The strata of the views in the database, in the order they are
+/// evaluated; see `strata`.
+pub fn evaluation_order(engine: &Storage) -> Result<Strata> {
+    let mut references = References::new();
+    for relation in engine.get_relations() {
+        if let Some(Intension(view)) = engine.get_relation(relation) {
+            for (_, body) in &view.rules {
+                add_edges(&mut references, relation, body)?;
+            }
+        }
+    }
+    Ok(Strata::new(references))
+}
+
+// The strata of the views, worked out once for each query planned.
+fn planned_strata(engine: &Storage, cache: &ViewCache) -> Result<Rc<Strata>> {
+    if let Some(strata) = cache.strata() {
+        return Ok(strata);
+    }
+    let strata = Rc::new(evaluation_order(engine)?);
+    cache.remember_strata(Rc::clone(&strata));
+    Ok(strata)
+}
+
+/// Record that the named view depends on each relation in `body`.
+fn add_dependencies(cache: &mut ViewCache, name: &str, body: &[ast::Term]) {
+    for term in body {
+        let term = match term {
+            ast::Term::Not(t) => &**t,
+            t => t
+        };
+        if let ast::Term::Compound(cterm) = term {
+            // The path built-ins read whichever table of edges is designated
+            // when they run, so like `holds`, they may change with any
+            // relation.
+            let relation = if graph::is_path_builtin(&cterm.relation) {
+                EVERY_RELATION.to_string()
+            } else {
+                cterm.relation.clone()
+            };
+            cache.add_dependency(relation, name.to_string());
+        }
+    }
+}
+
+/// Get the rules defining the named view.
+pub fn rules(engine: &Storage, name: &str) -> Result<Vec<ast::Rule>> {
+    match engine.get_relation(name) {
+        Some(Intension(view)) => Ok(view.to_rules(name)),
+        Some(Extension(_)) => Err(Error::NotIntensional(name.to_string())),
+        None => Err(Error::MalformedLine(
+                format!("No relation \"{}\" found.", name)))
+    }
+}
+
+/// Warnings about a rule whose body is probably slow or mistaken: parts of
+/// the body sharing no variables, whose answers are paired in every
+/// combination, and variables appearing only once in a body joining several
+/// relations, which join nothing and are often misspelt.
+///
+/// Built-in relations don't count as joining variables.
+pub fn lint(rule: &ast::Rule) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let (positive, _) = split_body(&rule.body);
+    let joined: Vec<&ast::Term> = positive.into_iter().filter(|t| match t {
+        ast::Term::Compound(c) => !is_builtin(&c.relation),
+        _ => false
+    }).collect();
+
+    // Group the joined terms, by their positions, into components linked by
+    // shared variables.
+    let mut components: Vec<(Vec<usize>, HashSet<String>)> = Vec::new();
+    for (i, term) in joined.iter().enumerate() {
+        let variables: HashSet<String> =
+            term.variables().into_iter().collect();
+        if variables.is_empty() {
+            continue;
+        }
+        let (linked, mut rest): (Vec<_>, Vec<_>) =
+            components.into_iter().partition(|(_, vs)| {
+                !vs.is_disjoint(&variables)
+            });
+        let mut merged = (vec!(i), variables);
+        for (terms, vs) in linked {
+            merged.0.extend(terms);
+            merged.1.extend(vs);
+        }
+        merged.0.sort();
+        rest.push(merged);
+        components = rest;
+    }
+    if components.len() > 1 {
+        components.sort_by_key(|(terms, _)| terms[0]);
+        let parts: Vec<String> = components.iter().map(|(terms, _)| {
+            let terms: Vec<String> =
+                terms.iter().map(|i| joined[*i].to_string()).collect();
+            terms.join(", ")
+        }).collect();
+        warnings.push(format!(
+            "{} share no variables, so their answers are paired in every \
+             combination", parts.join("; ")));
+    }
 
-        for i in 0..self.params.len() {
-            match self.params[i] {
-                ast::AtomicTerm::Atom(ref s) => {
-                    if *s != t[i] {
-                        return None;
-                    }
+    if joined.len() > 1 {
+        let mut uses: BTreeMap<String, usize> = BTreeMap::new();
+        for term in rule.body.iter().chain(Some(&rule.head)) {
+            let params = match term {
+                ast::Term::Compound(c) => &c.params,
+                ast::Term::Not(t) => match &**t {
+                    ast::Term::Compound(c) => &c.params,
+                    _ => continue
                 },
-                ast::AtomicTerm::Variable(ref s) => {
-                    let binding = variable_bindings.entry(s.to_string())
-                        .or_insert(t[i]);
-                    if *binding != t[i] {
-                        return None;
-                    }
+                _ => continue
+            };
+            for param in params {
+                if let ast::AtomicTerm::Variable(v) = param {
+                    *uses.entry(v.clone()).or_insert(0) += 1;
                 }
             }
         }
-        return Some(variable_bindings);
+        for (variable, _) in uses.iter().filter(|(_, n)| **n == 1) {
+            warnings.push(format!(
+                "{} appears only once, so it joins nothing; is it misspelt?",
+                variable));
+        }
     }
+    warnings
 }
 
-fn merge_frames<'a>(f1: &Frame<'a>, f2: &Frame<'a>) -> Option<Frame<'a>> {
-    // TODO - don't copy these
-    let mut result = BTreeMap::new();
-    for (var, binding1) in f1 {
-        match f2.get(var) {
-            Some(binding2) => { 
-                if binding1 != binding2 {
-                    return None;
-                } else {
-                    result.insert(var.clone(), binding1.clone());
-                }
-            }
-            None => {
-                result.insert(var.clone(), binding1.clone());
+/// Why a rule body can never hold, if that can be told without reading any
+/// facts: it uses `fail` or `not true`, compares constants, or a variable with
+/// itself, in a way that never holds, or uses a relation with the wrong number
+/// of parameters.
+///
+/// Rules that can never hold for any of the reasons but the last are skipped
+/// when planning a view; the last is an error when the rule is used, since
+/// the relation's arity may have changed after the rule was asserted.
+pub fn contradiction(engine: &Storage, body: &[ast::Term]) -> Option<String> {
+    if let Some(term) = body.iter().find(|t| never_holds(t)) {
+        return Some(format!("{} never holds", term));
+    }
+    for term in body {
+        // A negated term with the wrong arity is an error when planned, not
+        // a contradiction.
+        let (name, params) = match term {
+            ast::Term::Not(_) => continue,
+            t => match deconstruct_term(t.clone()) {
+                Ok(parts) => parts,
+                Err(_) => continue
             }
         };
+        if is_builtin(&name) {
+            continue;
+        }
+        let arity = match engine.get_relation(&name) {
+            Some(Extension(table)) => Some(table.arity()),
+            Some(Intension(view)) => view.arity(),
+            None => engine.virtual_table(&name).map(|t| t.arity())
+        };
+        if let Some(arity) = arity.filter(|a| *a != params.len()) {
+            return Some(format!("{} has arity {}, so {} never holds",
+                                name, arity, term));
+        }
     }
+    None
+}
 
-    for (var, binding2) in f2 {
-        match f1.get(var) {
-            Some(binding1) => { 
-                if binding1 != binding2 {
-                    return None;
-                } else {
-                    result.insert(var.clone(), binding2.clone());
-                }
-            }
-            None => {
-                result.insert(var.clone(), binding2.clone());
+// Can a term of a rule body be seen never to hold from its constants alone?
+fn never_holds(term: &ast::Term) -> bool {
+    let (negated, term) = match term {
+        ast::Term::Not(t) => (true, &**t),
+        t => (false, t)
+    };
+    let (name, params) = match deconstruct_term(term.clone()) {
+        Ok(parts) => parts,
+        Err(_) => return false
+    };
+    match name.as_str() {
+        "fail" => !negated,
+        "true" => negated,
+        "before" if params.len() == 2 => match (&params[0], &params[1]) {
+            (ast::AtomicTerm::Atom(a), ast::AtomicTerm::Atom(b)) =>
+                (a < b) == negated,
+            (ast::AtomicTerm::Variable(x), ast::AtomicTerm::Variable(y)) =>
+                x == y && !negated,
+            _ => false
+        },
+        _ => false
+    }
+}
+
+/// Replace all of the rules defining a view with the given rule.
+///
+/// If the view doesn't exist yet, this is the same as `assert`.
+pub fn redefine(engine: &mut Storage,
+                cache: &mut ViewCache,
+                rule: ast::Rule) -> Result<()> {
+    if rule.body.is_empty() {
+        return Err(Error::MalformedLine(
+                format!("can only redefine a view with a rule: {}", rule)));
+    }
+
+    // Check the new rule before removing the old ones, so that a rejected
+    // redefinition leaves the view unchanged.
+    let (name, _) = check_rule(engine, &rule, true)?;
+    if let Some(mut relation) = engine.get_relation_mut(&name) {
+        match *relation {
+            Extension(_) => return Err(Error::NotIntensional(name)),
+            Intension(ref mut view) => view.rules.clear()
+        }
+    }
+    cache.remove_dependencies(&name);
+
+    add_rule_to_view(engine, cache, rule)
+}
+
+/// Replace all of the rules defining the named view with `rules`, e.g. to
+/// restore an earlier version of it. With no rules, the view is left with
+/// none.
+pub fn replace_rules(engine: &mut Storage,
+                     cache: &mut ViewCache,
+                     name: &str,
+                     rules: Vec<ast::Rule>) -> Result<()> {
+    let mut rules = rules.into_iter();
+    match rules.next() {
+        Some(first) => redefine(engine, cache, first)?,
+        None => if let Some(mut relation) = engine.get_relation_mut(name) {
+            match *relation {
+                Extension(_) =>
+                    return Err(Error::NotIntensional(name.to_string())),
+                Intension(ref mut view) => view.rules.clear()
             }
+            cache.invalidate(name);
+            cache.remove_dependencies(name);
+        }
+    }
+    for rule in rules {
+        add_rule_to_view(engine, cache, rule)?;
+    }
+    Ok(())
+}
+
+/// Remove the rule at the given index (counting from zero) from the named view,
+/// returning it.
+pub fn retract_rule(engine: &mut Storage,
+                    cache: &mut ViewCache,
+                    name: &str,
+                    index: usize) -> Result<ast::Rule> {
+    let rule = {
+        let mut relation = engine.get_relation_mut(name).ok_or_else(|| {
+            Error::MalformedLine(format!("No relation \"{}\" found.", name))
+        })?;
+        let view = match *relation {
+            Extension(_) => return Err(Error::NotIntensional(name.to_string())),
+            Intension(ref mut view) => view
         };
+        if index >= view.rules.len() {
+            return Err(Error::MalformedLine(
+                    format!("{} has no rule number {}", name, index + 1)));
+        }
+
+        let rule = view.to_rules(name).remove(index);
+        view.rules.remove(index);
+        cache.invalidate(name);
+        cache.remove_dependencies(name);
+        for (_, body) in &view.rules {
+            add_dependencies(cache, name, body);
+        }
+        rule
+    };
+
+    Ok(rule)
+}
+
+/// Check that a fact or rule could be asserted (or with `replace`, used to
+/// redefine its view) without changing the database, returning a description
+/// of what it would do.
+pub fn check_assert(engine: &Storage,
+                    cache: &ViewCache,
+                    rule: &ast::Rule,
+                    replace: bool) -> Result<String> {
+    if rule.body.is_empty() {
+        if replace {
+            return Err(Error::MalformedLine(
+                format!("can only redefine a view with a rule: {}", rule)));
+        }
+        let (name, tuple) = check_fact(engine, cache, rule.head.clone())?;
+        return Ok(format!("would add fact to relation {}/{}",
+                          name, tuple.len()));
     }
 
-    return Some(result);
+    let (name, params) = check_rule(engine, rule, replace)?;
+    if replace {
+        Ok(format!("would replace the rules of relation {}/{}",
+                   name, params.len()))
+    } else {
+        Ok(format!("would add rule to relation {}/{}", name, params.len()))
+    }
 }
 
-//
-// Query planning.
-//
+/// Undo `assert`: remove the most recently asserted copy of a fact, or the
+/// last rule of its view identical to a rule.
+pub fn retract(engine: &mut Storage,
+               cache: &mut ViewCache,
+               rule: ast::Rule) -> Result<()> {
+    if rule.body.is_empty() {
+        return retract_fact(engine, cache, rule.head);
+    }
 
-/// Plan a cross join over arbitrarily many terms.
-fn plan_joins<'s: 'a, 'a>(
-        mut joins: LinkedList<Frames<'s, 'a>>) -> Frames<'s, 'a> {
-    let head = joins.pop_front();
-    match head {
-        None => panic!("Empty Join list"),
-        Some(term) => {
-            if joins.len() == 0 {
-                term
-            } else {
-                let rest: Frames<'s, 'a> = plan_joins(joins);
-                Box::new(Join::new(term, rest))
-            }
+    let (name, _) = deconstruct_term(rule.head.clone())?;
+    let index = rules(engine, &name)?.iter().rposition(|r| *r == rule)
+        .ok_or_else(|| Error::MalformedLine(
+                format!("{} has no rule {}", name, rule)))?;
+    retract_rule(engine, cache, &name, index)?;
+    Ok(())
+}
+
+fn retract_fact(engine: &mut Storage,
+                cache: &mut ViewCache,
+                fact: ast::Term) -> Result<()> {
+    let (name, params) = deconstruct_term(fact)?;
+    let tuple = to_atoms(params)?;
+    let overdeleted = overdelete(engine, cache, &name, &tuple);
+    {
+        let mut relation = engine.get_relation_mut(&name).ok_or_else(|| {
+            Error::MalformedLine(format!("No relation \"{}\" found.", name))
+        })?;
+        let table = match *relation {
+            Extension(ref mut table) => table,
+            Intension(_) => return Err(Error::NotExtensional(name.clone()))
+        };
+        if !table.retract(&tuple) {
+            return Err(Error::MalformedLine(
+                    format!("{} has no such fact", name)));
         }
     }
+
+    cache.invalidate(&name);
+    materialize_retract(engine, cache, &name, &tuple, overdeleted);
+    Ok(())
 }
 
-fn plan_recursive_rule<'s: 'a, 'a>(
-        engine: &'s Storage,
-        cache: &'s ViewCache,
-        name: &str,
-        rule: &[ast::Term],
-        formals: &[String],
-        all_tuples: &'a HashSet<Tuple<'s>>) -> Result<Tuples<'s, 'a>> {
-    let mut joins: LinkedList<Frames<'s, 'a>> = LinkedList::new();
-    for term in rule {
-        let (relation_name, params) = deconstruct_term(term.clone())?;
-        if relation_name == name {
-            let tuples = Box::new(SetNode::new(all_tuples));
-            let scan = PatternMatch::new(Pattern::new(params), tuples);
-            joins.push_back(Box::new(scan));
-        } else {
-            joins.push_back(query(engine, cache, term.clone())?);
+/// Set or clear the subsumption order used when evaluating the named recursive
+/// view. Columns are numbered from zero.
+pub fn set_subsumption(engine: &mut Storage,
+                       cache: &mut ViewCache,
+                       name: &str,
+                       subsumption: Option<Subsumption>) -> Result<()> {
+    let mut relation = engine.get_relation_mut(name).ok_or_else(|| {
+        Error::MalformedLine(format!("No relation \"{}\" found.", name))
+    })?;
+    let view = match *relation {
+        Extension(_) => return Err(Error::NotIntensional(name.to_string())),
+        Intension(ref mut view) => view
+    };
+    if let Some(ref s) = subsumption {
+        let arity = view.arity().unwrap_or(0);
+        if s.column >= arity {
+            return Err(Error::MalformedLine(
+                    format!("{} has no column number {}", name, s.column + 1)));
         }
     }
 
-    Ok(Box::new(IntensionalScan::new(formals.to_vec(), plan_joins(joins))))
+    view.subsumption = subsumption;
+    cache.invalidate(name);
+    Ok(())
 }
 
-/// Given a query, return all variable assignments over the database that
-/// satisfy that query.
-pub fn query<'s>(engine: &'s Storage,
-                 cache: &'s ViewCache,
-                 query: ast::Term) -> Result<Frames<'s, 's>> {
-    let (head, rest) = deconstruct_term(query)?;
+/// Replace the named view with a table containing its current contents.
+pub fn convert(engine: &mut Storage,
+               cache: &mut ViewCache,
+               name: &str) -> Result<()> {
+    let arity = match engine.get_relation(name) {
+        Some(Intension(view)) => view.arity().ok_or_else(|| {
+            Error::MalformedLine(format!("{} has no rules", name))
+        })?,
+        Some(Extension(_)) => return Err(Error::NotIntensional(name.into())),
+        None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", name)))
+    };
 
-    let scan = if let Some(cached) = cache.read_cache(&head) {
-        Box::new(VecPlan::new(cached))
-    } else {
-        let relation =
-            engine.get_relation(head.as_str())
-                  .ok_or(Error::MalformedLine(
-                          format!("No relation \"{}\" found.", head.as_str())))?;
-        match relation {
-            Extension(ref table) => Box::new(ExtensionalScan::new(table)),
-            Intension(view) => IntensionalScan::from_view(&head,
-                                                          engine,
-                                                          cache,
-                                                          view,
-                                                          false)?
+    let mut table = storage::Table::new(arity);
+    {
+        let params: Vec<ast::AtomicTerm> = (0..arity).map(|i| {
+            ast::AtomicTerm::Variable(format!("_{}", i))
+        }).collect();
+        for tuple in scan_relation(engine, cache, name, &params, false)? {
+            table.assert(tuple.into_iter().cloned().collect())?;
         }
-    };
+    }
 
+    if let Some(mut relation) = engine.get_relation_mut(name) {
+        *relation = Extension(table);
+    }
+    engine.dematerialize(name);
+    cache.invalidate(name);
+    cache.remove_dependencies(name);
+    Ok(())
+}
+
+/// Materialize the named recursive view: evaluate it, store the result, and
+/// from then on answer queries against the view from what was stored.
+/// Returns the number of tuples stored.
+///
+/// The materialization is kept up to date as facts are asserted and retracted
+/// by following the changes through the view's rules, if they just join
+/// relations and compare values, and use the changed tables directly: new
+/// facts semi-naively, deriving what follows from each fact, then from what
+/// that derived, and so on; and retracted facts by deleting and rederiving
+/// (DRed), deleting everything derived from the fact before putting back what
+/// can still be derived without it. Any other change to what the view depends
+/// on marks it outdated, to be evaluated again by `refresh_materialized`.
+pub fn materialize(engine: &mut Storage,
+                   cache: &mut ViewCache,
+                   name: &str) -> Result<usize> {
+    relation_arity(engine, name)?;
+    if let Some(Extension(_)) = engine.get_relation(name) {
+        return Err(Error::NotIntensional(name.to_string()));
+    }
+    if !cache.dependents(name).iter().any(|v| v == name) {
+        return Err(Error::MalformedLine(format!(
+            "only recursive views can be materialized, and {} is not",
+            name)));
+    }
+    let virtual_table = engine.virtual_tables().into_iter().find(|table| {
+        cache.dependents(table).iter().any(|v| v == name)
+    }).map(str::to_string);
+    if let Some(table) = virtual_table {
+        return Err(Error::MalformedLine(format!(
+            "{} depends on the virtual table {}, which can change unseen",
+            name, table)));
+    }
 
-    Ok(Box::new(PatternMatch::new(Pattern::new(rest), scan)))
+    cache.outdate(name);
+    rematerialize(engine, cache, name)
 }
 
-/// Given a query, return all variable assignments over the database that
-/// satisfy that query, using a semi-naive algorithm for recursive rules if
-/// needed.
-pub fn query_semi_naive<'s>(engine: &'s Storage,
-                            cache: &'s ViewCache,
-                            query: ast::Term) -> Result<Frames<'s, 's>> {
-    let (head, rest) = deconstruct_term(query)?;
+/// Drop the materialization of the named view, returning whether it had one.
+/// The view is evaluated from its rules again from then on.
+pub fn dematerialize(engine: &mut Storage, cache: &ViewCache, name: &str)
+        -> bool {
+    cache.refreshed(name);
+    engine.dematerialize(name)
+}
+
+/// Evaluate each materialized view outdated since it was last refreshed, and
+/// store the results as its materialization.
+///
+/// Fails with the first error evaluating any of them; those that fail stay
+/// outdated, and so are answered from their rules until refreshed.
+pub fn refresh_materialized(engine: &mut Storage, cache: &ViewCache)
+        -> Result<()> {
+    let mut result = Ok(());
+    for name in cache.outdated() {
+        if engine.materialization(&name).is_none() {
+            cache.refreshed(&name);
+            continue;
+        }
+        match engine.get_relation(&name) {
+            Some(Intension(view)) if view.arity().is_some() => (),
+            _ => {
+                warn!("dropping the materialization of {}, which is no \
+                       longer a view with rules", name);
+                dematerialize(engine, cache, &name);
+                continue;
+            }
+        }
+        debug!("refreshing the materialization of {}", name);
+        if let Err(e) = rematerialize(engine, cache, &name) {
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+// Evaluate a view from its rules, and store the result as its
+// materialization.
+fn rematerialize(engine: &mut Storage, cache: &ViewCache, name: &str)
+        -> Result<usize> {
+    let arity = relation_arity(engine, name)?;
+    let mut table = storage::Table::new(arity);
+    // Rederiving tuples binds their columns, and usually the first is the
+    // most selective.
+    if arity > 0 {
+        table.add_index(0);
+    }
+    {
+        let params: Vec<ast::AtomicTerm> = (0..arity).map(|i| {
+            ast::AtomicTerm::Variable(format!("_{}", i))
+        }).collect();
+        let scan = scan_relation(engine, cache, name, &params, true)?;
+        table.assert_batch(scan.map(|tuple| {
+            tuple.into_iter().cloned().collect()
+        }).collect())?;
+    }
+    let len = table.len();
+    engine.materialize(name, table)?;
+    cache.discard(name);
+    cache.refreshed(name);
+    Ok(len)
+}
+
+/// What `infer_schema` found in one column of a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnSchema {
+    /// The type of the column's values, after any prefix and ignoring null
+    /// markers: `integer`, `number`, `bool`, `symbol`, `string`, `mixed` or,
+    /// if there are no such values, `unknown`. Numbers and booleans stored as
+    /// symbols or strings have ` (as text)` appended.
+    pub kind: String,
+    /// The number of distinct values.
+    pub distinct: usize,
+    /// A prefix of every value ending in a separator like `_`, e.g. `id_`.
+    pub prefix: Option<String>,
+    /// The number of values that look like null markers, e.g. `null`, `n/a`
+    /// or, with the prefix `id_`, `id_NULL`.
+    pub nulls: usize
+}
+
+// Values which, ignoring case, probably stand for a missing value.
+const NULL_MARKERS: &'static [&'static str] =
+    &["", "-", "?", "na", "n/a", "nil", "none", "null"];
+
+/// Guess the type of each column of the named table from its contents, e.g.
+/// to see which columns of untyped legacy data hold numbers.
+pub fn infer_schema(engine: &Storage, name: &str)
+        -> Result<Vec<ColumnSchema>> {
+    let table = match engine.get_relation(name) {
+        Some(Extension(table)) => table,
+        Some(Intension(_)) => return Err(Error::NotExtensional(name.into())),
+        None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", name)))
+    };
+    Ok((0..table.arity()).map(|i| {
+        infer_column(&table.column(i).collect::<Vec<_>>())
+    }).collect())
+}
+
+fn infer_column(values: &[&Value]) -> ColumnSchema {
+    let prefix = common_prefix(values);
+    let skip = prefix.as_ref().map_or(0, String::len);
+
+    let mut nulls = 0;
+    let mut kinds = BTreeSet::new();
+    let mut as_text = false;
+    for value in values {
+        let kind = match value {
+            Value::Int(_) => "integer",
+            Value::Float(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Symbol(s) | Value::String(s) => {
+                let rest = &s[skip..];
+                if NULL_MARKERS.contains(&rest.to_lowercase().as_str()) {
+                    nulls += 1;
+                    continue;
+                }
+                match (text_kind(rest), value) {
+                    (Some(kind), _) => {
+                        as_text = true;
+                        kind
+                    },
+                    (None, Value::Symbol(_)) => "symbol",
+                    (None, _) => "string"
+                }
+            }
+        };
+        kinds.insert(kind);
+    }
+
+    let kind = match kinds.len() {
+        0 => "unknown",
+        1 => kinds.iter().next().unwrap(),
+        2 if kinds.contains("integer") && kinds.contains("number") => "number",
+        _ => "mixed"
+    };
+    let distinct: HashSet<&&Value> = values.iter().collect();
+    ColumnSchema {
+        kind: if as_text && kind != "mixed" {
+            format!("{} (as text)", kind)
+        } else {
+            kind.to_string()
+        },
+        distinct: distinct.len(),
+        prefix,
+        nulls
+    }
+}
 
-    let scan = if let Some(cached) = cache.read_cache(&head) {
-        Box::new(VecPlan::new(cached))
+// The type of the value written in some text, if it isn't just text.
+fn text_kind(text: &str) -> Option<&'static str> {
+    if text == "true" || text == "false" {
+        Some("bool")
+    } else if text.parse::<i64>().is_ok() {
+        Some("integer")
+    } else if text.parse::<f64>().is_ok()
+            && text.chars().any(|c| c.is_ascii_digit()) {
+        Some("number")
     } else {
-        let relation =
-            engine.get_relation(head.as_str())
-                  .ok_or(Error::MalformedLine(
-                          format!("No relation \"{}\" found.", head.as_str())))?;
-        match relation {
-            Extension(ref table) => Box::new(ExtensionalScan::new(table)),
-            Intension(view) => IntensionalScan::from_view(&head,
-                                                          engine,
-                                                          cache,
-                                                          view,
-                                                          true)?
+        None
+    }
+}
+
+// The longest prefix of every value, which must all be symbols or strings,
+// that ends in a character other than a letter or digit.
+fn common_prefix(values: &[&Value]) -> Option<String> {
+    let mut prefix: Option<&str> = None;
+    for value in values {
+        let text = match value {
+            Value::Symbol(s) | Value::String(s) => s.as_str(),
+            _ => return None
+        };
+        prefix = Some(match prefix {
+            None => text,
+            Some(p) => {
+                let len = p.char_indices()
+                           .zip(text.chars())
+                           .take_while(|((_, a), b)| a == b)
+                           .last()
+                           .map_or(0, |((i, a), _)| i + a.len_utf8());
+                &p[..len]
+            }
+        });
+    }
+
+    let prefix = prefix?;
+    let end = prefix.char_indices()
+                    .filter(|(_, c)| !c.is_alphanumeric())
+                    .last()
+                    .map(|(i, c)| i + c.len_utf8())?;
+    Some(prefix[..end].to_string())
+}
+
+/// Store the results of a query as a new table named `name`, with one column
+/// per variable in the query, in order of first appearance.
+///
+/// Returns the number of facts in the new table.
+pub fn create_table(engine: &mut Storage,
+                    cache: &mut ViewCache,
+                    name: &str,
+                    query: ast::Term) -> Result<usize> {
+    let valid_name = name.chars().next().map_or(false, char::is_lowercase)
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err(Error::MalformedLine(
+                format!("not a relation name: {}", name)));
+    }
+    check_not_builtin(name)?;
+    if engine.get_relation(name).is_some() {
+        return Err(Error::MalformedLine(
+                format!("relation \"{}\" already exists", name)));
+    }
+
+    let columns = query.variables();
+    let mut table = storage::Table::new(columns.len());
+    for frame in self::query(engine, cache, query)? {
+        table.assert(columns.iter().map(|c| frame[c].clone()).collect())?;
+    }
+
+    let len = table.len();
+    engine.get_or_create_relation(name.to_string(), Extension(table))?;
+    cache.invalidate(name);
+    Ok(len)
+}
+
+/// Copy the named relation from another database into this one.
+///
+/// Facts are added to an existing table of the same arity, and rules to an
+/// existing view; facts and rules that are already present are skipped.
+/// Returns the number of facts or rules copied.
+pub fn copy_relation(engine: &mut Storage,
+                     cache: &mut ViewCache,
+                     source: &Storage,
+                     name: &str) -> Result<usize> {
+    check_not_builtin(name)?;
+    let relation = source.get_relation(name).ok_or_else(|| {
+        Error::MalformedLine(format!("No relation \"{}\" found.", name))
+    })?;
+
+    let mut copied = 0;
+    match (relation, engine.get_relation(name)) {
+        (Extension(_), Some(Intension(_))) =>
+            return Err(Error::NotExtensional(name.to_string())),
+        (Intension(_), Some(Extension(_))) =>
+            return Err(Error::NotIntensional(name.to_string())),
+        (Extension(theirs), ours) => {
+            let mut known: HashSet<Vec<Value>> = HashSet::new();
+            if let Some(Extension(ours)) = ours {
+                if ours.arity() != theirs.arity() {
+                    return Err(Error::RelationArity {
+                        relation: name.to_string(),
+                        arity: ours.arity(),
+                        got: theirs.arity()
+                    });
+                }
+                known.extend(ours.into_iter().map(|t| {
+                    t.into_iter().cloned().collect()
+                }));
+            }
+
+            let mut facts = Vec::new();
+            for tuple in theirs {
+                let fact: Vec<Value> = tuple.into_iter().cloned().collect();
+                if known.insert(fact.clone()) {
+                    facts.push(fact);
+                }
+            }
+            if ours.is_none() {
+                let table = storage::Table::new(theirs.arity());
+                engine.get_or_create_relation(name.to_string(),
+                                              Extension(table))?;
+            }
+            for fact in facts {
+                engine.assert_fact(name.to_string(), fact)?;
+                copied += 1;
+            }
+            cache.invalidate(name);
+        },
+        (Intension(theirs), ours) => {
+            let known = match ours {
+                Some(Intension(ours)) => ours.to_rules(name),
+                _ => Vec::new()
+            };
+            for rule in theirs.to_rules(name) {
+                if !known.contains(&rule) {
+                    add_rule_to_view(engine, cache, rule)?;
+                    copied += 1;
+                }
+            }
         }
+    }
+    Ok(copied)
+}
+
+/// Limit the named table to its newest `max_len` facts, or with `None`, remove
+/// its limit.
+///
+/// Older facts are removed by `StorageEngine::expire`, which the writer thread
+/// calls periodically.
+pub fn set_retention(engine: &mut Storage,
+                     name: &str,
+                     max_len: Option<usize>) -> Result<()> {
+    let mut relation = engine.get_relation_mut(name).ok_or_else(|| {
+        Error::MalformedLine(format!("No relation \"{}\" found.", name))
+    })?;
+    match *relation {
+        Extension(ref mut table) => Ok(table.set_max_len(max_len)),
+        Intension(_) => Err(Error::NotExtensional(name.to_string()))
+    }
+}
+
+/// Index a column (numbered from 0) of a table, or with `on` false, remove its
+/// index; see `Table::add_index`. Returns whether that changed anything.
+pub fn set_index(engine: &mut Storage,
+                 name: &str,
+                 column: usize,
+                 on: bool) -> Result<bool> {
+    let mut relation = engine.get_relation_mut(name).ok_or_else(|| {
+        Error::MalformedLine(format!("No relation \"{}\" found.", name))
+    })?;
+    let table = match *relation {
+        Extension(ref mut table) => table,
+        Intension(_) => return Err(Error::NotExtensional(name.to_string()))
+    };
+    if column >= table.arity() {
+        return Err(Error::MalformedLine(
+                format!("{} has no column number {}", name, column + 1)));
+    }
+    Ok(if on { table.add_index(column) } else { table.drop_index(column) })
+}
+
+/// Change the arity of a table, dropping the columns past `arity`, or adding
+/// columns holding `fill` in every fact. Views using the table must then be
+/// redefined to match; views themselves change arity with `redefine`.
+pub fn alter(engine: &mut Storage,
+             cache: &mut ViewCache,
+             name: &str,
+             arity: usize,
+             fill: Option<Value>) -> Result<()> {
+    check_not_builtin(name)?;
+    check_not_scratch(engine, name)?;
+    let current = match engine.get_relation(name) {
+        Some(Extension(table)) => table.arity(),
+        Some(Intension(_)) =>
+            return Err(Error::NotExtensional(name.to_string())),
+        None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", name)))
     };
+    if arity == 0 {
+        return Err(Error::MalformedLine(
+                "a table needs at least one column".to_string()));
+    }
+    if arity > current && fill.is_none() {
+        return Err(Error::MalformedLine(
+                format!("a value is needed to fill the new columns of {}",
+                        name)));
+    }
+
+    // Columns used by references can't be dropped.
+    for other in engine.get_relations() {
+        if let Some(Extension(table)) = engine.get_relation(other) {
+            for reference in table.references() {
+                let used = if other == name {
+                    &reference.columns
+                } else if reference.relation == name {
+                    &reference.referenced
+                } else {
+                    continue
+                };
+                if let Some(column) = used.iter().find(|c| **c >= arity) {
+                    return Err(Error::MalformedLine(format!(
+                        "column {} of {} is used by a reference from {}",
+                        column + 1, name, other)));
+                }
+            }
+        }
+    }
+
+    // Nor can tables matched or asserted into by triggers change.
+    for other in engine.get_relations() {
+        if let Some(Extension(table)) = engine.get_relation(other) {
+            let used = table.triggers().iter().any(|trigger| {
+                other == name
+                    || trigger.actions.iter().any(|a| a.relation == name)
+            });
+            if used && arity != current {
+                return Err(Error::MalformedLine(format!(
+                    "{} is used by a trigger on {}", name, other)));
+            }
+        }
+    }
+
+    if let Some(mut relation) = engine.get_relation_mut(name) {
+        if let Extension(ref mut table) = *relation {
+            table.truncate_columns(arity);
+            for _ in current..arity {
+                table.add_column(fill.clone().unwrap());
+            }
+        }
+    }
+    cache.invalidate(name);
+    Ok(())
+}
 
+/// Add many facts at once, e.g. when loading a file of them.
+///
+/// Every fact is checked before any are added, and the cache is invalidated
+/// once per relation rather than once per fact. Returns the number of facts
+/// added.
+pub fn bulk_assert(engine: &mut Storage,
+                   cache: &mut ViewCache,
+                   facts: Vec<ast::Term>) -> Result<usize> {
+    let mut relations: BTreeMap<String, Vec<Vec<Value>>> = BTreeMap::new();
+    let count = facts.len();
+    for fact in facts {
+        let (name, params) = deconstruct_term(fact)?;
+        let tuple = to_atoms(params)?;
+        relations.entry(name).or_insert_with(Vec::new).push(tuple);
+    }
+
+    for (name, tuples) in &relations {
+        check_not_builtin(name)?;
+        check_not_scratch(engine, name)?;
+        let expected = match engine.get_relation(name) {
+            Some(Extension(table)) => table.arity(),
+            Some(Intension(_)) =>
+                return Err(Error::NotExtensional(name.clone())),
+            None => tuples[0].len()
+        };
+        if let Some(t) = tuples.iter().find(|t| t.len() != expected) {
+            return Err(Error::RelationArity {
+                relation: name.clone(),
+                arity: expected,
+                got: t.len()
+            });
+        }
+
+        // Facts may refer to others loaded alongside them.
+        if let Some(Extension(table)) = engine.get_relation(name) {
+            for reference in table.references() {
+                let mut keys = referenced_keys(engine, cache, reference)?;
+                for tuple in relations.get(&reference.relation)
+                                      .into_iter().flatten() {
+                    let tuple: Vec<&Value> = tuple.iter().collect();
+                    keys.insert(reference_key(&reference.referenced, &tuple));
+                }
+                for tuple in tuples {
+                    let tuple: Vec<&Value> = tuple.iter().collect();
+                    let key = reference_key(&reference.columns, &tuple);
+                    if !keys.contains(&key) {
+                        return Err(Error::MalformedLine(broken_reference(
+                            engine, name, reference, &tuple)));
+                    }
+                }
+            }
+        }
+    }
 
-    Ok(Box::new(PatternMatch::new(Pattern::new(rest), scan)))
+    for (name, tuples) in relations {
+        let arity = tuples[0].len();
+        engine.assert_facts(name.clone(), arity, tuples)?;
+        cache.invalidate(&name);
+    }
+    Ok(count)
 }
 
 //
-// Modifying the database.
+// References between relations.
 //
 
-/// Add a simple fact (one with no variables) to the database.
-fn simple_assert(engine: &mut Storage,
-                 cache: &mut ViewCache,
-                 fact: ast::Term) -> Result<()> {
-    let (head, rest) = deconstruct_term(fact)?;
-    let tuple = to_atoms(rest)?;
-    let arity = tuple.len();
-    let relation = storage::Relation::Extension(storage::Table::new(arity));
+/// Declare that every fact of a table must agree with some tuple of another
+/// relation on the given columns; see `storage::Reference`. `from` and `to`
+/// list the columns, numbered from 1, e.g. `works_in(2)` and `department(1)`.
+///
+/// Fails if any of the table's facts already break the reference.
+pub fn add_reference(engine: &mut Storage,
+                     cache: &ViewCache,
+                     from: ast::Term,
+                     to: ast::Term) -> Result<()> {
+    let (name, columns) = deconstruct_term(from)?;
+    check_not_scratch(engine, &name)?;
+    let arity = match engine.get_relation(&name) {
+        Some(Extension(table)) => table.arity(),
+        Some(Intension(_)) => return Err(Error::NotExtensional(name)),
+        None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", name)))
+    };
+    let (relation, referenced) = deconstruct_term(to)?;
+    let target_arity = relation_arity(engine, &relation)?;
+    let reference = storage::Reference {
+        columns: to_columns(&name, columns, arity)?,
+        referenced: to_columns(&relation, referenced, target_arity)?,
+        relation
+    };
+    if reference.columns.len() != reference.referenced.len() {
+        return Err(Error::MalformedLine(
+                "a reference needs as many columns on each side".to_string()));
+    }
 
-    match *engine.get_or_create_relation(head.clone(), relation) {
-        Extension(ref mut t) => t.assert(tuple),
-        Intension(_) => Err(Error::NotExtensional(head.clone()))
-    }?;
+    if let Some(broken) = broken_references(engine, cache, &name, &reference)?
+                              .into_iter().next() {
+        return Err(Error::MalformedLine(broken));
+    }
+    if let Some(mut relation) = engine.get_relation_mut(&name) {
+        if let Extension(ref mut table) = *relation {
+            table.add_reference(reference);
+        }
+    }
+    Ok(())
+}
 
-    Ok(cache.invalidate(&head))
+/// Check every fact of every table against the table's references, describing
+/// each fact that breaks one.
+pub fn check_references(engine: &Storage, cache: &ViewCache)
+        -> Result<Vec<String>> {
+    let mut names = engine.get_relations();
+    names.sort();
+    let mut broken = Vec::new();
+    for name in names {
+        if let Some(Extension(table)) = engine.get_relation(name) {
+            for reference in table.references() {
+                broken.extend(broken_references(engine, cache, name,
+                                                reference)?);
+            }
+        }
+    }
+    Ok(broken)
 }
 
-fn add_rule_to_view(engine: &mut Storage,
-                    cache: &mut ViewCache,
-                    rule: ast::Rule) -> Result<()> {
-    let (name, definition) = deconstruct_term(rule.head)?;
-    let params = to_variables(definition)?;
-    let relation = storage::Relation::Intension(AstView::new());
-    let mut rel_view = engine.get_or_create_relation(name.clone(), relation);
+// The facts of the named table breaking a reference from it.
+fn broken_references(engine: &Storage,
+                     cache: &ViewCache,
+                     name: &str,
+                     reference: &storage::Reference) -> Result<Vec<String>> {
+    let table = match engine.get_relation(name) {
+        Some(Extension(table)) => table,
+        _ => return Ok(Vec::new())
+    };
+    let keys = referenced_keys(engine, cache, reference)?;
+    Ok(table.select(&[]).filter(|fact| {
+        !keys.contains(&reference_key(&reference.columns, fact))
+    }).map(|fact| broken_reference(engine, name, reference, &fact)).collect())
+}
 
-    cache.invalidate(&name);
+// The values in the referenced columns of every tuple of the relation a
+// reference refers to.
+fn referenced_keys(engine: &Storage,
+                   cache: &ViewCache,
+                   reference: &storage::Reference)
+        -> Result<HashSet<Vec<Value>>> {
+    let params: Vec<ast::AtomicTerm> =
+        (0..relation_arity(engine, &reference.relation)?).map(|i| {
+            ast::AtomicTerm::Variable(format!("_{}", i))
+        }).collect();
+    let scan = scan_relation(engine, cache, &reference.relation, &params,
+                             false)?;
+    Ok(scan.map(|tuple| reference_key(&reference.referenced, &tuple)).collect())
+}
 
-    for term in &rule.body {
-        if let ast::Term::Compound(cterm) = term {
-            cache.add_dependency(cterm.relation.clone(), name.clone());
+fn reference_key(columns: &[usize], tuple: &[&Value]) -> Vec<Value> {
+    columns.iter().map(|c| tuple[*c].clone()).collect()
+}
+
+// Describe a fact of the named table breaking a reference, e.g.
+// `works_in(bob, sales) refers to a missing department(sales, _)`.
+fn broken_reference(engine: &Storage,
+                    name: &str,
+                    reference: &storage::Reference,
+                    fact: &[&Value]) -> String {
+    let arity = relation_arity(engine, &reference.relation).unwrap_or(0);
+    let mut target = vec!["_".to_string(); arity];
+    for (column, referenced) in reference.columns.iter()
+                                                 .zip(&reference.referenced) {
+        if *referenced < arity {
+            target[*referenced] = fact[*column].to_string();
         }
     }
+    let fact: Vec<String> = fact.iter().map(|v| v.to_string()).collect();
+    format!("{}({}) refers to a missing {}({})", name, fact.join(", "),
+            reference.relation, target.join(", "))
+}
 
-    match *rel_view {
-        Extension(_) => Err(Error::NotIntensional(name.clone())),
-        Intension(ref mut view) => Ok(view.add_rule(params, rule.body))
+/// Check that a query could be answered, i.e. that its relation exists and
+/// it gives that relation the right number of parameters, without evaluating
+/// anything.
+pub fn check_query(engine: &Storage, query: &ast::Term) -> Result<()> {
+    let (name, params) = deconstruct_term(query.clone())?;
+    if is_builtin(&name) {
+        return Ok(());
+    }
+    let arity = match engine.virtual_table(&name) {
+        Some(table) => table.arity(),
+        None => relation_arity(engine, &name)?
+    };
+    if arity != params.len() {
+        return Err(Error::RelationArity {
+            relation: name,
+            arity,
+            got: params.len()
+        });
+    }
+    Ok(())
+}
+
+fn relation_arity(engine: &Storage, name: &str) -> Result<usize> {
+    check_not_builtin(name)?;
+    match engine.get_relation(name) {
+        Some(Extension(table)) => Ok(table.arity()),
+        Some(Intension(view)) => view.arity().ok_or_else(|| {
+            Error::MalformedLine(format!("{} has no rules", name))
+        }),
+        None => Err(Error::MalformedLine(
+                format!("No relation \"{}\" found.", name)))
     }
 }
 
+// Convert the columns listed in a reference, numbered from 1, to indices.
+fn to_columns(name: &str, params: Vec<ast::AtomicTerm>, arity: usize)
+        -> Result<Vec<usize>> {
+    if params.is_empty() {
+        return Err(Error::MalformedLine(
+                format!("a reference needs columns of {}", name)));
+    }
+    params.into_iter().map(|param| match param {
+        ast::AtomicTerm::Atom(Value::Int(i)) if i >= 1 && i as usize <= arity
+            => Ok(i as usize - 1),
+        other => Err(Error::MalformedLine(
+                format!("{} has no column {}", name, other)))
+    }).collect()
+}
+
+/// Is the given fact already in the database?
+pub fn is_known(engine: &Storage,
+                cache: &ViewCache,
+                fact: &ast::Term) -> Result<bool> {
+    let (name, _) = deconstruct_term(fact.clone())?;
+    if engine.get_relation(&name).is_none() && !is_builtin(&name)
+            && engine.virtual_table(&name).is_none() {
+        return Ok(false);
+    }
+
+    Ok(query(engine, cache, fact.clone())?.next().is_some())
+}
+
 /// Add a fact or rule to the database.
 pub fn assert(engine: &mut Storage,
               cache: &mut ViewCache,
@@ -810,12 +5188,7 @@ pub fn initialize_view_cache(storage: &Storage, cache: &mut ViewCache) {
     for relation in storage.get_relations() {
         if let Some(Intension(view)) = storage.get_relation(relation) {
             for (_, body) in &view.rules {
-                for term in body {
-                    if let ast::Term::Compound(cterm) = term {
-                        cache.add_dependency(cterm.relation.clone(),
-                                             relation.to_string());
-                    }
-                }
+                add_dependencies(cache, relation, body);
             }
         }
     }
@@ -826,7 +5199,7 @@ pub fn initialize_view_cache(storage: &Storage, cache: &mut ViewCache) {
 //
 
 /// Attempt to convert an AtomicTerm to an atom.
-fn to_atom(t: ast::AtomicTerm) -> Result<String> {
+fn to_atom(t: ast::AtomicTerm) -> Result<Value> {
     match t {
         ast::AtomicTerm::Atom(a) => Ok(a),
         ast::AtomicTerm::Variable(v) =>
@@ -834,6 +5207,15 @@ fn to_atom(t: ast::AtomicTerm) -> Result<String> {
     }
 }
 
+/// Attempt to convert an AtomicTerm to the name of a relation.
+fn to_relation_name(t: ast::AtomicTerm) -> Result<String> {
+    match to_atom(t)? {
+        Value::Symbol(s) => Ok(s),
+        other => Err(Error::MalformedLine(
+                format!("not a relation name: {}", other)))
+    }
+}
+
 /// Attempt to convert an AtomicTerm to a variable.
 fn to_variable(t: ast::AtomicTerm) -> Result<String> {
     match t {
@@ -844,7 +5226,7 @@ fn to_variable(t: ast::AtomicTerm) -> Result<String> {
 }
 
 /// Convert a vector of AtomicTerms to atoms, failing if any are variables.
-fn to_atoms(v: Vec<ast::AtomicTerm>) -> Result<Vec<String>> {
+fn to_atoms(v: Vec<ast::AtomicTerm>) -> Result<Vec<Value>> {
     v.into_iter().map(to_atom).collect()
 }
 
@@ -858,15 +5240,31 @@ fn to_variables(v: Vec<ast::AtomicTerm>) -> Result<Vec<String>> {
 /// Fails if the term is not compound.
 fn deconstruct_term(t: ast::Term) -> Result<(String, Vec<ast::AtomicTerm>)> {
     match t {
-        ast::Term::Atomic(a) => Ok((to_atom(a)?, Vec::new())),
-        ast::Term::Compound(cterm) => Ok((cterm.relation, cterm.params))
+        ast::Term::Atomic(a) => Ok((to_relation_name(a)?, Vec::new())),
+        ast::Term::Compound(cterm) => Ok((cterm.relation, cterm.params)),
+        ast::Term::Not(t) => Err(Error::MalformedLine(
+                format!("expected a relation, not a negation: not {}", t)))
+    }
+}
+
+// Split a rule body into its positive terms and the terms it negates.
+fn split_body(body: &[ast::Term]) -> (Vec<&ast::Term>, Vec<&ast::Term>) {
+    let mut positive = Vec::new();
+    let mut negated = Vec::new();
+    for term in body {
+        match term {
+            ast::Term::Not(t) => negated.push(&**t),
+            t => positive.push(t)
+        }
     }
+    (positive, negated)
 }
 
-fn is_recursive(name: &str, rule: Vec<ast::Term>) -> Result<bool> {
-    for term in rule {
-        let (relation_name, _) = deconstruct_term(term)?;
-        if relation_name == name {
+// Does a rule use any of the given views, other than by negating them?
+fn refers_to(rule: &[ast::Term], views: &[String]) -> Result<bool> {
+    for term in split_body(rule).0 {
+        let (relation_name, _) = deconstruct_term(term.clone())?;
+        if views.contains(&relation_name) {
             return Ok(true);
         }
     }
@@ -0,0 +1,75 @@
+/// Writing query results to external file formats.
+///
+/// Parquet support pulls in a large dependency, so it is only compiled with
+/// the `parquet` feature enabled (`cargo build --features parquet`).
+
+use error::*;
+use eval::Frames;
+
+#[cfg(feature = "parquet")]
+use parquet::data_type::{ByteArray, ByteArrayType};
+#[cfg(feature = "parquet")]
+use parquet::file::properties::WriterProperties;
+#[cfg(feature = "parquet")]
+use parquet::file::writer::SerializedFileWriter;
+#[cfg(feature = "parquet")]
+use parquet::schema::parser::parse_message_type;
+
+#[cfg(feature = "parquet")]
+use std::fs;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+
+#[cfg(feature = "parquet")]
+fn err<E: ::std::fmt::Display>(e: E) -> Error {
+    Error::Export(e.to_string())
+}
+
+/// Write the given frames to a Parquet file at `path`, with one UTF-8 column
+/// per entry in `columns`.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(path: &str, columns: &[String], frames: Frames)
+        -> Result<()> {
+    let mut data: Vec<Vec<ByteArray>> = columns.iter().map(|_| Vec::new())
+                                                     .collect();
+    for frame in frames {
+        for (i, column) in columns.iter().enumerate() {
+            let value = frame.get(column.as_str())
+                             .map_or(String::new(), |v| v.to_string());
+            data[i].push(ByteArray::from(value.as_str()));
+        }
+    }
+
+    let fields: Vec<String> = columns.iter().map(|c| {
+        format!("REQUIRED BYTE_ARRAY {} (UTF8);", c)
+    }).collect();
+    let message = format!("message query {{ {} }}", fields.join(" "));
+    let schema = Arc::new(parse_message_type(&message).map_err(err)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+
+    let file = fs::File::create(path).map_err(err)?;
+    let mut writer = SerializedFileWriter::new(file, schema, properties)
+        .map_err(err)?;
+    {
+        let mut row_group = writer.next_row_group().map_err(err)?;
+        let mut columns = data.into_iter();
+        while let Some(mut column) = row_group.next_column().map_err(err)? {
+            let values = columns.next().unwrap_or_default();
+            column.typed::<ByteArrayType>()
+                  .write_batch(&values, None, None)
+                  .map_err(err)?;
+            column.close().map_err(err)?;
+        }
+        row_group.close().map_err(err)?;
+    }
+    writer.close().map_err(err)?;
+    Ok(())
+}
+
+/// Without the `parquet` feature, exporting to Parquet always fails.
+#[cfg(not(feature = "parquet"))]
+pub fn write_parquet(_path: &str, _columns: &[String], _frames: Frames)
+        -> Result<()> {
+    Err(Error::Export(
+        "data-goblin was built without the `parquet` feature".to_string()))
+}
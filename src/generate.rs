@@ -0,0 +1,140 @@
+/// Synthesized databases for benchmarking recursive queries at scale.
+///
+/// Each database is a graph: a table `edge(From, To)` and a view
+/// `path(From, To)` of the pairs of nodes connected by some chain of edges.
+/// The same shape, size and seed always give the same graph.
+
+use ast;
+use cache::ViewCache;
+use error::*;
+use eval;
+use lexer::Lexer;
+use parser::Parser;
+use storage::StorageEngine;
+use value::Value;
+
+/// The rules written with every generated graph.
+pub const PATH_RULES: &'static str =
+    "path(X, Y) :- edge(X, Y).\n\
+     path(X, Z) :- edge(X, Y), path(Y, Z).\n";
+
+/// The kinds of graph that can be generated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    /// `size` nodes, each but the first with one or two edges to random nodes
+    /// before it, like a hierarchy where some have two managers.
+    Dag,
+    /// A `size` by `size` grid, with edges right and down.
+    Grid,
+    /// `size` nodes with an edge between each ordered pair of distinct nodes
+    /// with probability 1/4, so usually full of cycles.
+    Dense
+}
+
+impl Shape {
+    /// The shape with the given name: `dag`, `grid` or `dense`.
+    pub fn parse(name: &str) -> Result<Shape> {
+        match name {
+            "dag" => Ok(Shape::Dag),
+            "grid" => Ok(Shape::Grid),
+            "dense" => Ok(Shape::Dense),
+            other => Err(Error::Command(format!(
+                "unknown shape {:?}; expected dag, grid or dense", other)))
+        }
+    }
+}
+
+// A small, fast pseudorandom number generator (splitmix64), so that graphs
+// depend only on their seed.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number in `0..n`, for `n > 0`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// The edges of a graph of the given shape and size, as pairs of node names.
+pub fn edges(shape: Shape, size: usize, seed: u64) -> Vec<(String, String)> {
+    let mut random = Random(seed);
+    let node = |i: usize| format!("n{}", i);
+    let mut result = Vec::new();
+    match shape {
+        Shape::Dag => for i in 1..size {
+            let first = random.below(i);
+            result.push((node(i), node(first)));
+            if i > 1 && random.below(2) == 0 {
+                let second = random.below(i);
+                if second != first {
+                    result.push((node(i), node(second)));
+                }
+            }
+        },
+        Shape::Grid => {
+            let cell = |row: usize, column: usize| {
+                format!("n{}_{}", row, column)
+            };
+            for row in 0..size {
+                for column in 0..size {
+                    if column + 1 < size {
+                        result.push((cell(row, column),
+                                     cell(row, column + 1)));
+                    }
+                    if row + 1 < size {
+                        result.push((cell(row, column),
+                                     cell(row + 1, column)));
+                    }
+                }
+            }
+        },
+        Shape::Dense => for i in 0..size {
+            for j in (0..size).filter(|j| *j != i) {
+                if random.below(4) == 0 {
+                    result.push((node(i), node(j)));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Write a graph of the given shape and size into the database in
+/// `data_dir`, returning the number of edges.
+///
+/// Fails without changing anything if the database already has an `edge` or
+/// `path` relation.
+pub fn generate(data_dir: String, shape: Shape, size: usize, seed: u64)
+        -> Result<usize> {
+    let mut engine = StorageEngine::new(data_dir)?;
+    for name in &["edge", "path"] {
+        if engine.get_relation(name).is_some() {
+            return Err(Error::Command(format!(
+                "the database already has a relation named {}", name)));
+        }
+    }
+
+    let facts: Vec<Vec<Value>> = edges(shape, size, seed).into_iter()
+        .map(|(from, to)| vec!(Value::from(from), Value::from(to)))
+        .collect();
+    let count = facts.len();
+    engine.assert_facts("edge".to_string(), 2, facts)?;
+
+    let mut cache = ViewCache::new();
+    let lexer = Lexer::new(PATH_RULES.chars()).map(Result::unwrap);
+    for line in Parser::new(lexer) {
+        if let ast::Line::Rule(rule) = line? {
+            eval::assert(&mut engine, &mut cache, rule)?;
+        }
+    }
+    engine.write_back();
+    Ok(count)
+}
@@ -0,0 +1,300 @@
+/// Path queries answered by graph algorithms over a table of edges, rather than
+/// by evaluating a recursive view to a fixpoint: the built-in relations
+///
+/// - `reachable(Src, Dst, Via)`, holding when a path of one or more edges
+///   leads from `Src` to `Dst`, with `Via` the node before `Dst` on a shortest
+///   such path, found by breadth-first search; and
+/// - `shortest_path_len(Src, Dst, N)`, holding when the shortest such path
+///   has length `N`, found by breadth-first search or, if edges have lengths
+///   of their own, Dijkstra's algorithm.
+///
+/// Both read the table of edges designated with `designate`, `edge` unless
+/// another is; the designation is per thread, like the join thresholds. Its
+/// first column is the node each edge leaves and its second the node the edge
+/// reaches. A third column, if any, is the length of each edge, which must be
+/// a non-negative number; otherwise every edge has length 1.
+
+use error::*;
+use storage::Table;
+use value::Value;
+
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+/// The table of edges read unless another is designated.
+pub const DEFAULT_EDGES: &'static str = "edge";
+
+/// Is the named relation one of the built-ins answered by this module?
+pub fn is_path_builtin(name: &str) -> bool {
+    name == "reachable" || name == "shortest_path_len"
+}
+
+thread_local! {
+    static EDGES: RefCell<String> = RefCell::new(DEFAULT_EDGES.to_string());
+}
+
+/// The name of the table of edges path built-ins on this thread read.
+pub fn edges() -> String {
+    EDGES.with(|e| e.borrow().clone())
+}
+
+/// Make path built-ins on this thread read the named table of edges.
+pub fn designate(relation: &str) {
+    EDGES.with(|e| *e.borrow_mut() = relation.to_string());
+}
+
+/// The nodes and edges of a table of edges, with each node numbered in order
+/// of first appearance.
+pub struct Graph<'t> {
+    nodes: Vec<&'t Value>,
+    numbers: HashMap<&'t Value, usize>,
+    // The edges leaving each node: the node reached, and the edge's length.
+    edges: Vec<Vec<(usize, Length)>>,
+    weighted: bool
+}
+
+// The length of an edge or a path. Lengths are whole numbers unless an edge
+// has a fractional length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Length {
+    Int(i64),
+    Float(f64)
+}
+
+impl Length {
+    fn add(self, other: Length) -> Length {
+        match (self, other) {
+            (Length::Int(a), Length::Int(b)) =>
+                Length::Int(a.saturating_add(b)),
+            (a, b) => Length::Float(a.as_f64() + b.as_f64())
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Length::Int(i) => i as f64,
+            Length::Float(x) => x
+        }
+    }
+
+    fn to_value(self) -> Value {
+        match self {
+            Length::Int(i) => Value::Int(i),
+            Length::Float(x) => Value::Float(x)
+        }
+    }
+}
+
+// Lengths are never NaN, so they can be ordered in Dijkstra's queue.
+#[derive(Clone, Copy, PartialEq)]
+struct Distance(Length);
+
+impl Eq for Distance {}
+
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Distance) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Distance {
+    fn cmp(&self, other: &Distance) -> Ordering {
+        self.0.as_f64().partial_cmp(&other.0.as_f64())
+            .expect("path length is NaN")
+    }
+}
+
+impl<'t> Graph<'t> {
+    /// Read the edges of a table with two columns, or three for weighted
+    /// edges. Fails if it has another number of columns, or an edge's length
+    /// isn't a non-negative number.
+    pub fn new(table: &'t Table) -> Result<Graph<'t>> {
+        let weighted = match table.arity() {
+            2 => false,
+            3 => true,
+            arity => return Err(Error::MalformedLine(format!(
+                "edges need two columns, or three with lengths, not {}",
+                arity)))
+        };
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            numbers: HashMap::new(),
+            edges: Vec::new(),
+            weighted
+        };
+        for edge in table.select(&[]) {
+            let length = match edge.get(2) {
+                None => Length::Int(1),
+                Some(Value::Int(i)) if *i >= 0 => Length::Int(*i),
+                Some(Value::Float(x)) if *x >= 0.0 => Length::Float(*x),
+                Some(other) => return Err(Error::MalformedLine(format!(
+                    "the edge from {} to {} has length {}, which is not a \
+                     non-negative number", edge[0], edge[1], other)))
+            };
+            let from = graph.number(edge[0]);
+            let to = graph.number(edge[1]);
+            graph.edges[from].push((to, length));
+        }
+        Ok(graph)
+    }
+
+    fn number(&mut self, node: &'t Value) -> usize {
+        if let Some(n) = self.numbers.get(node) {
+            return *n;
+        }
+        let n = self.nodes.len();
+        self.nodes.push(node);
+        self.numbers.insert(node, n);
+        self.edges.push(Vec::new());
+        n
+    }
+
+    /// The nodes that some edge leaves, in order.
+    pub fn sources(&self) -> Vec<&'t Value> {
+        let mut sources: Vec<&'t Value> = self.nodes.iter().enumerate()
+            .filter(|(n, _)| !self.edges[*n].is_empty())
+            .map(|(_, node)| *node)
+            .collect();
+        sources.sort();
+        sources
+    }
+
+    /// The nodes reachable from `source` by one or more edges, each with the
+    /// node before it on a path with the fewest edges, nearest first.
+    pub fn reachable(&self, source: &Value) -> Vec<(&'t Value, &'t Value)> {
+        let start = match self.numbers.get(source) {
+            Some(start) => *start,
+            None => return Vec::new()
+        };
+        let mut via: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut reached = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(node) = queue.pop_front() {
+            for (next, _) in &self.edges[node] {
+                if via[*next].is_none() {
+                    via[*next] = Some(node);
+                    reached.push((self.nodes[*next], self.nodes[node]));
+                    queue.push_back(*next);
+                }
+            }
+        }
+        reached
+    }
+
+    /// The nodes reachable from `source` by one or more edges, each with the
+    /// length of a shortest path to it, nearest first.
+    pub fn shortest_paths(&self, source: &Value) -> Vec<(&'t Value, Value)> {
+        let start = match self.numbers.get(source) {
+            Some(start) => *start,
+            None => return Vec::new()
+        };
+        if !self.weighted {
+            let mut lengths: HashMap<usize, i64> = HashMap::new();
+            return self.reachable(source).into_iter().map(|(node, via)| {
+                let length = match self.numbers[via] {
+                    n if n == start => 1,
+                    n => lengths[&n] + 1
+                };
+                lengths.insert(self.numbers[node], length);
+                (node, Value::Int(length))
+            }).collect();
+        }
+
+        // Dijkstra's algorithm: settle nodes in order of distance, starting
+        // from the edges leaving the source, so that it is only reached again
+        // by a cycle.
+        let mut settled: Vec<bool> = vec![false; self.nodes.len()];
+        let mut paths = Vec::new();
+        let mut queue = BinaryHeap::new();
+        for (next, length) in &self.edges[start] {
+            queue.push(Reverse((Distance(*length), *next)));
+        }
+        while let Some(Reverse((Distance(distance), node))) = queue.pop() {
+            if settled[node] {
+                continue;
+            }
+            settled[node] = true;
+            paths.push((self.nodes[node], distance.to_value()));
+            for (next, length) in &self.edges[node] {
+                if !settled[*next] {
+                    queue.push(Reverse((Distance(distance.add(*length)),
+                                        *next)));
+                }
+            }
+        }
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::Graph;
+    use storage::Table;
+    use value::Value;
+
+    fn table(edges: &[(&str, &str)], lengths: Option<&[Value]>) -> Table {
+        let arity = if lengths.is_some() { 3 } else { 2 };
+        let mut table = Table::new(arity);
+        let facts = edges.iter().enumerate().map(|(i, (from, to))| {
+            let mut fact = vec!(Value::from(*from), Value::from(*to));
+            if let Some(lengths) = lengths {
+                fact.push(lengths[i].clone());
+            }
+            fact
+        }).collect();
+        table.assert_batch(facts).unwrap();
+        table
+    }
+
+    fn names<'a, I: IntoIterator<Item = (&'a Value, Value)>>(pairs: I)
+            -> Vec<(String, String)> {
+        pairs.into_iter().map(|(a, b)| (a.to_string(), b.to_string()))
+             .collect()
+    }
+
+    #[test]
+    fn breadth_first() {
+        let t = table(&[("a", "b"), ("b", "c"), ("a", "c"), ("c", "a"),
+                        ("d", "e")], None);
+        let graph = Graph::new(&t).unwrap();
+        let reached = graph.reachable(&Value::from("a")).into_iter()
+            .map(|(node, via)| (node, via.clone()));
+        assert_eq!(names(reached), vec!(("b".to_string(), "a".to_string()),
+                                        ("c".to_string(), "a".to_string()),
+                                        ("a".to_string(), "c".to_string())));
+        assert_eq!(names(graph.shortest_paths(&Value::from("b"))),
+                   vec!(("c".to_string(), "1".to_string()),
+                        ("a".to_string(), "2".to_string()),
+                        ("b".to_string(), "3".to_string())));
+        assert!(graph.reachable(&Value::from("e")).is_empty());
+        assert!(graph.reachable(&Value::from("z")).is_empty());
+        assert_eq!(graph.sources(), vec!(&Value::from("a"), &Value::from("b"),
+                                         &Value::from("c"), &Value::from("d")));
+    }
+
+    #[test]
+    fn dijkstra() {
+        let t = table(&[("a", "b"), ("b", "c"), ("a", "c")],
+                      Some(&[Value::Int(1), Value::Int(2), Value::Int(5)]));
+        let graph = Graph::new(&t).unwrap();
+        assert_eq!(names(graph.shortest_paths(&Value::from("a"))),
+                   vec!(("b".to_string(), "1".to_string()),
+                        ("c".to_string(), "3".to_string())));
+
+        let t = table(&[("a", "b"), ("b", "c"), ("a", "c")],
+                      Some(&[Value::Int(1), Value::Float(0.5),
+                             Value::Int(5)]));
+        let graph = Graph::new(&t).unwrap();
+        assert_eq!(names(graph.shortest_paths(&Value::from("a"))),
+                   vec!(("b".to_string(), "1".to_string()),
+                        ("c".to_string(), "1.5".to_string())));
+
+        for bad in &[Value::Int(-1), Value::from("far")] {
+            let t = table(&[("a", "b")], Some(&[bad.clone()]));
+            assert!(Graph::new(&t).is_err());
+        }
+        assert!(Graph::new(&Table::new(1)).is_err());
+    }
+}
@@ -3,7 +3,9 @@
 use error::*;
 use tok::Tok;
 
+use std::fmt;
 use std::iter::Iterator;
+use std::time::Duration;
 
 #[derive(Debug)]
 enum Buffer {
@@ -12,15 +14,50 @@ enum Buffer {
     Lexing(char)
 }
 
+/// A place in the input, counting lines and columns from 1.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
 /// Adapts an `Iterator` over `char`s to an iterator over `Tok`s.
 pub struct Lexer<I: Iterator<Item = char>> {
     current: Buffer,
-    chars: I
+    chars: I,
+    /// Have we seen only whitespace since the last newline?
+    line_start: bool,
+    /// A token already lexed, to be returned before any more input is read,
+    /// and where it began.
+    pending: Option<(Tok, Position)>,
+    /// Where the current character is.
+    position: Position,
+    /// Where the last token returned began.
+    start: Position
 }
 
 impl<I: Iterator<Item = char>> Lexer<I> {
     pub fn new(chars: I) -> Self {
-        Lexer { chars: chars, current: Buffer::Uninitialized }
+        Lexer {
+            chars: chars,
+            current: Buffer::Uninitialized,
+            line_start: true,
+            pending: None,
+            position: Position { line: 1, column: 1 },
+            start: Position { line: 1, column: 1 }
+        }
+    }
+
+    /// Where the last token returned began. Errors are reported with this
+    /// position, too.
+    pub fn position(&self) -> Position {
+        self.start
     }
 
     fn peek(&mut self) -> Option<char> {
@@ -32,6 +69,14 @@ impl<I: Iterator<Item = char>> Lexer<I> {
     }
 
     fn next_char(&mut self) -> Option<char> {
+        if let Buffer::Lexing(c) = self.current {
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.column = 1;
+            } else {
+                self.position.column += 1;
+            }
+        }
         self.chars.next().map(|c| {
             self.current = Buffer::Lexing(c);
             c
@@ -41,8 +86,103 @@ impl<I: Iterator<Item = char>> Lexer<I> {
         })
     }
 
+    // Skip whitespace, including newlines, and comments: everything from a "%"
+    // to the end of its line.
     fn skip_whitespace(&mut self) {
-        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+        while let Some(c) = self.peek() {
+            if c == '%' {
+                while self.next_char().map_or(false, |c| c != '\n') {}
+                continue;
+            }
+            if !c.is_whitespace() {
+                return;
+            }
+            if c == '\n' {
+                self.line_start = true;
+            }
+            self.next_char();
+        }
+    }
+
+    // Lex the rest of the current line as a meta-command. Assumes the leading
+    // "." has already been consumed.
+    fn lex_command(&mut self) -> String {
+        let mut result = String::new();
+
+        while let Some(c) = self.peek().filter(|c| *c != '\n') {
+            result.push(c);
+            self.next_char();
+        }
+
+        result.trim().to_string()
+    }
+
+    // Lex what follows a "?" on the same line: `select` and a list of
+    // variables, `using` and a list of hints, `within` and a length of time,
+    // "->" and a relation name, or nothing. Assumes the "?" has already been
+    // consumed.
+    //
+    // Only looking at the rest of the line means that a query is complete as
+    // soon as its line is, without waiting for more input.
+    fn lex_query_end(&mut self) -> Result<Tok> {
+        self.skip_spaces();
+        if self.peek() == Some('-') {
+            match self.next_char() {
+                Some('>') => self.next_char(),
+                Some(c) => return Err(Self::unexpected(c)),
+                None => return Err(Error::Lexer(
+                        "expected \"->\" after \"?\"".to_string()))
+            };
+            self.skip_spaces();
+            let name = self.lex_name();
+            if !name.chars().next().map_or(false, |c| c.is_lowercase()) {
+                return Err(Error::Lexer(
+                        "expected a relation name after \"?->\"".to_string()));
+            }
+            return Ok(Tok::Capture(name));
+        }
+        if !self.peek().map_or(false, |c| c.is_lowercase()) {
+            return Ok(Tok::Query);
+        }
+
+        let start = self.position;
+        let word = self.lex_ident();
+        if word == "using" {
+            let hints = self.lex_command().split(',').map(|h| {
+                h.trim().to_string()
+            }).collect();
+            return Ok(Tok::Using(hints));
+        }
+        if word == "within" {
+            let text = self.lex_command();
+            return parse_duration(&text).map(Tok::Within).ok_or_else(|| {
+                Error::Lexer(format!(
+                    "expected a time like 2s or 500ms after within: {:?}",
+                    text))
+            });
+        }
+        if word != "select" {
+            self.pending = Some((Tok::Atom(word), start));
+            return Ok(Tok::Query);
+        }
+        let variables: Vec<String> = self.lex_command().split(',').map(|v| {
+            v.trim().to_string()
+        }).collect();
+        for v in &variables {
+            let mut chars = v.chars();
+            let valid = chars.next().map_or(false, |c| c.is_uppercase())
+                     && chars.all(|c| c.is_alphanumeric() || c == '_');
+            if !valid {
+                return Err(Error::Lexer(
+                        format!("expected a variable after select: {:?}", v)));
+            }
+        }
+        Ok(Tok::Select(variables))
+    }
+
+    // Skip spaces and tabs, but not newlines.
+    fn skip_spaces(&mut self) {
+        while let Some(_) = self.peek().filter(|c| *c == ' ' || *c == '\t') {
             self.next_char();
         }
     }
@@ -61,6 +201,84 @@ impl<I: Iterator<Item = char>> Lexer<I> {
         }
     }
 
+    fn append_digits(&mut self, result: &mut String) {
+        while let Some(c) = self.peek().filter(|c| c.is_digit(10)) {
+            result.push(c);
+            self.next_char();
+        }
+    }
+
+    // Lex an integer or float literal. A "." after the integer part only
+    // belongs to the number if a digit follows it; otherwise it ends a line.
+    fn lex_number(&mut self, negative: bool) -> Result<Tok> {
+        let mut result = String::new();
+        if negative {
+            result.push('-');
+        }
+        self.append_digits(&mut result);
+
+        if self.peek() == Some('.') {
+            let dot = self.position;
+            match self.next_char() {
+                Some(c) if c.is_digit(10) => {
+                    result.push('.');
+                    self.append_digits(&mut result);
+                    return result.parse().map(Tok::Float).map_err(|e| {
+                        Error::Lexer(format!("bad float {}: {}", result, e))
+                    });
+                },
+                _ => self.pending = Some((Tok::Dot, dot))
+            }
+        }
+
+        result.parse().map(Tok::Int).map_err(|e| {
+            Error::Lexer(format!("bad integer {}: {}", result, e))
+        })
+    }
+
+    // Lex a string literal. Assumes the opening quote has been consumed.
+    fn lex_string(&mut self) -> Result<Tok> {
+        let mut result = String::new();
+
+        loop {
+            match self.next_char() {
+                Some('"') => {
+                    self.next_char();
+                    return Ok(Tok::Str(result));
+                },
+                Some('\\') => match self.next_char() {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some(c) if c == '"' || c == '\\' => result.push(c),
+                    Some(c) => return Err(Error::Lexer(
+                            format!("unknown escape sequence: \\{}", c))),
+                    None => break
+                },
+                Some(c) => result.push(c),
+                None => break
+            }
+        }
+
+        Err(Error::Lexer("unterminated string literal".to_string()))
+    }
+
+    // A query parameter like `$1`, to be replaced with a constant after
+    // parsing. Parameters are lexed as variables named e.g. "$1".
+    fn lex_param(&mut self) -> Result<Tok> {
+        let mut result = "$".to_string();
+        let mut c = self.next_char();
+        while let Some(d) = c.filter(|d| d.is_digit(10)) {
+            result.push(d);
+            c = self.next_char();
+        }
+
+        if result.len() == 1 {
+            Err(Error::Lexer("expected a number after \"$\"".to_string()))
+        } else {
+            Ok(Tok::Variable(result))
+        }
+    }
+
     fn lex_ident(&mut self) -> String {
         let mut result = String::new();
 
@@ -68,29 +286,43 @@ impl<I: Iterator<Item = char>> Lexer<I> {
         result
     }
 
-    fn unrecognized(c: char) -> Error {
-        Error::Lexer(format!("unrecognized character: {}", c))
-    }
+    // Lex an atom, which may be a relation name with namespaces, like
+    // `hr.employee`. A "." only belongs to the name if a lowercase letter
+    // follows it; otherwise it ends a line.
+    fn lex_name(&mut self) -> String {
+        let mut result = self.lex_ident();
 
-    fn unexpected(c: char) -> Error {
-        Error::Lexer(format!("unexpected character: {}", c))
+        while self.peek() == Some('.') {
+            let dot = self.position;
+            match self.next_char() {
+                Some(c) if c.is_lowercase() => {
+                    result.push('.');
+                    self.append_ident(&mut result);
+                },
+                _ => {
+                    self.pending = Some((Tok::Dot, dot));
+                    break;
+                }
+            }
+        }
+        result
     }
-}
 
-impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
-    type Item = Result<Tok>;
-
-    fn next(&mut self) -> Option<Result<Tok>> {
-        self.skip_whitespace();
-        let c = self.peek()?;
+    // Lex the token beginning with `c`, the current character. `line_start`
+    // is whether only whitespace comes before it on its line.
+    fn lex_token(&mut self, c: char, line_start: bool)
+            -> Option<Result<Tok>> {
         match c {
             ',' => {
                 self.next_char();
                 Some(Ok(Tok::Comma))
             },
             '.' => {
-                self.next_char();
-                Some(Ok(Tok::Dot))
+                match self.next_char() {
+                    Some(c) if line_start && c.is_alphabetic() =>
+                        Some(Ok(Tok::Command(self.lex_command()))),
+                    _ => Some(Ok(Tok::Dot))
+                }
             },
             ':' => {
                 let c = self.next_char()?;
@@ -104,7 +336,7 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
             },
             '?' => {
                 self.next_char();
-                Some(Ok(Tok::Query))
+                Some(self.lex_query_end())
             },
             '(' => {
                 self.next_char();
@@ -114,11 +346,126 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
                 self.next_char();
                 Some(Ok(Tok::CloseParen))
             },
-            c if c.is_lowercase() => Some(Ok(Tok::Atom(self.lex_ident()))),
+            '{' => {
+                self.next_char();
+                Some(Ok(Tok::OpenBrace))
+            },
+            '}' => {
+                self.next_char();
+                Some(Ok(Tok::CloseBrace))
+            },
+            '-' => match self.next_char() {
+                Some(c) if c.is_digit(10) => Some(self.lex_number(true)),
+                Some('>') => {
+                    self.next_char();
+                    Some(Ok(Tok::Arrow))
+                },
+                _ => Some(Ok(Tok::Minus))
+            },
+            '+' => {
+                self.next_char();
+                Some(Ok(Tok::Plus))
+            },
+            '*' => {
+                self.next_char();
+                Some(Ok(Tok::Times))
+            },
+            '/' => {
+                self.next_char();
+                Some(Ok(Tok::Divide))
+            },
+            '<' => {
+                self.next_char();
+                Some(Ok(Tok::Less))
+            },
+            '>' => match self.next_char() {
+                Some('=') => {
+                    self.next_char();
+                    Some(Ok(Tok::GreaterOrEqual))
+                },
+                _ => Some(Ok(Tok::Greater))
+            },
+            '=' => match self.next_char() {
+                Some('<') => {
+                    self.next_char();
+                    Some(Ok(Tok::LessOrEqual))
+                },
+                _ => Some(Ok(Tok::Equal))
+            },
+            '!' => match self.next_char() {
+                Some('=') => {
+                    self.next_char();
+                    Some(Ok(Tok::NotEqual))
+                },
+                Some(c) => Some(Err(Self::unexpected(c))),
+                None => Some(Err(Error::Lexer(
+                        "expected \"=\" after \"!\"".to_string())))
+            },
+            '"' => Some(self.lex_string()),
+            '$' => Some(self.lex_param()),
+            c if c.is_digit(10) => Some(self.lex_number(false)),
+            c if c.is_lowercase() => Some(Ok(Tok::Atom(self.lex_name()))),
             c if c.is_uppercase() => Some(Ok(Tok::Variable(self.lex_ident()))),
             c => Some(Err(Self::unrecognized(c)))
         }
     }
+
+    fn unrecognized(c: char) -> Error {
+        Error::Lexer(format!("unrecognized character: {}", c))
+    }
+
+    fn unexpected(c: char) -> Error {
+        Error::Lexer(format!("unexpected character: {}", c))
+    }
+}
+
+/// Would the lexer read this text as a number: digits after an optional minus
+/// sign, and optionally a "." and more digits? Text like `inf` or `1e5` isn't
+/// a number here, though Rust would parse it as one.
+pub fn is_number(text: &str) -> bool {
+    let digits = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_digit(10))
+    };
+    let unsigned = if text.starts_with('-') { &text[1..] } else { text };
+    match unsigned.find('.') {
+        Some(dot) => digits(&unsigned[..dot]) && digits(&unsigned[dot + 1..]),
+        None => digits(unsigned)
+    }
+}
+
+// Parse a length of time: a whole number of milliseconds (`ms`), seconds (`s`)
+// or minutes (`m`).
+fn parse_duration(text: &str) -> Option<Duration> {
+    let digits = text.find(|c: char| !c.is_digit(10))?;
+    let n: u64 = text[..digits].parse().ok()?;
+    match &text[digits..] {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n * 60)),
+        _ => None
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
+    type Item = Result<Tok>;
+
+    fn next(&mut self) -> Option<Result<Tok>> {
+        if let Some((tok, start)) = self.pending.take() {
+            self.start = start;
+            return Some(Ok(tok));
+        }
+
+        self.skip_whitespace();
+        let c = self.peek()?;
+        self.start = self.position;
+        let line_start = self.line_start;
+        self.line_start = false;
+        let start = self.start;
+        Some(self.lex_token(c, line_start)?.map_err(|e| match e {
+            Error::Lexer(msg) => Error::Lexer(format!("{} ({})", msg, start)),
+            e => e
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -126,6 +473,8 @@ mod tests {
     use tok::Tok;
     use lexer::Lexer;
 
+    use std::time::Duration;
+
     fn lex_test(x: &str) -> Option<Vec<Tok>> {
         Lexer::new(x.chars())
             .map(Result::ok)
@@ -140,10 +489,123 @@ mod tests {
         assert_eq!(lex_test("."), Some(vec!(Tok::Dot)));
         assert_eq!(lex_test(","), Some(vec!(Tok::Comma)));
         assert_eq!(lex_test(":-"), Some(vec!(Tok::Means)));
+        assert_eq!(lex_test("->"), Some(vec!(Tok::Arrow)));
+        assert_eq!(lex_test("{ }"),
+                   Some(vec!(Tok::OpenBrace, Tok::CloseBrace)));
         assert_eq!(lex_test(" ? , . :-"),
                    Some(vec!(Tok::Query, Tok::Comma, Tok::Dot, Tok::Means)));
     }
 
+    #[test]
+    fn operators() {
+        assert_eq!(lex_test("< > =< >= = != + - * /"),
+                   Some(vec!(Tok::Less, Tok::Greater, Tok::LessOrEqual,
+                             Tok::GreaterOrEqual, Tok::Equal, Tok::NotEqual,
+                             Tok::Plus, Tok::Minus, Tok::Times, Tok::Divide)));
+        // The longest operator is taken, without needing spaces.
+        assert_eq!(lex_test("X>=Y=<Z"),
+                   Some(vec!(Tok::Variable("X".to_string()),
+                             Tok::GreaterOrEqual,
+                             Tok::Variable("Y".to_string()),
+                             Tok::LessOrEqual,
+                             Tok::Variable("Z".to_string()))));
+        assert_eq!(lex_test("<="), Some(vec!(Tok::Less, Tok::Equal)));
+        assert_eq!(lex_test("X - 1, -1 ->"),
+                   Some(vec!(Tok::Variable("X".to_string()),
+                             Tok::Minus,
+                             Tok::Int(1),
+                             Tok::Comma,
+                             Tok::Int(-1),
+                             Tok::Arrow)));
+        assert_eq!(lex_test("!"), None);
+        assert_eq!(lex_test("a ! b"), None);
+    }
+
+    #[test]
+    fn names() {
+        let atom = |s: &str| Tok::Atom(s.to_string());
+        assert_eq!(lex_test("hr.employee(X)."),
+                   Some(vec!(atom("hr.employee"), Tok::OpenParen,
+                             Tok::Variable("X".to_string()), Tok::CloseParen,
+                             Tok::Dot)));
+        assert_eq!(lex_test("a.b.c.\nd"),
+                   Some(vec!(atom("a.b.c"), Tok::Dot, atom("d"))));
+        assert_eq!(lex_test("a.B"),
+                   Some(vec!(atom("a"), Tok::Dot,
+                             Tok::Variable("B".to_string()))));
+        assert_eq!(lex_test("x(X)?-> out.all"),
+                   Some(vec!(atom("x"), Tok::OpenParen,
+                             Tok::Variable("X".to_string()), Tok::CloseParen,
+                             Tok::Capture("out.all".to_string()))));
+    }
+
+    #[test]
+    fn positions() {
+        let mut lexer = Lexer::new("a(X) :-\n  b(X) >= 1.".chars());
+        let mut starts = Vec::new();
+        while let Some(tok) = lexer.next() {
+            tok.unwrap();
+            let position = lexer.position();
+            starts.push((position.line, position.column));
+        }
+        assert_eq!(starts, vec!((1, 1), (1, 2), (1, 3), (1, 4), (1, 6),
+                                (2, 3), (2, 4), (2, 5), (2, 6), (2, 8),
+                                (2, 11), (2, 12)));
+
+        let error = Lexer::new("a.\nb(X) :- c(X) @ d.".chars())
+            .find_map(Result::err).unwrap();
+        assert_eq!(error.to_string(), "lexer error: unrecognized character: \
+                                       @ (line 2, column 14)");
+    }
+
+    #[test]
+    fn select() {
+        let select = |vars: &[&str]| {
+            Tok::Select(vars.iter().map(|v| v.to_string()).collect())
+        };
+        assert_eq!(lex_test("? select E"), Some(vec!(select(&["E"]))));
+        assert_eq!(lex_test("?select E,M\nb"),
+                   Some(vec!(select(&["E", "M"]), Tok::Atom("b".to_string()))));
+        assert_eq!(lex_test("?\nselect"),
+                   Some(vec!(Tok::Query, Tok::Atom("select".to_string()))));
+        assert_eq!(lex_test("? a"),
+                   Some(vec!(Tok::Query, Tok::Atom("a".to_string()))));
+        assert_eq!(lex_test("? select e"), None);
+        assert_eq!(lex_test("? using seminaive, no_cache\nb"),
+                   Some(vec!(Tok::Using(vec!("seminaive".to_string(),
+                                             "no_cache".to_string())),
+                             Tok::Atom("b".to_string()))));
+        assert_eq!(lex_test("? select"), None);
+    }
+
+    #[test]
+    fn within() {
+        let within = |millis| Some(vec!(Tok::Within(
+                        Duration::from_millis(millis))));
+        assert_eq!(lex_test("? within 2s"), within(2000));
+        assert_eq!(lex_test("?within 250ms "), within(250));
+        assert_eq!(lex_test("? within 1m\na"),
+                   Some(vec!(Tok::Within(Duration::from_secs(60)),
+                             Tok::Atom("a".to_string()))));
+        assert_eq!(lex_test("? within"), None);
+        assert_eq!(lex_test("? within 2"), None);
+        assert_eq!(lex_test("? within s"), None);
+        assert_eq!(lex_test("? within 2 s"), None);
+        assert_eq!(lex_test("? within 2h"), None);
+    }
+
+    #[test]
+    fn capture() {
+        assert_eq!(lex_test("?-> last"),
+                   Some(vec!(Tok::Capture("last".to_string()))));
+        assert_eq!(lex_test("? ->last\na"),
+                   Some(vec!(Tok::Capture("last".to_string()),
+                             Tok::Atom("a".to_string()))));
+        assert_eq!(lex_test("?-> Last"), None);
+        assert_eq!(lex_test("?->"), None);
+        assert_eq!(lex_test("?-x"), None);
+    }
+
     #[test]
     fn empty() {
         assert_eq!(lex_test(""), Some(vec!()));
@@ -177,6 +639,82 @@ mod tests {
                              Tok::Variable("Var3".to_string()))));
     }
 
+    #[test]
+    fn literals() {
+        assert_eq!(lex_test("42"), Some(vec!(Tok::Int(42))));
+        assert_eq!(lex_test("-7"), Some(vec!(Tok::Int(-7))));
+        assert_eq!(lex_test("2.5"), Some(vec!(Tok::Float(2.5))));
+        assert_eq!(lex_test("salary(x, 10)."),
+                   Some(vec!(Tok::Atom("salary".to_string()),
+                             Tok::OpenParen,
+                             Tok::Atom("x".to_string()),
+                             Tok::Comma,
+                             Tok::Int(10),
+                             Tok::CloseParen,
+                             Tok::Dot)));
+        assert_eq!(lex_test("n(3)."),
+                   Some(vec!(Tok::Atom("n".to_string()),
+                             Tok::OpenParen,
+                             Tok::Int(3),
+                             Tok::CloseParen,
+                             Tok::Dot)));
+        assert_eq!(lex_test("\"a \\\"b\\\"\""),
+                   Some(vec!(Tok::Str("a \"b\"".to_string()))));
+        assert_eq!(lex_test("\"unterminated"), None);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(lex_test(".tables"),
+                   Some(vec!(Tok::Command("tables".to_string()))));
+        assert_eq!(lex_test("  .set deterministic on  \n"),
+                   Some(vec!(Tok::Command("set deterministic on"
+                                              .to_string()))));
+        assert_eq!(lex_test("a.\n.export_query \"a(X)?\" out"),
+                   Some(vec!(Tok::Atom("a".to_string()),
+                             Tok::Dot,
+                             Tok::Command("export_query \"a(X)?\" out"
+                                              .to_string()))));
+        // Only a "." at the start of a line begins a command.
+        assert_eq!(lex_test("a. b."),
+                   Some(vec!(Tok::Atom("a".to_string()),
+                             Tok::Dot,
+                             Tok::Atom("b".to_string()),
+                             Tok::Dot)));
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(lex_test("a(X, $1, $23)"),
+                   Some(vec!(Tok::Atom("a".to_string()),
+                             Tok::OpenParen,
+                             Tok::Variable("X".to_string()),
+                             Tok::Comma,
+                             Tok::Variable("$1".to_string()),
+                             Tok::Comma,
+                             Tok::Variable("$23".to_string()),
+                             Tok::CloseParen)));
+        assert_eq!(lex_test("a($)"), None);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(lex_test("a. % b.\n% c.\nd(\"%\")."),
+                   Some(vec!(Tok::Atom("a".to_string()),
+                             Tok::Dot,
+                             Tok::Atom("d".to_string()),
+                             Tok::OpenParen,
+                             Tok::Str("%".to_string()),
+                             Tok::CloseParen,
+                             Tok::Dot)));
+        // A line after a comment may still be a command.
+        assert_eq!(lex_test("a. % b\n.tables"),
+                   Some(vec!(Tok::Atom("a".to_string()),
+                             Tok::Dot,
+                             Tok::Command("tables".to_string()))));
+        assert_eq!(lex_test("% only a comment"), Some(vec!()));
+    }
+
     #[test]
     fn combined() {
          assert_eq!(lex_test("rule(Var, atom) :- first(atom, Var),
@@ -0,0 +1,222 @@
+/// Limits on the work a query may do, so that one runaway query, e.g. over a
+/// recursive view that derives far more than expected, can't tie up the
+/// database for everyone else.
+///
+/// Limits are per thread, like profiles. While a query runs under limits (see
+/// `eval::query_limited`), the plan nodes doing its work count the rows
+/// returned and the tuples derived, and stop early once a limit is exceeded;
+/// the query then fails with `Error::LimitExceeded`.
+///
+/// A query may instead be given a budget of time (see `Limits::budget`), after
+/// which recursive views stop deriving tuples and the query is answered from
+/// what they have derived so far, marked as partial rather than failing.
+
+use error::*;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The most work a query may do. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// The most answers the query may return.
+    pub max_rows: Option<usize>,
+    /// The most tuples recursive views may derive while answering it.
+    pub max_derived: Option<usize>,
+    /// The longest the query may run.
+    pub timeout: Option<Duration>,
+    /// How long recursive views may be evaluated before the query is answered
+    /// from the tuples they have derived so far.
+    pub budget: Option<Duration>
+}
+
+/// Which limit a query exceeded, and what it was.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Rows(usize),
+    Derived(usize),
+    Time(Duration)
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Rows(max) => write!(f, "more than {} rows returned", max),
+            Limit::Derived(max) =>
+                write!(f, "more than {} tuples derived", max),
+            Limit::Time(max) =>
+                write!(f, "ran longer than {}ms", millis(*max))
+        }
+    }
+}
+
+/// How a query whose budget ran out was cut short.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partial {
+    /// The budget the query had.
+    pub budget: Duration,
+    /// The recursive views stopped before reaching a fixpoint, in the order
+    /// they were stopped, each with the iteration of its recursive rules it
+    /// had reached: 0 if stopped before applying them at all.
+    pub views: Vec<(String, usize)>
+}
+
+impl fmt::Display for Partial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "partial answers after {}ms", millis(self.budget))?;
+        let views: Vec<String> = self.views.iter().map(|(view, iteration)| {
+            format!("{} reached iteration {}", view, iteration)
+        }).collect();
+        if !views.is_empty() {
+            write!(f, ": {}", views.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A duration in whole milliseconds, as limits on time are given.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+struct Guard {
+    limits: Limits,
+    started: Instant,
+    rows: usize,
+    derived: usize,
+    exceeded: Option<Limit>,
+    // Whether the budget has run out, and the views it stopped.
+    spent: bool,
+    cut_short: Vec<(String, usize)>
+}
+
+impl Guard {
+    // Note that `limit` was exceeded, if nothing else was first.
+    fn exceed(&mut self, limit: Limit) -> bool {
+        self.exceeded.get_or_insert(limit);
+        false
+    }
+
+    fn within_time(&mut self) -> bool {
+        match self.limits.timeout {
+            Some(max) if self.started.elapsed() > max =>
+                self.exceed(Limit::Time(max)),
+            _ => self.exceeded.is_none()
+        }
+    }
+
+    fn within_budget(&mut self) -> bool {
+        if let Some(budget) = self.limits.budget {
+            if self.started.elapsed() > budget {
+                self.spent = true;
+            }
+        }
+        !self.spent
+    }
+}
+
+thread_local! {
+    static GUARD: RefCell<Option<Guard>> = RefCell::new(None);
+}
+
+// Apply `f` to the limits being enforced on this thread, if any; with none,
+// everything is within them.
+fn with_guard<F: FnOnce(&mut Guard) -> bool>(f: F) -> bool {
+    GUARD.with(|g| g.borrow_mut().as_mut().map_or(true, f))
+}
+
+/// Start enforcing limits on this thread, discarding any earlier ones.
+pub fn start(limits: Limits) {
+    GUARD.with(|g| {
+        *g.borrow_mut() = Some(Guard {
+            limits,
+            started: Instant::now(),
+            rows: 0,
+            derived: 0,
+            exceeded: None,
+            spent: false,
+            cut_short: Vec::new()
+        });
+    });
+}
+
+/// Stop enforcing limits, failing if any was exceeded. Otherwise, says how
+/// the query was cut short if its budget ran out.
+pub fn finish() -> Result<Option<Partial>> {
+    let guard = match GUARD.with(|g| g.borrow_mut().take()) {
+        Some(guard) => guard,
+        None => return Ok(None)
+    };
+    match (guard.exceeded, guard.limits.budget) {
+        (Some(limit), _) => Err(Error::LimitExceeded(limit)),
+        (None, Some(budget)) if guard.spent => Ok(Some(Partial {
+            budget,
+            views: guard.cut_short
+        })),
+        _ => Ok(None)
+    }
+}
+
+/// Fail if a limit has been exceeded, without stopping enforcement.
+pub fn check() -> Result<()> {
+    let exceeded = GUARD.with(|g| {
+        g.borrow().as_ref().and_then(|g| g.exceeded)
+    });
+    match exceeded {
+        Some(limit) => Err(Error::LimitExceeded(limit)),
+        None => Ok(())
+    }
+}
+
+/// Is the query still within its limits and its budget? Checks the time taken
+/// so far.
+pub fn within() -> bool {
+    with_guard(|g| g.within_time() && g.within_budget())
+}
+
+/// Is the query still within its budget? Checks the time taken so far.
+pub fn within_budget() -> bool {
+    with_guard(|g| g.within_budget())
+}
+
+/// Has the budget run out? Unlike `within_budget`, doesn't check the time, so
+/// only says whether something has been stopped for it.
+pub fn spent() -> bool {
+    !with_guard(|g| !g.spent)
+}
+
+/// If the budget has run out, note that the named recursive view was stopped
+/// in iteration `iteration` of its recursive rules, and return true: the
+/// tuples it has derived are only some of its tuples.
+pub fn cut_short(view: &str, iteration: usize) -> bool {
+    !with_guard(|g| {
+        if g.spent {
+            g.cut_short.push((view.to_string(), iteration));
+        }
+        !g.spent
+    })
+}
+
+/// Count a row about to be returned, and say whether it may be.
+pub fn row() -> bool {
+    with_guard(|g| {
+        g.rows += 1;
+        match g.limits.max_rows {
+            Some(max) if g.rows > max => g.exceed(Limit::Rows(max)),
+            _ => g.within_time()
+        }
+    })
+}
+
+/// Count a tuple derived by a recursive view, and say whether evaluation may
+/// go on.
+pub fn derived() -> bool {
+    with_guard(|g| {
+        g.derived += 1;
+        match g.limits.max_derived {
+            Some(max) if g.derived > max => g.exceed(Limit::Derived(max)),
+            _ => g.exceeded.is_none()
+        }
+    })
+}
@@ -5,41 +5,214 @@
 #![feature(trait_alias)]
 #![feature(type_ascription)]
 
+pub mod advisor;
 pub mod ast;
 pub mod cache;
 pub mod driver;
 pub mod error;
 pub mod eval;
+pub mod export;
+pub mod generate;
+pub mod graph;
 pub mod lexer;
+pub mod limits;
 pub mod parser;
+pub mod profile;
+pub mod row;
+pub mod sandbox;
+pub mod spill;
+pub mod strata;
 pub mod tok;
 pub mod storage;
+pub mod suite;
+pub mod value;
 
 extern crate colored;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
+extern crate toml;
+#[cfg(feature = "parquet")]
+extern crate parquet;
 
 const DEFAULT_DATA_DIR: &'static str = "./data/";
 
+const USAGE: &'static str =
+    "usage: data-goblin [--auto-index] [--script <file>]\n       \
+            data-goblin --execute <query> [--param <value>]...\n       \
+            data-goblin generate <dag|grid|dense> <size> [<seed>]\n       \
+            data-goblin test <suite.toml>";
+
+// `data-goblin test <suite>`: run a test suite, printing how each failing
+// query's answers differed from those expected; see `suite`. Returns whether
+// every query passed.
+fn test_suite(path: &str) -> error::Result<bool> {
+    let (passed, failures) = suite::run(path)?;
+    for failure in &failures {
+        println!("FAIL {}", failure.query);
+        for answer in &failure.missing {
+            println!("-\t{}", answer);
+        }
+        for answer in &failure.unexpected {
+            println!("+\t{}", answer);
+        }
+    }
+    println!("{} passed, {} failed", passed, failures.len());
+    Ok(failures.is_empty())
+}
+
+// `data-goblin generate <shape> <size> [<seed>]`: write a graph for
+// benchmarking into the default data directory; see `generate`.
+fn generate(shape: &str, size: &str, seed: &str) -> error::Result<()> {
+    let number = |arg: &str| arg.parse().map_err(|_| {
+        error::Error::Command(format!("expected a number, got {}", arg))
+    });
+    let shape = generate::Shape::parse(shape)?;
+    let count = generate::generate(DEFAULT_DATA_DIR.to_string(), shape,
+                                   number(size)? as usize, number(seed)?)?;
+    println!("generated {} edges", count);
+    Ok(())
+}
+
 fn main() {
-    driver::Driver::from_stdin(DEFAULT_DATA_DIR.to_string()).run()
+    env_logger::init();
+    let all: Vec<String> = std::env::args().skip(1).collect();
+    if all.first().map(String::as_str) == Some("generate") {
+        let result = match &all[1..] {
+            [shape, size] => generate(shape, size, "0"),
+            [shape, size, seed] => generate(shape, size, seed),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
+        }
+        return;
+    }
+    if all.first().map(String::as_str) == Some("test") {
+        match &all[1..] {
+            [path] => match test_suite(path) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1)
+                }
+            },
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2)
+            }
+        }
+    }
+
+    let mut args = all.into_iter();
+    let mut execute = None;
+    let mut script = None;
+    let mut params = Vec::new();
+    let mut auto_index = false;
+    while let Some(arg) = args.next() {
+        if arg == "--auto-index" {
+            auto_index = true;
+            continue;
+        }
+        match (arg.as_str(), args.next()) {
+            ("--execute", Some(query)) => execute = Some(query),
+            ("--script", Some(path)) => script = Some(path),
+            ("--param", Some(param)) => params.push(param),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2)
+            }
+        }
+    }
+
+    let data_dir = DEFAULT_DATA_DIR.to_string();
+    let result = match (execute, script) {
+        (Some(query), None) if !auto_index =>
+            driver::execute(data_dir, &query, &params),
+        (None, Some(path)) if params.is_empty() =>
+            driver::Driver::from_script(&path, data_dir).map(|d| {
+                if auto_index { d.auto_index() } else { d }.run()
+            }),
+        (None, None) if params.is_empty() => {
+            let d = driver::Driver::from_stdin(data_dir);
+            Ok(if auto_index { d.auto_index() } else { d }.run())
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2)
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    }
 }
 
 // Integration tests go here.
 #[cfg(test)]
 mod tests {
+    use advisor;
     use ast;
     use storage::*;
+    use suite;
+    use error;
     use eval;
     use cache;
+    use driver;
+    use generate;
+    use graph;
+    use value;
     use lexer::Lexer;
+    use limits;
+    use parser;
+    use profile;
     use parser::Parser;
+    use row;
+    use sandbox::Sandbox;
+    use spill;
 
-    use std::collections::HashSet;
+    use std::collections::{BTreeMap, HashSet};
     extern crate test;
 
+    type Engine = StorageEngine<eval::AstView>;
+
+    // Run a query, returning its results as owned frames.
+    fn run(engine: &Engine, cache: &cache::ViewCache, query: &str)
+            -> error::Result<Vec<BTreeMap<String, String>>> {
+        eval::query_owned(engine, cache, parser::parse_query(query)?)
+    }
+
+    // Create the empty directory `dir` for a test, first removing anything an
+    // earlier run left there. Tests remove it again once they pass.
+    fn test_dir(dir: &str) -> &str {
+        if std::fs::read_dir(dir).is_ok() {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+        std::fs::create_dir(dir).unwrap();
+        dir
+    }
+
+    // Assert a single fact or rule.
+    fn assert_line(engine: &mut Engine, cache: &mut cache::ViewCache,
+                   line: &str) -> error::Result<()> {
+        let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+        match Parser::new(lexer).next().unwrap()? {
+            ast::Line::Rule(r) => eval::assert(engine, cache, r),
+            ast::Line::Redefine(r) => eval::redefine(engine, cache, r),
+            _ => panic!("parsed assertion as something else")
+        }
+    }
+
     #[test]
     fn simple_sentences() {
         let engine = StorageEngine::new("test_data/grammar".to_string())
@@ -148,6 +321,2308 @@ mod tests {
 
     }
 
+    #[test]
+    fn builtins() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+
+        assert_eq!(run(&engine, &cache, "true?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "fail?").unwrap().len(), 0);
+        assert!(run(&engine, &cache, "true(X)?").is_err());
+
+        assert_line(&mut engine, &mut cache,
+                    "always(X) :- object(X), true.").unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "never(X) :- object(X), fail.").unwrap();
+        assert_eq!(run(&engine, &cache, "always(X)?").unwrap().len(), 2);
+        assert_eq!(run(&engine, &cache, "never(X)?").unwrap().len(), 0);
+
+        assert!(assert_line(&mut engine, &mut cache, "true.").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "fail(X) :- object(X).").is_err());
+    }
+
+    #[test]
+    fn known_facts() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let known = |engine: &Engine, cache: &cache::ViewCache, fact: &str| {
+            let term = parser::parse_query(&format!("{}?", fact)).unwrap();
+            eval::is_known(engine, cache, &term).unwrap()
+        };
+
+        assert!(known(&engine, &cache, "case(first, i)"));
+        assert!(!known(&engine, &cache, "case(first, he)"));
+        assert!(!known(&engine, &cache, "unknown_relation(a)"));
+
+        assert_line(&mut engine, &mut cache, "case(first, he).").unwrap();
+        assert!(known(&engine, &cache, "case(first, he)"));
+    }
+
+    #[test]
+    fn redefine_and_retract() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let query = "pronoun(X)?";
+
+        assert_line(&mut engine, &mut cache,
+                    "pronoun(X) :- case(P, X).").unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "pronoun(X) :- object(X).").unwrap();
+        assert_eq!(run(&engine, &cache, query).unwrap().len(), 5);
+        assert_eq!(eval::rules(&engine, "pronoun").unwrap().len(), 2);
+
+        assert_line(&mut engine, &mut cache,
+                    "redefine pronoun(X) :- object(X).").unwrap();
+        assert_eq!(eval::rules(&engine, "pronoun").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, query).unwrap().len(), 2);
+
+        // A rejected redefinition leaves the old rules in place.
+        assert!(assert_line(&mut engine, &mut cache,
+                            "redefine pronoun(he) :- object(X).").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "redefine pronoun(X) :- object(Y), \
+                                      not case(P, X).").is_err());
+        assert_eq!(eval::rules(&engine, "pronoun").unwrap().len(), 1);
+
+        assert!(eval::retract_rule(&mut engine, &mut cache, "pronoun", 1)
+                .is_err());
+        let rule = eval::retract_rule(&mut engine, &mut cache, "pronoun", 0)
+            .unwrap();
+        assert_eq!(rule.to_string(), "pronoun(X) :- object(X).");
+        assert!(run(&engine, &cache, query).unwrap().is_empty());
+
+        assert!(assert_line(&mut engine, &mut cache,
+                            "redefine case(X, Y) :- object(X).").is_err());
+    }
+
+    #[test]
+    fn retraction() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let retract = |engine: &mut Engine, cache: &mut cache::ViewCache,
+                       line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Rule(r) => eval::retract(engine, cache, r),
+                _ => panic!("parsed retraction as something else")
+            }
+        };
+        let objects = |engine: &Engine, cache: &cache::ViewCache| {
+            run(engine, cache, "object(X)?").unwrap().len()
+        };
+
+        let before = objects(&engine, &cache);
+        assert_line(&mut engine, &mut cache, "object(x).").unwrap();
+        assert_line(&mut engine, &mut cache, "object(x).").unwrap();
+        retract(&mut engine, &mut cache, "object(x).").unwrap();
+        assert_eq!(objects(&engine, &cache), before + 1);
+        retract(&mut engine, &mut cache, "object(x).").unwrap();
+        assert_eq!(objects(&engine, &cache), before);
+        assert!(retract(&mut engine, &mut cache, "object(x).").is_err());
+
+        assert_line(&mut engine, &mut cache,
+                    "pronoun(X) :- object(X).").unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "pronoun(X) :- case(P, X).").unwrap();
+        assert_eq!(run(&engine, &cache, "pronoun(X)?").unwrap().len(), 5);
+        retract(&mut engine, &mut cache, "pronoun(X) :- object(X).").unwrap();
+        let rules = eval::rules(&engine, "pronoun").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].to_string(), "pronoun(X) :- case(P, X).");
+        assert!(retract(&mut engine, &mut cache,
+                        "pronoun(X) :- object(X).").is_err());
+        assert!(retract(&mut engine, &mut cache, "pronoun(it).").is_err());
+    }
+
+    #[test]
+    fn dry_run() {
+        let engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let check = |line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Rule(r) =>
+                    eval::check_assert(&engine, &cache, &r, false),
+                ast::Line::Redefine(r) =>
+                    eval::check_assert(&engine, &cache, &r, true),
+                _ => panic!("parsed assertion as something else")
+            }
+        };
+
+        assert_eq!(check("edge(a, b).").unwrap(),
+                   "would add fact to relation edge/2");
+        assert!(engine.get_relation("edge").is_none());
+        assert_eq!(check("object(x).").unwrap(),
+                   "would add fact to relation object/1");
+        assert_eq!(check("path(X, Y) :- object(X), object(Y).").unwrap(),
+                   "would add rule to relation path/2");
+        assert!(engine.get_relation("path").is_none());
+        assert_eq!(check("redefine path(X) :- object(X).").unwrap(),
+                   "would replace the rules of relation path/1");
+
+        assert!(check("object(x, y).").is_err());
+        assert!(check("simple_sentence(i, eat, it).").is_err());
+        assert!(check("object(X) :- case(X, Y).").is_err());
+        assert!(check("lonely(X) :- not object(X).").is_err());
+        assert!(check("true.").is_err());
+    }
+
+    #[test]
+    fn schema_inference() {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let columns = eval::infer_schema(&engine, "employee_hierarchy")
+            .unwrap();
+        let summary: Vec<_> = columns.iter().map(|c| {
+            (c.kind.as_str(), c.prefix.as_ref().map(String::as_str), c.nulls)
+        }).collect();
+        assert_eq!(summary, vec!(("integer (as text)", Some("id_"), 0),
+                                 ("symbol", None, 0),
+                                 ("integer (as text)", Some("salary_"), 0),
+                                 ("integer (as text)", Some("id_"), 1)));
+        assert_eq!(columns[0].distinct, 100);
+
+        for line in &["t(1, \"x\", 2.5).", "t(2, \"N/A\", b)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let kinds: Vec<_> = eval::infer_schema(&engine, "t").unwrap()
+            .into_iter().map(|c| (c.kind, c.nulls)).collect();
+        assert_eq!(kinds, vec!(("integer".to_string(), 0),
+                               ("string".to_string(), 1),
+                               ("mixed".to_string(), 0)));
+        assert!(eval::infer_schema(&engine, "reports").is_err());
+    }
+
+    #[test]
+    fn references() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["department(sales, 1).", "department(ops, 2).",
+                      "works_in(bob, sales).", "works_in(eve, ops)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let reference = |engine: &mut Engine, cache: &cache::ViewCache,
+                         line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Ref(from, to) =>
+                    eval::add_reference(engine, cache, from, to),
+                _ => panic!("parsed reference as something else")
+            }
+        };
+
+        assert!(reference(&mut engine, &cache,
+                          "ref works_in(2) -> department(3).").is_err());
+        assert!(reference(&mut engine, &cache,
+                          "ref works_in(1, 2) -> department(1).").is_err());
+        assert!(reference(&mut engine, &cache,
+                          "ref works_in(1) -> department(1).").is_err());
+        reference(&mut engine, &cache,
+                  "ref works_in(2) -> department(1).").unwrap();
+
+        assert_line(&mut engine, &mut cache, "works_in(ann, ops).").unwrap();
+        match assert_line(&mut engine, &mut cache, "works_in(al, legal).") {
+            Err(error::Error::MalformedLine(s)) =>
+                assert_eq!(s, "works_in(al, legal) refers to a missing \
+                               department(legal, _)"),
+            other => panic!("unexpected result {:?}", other)
+        }
+        let facts = vec!(parser::parse_query("works_in(al, legal)?").unwrap(),
+                         parser::parse_query("department(legal, 3)?").unwrap());
+        assert_eq!(eval::bulk_assert(&mut engine, &mut cache, facts).unwrap(),
+                   2);
+
+        assert!(eval::check_references(&engine, &cache).unwrap().is_empty());
+        let lexer = Lexer::new("department(ops, 2).".chars())
+            .map(Result::unwrap);
+        match Parser::new(lexer).next().unwrap().unwrap() {
+            ast::Line::Rule(r) =>
+                eval::retract(&mut engine, &mut cache, r).unwrap(),
+            _ => panic!("parsed retraction as something else")
+        }
+        assert_eq!(eval::check_references(&engine, &cache).unwrap(),
+                   vec!("works_in(eve, ops) refers to a missing \
+                         department(ops, _)",
+                        "works_in(ann, ops) refers to a missing \
+                         department(ops, _)"));
+    }
+
+    #[test]
+    fn subsumption() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b, 1).", "edge(b, c, 1).", "edge(a, c, 5).",
+                      "edge(c, d, 2).",
+                      "first_hop(X, Y, W) :- edge(X, Y, W).",
+                      "first_hop(X, Z, W) :- first_hop(X, Y, W), \
+                                             edge(Y, Z, V)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let query = "first_hop(a, Y, W)?";
+        assert_eq!(run(&engine, &cache, query).unwrap().len(), 5);
+
+        let min = eval::Subsumption { column: 2, keep: eval::Extremum::Min };
+        eval::set_subsumption(&mut engine, &mut cache, "first_hop",
+                              Some(min)).unwrap();
+        let mut frames = run(&engine, &cache, query).unwrap();
+        frames.sort();
+        let weights: Vec<(&str, &str)> = frames.iter().map(|f| {
+            (f["Y"].as_str(), f["W"].as_str())
+        }).collect();
+        assert_eq!(weights, vec![("b", "1"), ("c", "1"), ("d", "1")]);
+
+        let term = parser::parse_query(query).unwrap();
+        assert_eq!(eval::query_semi_naive(&engine, &cache, term).unwrap()
+                       .count(), 3);
+
+        let out_of_range =
+            eval::Subsumption { column: 3, keep: eval::Extremum::Max };
+        assert!(eval::set_subsumption(&mut engine, &mut cache, "first_hop",
+                                      Some(out_of_range)).is_err());
+        assert!(eval::set_subsumption(&mut engine, &mut cache, "edge", None)
+                .is_err());
+        eval::set_subsumption(&mut engine, &mut cache, "first_hop", None)
+            .unwrap();
+        assert_eq!(run(&engine, &cache, query).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn negated_queries() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let absent = |query: &str| {
+            let term = parser::parse_query(query).unwrap();
+            eval::absent(&engine, &cache, term)
+        };
+
+        assert!(!absent("underling(id_10005, id_10002)?").unwrap());
+        assert!(absent("underling(id_10002, id_10005)?").unwrap());
+        assert!(!absent("underling(X, id_10002)?").unwrap());
+        assert!(absent("underling(id_NULL, X)?").unwrap());
+        assert!(absent("no_such_relation(X)?").is_err());
+    }
+
+    #[test]
+    fn scoped_views() {
+        // Run a `with` query, returning the sorted values of Y.
+        fn with(engine: &mut Engine, line: &str)
+                -> error::Result<Vec<String>> {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            let (rules, query) = match Parser::new(lexer).next().unwrap()? {
+                ast::Line::With(rules, query) => (rules, query),
+                _ => panic!("parsed with query as something else")
+            };
+            eval::query_with(engine, rules, query, |frames| {
+                let mut values: Vec<String> =
+                    frames.map(|f| f["Y"].to_string()).collect();
+                values.sort();
+                values
+            })
+        }
+
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = "with mine(X) :- underling(id_10005, X). mine(Y)?";
+        let scoped = with(&mut engine, query).unwrap();
+        let mut direct: Vec<String> =
+            run(&engine, &cache, "underling(id_10005, Y)?").unwrap()
+                .into_iter().map(|f| f["Y"].clone()).collect();
+        direct.sort();
+        assert_eq!(scoped, direct);
+        assert!(engine.get_relation("mine").is_none());
+
+        // Scoped views may be recursive and use each other.
+        let query = "with up(X, Y) :- employee_hierarchy(X, N, S, Y). \
+                     up(X, Z) :- up(X, Y), employee_hierarchy(Y, N, S, Z). \
+                     top(Y) :- up(id_10005, Y). top(Y)?";
+        assert_eq!(with(&mut engine, query).unwrap(), direct);
+
+        // They can't replace relations in the database, and are forgotten
+        // even if the query fails.
+        for query in &["with reports(X, Y) :- underling(X, Y). reports(X, Y)?",
+                       "with mine(Y) :- underling(id_10005, X), \
+                                        not reports(Y, X). mine(Y)?",
+                       "with mine(Y) :- nothing(Y). mine(Y)?",
+                       "with mine(id_10005). mine(Y)?"] {
+            assert!(with(&mut engine, query).is_err());
+            assert!(engine.get_relation("mine").is_none());
+        }
+        assert!(cache.read_cache("mine", &[None]).is_none());
+    }
+
+    #[test]
+    fn captured_results() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let capture = |engine: &mut Engine, cache: &mut cache::ViewCache,
+                       line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap()? {
+                ast::Line::Capture(t, name) =>
+                    eval::capture(engine, cache, t, name),
+                _ => panic!("parsed capture as something else")
+            }
+        };
+
+        assert_eq!(capture(&mut engine, &mut cache,
+                           "case(X, Y)?-> last").unwrap(), 3);
+        assert_eq!(run(&engine, &cache, "last(third, Y)?").unwrap(),
+                   run(&engine, &cache, "case(third, Y)?").unwrap());
+        assert_line(&mut engine, &mut cache, "third(Y) :- last(third, Y).")
+            .unwrap();
+        assert_eq!(run(&engine, &cache, "third(Y)?").unwrap().len(), 2);
+
+        // Storing results again replaces them, and views of them.
+        assert_eq!(capture(&mut engine, &mut cache,
+                           "case(third, Y)?-> last").unwrap(), 2);
+        assert!(run(&engine, &cache, "last(X, Y)?").is_err());
+        assert_eq!(run(&engine, &cache, "last(X)?").unwrap().len(), 2);
+        assert!(run(&engine, &cache, "third(Y)?").is_err());
+
+        // Stored results can't be changed, or replace durable relations.
+        assert!(assert_line(&mut engine, &mut cache, "last(me).").is_err());
+        assert!(capture(&mut engine, &mut cache, "last(X)?-> object").is_err());
+        assert!(capture(&mut engine, &mut cache, "object(it)?-> x").is_err());
+        assert!(capture(&mut engine, &mut cache, "object(X)?-> true").is_err());
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z).",
+                      "far(X) :- path(a, X), not edge(a, X).",
+                      "pronoun(X) :- object(X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        let names = |names: &[&str]| -> Vec<String> {
+            names.iter().map(|n| n.to_string()).collect()
+        };
+        assert_eq!(cache.dependents("edge"), names(&["far", "path"]));
+        assert_eq!(cache.dependents("path"), names(&["far", "path"]));
+        assert_eq!(cache.dependents("far"), names(&[]));
+        assert_eq!(cache.dependents("object"), names(&["pronoun"]));
+
+        let graph = cache.dependency_graph();
+        assert_eq!(graph.keys().collect::<Vec<_>>(),
+                   vec!("edge", "object", "path"));
+        assert_eq!(graph["edge"], names(&["far", "path"]));
+
+        assert_eq!(cache.cycles(), vec!(names(&["path"])));
+
+        // Redefining a view forgets what it used to depend on.
+        assert_line(&mut engine, &mut cache,
+                    "redefine far(X) :- object(X).").unwrap();
+        assert_eq!(cache.dependents("edge"), names(&["path"]));
+        assert_eq!(cache.dependents("object"), names(&["far", "pronoun"]));
+
+        // Mutually recursive views form one group.
+        for line in &["even(X) :- zero(X).",
+                      "even(X) :- succ(Y, X), odd(Y).",
+                      "odd(X) :- succ(Y, X), even(Y)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        assert_eq!(cache.cycles(),
+                   vec!(names(&["even", "odd"]), names(&["path"])));
+    }
+
+    #[test]
+    fn holds() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["likes(i, it).",
+                      "related(R, X) :- holds(R, i, X).",
+                      "reflected(X, Y) :- holds(R, X, Y), holds(R, Y, X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        // Only tables with two columns are included.
+        assert_eq!(run(&engine, &cache, "holds(R, X, Y)?").unwrap().len(), 4);
+        assert_eq!(run(&engine, &cache, "holds(case, X, Y)?").unwrap().len(),
+                   3);
+        assert!(run(&engine, &cache, "holds(verb, X, Y)?").unwrap()
+                                                            .is_empty());
+        assert!(run(&engine, &cache, "holds(R, X)?").is_err());
+
+        let related = run(&engine, &cache, "related(R, X)?").unwrap();
+        assert_eq!(related.len(), 1);
+        assert_eq!(related[0]["R"], "likes");
+        assert!(run(&engine, &cache, "reflected(X, Y)?").unwrap().is_empty());
+
+        // Views using `holds` see changes to any table.
+        assert_line(&mut engine, &mut cache, "dislikes(it, i).").unwrap();
+        assert_eq!(run(&engine, &cache, "related(R, X)?").unwrap().len(), 1);
+        assert_line(&mut engine, &mut cache, "likes(it, i).").unwrap();
+        assert_eq!(run(&engine, &cache, "reflected(X, Y)?").unwrap().len(), 2);
+        assert_eq!(cache.dependents("likes"),
+                   vec!("reflected".to_string(), "related".to_string()));
+    }
+
+    #[test]
+    fn holds_and_count() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = |text: &str| parser::parse_query(text).unwrap();
+
+        assert!(eval::holds(&engine, &cache,
+                            query("reports(id_10005, id_10002)?")).unwrap());
+        assert!(!eval::holds(&engine, &cache,
+                             query("reports(id_10002, id_10005)?")).unwrap());
+        assert_eq!(eval::count(&engine, &cache, query("reports(E, M)?"))
+                       .unwrap(),
+                   run(&engine, &cache, "reports(E, M)?").unwrap().len());
+
+        // Whole tables, and views once cached, are counted without a scan.
+        let text = "employee_hierarchy(E, F, L, M)?";
+        assert_eq!(eval::count(&engine, &cache, query(text)).unwrap(),
+                   run(&engine, &cache, text).unwrap().len());
+        run(&engine, &cache, "reports(E, M)?").unwrap();
+        assert_eq!(cache.cached_len("reports", &[None, None]),
+                   Some(eval::count(&engine, &cache, query("reports(E, M)?"))
+                            .unwrap()));
+        assert_eq!(eval::count(&engine, &cache, query("reports(E, E)?"))
+                       .unwrap(),
+                   run(&engine, &cache, "reports(E, E)?").unwrap().len());
+        assert_eq!(eval::count(&engine, &cache,
+                               query("reports(id_NULL, M)?")).unwrap(),
+                   0);
+        assert!(eval::holds(&engine, &cache, query("nothing(X)?")).is_err());
+    }
+
+    #[test]
+    fn rows() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        assert_line(&mut engine, &mut cache,
+                    "salary(al, 100, \"Al Smith\", 1.5).").unwrap();
+
+        let query = parser::parse_query("salary(N, S, F, R)?").unwrap();
+        let rows = row::query_rows(&engine, &cache, query).unwrap();
+        assert_eq!(rows.len(), 1);
+        let row = &rows[0];
+        assert_eq!(row.columns(), vec!("N", "S", "F", "R"));
+        assert_eq!(::serde_json::to_string(row).unwrap(),
+                   r#"{"N":"al","S":100,"F":"Al Smith","R":1.5}"#);
+        assert_eq!(row.get::<i64>("S").unwrap(), 100);
+        assert_eq!(row.get::<f64>("S").unwrap(), 100.0);
+        assert_eq!(row.get::<String>("N").unwrap(), "al");
+        assert_eq!(row.get::<value::Value>("N").unwrap(),
+                   value::Value::from("al"));
+        assert!(row.get::<i64>("N").is_err());
+        assert!(row.get::<i64>("X").is_err());
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        #[allow(non_snake_case)]
+        struct Salary { N: String, S: i64, F: String, R: f64 }
+        assert_eq!(row.deserialize::<Salary>().unwrap(),
+                   Salary { N: "al".to_string(), S: 100,
+                            F: "Al Smith".to_string(), R: 1.5 });
+        assert!(row.deserialize::<(i64, i64)>().is_err());
+
+        // Borrowed rows give the same values without copying them.
+        let query = parser::parse_query("salary(N, S, F, R)?").unwrap();
+        let copied = row::query_refs(&engine, &cache, query, |refs| {
+            let refs: Vec<row::RowRef> = refs.collect();
+            assert_eq!(refs.len(), 1);
+            let borrowed = &refs[0];
+            assert_eq!(borrowed.columns(), ["N", "S", "F", "R"]);
+            assert_eq!(borrowed.get_str("F").unwrap(), "Al Smith");
+            assert_eq!(borrowed.get::<i64>("S").unwrap(), 100);
+            assert!(borrowed.get_str("S").is_err());
+            assert!(borrowed.value("X").is_err());
+            assert_eq!(*borrowed.values()[0], value::Value::from("al"));
+            borrowed.to_row()
+        }).unwrap();
+        assert_eq!(&copied, row);
+    }
+
+    #[test]
+    fn repeated_variables() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, a).",
+                      "edge(a, b).",
+                      "edge(b, a).",
+                      "edge(b, c).",
+                      "edge(c, c).",
+                      "link(X, Y) :- edge(X, Y).",
+                      "reach(X, Y) :- edge(X, Y).",
+                      "reach(X, Z) :- edge(X, Y), reach(Y, Z).",
+                      "mutual(X, Y) :- edge(X, Y), edge(Y, X).",
+                      "looped(X) :- link(X, X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let answers = |query: &str| {
+            let mut answers: Vec<String> = run(&engine, &cache, query)
+                .unwrap().into_iter().map(|f| f["X"].clone()).collect();
+            answers.sort();
+            answers.dedup();
+            answers
+        };
+
+        // A variable repeated in a term matches only equal values, whether
+        // the term is for a table, a view, or a cached view.
+        assert_eq!(answers("edge(X, X)?"), vec!("a", "c"));
+        assert_eq!(answers("link(X, X)?"), vec!("a", "c"));
+        assert_eq!(answers("link(X, Y)?").len(), 3);
+        assert!(cache.is_cached("link", &[None, None]));
+        assert_eq!(answers("link(X, X)?"), vec!("a", "c"));
+        assert_eq!(answers("looped(X)?"), vec!("a", "c"));
+        assert_eq!(answers("reach(X, X)?"), vec!("a", "b", "c"));
+        assert_eq!(answers("mutual(X, X)?"), vec!("a", "c"));
+        assert_eq!(answers("mutual(X, b)?"), vec!("a"));
+    }
+
+    #[test]
+    fn extrema() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["record(a, 1, x).",
+                      "record(a, 3, z).",
+                      "record(a, 3, y).",
+                      "record(b, 2, w).",
+                      "latest(K, T, V) :- record(K, T, V), max_by(K, T).",
+                      "first(V) :- record(K, T, V), min_by(T).",
+                      "late(K, V) :- record(K, T, V), before(1, T), \
+                                     min_by(K, T)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        // Ties go to the least frame, here the one with the least V.
+        let latest = run(&engine, &cache, "latest(K, T, V)?").unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!((&latest[0]["T"], &latest[0]["V"]), (&"3".to_string(),
+                                                        &"y".to_string()));
+        assert_eq!(latest[1]["V"], "w");
+        let first = run(&engine, &cache, "first(V)?").unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0]["V"], "x");
+        // Extrema are taken after the rest of the body is applied.
+        assert_eq!(run(&engine, &cache, "late(a, V)?").unwrap()[0]["V"], "y");
+
+        assert!(run(&engine, &cache, "min_by(X, Y)?").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "bad(V) :- record(K, T, V), not min_by(T).")
+                    .is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "bad(V) :- record(K, T, V), max_by(U).")
+                    .is_err());
+    }
+
+    #[test]
+    fn read_your_writes() {
+        let dir = test_dir("_test_read_your_writes_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\n.set deterministic on\n\
+             log(1).\nrecent(X) :- log(X).\nrecent(X)?\n\
+             log(2).\nrecent(X)?\n\
+             .retain log 2\nlog(3).\nrecent(X)?\n\
+             .retain log 1\nrecent(X)?\n", output)).unwrap();
+
+        // Every query sees the facts asserted before it, and the view cached
+        // from them always agrees with the table, even as the writer thread
+        // writes changes back and facts are expired.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\trecent(X)?\nX\n1\n\
+                    #query 2\trecent(X)?\nX\n1\n2\n\
+                    #query 3\trecent(X)?\nX\n2\n3\n\
+                    #query 4\trecent(X)?\nX\n3\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn triggers() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["works_in(bob, sales).", "member(sales, bob).",
+                      "staff(bob).", "counted(X) :- staff(X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let trigger = |engine: &mut Engine, line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Trigger(pattern, actions) =>
+                    eval::add_trigger(engine, pattern, actions),
+                _ => panic!("parsed trigger as something else")
+            }
+        };
+
+        trigger(&mut engine,
+                "on works_in(E, D) -> member(D, E), staff(E).").unwrap();
+        trigger(&mut engine, "on staff(E) -> log(added, E).").unwrap();
+        trigger(&mut engine, "on works_in(E, ops) -> on_call(E).").unwrap();
+        assert!(trigger(&mut engine, "on works_in(E) -> staff(E).").is_err());
+        assert!(trigger(&mut engine, "on works_in(E, D) -> staff(X).")
+                    .is_err());
+        assert!(trigger(&mut engine, "on works_in(E, D) -> counted(E).")
+                    .is_err());
+        assert!(trigger(&mut engine, "on counted(E) -> staff(E).").is_err());
+
+        // Facts asserted by triggers fire triggers of their own, and are seen
+        // by views.
+        assert_eq!(run(&engine, &cache, "counted(X)?").unwrap().len(), 1);
+        assert_line(&mut engine, &mut cache, "works_in(eve, ops).").unwrap();
+        assert_eq!(run(&engine, &cache, "member(ops, eve)?").unwrap().len(),
+                   1);
+        assert_eq!(run(&engine, &cache, "log(added, eve)?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "on_call(E)?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "counted(X)?").unwrap().len(), 2);
+        assert_line(&mut engine, &mut cache, "works_in(al, sales).").unwrap();
+        assert_eq!(run(&engine, &cache, "on_call(E)?").unwrap().len(), 1);
+
+        // Tables triggers use can't change arity.
+        assert!(eval::alter(&mut engine, &mut cache, "log", 3,
+                            Some(value::Value::from("x"))).is_err());
+
+        // A trigger asserting into its own table loops until it is stopped.
+        trigger(&mut engine, "on log(A, E) -> log(E, A).").unwrap();
+        assert!(assert_line(&mut engine, &mut cache, "staff(ann).").is_err());
+    }
+
+    #[test]
+    fn query_diffs() {
+        let dir = test_dir("_test_query_diffs_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\n.diff on\n\
+             edge(a, b).\nedge(b, c).\n\
+             path(X, Y) :- edge(X, Y).\n\
+             path(X, Z) :- path(X, Y), edge(Y, Z).\n\
+             path(a, X)?\n\
+             edge(c, d).\npath(a, X)?\n\
+             .undo\npath(a, X)?\npath(a, X)?\n", output)).unwrap();
+
+        // The first run of a query prints every answer, and later runs only
+        // the changes.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tpath(a, X)?\nX\nb\nc\n\
+                    #query 2\tpath(a, X)?\ndiff\tX\n+\td\n\
+                    #query 3\tpath(a, X)?\ndiff\tX\n-\td\n\
+                    #query 4\tpath(a, X)?\ndiff\tX\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prepared_queries() {
+        let dir = test_dir("_test_prepared_queries_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\nboss(al, bo).\nboss(bo, cy).\n\
+             .save_query bosses boss($1, X)?\n\
+             .save_query missing manager($1, X)?\n\
+             .save_query wide boss($1, X, Y)?\n\
+             .save_query gap boss($2, X)?\n\
+             .run bosses al\n.run bosses\n.run bosses bo\n", output))
+            .unwrap();
+
+        // Only queries that could be answered are saved, and each is run
+        // only with all of its parameters.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tboss(al, X)?\nX\nbo\n\
+                    #query 2\tboss(bo, X)?\nX\ncy\n");
+        let engine = StorageEngine::<eval::AstView>::new(
+            format!("{}/data", dir)).unwrap();
+        assert_eq!(engine.saved_queries(), vec!(("bosses", "boss($1, X)?")));
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rule_history() {
+        let dir = test_dir("_test_rule_history_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\n.set deterministic on\nedge(a, b).\nedge(b, c).\n\
+             path(X, Y) :- edge(X, Y).\n\
+             path(X, Z) :- path(X, Y), edge(Y, Z).\npath(a, X)?\n\
+             redefine path(X, Y) :- edge(Y, X).\npath(a, X)?\n\
+             .revert path 2\npath(a, X)?\n.revert path 5\n", output))
+            .unwrap();
+
+        // Reverting restores the rules of a version, and is itself a new
+        // version.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tpath(a, X)?\nX\nb\nc\n\
+                    #query 2\tpath(a, X)?\nX\n\
+                    #query 3\tpath(a, X)?\nX\nb\nc\n");
+        let engine = StorageEngine::<eval::AstView>::new(
+            format!("{}/data", dir)).unwrap();
+        let history = engine.history("path");
+        let changes: Vec<&str> =
+            history.iter().map(|v| v.change.as_str()).collect();
+        assert_eq!(changes, vec!("path(X, Y) :- edge(X, Y).",
+                                 "path(X, Z) :- path(X, Y), edge(Y, Z).",
+                                 "redefine path(X, Y) :- edge(Y, X).",
+                                 "revert to version 2"));
+        assert_eq!(history[1].rules.len(), 2);
+        assert_eq!(history[3].rules, history[1].rules);
+        assert!(engine.history("edge").is_empty());
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn string_history() {
+        let dir = test_dir("_test_string_history_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script,
+                       "tag(a, \"bell\u{7}\r\").\n\
+                        rung(X) :- tag(X, \"bell\u{7}\r\").\n\
+                        redefine rung(X) :- tag(X, \"bell\").\n").unwrap();
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+
+        // Rules with control characters in their strings are read back from
+        // the history of a reopened database.
+        std::fs::write(&script, format!(
+            ".output {}\n.revert rung 1\nrung(X)?\n", output)).unwrap();
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\trung(X)?\nX\na\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn last_result() {
+        let dir = test_dir("_test_last_result_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\nsalary(al, 100).\nsalary(bo, 200).\n\
+             boss(al, bo).\nboss(bo, cy).\nboss(X, bo)?\n\
+             paid(X, S) :- last_result(X), salary(X, S).\npaid(X, S)?\n\
+             boss(al, bo)?\nlast_result(X, S)?\n\
+             boss(X, cy)?\nlast_result(X)?\n", output)).unwrap();
+
+        // Each query with variables replaces the stored answers; those
+        // without leave them be.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tboss(X, bo)?\nX\nal\n\
+                    #query 2\tpaid(X, S)?\nX\tS\nal\t100\n\
+                    #query 3\tboss(al, bo)?\n\n\n\
+                    #query 4\tlast_result(X, S)?\nX\tS\nal\t100\n\
+                    #query 5\tboss(X, cy)?\nX\nbo\n\
+                    #query 6\tlast_result(X)?\nX\nbo\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn timing() {
+        let dir = test_dir("_test_timing_dir");
+        let script = format!("{}/script.dl", dir);
+        let output = format!("{}/out.tsv", dir);
+        std::fs::write(&script, format!(
+            ".output {}\n.time on\nedge(a, b).\n\
+             path(X, Y) :- edge(X, Y).\npath(a, X)?\npath(a, X)?\n\
+             .set time off\npath(X, b)?\n", output)).unwrap();
+
+        // Timings go to standard error, leaving the results as they were.
+        driver::Driver::from_script(&script, format!("{}/data", dir))
+            .unwrap().run();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "#query 1\tpath(a, X)?\nX\nb\n\
+                    #query 2\tpath(a, X)?\nX\nb\n\
+                    #query 3\tpath(X, b)?\nX\na\n");
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // Whether a query will hit the cache can be told beforehand.
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let id = value::Value::Symbol("id_10005".to_string());
+        assert!(!cache.is_cached("underling", &[None, None]));
+        run(&engine, &cache, "underling(X, Y)?").unwrap();
+        assert!(cache.is_cached("underling", &[None, None]));
+        assert!(cache.is_cached("underling", &[Some(id), None]));
+        assert!(!cache.is_cached("reports", &[None, None]));
+    }
+
+    #[test]
+    fn arity_changes() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "hop(X, Y) :- edge(X, Y)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        match assert_line(&mut engine, &mut cache, "edge(a, b, c).") {
+            Err(error::Error::RelationArity { relation, arity: 2, got: 3 }) =>
+                assert_eq!(relation, "edge"),
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert!(assert_line(&mut engine, &mut cache,
+                            "hop(X) :- edge(X, Y).").is_err());
+        assert_line(&mut engine, &mut cache,
+                    "redefine hop(X) :- edge(X, Y).").unwrap();
+        assert!(run(&engine, &cache, "edge(X)?").is_err());
+
+        // Tables change arity only when asked to.
+        assert!(eval::alter(&mut engine, &mut cache, "edge", 3, None)
+            .is_err());
+        assert!(eval::alter(&mut engine, &mut cache, "hop", 2, None).is_err());
+        eval::alter(&mut engine, &mut cache, "edge", 3,
+                    Some(value::Value::Int(1))).unwrap();
+        assert_line(&mut engine, &mut cache, "edge(b, c, 2).").unwrap();
+        assert_eq!(run(&engine, &cache, "edge(a, Y, W)?").unwrap().len(), 1);
+        assert!(run(&engine, &cache, "hop(X)?").is_err());
+
+        eval::alter(&mut engine, &mut cache, "edge", 1, None).unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "redefine hop(X) :- edge(X).").unwrap();
+        assert_eq!(run(&engine, &cache, "hop(X)?").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn negated_body_terms() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["node(a).", "node(b).", "node(c).", "node(d).",
+                      "edge(a, b).", "edge(b, c).", "edge(c, d).",
+                      "blocked(c).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z).",
+                      "unreachable(X) :- node(X), not path(a, X).",
+                      "sink(X) :- node(X), not edge(X, Y).",
+                      "open(X, Y) :- edge(X, Y), not blocked(Y).",
+                      "open(X, Z) :- open(X, Y), edge(Y, Z), \
+                                     not blocked(Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let answers = |engine: &Engine, cache: &cache::ViewCache, query| {
+            let mut answers: Vec<String> =
+                run(engine, cache, query).unwrap().into_iter().map(|f| {
+                    f.values().cloned().collect::<Vec<_>>().join(" ")
+                }).collect();
+            answers.sort();
+            answers
+        };
+
+        assert_eq!(answers(&engine, &cache, "unreachable(X)?"), vec!["a"]);
+        assert_eq!(answers(&engine, &cache, "sink(X)?"), vec!["d"]);
+        assert_eq!(answers(&engine, &cache, "open(X, Y)?"),
+                   vec!["a b", "c d"]);
+        let term = parser::parse_query("open(X, Y)?").unwrap();
+        assert_eq!(eval::query_semi_naive(&engine, &cache, term).unwrap()
+                       .count(), 2);
+
+        // Asserting into a negated relation invalidates views using it.
+        assert_line(&mut engine, &mut cache, "blocked(d).").unwrap();
+        assert_eq!(answers(&engine, &cache, "open(X, Y)?"), vec!["a b"]);
+
+        // Head variables must be bound outside of negated terms, and a view
+        // can't negate itself.
+        assert!(assert_line(&mut engine, &mut cache,
+                            "lonely(X) :- not edge(X, Y).").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "odd(X) :- node(X), not odd(X).").is_err());
+    }
+
+    #[test]
+    fn stratification() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["node(a).", "edge(a, b).",
+                      "free(X) :- node(X), not taken(X).",
+                      "taken(X) :- edge(X, Y)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        let err = assert_line(&mut engine, &mut cache,
+                              "taken(X) :- free(X), edge(Y, X).")
+            .unwrap_err().to_string();
+        assert!(err.contains("taken(X) :- free(X), edge(Y, X)."));
+        assert!(err.contains("free -> not taken -> free"));
+        assert_eq!(eval::rules(&engine, "taken").unwrap().len(), 1);
+
+        // A rejected redefinition leaves the old rules in place.
+        assert!(assert_line(&mut engine, &mut cache,
+                            "redefine taken(X) :- free(X).").is_err());
+        assert_eq!(eval::rules(&engine, "taken").unwrap().len(), 1);
+
+        // Redefining the negated view so it no longer depends on the view
+        // negating it is fine.
+        assert_line(&mut engine, &mut cache,
+                    "redefine free(X) :- node(X).").unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "redefine taken(X) :- free(X).").unwrap();
+
+        // So is a view taking an extremum over a view depending on it.
+        for line in &["base(g, 5).", "link(5, 1).",
+                      "m(G, V) :- m2(G, V), min_by(G, V).",
+                      "m2(G, V) :- base(G, V)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let err = assert_line(&mut engine, &mut cache,
+                              "m2(G, V) :- m(G, W), link(W, V).")
+            .unwrap_err().to_string();
+        assert!(err.contains("m -> extremum of m2 -> m"));
+        assert_eq!(run(&engine, &cache, "m(G, V)?").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn mutual_recursion() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["zero(0).", "succ(0, 1).", "succ(1, 2).", "succ(2, 3).",
+                      "succ(3, 4).",
+                      "even(X) :- zero(X).",
+                      "even(X) :- succ(Y, X), odd(Y).",
+                      "odd(X) :- succ(Y, X), even(Y).",
+                      "both(X) :- even(X), odd(X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        let strata = eval::evaluation_order(&engine).unwrap();
+        let views: Vec<(Vec<String>, bool)> = strata.needed_by("both")
+            .into_iter().map(|s| (s.views.clone(), s.recursive)).collect();
+        assert_eq!(views, vec!((vec!("even".to_string(),
+                                     "odd".to_string()), true),
+                               (vec!("both".to_string()), false)));
+
+        // Views in the same stratum are evaluated together, by either loop.
+        let answers = |engine: &Engine, cache: &cache::ViewCache, query| {
+            let mut answers: Vec<String> = run(engine, cache, query).unwrap()
+                .into_iter().map(|frame| frame["X"].clone()).collect();
+            answers.sort();
+            answers
+        };
+        assert_eq!(answers(&engine, &cache, "even(X)?"), vec!("0", "2", "4"));
+        assert_eq!(answers(&engine, &cache, "odd(X)?"), vec!("1", "3"));
+        assert!(answers(&engine, &cache, "both(X)?").is_empty());
+        for semi_naive in &[false, true] {
+            let stats = eval::fixpoint_stats(&engine, &cache, "odd",
+                                             *semi_naive).unwrap();
+            let derived: usize = stats.iter().map(|s| s.delta).sum();
+            assert_eq!(derived, 5);
+        }
+        let term = parser::parse_query("odd(X)?").unwrap();
+        assert_eq!(eval::count_derivations(&engine, term).unwrap().len(), 2);
+
+        // Negating a view in the same stratum is still rejected.
+        let err = assert_line(&mut engine, &mut cache,
+                              "odd(X) :- succ(Y, X), not even(X).")
+            .unwrap_err().to_string();
+        assert!(err.contains("odd -> not even -> odd"));
+    }
+
+    #[test]
+    fn before() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["score(alice, 3).", "score(bob, 1).", "score(carol, 2).",
+                      "lower(X, Y) :- score(X, A), score(Y, B), before(A, B).",
+                      "not_lower(X, Y) :- score(X, A), score(Y, B), \
+                                          not before(A, B).",
+                      "beaten(X) :- score(X, A), score(Y, B), before(B, A).",
+                      "lowest(X) :- score(X, A), not beaten(X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        assert_eq!(run(&engine, &cache, "lower(X, Y)?").unwrap().len(), 3);
+        assert_eq!(run(&engine, &cache, "not_lower(X, Y)?").unwrap().len(),
+                   6);
+        let lowest = run(&engine, &cache, "lowest(X)?").unwrap();
+        assert_eq!(lowest.len(), 1);
+        assert_eq!(lowest[0]["X"], "bob");
+        let term = parser::parse_query("lowest(X)?").unwrap();
+        assert_eq!(eval::count_derivations(&engine, term).unwrap().len(), 1);
+
+        // Queried directly, both arguments must be given. Values of different
+        // types are ordered by type.
+        assert_eq!(run(&engine, &cache, "before(1, 2.5)?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "before(b, a)?").unwrap().len(), 0);
+        assert_eq!(run(&engine, &cache, "before(2, a)?").unwrap().len(), 1);
+        assert!(run(&engine, &cache, "before(X, 2)?").is_err());
+
+        assert!(assert_line(&mut engine, &mut cache,
+                            "bad(X) :- score(X, A), before(A, B).").is_err());
+        assert!(assert_line(&mut engine, &mut cache, "before(a, b).")
+                .is_err());
+    }
+
+    #[test]
+    fn contradictions() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["score(alice, 3).", "score(bob, 1).",
+                      "high(X) :- score(X, A), before(2, A).",
+                      "high(X) :- score(X, A), before(3, 1).",
+                      "high(X) :- high(X), fail.",
+                      "none(X) :- missing(X), not true.",
+                      "none(X) :- missing(X), before(A, A), missing(A).",
+                      "all(X) :- score(X, A), not before(2, 1)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+
+        // Rules that can never hold are skipped, so the relations they use
+        // are never read, even when they don't exist.
+        let rules = eval::rules(&engine, "high").unwrap();
+        let reasons: Vec<Option<String>> = rules.iter().map(|r| {
+            eval::contradiction(&engine, &r.body)
+        }).collect();
+        assert_eq!(reasons, vec!(None,
+                                 Some("before(3, 1) never holds".to_string()),
+                                 Some("fail never holds".to_string())));
+        assert_eq!(run(&engine, &cache, "high(X)?").unwrap().len(), 1);
+        let term = parser::parse_query("high(X)?").unwrap();
+        assert_eq!(eval::count_derivations(&engine, term).unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "none(X)?").unwrap().len(), 0);
+        assert_eq!(run(&engine, &cache, "all(X)?").unwrap().len(), 2);
+
+        // Using a relation with the wrong arity is reported, but still an
+        // error when queried.
+        assert_line(&mut engine, &mut cache, "bad(X) :- score(X, A, B).")
+            .unwrap();
+        let rule = &eval::rules(&engine, "bad").unwrap()[0];
+        assert_eq!(eval::contradiction(&engine, &rule.body).unwrap(),
+                   "score has arity 2, so score(X, A, B) never holds");
+        assert!(run(&engine, &cache, "bad(X)?").is_err());
+    }
+
+    #[test]
+    fn plan_shapes() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["e(a, b).", "f(b, c).",
+                      "v(X, Z) :- e(X, Y), f(Y, Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let shape = |engine: &StorageEngine<eval::AstView>| {
+            let query = parser::parse_query("v(X, Z)?").unwrap();
+            eval::plan_shape(engine, query).unwrap()
+        };
+
+        // Shapes leave out rows and times, and don't depend on the cache.
+        let before = shape(&engine);
+        assert_eq!(before, "v(X, Z) [view]\n  e(X, Y) [table]\n  \
+                            f(Y, Z) [table]\n");
+        assert_eq!(run(&engine, &cache, "v(X, Z)?").unwrap().len(), 1);
+        assert_eq!(shape(&engine), before);
+
+        assert_line(&mut engine, &mut cache,
+                    "redefine v(X, Z) :- f(Y, Z), e(X, Y).").unwrap();
+        assert_eq!(shape(&engine), "v(X, Z) [view]\n  f(Y, Z) [table]\n  \
+                                    e(X, Y) [table]\n");
+    }
+
+    #[test]
+    fn index_advisor() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        // Each person has one of 100 ids, and one of 2 teams.
+        let people = (0..2000).map(|i| vec!(
+            value::Value::from(format!("p{}", i % 100).as_str()),
+            value::Value::from(if i % 2 == 0 { "red" } else { "blue" })
+        )).collect();
+        engine.assert_facts("person".to_string(), 2, people).unwrap();
+        engine.assert_facts("small".to_string(), 1,
+                            vec!(vec!(value::Value::from("x")))).unwrap();
+        let decisions = |engine: &Engine| {
+            advisor::advise(engine).into_iter().map(|a| {
+                (a.relation, a.column, a.decision)
+            }).collect::<Vec<_>>()
+        };
+
+        advisor::reset();
+        assert_eq!(run(&engine, &cache, "person(p1, T)?").unwrap().len(), 20);
+        assert_eq!(decisions(&engine),
+                   vec!(("person".to_string(), 0, advisor::Decision::Rare)));
+        for _ in 0..advisor::MIN_LOOKUPS {
+            run(&engine, &cache, "person(p1, T)?").unwrap();
+            run(&engine, &cache, "person(P, red)?").unwrap();
+            run(&engine, &cache, "small(x)?").unwrap();
+        }
+        assert_eq!(decisions(&engine), vec!(
+            ("person".to_string(), 0, advisor::Decision::Index),
+            ("person".to_string(), 1, advisor::Decision::Unselective),
+            ("small".to_string(), 0, advisor::Decision::Small)));
+
+        // Indexed scans give the same answers, and show in plans.
+        let query = parser::parse_query("person(p1, T)?").unwrap();
+        assert_eq!(eval::plan_shape(&engine, query.clone()).unwrap(),
+                   "person(p1, T) [table]\n");
+        assert!(eval::set_index(&mut engine, "person", 0, true).unwrap());
+        assert_eq!(eval::plan_shape(&engine, query).unwrap(),
+                   "person(p1, T) [index]\n");
+        assert_eq!(run(&engine, &cache, "person(p1, T)?").unwrap().len(), 20);
+        assert_eq!(run(&engine, &cache, "person(p1, blue)?").unwrap().len(),
+                   20);
+        assert_eq!(decisions(&engine)[0].2, advisor::Decision::Indexed);
+        assert!(eval::set_index(&mut engine, "person", 2, true).is_err());
+        assert!(eval::set_index(&mut engine, "nobody", 0, true).is_err());
+
+        advisor::reset();
+        assert!(decisions(&engine).is_empty());
+        assert_line(&mut engine, &mut cache, "team(P) :- person(P, red).")
+            .unwrap();
+        assert!(eval::set_index(&mut engine, "team", 0, true).is_err());
+    }
+
+    #[test]
+    fn shared_variables() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["r(a, 1).", "r(b, 2).", "r(c, 1).",
+                      "s(1, x).", "s(2, y).", "s(1, z).",
+                      "t(a, u).", "t(b, v).", "t(d, w).",
+                      "q(X, Y, Z, W) :- r(X, Y), s(Y, Z), t(X, W).",
+                      "pair(X, W) :- r(X, Y), t(Z, W)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let answers = |query: &str, variables: &[&str]| {
+            let mut answers: Vec<String> = run(&engine, &cache, query).unwrap()
+                .into_iter().map(|frame| {
+                    variables.iter().map(|v| frame[*v].clone())
+                             .collect::<Vec<_>>().join(" ")
+                }).collect();
+            answers.sort();
+            answers
+        };
+
+        // `r` shares both of its variables with the join of `s` and `t`,
+        // which share none, and so are joined as a cross product.
+        assert_eq!(answers("q(X, Y, Z, W)?", &["X", "Y", "Z", "W"]),
+                   vec!("a 1 x u", "a 1 z u", "b 2 y v"));
+        assert_eq!(answers("pair(X, W)?", &["X", "W"]),
+                   vec!("a u", "a v", "a w", "b u", "b v", "b w",
+                        "c u", "c v", "c w"));
+    }
+
+    #[test]
+    fn join_strategies() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let edges = (0..300).map(|i| vec!(
+            value::Value::from(format!("n{}", i % 40).as_str()),
+            value::Value::from(format!("n{}", i % 70).as_str())
+        )).collect();
+        engine.assert_facts("edge".to_string(), 2, edges).unwrap();
+        assert_line(&mut engine, &mut cache,
+                    "hop2(X, Z) :- edge(X, Y), edge(Y, Z).").unwrap();
+        let answers = |engine: &Engine| {
+            let query = parser::parse_query("hop2(X, Z)?").unwrap();
+            let roots = eval::explain_analyze(engine, &cache::ViewCache::new(),
+                                              query.clone()).unwrap();
+            let joins = roots[0].borrow().joins.keys().cloned()
+                                .collect::<Vec<_>>();
+            // The frames are read only once the plan is dropped, so values
+            // read back from spilled frames must outlive it.
+            let cache = cache::ViewCache::new();
+            let frames: Vec<eval::Frame> = eval::query(engine, &cache, query)
+                .unwrap().collect();
+            let mut answers: Vec<Vec<String>> = frames.iter().map(|frame| {
+                frame.values().map(|v| v.to_string()).collect()
+            }).collect();
+            answers.sort();
+            (joins, answers)
+        };
+
+        let (joins, nested) = answers(&engine);
+        assert_eq!(joins, vec!("nested_loop"));
+        assert!(!nested.is_empty());
+
+        spill::configure(spill::JoinConfig {
+            nested_loop_max: 10,
+            .. Default::default()
+        });
+        let (joins, hashed) = answers(&engine);
+        assert_eq!(joins, vec!("hash"));
+        assert_eq!(hashed, nested);
+
+        // Over budget, both sides are spilled, and partitions still over
+        // budget are joined a block at a time. The budget wins over the
+        // nested loop threshold.
+        for (nested_loop_max, budget) in &[(10, 100), (10, 3), (1000, 100)] {
+            spill::configure(spill::JoinConfig {
+                nested_loop_max: *nested_loop_max,
+                memory_budget: *budget,
+                partitions: 4
+            });
+            let (joins, spilled) = answers(&engine);
+            assert_eq!(joins, vec!("grace"));
+            assert_eq!(spilled, nested);
+        }
+
+        // Values read back are kept once each until freed, however many
+        // frames hold them: here, the names of at most 70 nodes.
+        let mut kept = cache::ViewCache::new();
+        let query = parser::parse_query("hop2(X, Z)?").unwrap();
+        assert_eq!(eval::query(&engine, &kept, query).unwrap().count(),
+                   nested.len());
+        assert!(kept.spilled_len() > 0 && kept.spilled_len() <= 70);
+        kept.free_spilled();
+        assert_eq!(kept.spilled_len(), 0);
+        spill::configure(Default::default());
+    }
+
+    #[test]
+    fn materialized_views() {
+        let dir = test_dir("_test_materialized_views_dir");
+        let mut engine = StorageEngine::new(dir.to_string()).unwrap();
+        let mut cache = cache::ViewCache::new();
+        let retract = |engine: &mut Engine, cache: &mut cache::ViewCache,
+                       line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Rule(r) => eval::retract(engine, cache, r),
+                _ => panic!("parsed retraction as something else")
+            }
+        };
+        // `reach` has the same rules as `path`, but isn't materialized, so
+        // the two always have the same tuples.
+        let paths = |engine: &Engine, cache: &cache::ViewCache| {
+            let tuples = |query| {
+                let mut tuples: Vec<Vec<String>> =
+                    run(engine, cache, query).unwrap().into_iter()
+                        .map(|frame| frame.values().cloned().collect())
+                        .collect();
+                tuples.sort();
+                tuples
+            };
+            let path = tuples("path(X, Y)?");
+            assert_eq!(path, tuples("reach(X, Y)?"));
+            assert!(!cache.is_outdated("path"));
+            path.len()
+        };
+        for line in &["path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- edge(X, Y), path(Y, Z).",
+                      "reach(X, Y) :- edge(X, Y).",
+                      "reach(X, Z) :- edge(X, Y), reach(Y, Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        for i in 0..20 {
+            assert_line(&mut engine, &mut cache,
+                        &format!("edge(n{}, n{}).", i, i + 1)).unwrap();
+        }
+        assert_eq!(eval::materialize(&mut engine, &mut cache, "path").unwrap(),
+                   210);
+        assert!(eval::materialize(&mut engine, &mut cache, "edge").is_err());
+        let query = parser::parse_query("path(n3, X)?").unwrap();
+        assert_eq!(eval::plan_shape(&engine, query).unwrap(),
+                   "path(n3, X) [materialized]\n");
+
+        // New facts are followed through the rules, cycles included.
+        assert_line(&mut engine, &mut cache, "edge(n20, n5).").unwrap();
+        assert_eq!(paths(&engine, &cache), 346);
+        assert_line(&mut engine, &mut cache, "edge(n2, n9).").unwrap();
+        assert_line(&mut engine, &mut cache, "edge(n9, x).").unwrap();
+        assert_eq!(paths(&engine, &cache), 367);
+
+        // So are retracted ones, keeping what can still be derived.
+        retract(&mut engine, &mut cache, "edge(n20, n5).").unwrap();
+        assert_eq!(paths(&engine, &cache), 220);
+        retract(&mut engine, &mut cache, "edge(n9, x).").unwrap();
+        assert_eq!(paths(&engine, &cache), 210);
+        assert_line(&mut engine, &mut cache, "edge(n0, n1).").unwrap();
+        retract(&mut engine, &mut cache, "edge(n0, n1).").unwrap();
+        assert_eq!(paths(&engine, &cache), 210);
+
+        // Other changes outdate the materialization until it is refreshed,
+        // and meanwhile the view is evaluated from its rules.
+        assert_line(&mut engine, &mut cache, "path(X, Y) :- edge(Y, X).")
+            .unwrap();
+        assert_line(&mut engine, &mut cache, "reach(X, Y) :- edge(Y, X).")
+            .unwrap();
+        assert!(cache.is_outdated("path"));
+        assert_eq!(run(&engine, &cache, "path(X, Y)?").unwrap().len(), 256);
+        eval::refresh_materialized(&mut engine, &cache).unwrap();
+        assert_eq!(paths(&engine, &cache), 256);
+        engine.write_back();
+        assert_line(&mut engine, &mut cache, "edge(n20, x).").unwrap();
+        engine.write_back();
+        std::mem::drop(engine);
+
+        // The materialization is read back, along with the tuples added since
+        // it was last written.
+        let mut engine = StorageEngine::new(dir.to_string()).unwrap();
+        let mut cache = cache::ViewCache::new();
+        eval::initialize_view_cache(&engine, &mut cache);
+        assert_eq!(engine.materialized(), vec!("path"));
+        assert_eq!(engine.materialization("path").unwrap().len(), 279);
+        assert_eq!(paths(&engine, &cache), 279);
+        assert!(eval::dematerialize(&mut engine, &cache, "path"));
+        engine.write_back();
+        let files: Vec<String> = std::fs::read_dir(dir).unwrap().map(|entry| {
+            entry.unwrap().file_name().into_string().unwrap()
+        }).filter(|name| name.starts_with("path")).collect();
+        assert_eq!(files, vec!("path"));
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn query_limits() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "edge(b, c).", "edge(c, d).",
+                      "edge(d, a).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let count = |query: &str, limits: &limits::Limits| {
+            let term = parser::parse_query(query).unwrap();
+            eval::query_limited(&engine, &cache, term, &Default::default(),
+                                limits, |frames| frames.count())
+        };
+
+        let none = limits::Limits::default();
+        let rows = limits::Limits { max_rows: Some(3), .. none };
+        match count("edge(X, Y)?", &rows) {
+            Err(error::Error::LimitExceeded(limits::Limit::Rows(3))) => (),
+            other => panic!("expected too many rows, got {:?}", other)
+        }
+        assert_eq!(count("edge(a, Y)?", &rows).unwrap(), 1);
+
+        // The recursive view is stopped while deriving, and isn't cached.
+        let derived = limits::Limits { max_derived: Some(10), .. none };
+        match count("path(X, Y)?", &derived) {
+            Err(error::Error::LimitExceeded(limits::Limit::Derived(10))) => (),
+            other => panic!("expected too many tuples derived, got {:?}",
+                            other)
+        }
+        let time = limits::Limits {
+            timeout: Some(std::time::Duration::from_millis(0)),
+            .. none
+        };
+        match count("path(X, Y)?", &time) {
+            Err(error::Error::LimitExceeded(limits::Limit::Time(_))) => (),
+            other => panic!("expected a timeout, got {:?}", other)
+        }
+        assert_eq!(count("path(X, Y)?", &derived).unwrap_err().to_string(),
+                   "limit exceeded: more than 10 tuples derived");
+        assert_eq!(count("path(X, Y)?", &none).unwrap(), 16);
+    }
+
+    #[test]
+    fn path_builtins() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "edge(b, c).", "edge(c, a).",
+                      "edge(c, d).", "edge(a, d).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z).",
+                      "reach(X, Y) :- reachable(X, Y, V).",
+                      "far(X, Y) :- shortest_path_len(X, Y, N), \
+                       before(1, N)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let tuples = |engine: &Engine, cache: &cache::ViewCache, query| {
+            let mut tuples: Vec<Vec<String>> =
+                run(engine, cache, query).unwrap().into_iter()
+                    .map(|frame| frame.values().cloned().collect())
+                    .collect();
+            tuples.sort();
+            tuples
+        };
+
+        // The same nodes are reachable as the recursive view finds, whether
+        // or not the source is given.
+        assert_eq!(tuples(&engine, &cache, "reach(X, Y)?"),
+                   tuples(&engine, &cache, "path(X, Y)?"));
+        assert_eq!(tuples(&engine, &cache, "reachable(b, Y, V)?"),
+                   vec!(vec!("a", "b"), vec!("b", "c"), vec!("c", "a"),
+                        vec!("c", "d")));
+        assert_eq!(tuples(&engine, &cache, "shortest_path_len(a, d, N)?"),
+                   vec!(vec!("1")));
+        assert_eq!(tuples(&engine, &cache, "shortest_path_len(a, a, N)?"),
+                   vec!(vec!("3")));
+        assert_eq!(tuples(&engine, &cache, "far(b, Y)?"),
+                   vec!(vec!("a"), vec!("b"), vec!("d")));
+
+        // Each path found is one derivation.
+        let derivations = |engine: &Engine, query| {
+            let term = parser::parse_query(query).unwrap();
+            eval::count_derivations(engine, term).unwrap().into_iter()
+                .map(|(values, n)| (values[0].to_string(), n))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(derivations(&engine, "reach(a, Y)?"),
+                   vec!(("a".to_string(), 1), ("b".to_string(), 1),
+                        ("c".to_string(), 1), ("d".to_string(), 1)));
+        assert_eq!(derivations(&engine, "far(b, Y)?"),
+                   vec!(("a".to_string(), 1), ("b".to_string(), 1),
+                        ("d".to_string(), 1)));
+
+        // Views using them follow changes to the edges.
+        assert_line(&mut engine, &mut cache, "edge(b, a).").unwrap();
+        assert_eq!(tuples(&engine, &cache, "far(b, Y)?"),
+                   vec!(vec!("b"), vec!("d")));
+
+        // Another table can be designated, with lengths for Dijkstra's
+        // algorithm.
+        for line in &["road(a, b, 5).", "road(b, c, 1).", "road(a, c, 2.5)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        graph::designate("road");
+        cache.invalidate(cache::EVERY_RELATION);
+        assert_eq!(tuples(&engine, &cache, "shortest_path_len(a, Y, N)?"),
+                   vec!(vec!("2.5", "c"), vec!("5", "b")));
+        assert_eq!(tuples(&engine, &cache, "far(a, Y)?"),
+                   vec!(vec!("b"), vec!("c")));
+        graph::designate("object");
+        assert!(run(&engine, &cache, "reachable(a, Y, V)?").is_err());
+        graph::designate(graph::DEFAULT_EDGES);
+        assert!(assert_line(&mut engine, &mut cache, "reachable(a, b, a).")
+                    .is_err());
+    }
+
+    #[test]
+    fn partial_answers() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "edge(b, c).", "edge(c, d).",
+                      "edge(d, a).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let within = |line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            let (term, budget) = match Parser::new(lexer).next().unwrap() {
+                Ok(ast::Line::Within(term, budget)) => (term, budget),
+                other => panic!("expected a query with a budget, got {:?}",
+                                other)
+            };
+            let limits = limits::Limits {
+                budget: Some(budget),
+                .. Default::default()
+            };
+            eval::query_partial(&engine, &cache, term, &Default::default(),
+                                &limits, |frames| frames.count()).unwrap()
+        };
+
+        // Out of time, the view is stopped before its recursive rules are
+        // applied, and its partial tuples aren't cached.
+        let (_, partial) = within("path(X, Y)? within 0ms");
+        let partial = partial.expect("expected partial answers");
+        assert_eq!(partial.views, vec!(("path".to_string(), 0)));
+        assert_eq!(partial.to_string(),
+                   "partial answers after 0ms: path reached iteration 0");
+        assert!(cache.read_cache("path", &[None, None]).is_none());
+
+        assert_eq!(within("path(X, Y)? within 1m"), (16, None));
+        assert_eq!(within("path(a, Y)? within 1m"), (4, None));
+    }
+
+    #[test]
+    fn sandbox() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "edge(b, c).", "secret(a).",
+                      "admin(X) :- admin_override(X)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let mut sandbox = Sandbox::new(vec!["edge".to_string()],
+                                       limits::Limits::default());
+        let mut assert = |sandbox: &mut Sandbox, engine: &mut Engine,
+                          line: &str| {
+            let lexer = Lexer::new(line.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Rule(r) => sandbox.assert(engine, &mut cache, r),
+                _ => panic!("parsed assertion as something else")
+            }
+        };
+        let refused = |result: error::Result<()>| match result {
+            Err(error::Error::Sandbox(_)) => (),
+            other => panic!("expected a sandbox error, got {:?}", other)
+        };
+
+        // Existing relations can't be changed, even those that may be read.
+        refused(assert(&mut sandbox, &mut engine, "edge(c, d)."));
+        refused(assert(&mut sandbox, &mut engine, "secret(b)."));
+        refused(assert(&mut sandbox, &mut engine, "leak(X) :- secret(X)."));
+        refused(assert(&mut sandbox, &mut engine,
+                       "leak(X) :- not secret(X), edge(X, Y)."));
+        refused(assert(&mut sandbox, &mut engine,
+                       "leak(X) :- holds(secret, X)."));
+        assert!(engine.get_relation("leak").is_none());
+
+        // Nor can relations that existing views use be created.
+        refused(assert(&mut sandbox, &mut engine, "admin_override(eve)."));
+        refused(assert(&mut sandbox, &mut engine,
+                       "admin_override(X) :- edge(X, Y)."));
+        assert!(engine.get_relation("admin_override").is_none());
+
+        // The program's own relations can be added to and read.
+        for line in &["hop(X, Y) :- edge(X, Y).",
+                      "hop(X, Z) :- hop(X, Y), edge(Y, Z).",
+                      "mine(z)."] {
+            assert(&mut sandbox, &mut engine, line).unwrap();
+        }
+        let mut created: Vec<&str> = sandbox.created().collect();
+        created.sort();
+        assert_eq!(created, vec!["hop", "mine"]);
+
+        let engine = engine;
+        let count = |sandbox: &Sandbox, query: &str| {
+            let term = parser::parse_query(query).unwrap();
+            sandbox.query(&engine, &cache, term, |frames| frames.count())
+        };
+        assert_eq!(count(&sandbox, "hop(a, Y)?").unwrap(), 2);
+        assert_eq!(count(&sandbox, "mine(X)?").unwrap(), 1);
+        match count(&sandbox, "secret(X)?") {
+            Err(error::Error::Sandbox(_)) => (),
+            other => panic!("expected a sandbox error, got {:?}", other)
+        }
+
+        // Queries are held to the sandbox's limits.
+        let strict = Sandbox::new(
+            vec!["edge".to_string()],
+            limits::Limits { max_rows: Some(1), .. Default::default() });
+        match count(&strict, "edge(X, Y)?") {
+            Err(error::Error::LimitExceeded(limits::Limit::Rows(1))) => (),
+            other => panic!("expected too many rows, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn derivation_counts() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b).", "edge(b, c).", "edge(a, c).",
+                      "edge(c, d).",
+                      "path(X, Y) :- edge(X, Y).",
+                      "path(X, Z) :- path(X, Y), edge(Y, Z)."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        let count = |engine: &Engine, query: &str| {
+            let term = parser::parse_query(query).unwrap();
+            eval::count_derivations(engine, term).map(|answers| {
+                answers.into_iter().map(|(values, n)| {
+                    let values: Vec<String> =
+                        values.iter().map(|v| v.to_string()).collect();
+                    (values.join(" "), n)
+                }).collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(count(&engine, "path(a, Y)?").unwrap(),
+                   vec![("b".to_string(), 1),
+                        ("c".to_string(), 2),
+                        ("d".to_string(), 2)]);
+        assert_eq!(count(&engine, "path(a, d)?").unwrap(),
+                   vec![("".to_string(), 2)]);
+        assert_eq!(count(&engine, "edge(X, X)?").unwrap(), vec![]);
+
+        // A cycle gives some paths infinitely many derivations.
+        assert_line(&mut engine, &mut cache, "edge(d, a).").unwrap();
+        assert!(count(&engine, "path(X, Y)?").is_err());
+    }
+
+    #[test]
+    fn kind_collisions() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let query = "simple_sentence(SUBJECT, VERB, OBJECT)?";
+        let sorted = |engine: &Engine, cache: &cache::ViewCache| {
+            let mut frames = run(engine, cache, query).unwrap();
+            frames.sort();
+            frames
+        };
+        let sentences = sorted(&engine, &cache);
+
+        assert!(assert_line(&mut engine, &mut cache,
+                            "simple_sentence(i, eat, it).").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "object(X) :- case(P, X).").is_err());
+        assert!(assert_line(&mut engine, &mut cache, "object(a, b).")
+                .is_err());
+        assert_eq!(sorted(&engine, &cache), sentences);
+        assert_eq!(run(&engine, &cache, "object(X)?").unwrap().len(), 2);
+
+        eval::convert(&mut engine, &mut cache, "simple_sentence").unwrap();
+        match engine.get_relation("simple_sentence") {
+            Some(Relation::Extension(t)) =>
+                assert_eq!(t.len(), sentences.len()),
+            _ => panic!("view was not converted to a table")
+        }
+        assert_eq!(sorted(&engine, &cache), sentences);
+        assert_line(&mut engine, &mut cache, "simple_sentence(i, eat, it).")
+            .unwrap();
+        assert_eq!(run(&engine, &cache, query).unwrap().len(),
+                   sentences.len() + 1);
+    }
+
+    #[test]
+    fn sorted_frames() {
+        let engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = parser::parse_query("simple_sentence(S, V, O)?").unwrap();
+        let columns = query.variables();
+        let frames = eval::sorted(eval::query(&engine, &cache, query).unwrap(),
+                                  &columns);
+        let tuples: Vec<Vec<String>> = frames.map(|frame| {
+            columns.iter().map(|c| frame[c].to_string()).collect()
+        }).collect();
+
+        let mut expected = tuples.clone();
+        expected.sort();
+        assert_eq!(tuples.len(), 18);
+        assert_eq!(tuples, expected);
+        assert_eq!(tuples[0], vec!("he", "eats", "him"));
+    }
+
+    #[test]
+    fn create_table() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let query = |q: &str| parser::parse_query(q).unwrap();
+
+        let len = eval::create_table(&mut engine, &mut cache, "sentences",
+                                     query("simple_sentence(S, V, it)?"))
+            .unwrap();
+        assert_eq!(len, 9);
+        assert_eq!(run(&engine, &cache, "sentences(he, V)?").unwrap().len(),
+                   3);
+        assert_line(&mut engine, &mut cache, "sentences(you, eat).").unwrap();
+        assert_eq!(run(&engine, &cache, "sentences(S, V)?").unwrap().len(),
+                   10);
+
+        assert!(eval::create_table(&mut engine, &mut cache, "sentences",
+                                   query("object(X)?")).is_err());
+        assert!(eval::create_table(&mut engine, &mut cache, "Objects",
+                                   query("object(X)?")).is_err());
+        assert!(eval::create_table(&mut engine, &mut cache, "true",
+                                   query("object(X)?")).is_err());
+    }
+
+    #[test]
+    fn copy_relation() {
+        let source = StorageEngine::open_read_only(
+            "test_data/hierarchy".to_string()).unwrap();
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let facts = run(&source, &cache, "employee_hierarchy(A, B, C, D)?")
+            .unwrap().len();
+
+        assert_eq!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                       "employee_hierarchy").unwrap(),
+                   facts);
+        assert_eq!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                       "underling").unwrap(),
+                   2);
+        let sorted = |engine: &Engine| {
+            let mut frames = run(engine, &cache, "underling(L, H)?").unwrap();
+            frames.sort();
+            frames
+        };
+        assert_eq!(sorted(&engine), sorted(&source));
+
+        // Copying again adds nothing new.
+        assert_eq!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                       "employee_hierarchy").unwrap(),
+                   0);
+        assert_eq!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                       "underling").unwrap(),
+                   0);
+
+        assert_line(&mut engine, &mut cache, "reports(a, b).").unwrap();
+        assert!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                    "reports").is_err());
+        assert!(eval::copy_relation(&mut engine, &mut cache, &source,
+                                    "nonexistent").is_err());
+        assert!(StorageEngine::<eval::AstView>::open_read_only(
+                "test_data/nonexistent".to_string()).is_err());
+    }
+
+    #[test]
+    fn bulk_assert() {
+        let mut engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let facts = |text: &str| text.split(';').map(|f| {
+            parser::parse_query(&format!("{}?", f)).unwrap()
+        }).collect();
+
+        // Populate the cache for a view of `object`.
+        assert_line(&mut engine, &mut cache, "thing(X) :- object(X).").unwrap();
+        assert_eq!(run(&engine, &cache, "thing(X)?").unwrap().len(), 2);
+
+        assert_eq!(eval::bulk_assert(&mut engine, &mut cache,
+                                     facts("object(a); new(a, 1); new(b, 2)"))
+                       .unwrap(),
+                   3);
+        assert_eq!(run(&engine, &cache, "thing(X)?").unwrap().len(), 3);
+        assert_eq!(run(&engine, &cache, "new(X, Y)?").unwrap().len(), 2);
+
+        // Nothing is added if any fact is bad.
+        assert!(eval::bulk_assert(&mut engine, &mut cache,
+                                  facts("object(b); new(c)")).is_err());
+        assert!(eval::bulk_assert(&mut engine, &mut cache,
+                                  facts("object(b); thing(c)")).is_err());
+        assert_eq!(run(&engine, &cache, "object(X)?").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn batched_query() {
+        use eval::Plan;
+
+        let engine = StorageEngine::new("test_data/grammar".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = "simple_sentence(SUBJECT, VERB, OBJECT)?";
+        let term = match Parser::new(Lexer::new(query.chars())
+                                         .map(Result::unwrap))
+                       .next().unwrap().unwrap() {
+            ast::Line::Query(t) => t,
+            _ => panic!("parsed query as assertion")
+        };
+
+        let one_at_a_time: Vec<_> =
+            eval::query(&engine, &cache, term.clone()).unwrap().collect();
+
+        let mut plan = eval::query(&engine, &cache, term).unwrap();
+        let mut batched = Vec::new();
+        loop {
+            let batch = plan.next_batch(5);
+            assert!(batch.len() <= 5);
+            if batch.is_empty() {
+                break;
+            }
+            batched.extend(batch);
+        }
+
+        assert_eq!(one_at_a_time.len(), 18);
+        assert_eq!(batched.len(), one_at_a_time.len());
+        for frame in &one_at_a_time {
+            assert!(batched.contains(frame));
+        }
+    }
+
+    #[test]
+    fn bound_view_queries() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let id = value::Value::Symbol("id_10005".to_string());
+
+        let mut bound = run(&engine, &cache, "underling(id_10005, X)?")
+            .unwrap();
+        bound.sort();
+        assert!(!bound.is_empty());
+        let pattern = vec![Some(id.clone()), None];
+        assert!(cache.read_cache("underling", &pattern).is_some());
+        assert!(cache.read_cache("underling", &[None, None]).is_none());
+
+        // Repeating the query is served from its own cache entry.
+        let mut cached = run(&engine, &cache, "underling(id_10005, X)?")
+            .unwrap();
+        cached.sort();
+        assert_eq!(bound, cached);
+
+        // Other bindings and the whole view aren't answered from it.
+        run(&engine, &cache, "underling(id_10006, X)?").unwrap();
+        let other = value::Value::Symbol("id_10006".to_string());
+        assert!(cache.read_cache("underling", &[Some(other), None]).is_some());
+        let all = run(&engine, &cache, "underling(Y, X)?").unwrap();
+        let mut filtered: Vec<_> = all.into_iter().filter(|f| {
+            f["Y"] == "id_10005"
+        }).map(|mut f| {
+            f.remove("Y");
+            f
+        }).collect();
+        filtered.sort();
+        assert_eq!(bound, filtered);
+    }
+
+    #[test]
+    fn fixpoint_stats() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let total = run(&engine, &cache, "underling(X, Y)?").unwrap().len();
+
+        for &semi_naive in &[false, true] {
+            let stats = eval::fixpoint_stats(&engine, &cache, "underling",
+                                             semi_naive).unwrap();
+            assert!(stats.len() > 1);
+            assert_eq!(stats[0].rules_applied, 0);
+            assert_eq!(stats.last().unwrap().delta, 0);
+            assert_eq!(stats.iter().map(|s| s.delta).sum::<usize>(), total);
+            assert!(stats[1..].iter().all(|s| s.rules_applied > 0));
+        }
+
+        let reports = eval::fixpoint_stats(&engine, &cache, "reports", false)
+            .unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(eval::fixpoint_stats(&engine, &cache, "employee_hierarchy",
+                                     false).is_err());
+    }
+
+    #[test]
+    fn abandoned_scans_not_cached() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = || parser::parse_query("underling(X, Y)?").unwrap();
+
+        {
+            let mut frames = eval::query(&engine, &cache, query()).unwrap();
+            assert!(frames.next().is_some());
+        }
+        assert!(cache.read_cache("underling", &[None, None]).is_none());
+
+        let total = eval::query(&engine, &cache, query()).unwrap().count();
+        let cached = cache.read_cache("underling", &[None, None]).unwrap();
+        assert_eq!(cached.len(), total);
+        assert_eq!(eval::query(&engine, &cache, query()).unwrap().count(),
+                   total);
+    }
+
+    #[test]
+    fn explain_analyze() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let query = || parser::parse_query("underling(X, Y)?").unwrap();
+
+        let roots = eval::explain_analyze(&engine, &cache, query()).unwrap();
+        assert_eq!(roots.len(), 1);
+        let root = roots[0].borrow();
+        let total = run(&engine, &cache, "underling(X, Y)?").unwrap().len();
+        assert_eq!(root.rows, total);
+        assert_eq!((root.kind, root.cache_hit), ("view", Some(false)));
+        let tables: Vec<_> = root.children.iter().map(|c| c.borrow()).collect();
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|c| c.kind == "table" && c.rows > 0));
+
+        // The first run cached the view.
+        let roots = eval::explain_analyze(&engine, &cache, query()).unwrap();
+        let root = roots[0].borrow();
+        assert_eq!((root.rows, root.cache_hit), (total, Some(true)));
+        assert!(root.children.is_empty());
+
+        // Nothing is recorded outside of `explain_analyze`.
+        assert!(profile::enter(&"underling(X, Y)").is_none());
+    }
+
+    #[test]
+    fn fixpoints_planned_once() {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        assert_line(&mut engine, &mut cache,
+                    "peer(X, Y) :- underling(X, Z), underling(Y, Z).")
+            .unwrap();
+        let query = parser::parse_query("peer(X, Y)?").unwrap();
+
+        // Only the first term referring to the recursive view evaluates it.
+        let roots = eval::explain_analyze(&engine, &cache, query).unwrap();
+        let root = roots[0].borrow();
+        let views: Vec<_> = root.children.iter().map(|c| c.borrow()).collect();
+        assert_eq!(views.len(), 2);
+        assert!(!views[0].children.is_empty());
+        assert!(views[1].children.is_empty());
+        assert_eq!(views[0].rows, views[1].rows);
+        assert!(cache.fixpoint("underling").is_none());
+    }
+
+    #[test]
+    fn registered_builtins() {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        eval::register_builtin(&engine, "pay", vec![true, false], |args| {
+            match args[0] {
+                value::Value::Symbol(ref s) if s.starts_with("salary_") =>
+                    s[7..].parse().map(|i| vec![vec![value::Value::Int(i)]])
+                          .unwrap_or_default(),
+                _ => Vec::new()
+            }
+        }).unwrap();
+        assert!(eval::is_builtin("pay"));
+        assert!(eval::register_builtin(&engine, "before", vec![], |_| {
+            Vec::new()
+        }).is_err());
+        assert!(eval::register_builtin(&engine, "reports", vec![], |_| {
+            Vec::new()
+        }).is_err());
+
+        // The output argument is bound for the rest of the body.
+        assert_line(&mut engine, &mut cache,
+                    "paid(E, P) :- employee_hierarchy(E, N, S, M), \
+                     pay(S, P), before(100000, P).").unwrap();
+        let paid = run(&engine, &cache, "paid(E, P)?").unwrap();
+        assert!(!paid.is_empty());
+        assert!(paid.iter().any(|f| {
+            f["E"] == "id_10001" && f["P"] == "105000"
+        }));
+        assert!(paid.iter().all(|f| f["P"].parse::<i64>().unwrap() > 100000));
+        assert_line(&mut engine, &mut cache,
+                    "unpaid(E) :- employee_hierarchy(E, N, S, M), \
+                     not pay(S, S).").unwrap();
+        assert_eq!(run(&engine, &cache, "unpaid(E)?").unwrap().len(),
+                   run(&engine, &cache, "reports(E, M)?").unwrap().len());
+
+        // Queried directly, it needs its input given.
+        let answers = run(&engine, &cache, "pay(salary_5, P)?").unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0]["P"], "5");
+        assert!(run(&engine, &cache, "pay(S, P)?").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "bad(P) :- pay(S, P).").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "bad(P) :- pay(salary_5, 2, P).").is_err());
+    }
+
+    #[test]
+    fn virtual_tables() {
+        use std::sync::{Arc, Mutex};
+
+        struct Shared(Arc<Mutex<Vec<Vec<value::Value>>>>);
+
+        impl VirtualTable for Shared {
+            fn arity(&self) -> usize {
+                2
+            }
+
+            fn facts(&self) -> error::Result<Vec<Vec<value::Value>>> {
+                Ok(self.0.lock().unwrap().clone())
+            }
+        }
+
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        let fact = |a: &str, b: &str| vec![value::Value::from(a),
+                                           value::Value::from(b)];
+        let facts = Arc::new(Mutex::new(vec![fact("a", "b"), fact("b", "c")]));
+        engine.attach("link".to_string(), Box::new(Shared(facts.clone())))
+              .unwrap();
+
+        // Virtual tables are scanned like tables, in rules as well.
+        assert_line(&mut engine, &mut cache,
+                    "linked(X, Z) :- link(X, Y), link(Y, Z).").unwrap();
+        assert_eq!(run(&engine, &cache, "link(a, X)?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "linked(X, Z)?").unwrap().len(), 1);
+        assert!(eval::is_known(&engine, &cache,
+                               &parser::parse_query("link(b, c)?").unwrap())
+                    .unwrap());
+
+        // Views cached from them are recomputed once invalidated.
+        facts.lock().unwrap().push(fact("c", "d"));
+        cache.invalidate("link");
+        assert_eq!(run(&engine, &cache, "linked(X, Z)?").unwrap().len(), 2);
+
+        // They can't be changed.
+        assert!(assert_line(&mut engine, &mut cache, "link(d, e).").is_err());
+        assert!(assert_line(&mut engine, &mut cache,
+                            "link(X, Y) :- linked(X, Y).").is_err());
+        assert!(run(&engine, &cache, "link(X)?").is_err());
+    }
+
+    #[test]
+    fn query_hints() {
+        let engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let cache = cache::ViewCache::new();
+        let answers = |options: &ast::QueryOptions| {
+            let query = parser::parse_query("underling(X, Y)?").unwrap();
+            let mut answers: Vec<String> =
+                eval::query_using(&engine, &cache, query, options, |frames| {
+                    frames.map(|f| format!("{} {}", f["X"], f["Y"])).collect()
+                }).unwrap();
+            answers.sort();
+            answers
+        };
+
+        // Every strategy gives the same answers, but `no_cache` leaves
+        // nothing behind in the cache.
+        let no_cache = ast::QueryOptions { semi_naive: true, no_cache: true };
+        let expected = answers(&no_cache);
+        assert!(!expected.is_empty());
+        assert!(!cache.is_cached("underling", &[None, None]));
+        let semi_naive = ast::QueryOptions { semi_naive: true,
+                                             no_cache: false };
+        assert_eq!(answers(&semi_naive), expected);
+        assert!(cache.is_cached("underling", &[None, None]));
+        assert_eq!(answers(&Default::default()), expected);
+    }
+
+    #[test]
+    fn lint() {
+        let lint = |text: &str| {
+            let lexer = Lexer::new(text.chars()).map(Result::unwrap);
+            match Parser::new(lexer).next().unwrap().unwrap() {
+                ast::Line::Rule(rule) => eval::lint(&rule),
+                _ => panic!("expected a rule")
+            }
+        };
+
+        assert!(lint("grandparent(X, Z) :- parent(X, Y), parent(Y, Z).")
+                .is_empty());
+        assert!(lint("adult(X) :- person(X, A), A >= 18.").is_empty());
+
+        let warnings = lint("pair(X, Y) :- person(X), person(Y).");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("person(X); person(Y) share no"));
+
+        let warnings =
+            lint("sibling(X, Y) :- parent(P, X), parent(Q, Y), X != Y.");
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[1].starts_with("P appears only once"));
+        assert!(warnings[2].starts_with("Q appears only once"));
+    }
+
+    #[test]
+    fn generated_graphs() {
+        use generate::Shape;
+
+        // Graphs depend only on their shape, size and seed.
+        assert_eq!(generate::edges(Shape::Dag, 50, 1),
+                   generate::edges(Shape::Dag, 50, 1));
+        assert_ne!(generate::edges(Shape::Dense, 50, 1),
+                   generate::edges(Shape::Dense, 50, 2));
+        assert_eq!(generate::edges(Shape::Grid, 3, 0).len(), 12);
+        assert!(generate::edges(Shape::Dag, 50, 1).iter().all(|(from, to)| {
+            to[1..].parse::<usize>().unwrap() <
+                from[1..].parse::<usize>().unwrap()
+        }));
+        assert!(Shape::parse("tree").is_err());
+
+        let dir = test_dir("_test_generated_graphs_dir");
+        assert_eq!(generate::generate(dir.to_string(), Shape::Grid, 3, 0)
+                       .unwrap(),
+                   12);
+        assert!(generate::generate(dir.to_string(), Shape::Dag, 3, 0)
+                    .is_err());
+        let engine = StorageEngine::new(dir.to_string()).unwrap();
+        let mut cache = cache::ViewCache::new();
+        eval::initialize_view_cache(&engine, &mut cache);
+        // Every cell reaches those below and to the right of it.
+        assert_eq!(run(&engine, &cache, "path(n0_0, X)?").unwrap().len(), 8);
+        assert_eq!(run(&engine, &cache, "path(n1_1, X)?").unwrap().len(), 3);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nullary_facts() {
+        let dir = test_dir("_test_nullary_facts_dir");
+        let mut engine = StorageEngine::new(dir.to_string()).unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["rain.", "lawn(grass).",
+                      "wet(X) :- lawn(X), rain."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        engine.write_back();
+        std::mem::drop(engine);
+
+        // Facts of arity 0 have no atoms, but are still there on reopening.
+        let engine: Engine = StorageEngine::new(dir.to_string()).unwrap();
+        let cache = cache::ViewCache::new();
+        assert_eq!(run(&engine, &cache, "rain?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "wet(X)?").unwrap().len(), 1);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rules_file() {
+        let dir = test_dir("_test_rules_file_dir");
+        let mut engine = StorageEngine::new(dir.to_string()).unwrap();
+        let mut cache = cache::ViewCache::new();
+        for line in &["edge(a, b, 1).", "edge(b, c, 2).", "edge(a, c, 5).",
+                      "cost(X, Y, C) :- edge(X, Y, C).",
+                      "cost(X, Z, C) :- cost(X, Y, C), edge(Y, Z, D).",
+                      "sign(a, \"stop\u{7}\r\n\").",
+                      "stop(X) :- sign(X, \"stop\u{7}\r\n\")."] {
+            assert_line(&mut engine, &mut cache, line).unwrap();
+        }
+        eval::set_subsumption(&mut engine, &mut cache, "cost",
+                              eval::Subsumption::parse("3", "min"))
+            .unwrap();
+        engine.describe("cost", "cheapest paths".to_string()).unwrap();
+        engine.set_rules_file(true).unwrap();
+        // Strings are written as the lexer reads them: control characters
+        // without escapes of their own are written as they are.
+        let text = std::fs::read_to_string(
+            std::path::Path::new(dir).join(RULES_FILE)).unwrap();
+        assert_eq!(text.lines().collect::<Vec<_>>(), vec!(
+            "cost(X, Y, C) :- edge(X, Y, C).",
+            "cost(X, Z, C) :- cost(X, Y, C), edge(Y, Z, D).",
+            ".subsume cost 3 min",
+            ".describe cost \"cheapest paths\"",
+            "",
+            "stop(X) :- sign(X, \"stop\u{7}\r\\n\")."));
+        engine.write_back();
+        std::mem::drop(engine);
+
+        // Everything about the view survives being read back.
+        let engine = StorageEngine::new(dir.to_string()).unwrap();
+        let cache = cache::ViewCache::new();
+        assert_eq!(eval::rules(&engine, "cost").unwrap().len(), 2);
+        assert_eq!(engine.description("cost"), Some("cheapest paths"));
+        match engine.get_relation("cost") {
+            Some(Relation::Intension(view)) =>
+                assert_eq!(view.subsumption(),
+                           eval::Subsumption::parse("3", "min").as_ref()),
+            _ => panic!("cost is not a view")
+        }
+        assert_eq!(run(&engine, &cache, "cost(a, c, C)?").unwrap().len(), 1);
+        assert_eq!(run(&engine, &cache, "stop(X)?").unwrap().len(), 1);
+        std::mem::drop(engine);
+
+        // Anything but rules and their settings is refused.
+        std::fs::write(std::path::Path::new(dir).join(RULES_FILE),
+                       "edge(c, d, 1).\n").unwrap();
+        assert!(StorageEngine::<eval::AstView>::new(dir.to_string()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_suites() {
+        assert_eq!(suite::run("test_data/suite/got.toml").unwrap(),
+                   (2, vec!()));
+
+        // Answers are compared whatever their order, and differences in
+        // either direction are reported.
+        let dir = test_dir("_test_suites_dir");
+        std::fs::write(format!("{}/edges.dl", dir),
+                       "edge(a, b).\nedge(b, c).\n\
+                        path(X, Y) :- edge(X, Y).\n").unwrap();
+        std::fs::write(format!("{}/paths.tsv", dir), "X\tY\nb\tc\na\tc\n")
+            .unwrap();
+        std::fs::write(format!("{}/suite.toml", dir),
+                       "program = [\"edges.dl\"]\n\
+                        [[query]]\nquery = \"path(X, Y)?\"\n\
+                        expected = \"paths.tsv\"\n").unwrap();
+        let path = format!("{}/suite.toml", dir);
+        assert_eq!(suite::run(&path).unwrap(),
+                   (0, vec!(suite::Failure {
+                       query: "path(X, Y)?".to_string(),
+                       missing: vec!("a\tc".to_string()),
+                       unexpected: vec!("a\tb".to_string())
+                   })));
+
+        // So are files that can't be read, or hold anything but a program.
+        std::fs::write(format!("{}/edges.dl", dir), "edge(X, Y)?\n").unwrap();
+        assert!(suite::run(&path).is_err());
+        assert!(suite::run("test_data/suite/missing.toml").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn view_matching() {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        assert_line(&mut engine, &mut cache,
+                    "over(X, Y) :- underling(Y, X).").unwrap();
+        let query = || parser::parse_query("over(id_10005, Y)?").unwrap();
+        let mut direct = run(&engine, &cache, "over(id_10005, Y)?").unwrap();
+        direct.sort();
+        assert!(!direct.is_empty());
+
+        // Once the whole of `underling` is cached, `over` is answered from it.
+        run(&engine, &cache, "underling(X, Y)?").unwrap();
+        let roots = eval::explain_analyze(&engine, &cache, query()).unwrap();
+        let root = roots[0].borrow();
+        assert_eq!((root.rows, root.cache_hit), (direct.len(), Some(true)));
+        assert!(root.children.is_empty());
+        let mut matched = run(&engine, &cache, "over(id_10005, Y)?").unwrap();
+        matched.sort();
+        assert_eq!(matched, direct);
+
+        // Only simple selections are rewritten.
+        assert_line(&mut engine, &mut cache,
+                    "over(X, Y) :- reports(Y, X).").unwrap();
+        let roots = eval::explain_analyze(&engine, &cache, query()).unwrap();
+        assert_eq!(roots[0].borrow().cache_hit, Some(false));
+    }
+
+    #[test]
+    fn deferred_invalidation() {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+            .unwrap();
+        let mut cache = cache::ViewCache::new();
+        eval::initialize_view_cache(&engine, &mut cache);
+        let reports = run(&engine, &cache, "reports(X, Y)?").unwrap().len();
+        let underlings = run(&engine, &cache, "underling(X, Y)?").unwrap()
+            .len();
+        assert!(run(&engine, &cache, "reports(new, Y)?").unwrap().is_empty());
+
+        // Asserting facts only marks the views using them as stale...
+        for fact in &["employee_hierarchy(new, kim, salary_1, id_10005).",
+                      "employee_hierarchy(newer, lee, salary_1, new)."] {
+            assert_line(&mut engine, &mut cache, fact).unwrap();
+        }
+        assert!(cache.is_stale("reports") && cache.is_stale("underling"));
+        assert!(cache.read_cache("reports", &[None, None]).is_none());
+
+        // ...which the next query brings up to date with the new facts.
+        assert_eq!(run(&engine, &cache, "reports(X, Y)?").unwrap().len(),
+                   reports + 2);
+        assert!(!cache.is_stale("reports"));
+        let new = value::Value::Symbol("new".to_string());
+        let cached = cache.read_cache("reports", &[Some(new), None]).unwrap();
+        assert_eq!(cached.len(), 1);
+
+        // Recursive views are recomputed instead.
+        let added = run(&engine, &cache, "underling(X, Y)?").unwrap().len()
+            - underlings;
+        assert!(added > 2);
+        assert!(!cache.is_stale("underling"));
+    }
+
 
     #[bench]
     fn simple_view_query(b: &mut test::Bencher) {
@@ -170,6 +2645,21 @@ mod tests {
         });
     }
 
+    #[bench]
+    fn generated_grid(b: &mut test::Bencher) {
+        let dir = test_dir("_bench_generated_grid_dir");
+        generate::generate(dir.to_string(), generate::Shape::Grid, 10, 0)
+            .unwrap();
+        let engine = StorageEngine::new(dir.to_string()).unwrap();
+        b.iter(|| {
+            let cache = cache::ViewCache::new();
+            let query = parser::parse_query("path(X, Y)?").unwrap();
+            eval::query_semi_naive(&engine, &cache, query).unwrap().count()
+        });
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
     #[bench]
     fn recursive_query(b: &mut test::Bencher) {
         let engine = StorageEngine::new("test_data/hierarchy".to_string())
@@ -189,6 +2679,20 @@ mod tests {
         });
     }
 
+    #[bench]
+    fn repeated_recursive_view(b: &mut test::Bencher) {
+        let mut engine = StorageEngine::new("test_data/hierarchy".to_string())
+              .unwrap();
+        assert_line(&mut engine, &mut cache::ViewCache::new(),
+                    "peer(X, Y) :- underling(X, Z), underling(Y, Z).")
+            .unwrap();
+        b.iter(|| {
+            let cache = cache::ViewCache::new();
+            let query = parser::parse_query("peer(X, Y)?").unwrap();
+            eval::query(&engine, &cache, query).unwrap().count()
+        });
+    }
+
     #[bench]
     fn recursive_query_semi_naive(b: &mut test::Bencher) {
         let engine = StorageEngine::new("test_data/hierarchy".to_string())
@@ -2,9 +2,14 @@
 
 use error::*;
 use ast::*;
+use lexer;
+use lexer::Lexer;
 use tok::Tok;
+use value::Value;
 
+use std::collections::{HashMap, VecDeque};
 use std::iter::Iterator;
+use std::mem;
 
 // A useful macro for dealing with Option<Result>s.
 macro_rules! try_get {
@@ -15,14 +20,34 @@ macro_rules! try_get {
     })
 }
 
+// Rules defined with `template`, whose parameters stand for relation names
+// given by `instantiate`.
+struct Template {
+    params: Vec<String>,
+    rules: Vec<Rule>
+}
+
 pub struct Parser<I: Iterator<Item = Tok>> {
     tokens: I,
-    current: Option<Tok>
+    current: Option<Tok>,
+    /// Lines already parsed but not yet returned, from rules with several
+    /// heads or instantiated templates.
+    pending: VecDeque<Line>,
+    templates: HashMap<String, Template>,
+    /// The parameters of the template being parsed, if any, which may be used
+    /// as relation names.
+    template_params: Vec<String>
 }
 
 impl<I: Iterator<Item = Tok>> Parser<I> {
     pub fn new(tokens: I) -> Self {
-        Parser { tokens: tokens, current: None }
+        Parser {
+            tokens: tokens,
+            current: None,
+            pending: VecDeque::new(),
+            templates: HashMap::new(),
+            template_params: Vec::new()
+        }
     }
 
     fn next_token(&mut self) -> Option<Tok> {
@@ -40,6 +65,13 @@ impl<I: Iterator<Item = Tok>> Parser<I> {
     // just return an atomic term from that string.
     fn term_from_atom(&mut self, atom: String) -> Option<Result<Term>> {
         let next_token = self.next_token()?;
+        self.term_from_atom_followed_by(atom, next_token)
+    }
+
+    // Parse a term beginning with the given atom string, where the token after
+    // the atom has already been consumed.
+    fn term_from_atom_followed_by(&mut self, atom: String, next_token: Tok)
+            -> Option<Result<Term>> {
         // the token after an atom that begins a term should be either:
         //  OpenParen - if the atom is a relation name
         //  CloseParen - if the atom is at the end of the parameters list
@@ -47,41 +79,107 @@ impl<I: Iterator<Item = Tok>> Parser<I> {
         //  Comma - if the atom is in the parameters of a compound term
         //  Dot - if the atom is its own rule with no body
         match next_token {
-            Tok::OpenParen => {
-                let params = try_get!(self.parse_atomic_term_list());
-                // Advance past the final closing paren
-                self.next_token()?;
-                Some(Ok(Term::Compound(
-                            CompoundTerm {
-                                relation: atom.to_string(),
-                                params: params
-                            })))
-            },
+            Tok::OpenParen | Tok::Query | Tok::Dot if is_reserved(&atom) =>
+                Self::err(format!(
+                    "{} is a reserved word, so can't be used as a relation \
+                     name; write \"{}\" to use it anyway.", atom, atom)),
+            Tok::OpenParen => self.compound_term(atom),
             Tok::Query | Tok::Dot | Tok::Comma | Tok::CloseParen
-                => Some(Ok(Term::Atomic(AtomicTerm::Atom(atom.to_string())))),
+                => Some(Ok(Term::Atomic(
+                            AtomicTerm::Atom(Value::Symbol(atom))))),
             other => Some(Err(Error::Parser(
                     format!("Unexpected token after an atom: {:?}", other))))
 
         }
     }
 
+    // Parse the parameters of a compound term. Assumes the open paren has
+    // been consumed.
+    fn compound_term(&mut self, relation: String) -> Option<Result<Term>> {
+        let params = try_get!(self.parse_atomic_term_list());
+        // Advance past the final closing paren
+        self.next_token()?;
+        Some(Ok(Term::Compound(
+                    CompoundTerm {
+                        relation: relation,
+                        params: params
+                    })))
+    }
+
     // Greedily parse a term (take the largest term we can parse)
     fn parse_term(&mut self) -> Option<Result<Term>> {
         let tok = self.next_token()?;
+        self.parse_term_from(tok)
+    }
+
+    // Parse a term beginning with the given (already consumed) token.
+    fn parse_term_from(&mut self, tok: Tok) -> Option<Result<Term>> {
         match tok {
+            Tok::Atom(ref atom) if atom == "not" => self.negated_term(),
             Tok::Atom(atom) => self.term_from_atom(atom),
             Tok::Variable(var) => {
                 // Since parse_term needs to get the next token after the term,
                 // we need to advance the token iterator here
-                self.next_token()?;
+                let next = self.next_token()?;
+                // In a template, a parameter can stand for a relation.
+                if next == Tok::OpenParen
+                        && self.template_params.contains(&var) {
+                    return self.compound_term(var);
+                }
                 Some(Ok(Term::Atomic(AtomicTerm::Variable(var))))
             },
+            Tok::Int(i) => self.literal(Value::Int(i)),
+            Tok::Float(x) => self.literal(Value::Float(x)),
+            Tok::Str(string) => self.quoted(string),
             _ => Self::err(
                     format!("Unexpected token at the start of a term: {:?}",
                             tok))
         }
     }
 
+    // Parse a term after `not`. If no relation follows, "not" is just an
+    // ordinary atom.
+    fn negated_term(&mut self) -> Option<Result<Term>> {
+        match self.next_token()? {
+            Tok::Atom(name) => {
+                let term = try_get!(self.term_from_atom(name));
+                Some(Ok(Term::Not(Box::new(term))))
+            },
+            Tok::Variable(ref var) if self.template_params.contains(var) => {
+                let term = try_get!(self.parse_term_from(
+                        Tok::Variable(var.clone())));
+                Some(Ok(Term::Not(Box::new(term))))
+            },
+            next => self.term_from_atom_followed_by("not".to_string(), next)
+        }
+    }
+
+    // Parse a term beginning with a quoted string: a compound term if an open
+    // paren follows, whose relation may be a reserved word, and otherwise a
+    // string literal.
+    fn quoted(&mut self, string: String) -> Option<Result<Term>> {
+        if self.next_token()? != Tok::OpenParen {
+            return Some(Ok(Term::Atomic(
+                        AtomicTerm::Atom(Value::String(string)))));
+        }
+
+        let mut chars = string.chars();
+        let identifier = chars.next().map_or(false, |c| c.is_lowercase())
+                      && chars.all(|c| c.is_alphanumeric() || c == '_');
+        if !identifier {
+            return Self::err(format!(
+                "Quoted relation names must still be identifiers: {:?}",
+                string));
+        }
+        self.compound_term(string)
+    }
+
+    // Finish parsing a literal value as an atomic term.
+    fn literal(&mut self, value: Value) -> Option<Result<Term>> {
+        self.next_token()?;
+        Some(Ok(Term::Atomic(AtomicTerm::Atom(value))))
+    }
+
     // Parse the body of a rule - a list of terms forming a conjunction
     // Assumes there will be at least one term.
     fn parse_term_list(&mut self) -> Option<Result<Vec<Term>>> {
@@ -95,6 +193,31 @@ impl<I: Iterator<Item = Tok>> Parser<I> {
         Some(Ok(terms))
     }
 
+    // Parse the body of a rule: a conjunction of terms, any of which may be a
+    // parenthesized conjunction of its own, as in `a(X), (b(X), c(X))`.
+    // Groups are flattened, since they mean the same as their terms would
+    // without the parentheses.
+    fn parse_body(&mut self) -> Option<Result<Vec<Term>>> {
+        let mut terms = Vec::new();
+        loop {
+            match self.next_token()? {
+                Tok::OpenParen => {
+                    terms.extend(try_get!(self.parse_body()));
+                    if self.current != Some(Tok::CloseParen) {
+                        return Self::err(
+                            "Expected \")\" after a group of terms."
+                                .to_string());
+                    }
+                    self.next_token()?;
+                },
+                tok => terms.push(try_get!(self.parse_term_from(tok)))
+            }
+            if self.current != Some(Tok::Comma) {
+                return Some(Ok(terms));
+            }
+        }
+    }
+
     fn parse_atomic_term_list(&mut self) -> Option<Result<Vec<AtomicTerm>>> {
         let list = try_get!(self.parse_term_list());
         let mut atomic_terms = Vec::new();
@@ -104,34 +227,57 @@ impl<I: Iterator<Item = Tok>> Parser<I> {
                 Term::Compound(_) => {
                     return Self::err(
                         "Syntax Error: nested compound term.".to_string());
+                },
+                Term::Not(_) => {
+                    return Self::err(
+                        "Syntax Error: negated term as a parameter."
+                            .to_string());
                 }
             }
         }
         Some(Ok(atomic_terms))
     }
 
-    fn err<T>(msg: String) -> Option<Result<T>> {
-        Some(Err(Error::Parser(msg)))
-    }
-}
-
-impl<I: Iterator<Item = Tok>> Iterator for Parser<I> {
-    type Item = Result<Line>;
-
-    fn next(&mut self) -> Option<Result<Line>> {
-        // First, parse a term. Then, by examining the next token
-        // we know what kind of line we're looking at.
-        let first_term = try_get!(self.parse_term());
+    // Finish parsing a line that begins with the given term.
+    fn line_from_term(&mut self, first_term: Term) -> Option<Result<Line>> {
         Some(Ok(match self.current {
             Some(Tok::Dot) => Line::Rule(Rule {
                 head: first_term,
                 body: vec!()
             }),
             Some(Tok::Query) => Line::Query(first_term),
+            Some(Tok::Select(ref columns)) => {
+                let variables = first_term.variables();
+                if let Some(c) = columns.iter()
+                                        .find(|c| !variables.contains(c)) {
+                    return Self::err(format!(
+                        "Selected variable {} does not appear in the query.",
+                        c));
+                }
+                Line::Select(first_term, columns.clone())
+            },
+            Some(Tok::Using(ref hints)) => {
+                let mut options = QueryOptions::default();
+                for hint in hints {
+                    match hint.as_str() {
+                        "naive" => options.semi_naive = false,
+                        "seminaive" => options.semi_naive = true,
+                        "no_cache" => options.no_cache = true,
+                        other => return Self::err(format!(
+                            "Unknown hint {:?}; expected naive, seminaive or \
+                             no_cache.", other))
+                    }
+                }
+                Line::Using(first_term, options)
+            },
+            Some(Tok::Within(budget)) => Line::Within(first_term, budget),
+            Some(Tok::Capture(ref name)) =>
+                Line::Capture(first_term, name.clone()),
             Some(Tok::Means) => {
-                let term_list = try_get!(self.parse_term_list());
-                Line::Rule(Rule { head: first_term, body: term_list })
+                let body = try_get!(self.parse_body());
+                Line::Rule(Rule { head: first_term, body })
             },
+            Some(Tok::Comma) => return self.multi_head_rule(first_term),
             Some(_) =>
                 return Self::err(format!(
                     "Unexpected token following a term. Token: {:?}",
@@ -142,13 +288,479 @@ impl<I: Iterator<Item = Tok>> Iterator for Parser<I> {
                     first_term))
         }))
     }
+
+    // Finish parsing a rule with several heads, the first already parsed, as
+    // one rule per head sharing the same body. The rules after the first are
+    // returned by later calls to `next`.
+    fn multi_head_rule(&mut self, first_head: Term) -> Option<Result<Line>> {
+        let mut heads = vec!(first_head);
+        while let Some(Tok::Comma) = self.current {
+            heads.push(try_get!(self.parse_term()));
+        }
+        if self.current != Some(Tok::Means) {
+            return Self::err(
+                "Expected \":-\" after the heads of a rule.".to_string());
+        }
+
+        let body = try_get!(self.parse_body());
+        let mut rules = heads.into_iter().map(|head| {
+            Line::Rule(Rule { head, body: body.clone() })
+        });
+        let first = rules.next();
+        self.pending.extend(rules);
+        first.map(Ok)
+    }
+
+    // Finish parsing a line beginning with the atom `keyword`. If a term
+    // follows, it must be a query, which `line` wraps. Otherwise the keyword
+    // is just an ordinary atom.
+    fn keyword_query(&mut self, keyword: &str, line: fn(Term) -> Line)
+            -> Option<Result<Line>> {
+        let next = self.next_token()?;
+        if let Tok::Atom(name) = next {
+            let query = try_get!(self.term_from_atom(name));
+            if self.current != Some(Tok::Query) {
+                return Self::err(
+                    format!("Expected \"?\" after a {} query.", keyword));
+            }
+            return Some(Ok(line(query)));
+        }
+
+        let first_term = try_get!(self.term_from_atom_followed_by(
+                keyword.to_string(), next));
+        self.line_from_term(first_term)
+    }
+
+    // Finish parsing a line beginning with the atom `with`: one or more rules,
+    // then a query. If no term follows, "with" is just an ordinary atom.
+    fn with_query(&mut self) -> Option<Result<Line>> {
+        let mut term = match self.next_token()? {
+            Tok::Atom(name) => try_get!(self.term_from_atom(name)),
+            next => {
+                let first_term = try_get!(self.term_from_atom_followed_by(
+                        "with".to_string(), next));
+                return self.line_from_term(first_term);
+            }
+        };
+
+        let mut rules = Vec::new();
+        loop {
+            match try_get!(self.line_from_term(term)) {
+                Line::Query(query) if !rules.is_empty() =>
+                    return Some(Ok(Line::With(rules, query))),
+                Line::Rule(rule) => rules.push(rule),
+                _ => return Self::err(
+                        "Expected a rule after \"with\".".to_string())
+            }
+            while let Some(Line::Rule(rule)) = self.pending.pop_front() {
+                rules.push(rule);
+            }
+            term = try_get!(self.parse_term());
+        }
+    }
+
+    // Finish parsing a line beginning with the atom `ref`. If no term follows,
+    // "ref" is just an ordinary atom.
+    fn reference(&mut self) -> Option<Result<Line>> {
+        let from = match self.next_token()? {
+            Tok::Atom(name) => try_get!(self.term_from_atom(name)),
+            next => {
+                let first_term = try_get!(self.term_from_atom_followed_by(
+                        "ref".to_string(), next));
+                return self.line_from_term(first_term);
+            }
+        };
+        if self.current != Some(Tok::Arrow) {
+            return Self::err(
+                "Expected \"->\" after the first term of a reference."
+                    .to_string());
+        }
+
+        let to = try_get!(self.parse_term());
+        if self.current != Some(Tok::Dot) {
+            return Self::err("Expected \".\" after a reference.".to_string());
+        }
+        Some(Ok(Line::Ref(from, to)))
+    }
+
+    // Finish parsing a line beginning with the atom `on`: a term, "->" and the
+    // facts to assert whenever a fact matching the term is. If no term
+    // follows, "on" is just an ordinary atom.
+    fn trigger(&mut self) -> Option<Result<Line>> {
+        let pattern = match self.next_token()? {
+            Tok::Atom(name) => try_get!(self.term_from_atom(name)),
+            next => {
+                let first_term = try_get!(self.term_from_atom_followed_by(
+                        "on".to_string(), next));
+                return self.line_from_term(first_term);
+            }
+        };
+        if self.current != Some(Tok::Arrow) {
+            return Self::err(
+                "Expected \"->\" after the term of a trigger.".to_string());
+        }
+
+        let actions = try_get!(self.parse_term_list());
+        if self.current != Some(Tok::Dot) {
+            return Self::err("Expected \".\" after a trigger.".to_string());
+        }
+        Some(Ok(Line::Trigger(pattern, actions)))
+    }
+
+    // Finish parsing a line beginning with the atom `template`: a name, its
+    // parameters, and rules in braces using them. The template is kept for
+    // `instantiate`, and parsing goes on to the next line. If no name follows,
+    // "template" is just an ordinary atom.
+    fn template(&mut self) -> Option<Result<Line>> {
+        let name = match self.next_token()? {
+            Tok::Atom(name) => name,
+            next => {
+                let first_term = try_get!(self.term_from_atom_followed_by(
+                        "template".to_string(), next));
+                return self.line_from_term(first_term);
+            }
+        };
+        let params = match try_get!(self.term_from_atom(name.clone())) {
+            Term::Compound(c) => c.params,
+            _ => return Self::err(
+                    "Expected parameters after the name of a template."
+                        .to_string())
+        };
+        let mut variables = Vec::new();
+        for param in params {
+            match param {
+                AtomicTerm::Variable(v) => variables.push(v),
+                AtomicTerm::Atom(a) => return Self::err(format!(
+                        "Template parameters must be variables, not {}.", a))
+            }
+        }
+        if self.current != Some(Tok::OpenBrace) {
+            return Self::err(
+                "Expected \"{\" after the parameters of a template."
+                    .to_string());
+        }
+
+        self.template_params = variables;
+        let rules = self.template_rules();
+        let params = mem::replace(&mut self.template_params, Vec::new());
+        let rules = try_get!(rules);
+        self.templates.insert(name, Template { params, rules });
+        self.next()
+    }
+
+    // Parse the rules of a template, up to the closing brace. Assumes the
+    // opening brace has been consumed.
+    fn template_rules(&mut self) -> Option<Result<Vec<Rule>>> {
+        let mut rules = Vec::new();
+        loop {
+            let term = match self.next_token()? {
+                Tok::CloseBrace if !rules.is_empty() => return Some(Ok(rules)),
+                tok => try_get!(self.parse_term_from(tok))
+            };
+            match try_get!(self.line_from_term(term)) {
+                Line::Rule(rule) => rules.push(rule),
+                _ => return Self::err(
+                        "Expected a rule in a template.".to_string())
+            }
+            while let Some(Line::Rule(rule)) = self.pending.pop_front() {
+                rules.push(rule);
+            }
+        }
+    }
+
+    // Finish parsing a line beginning with the atom `instantiate`: a template
+    // applied to relation names, which stands for the template's rules with
+    // those names in place of its parameters. If no name follows,
+    // "instantiate" is just an ordinary atom.
+    fn instantiation(&mut self) -> Option<Result<Line>> {
+        let term = match self.next_token()? {
+            Tok::Atom(name) => try_get!(self.term_from_atom(name)),
+            next => {
+                let first_term = try_get!(self.term_from_atom_followed_by(
+                        "instantiate".to_string(), next));
+                return self.line_from_term(first_term);
+            }
+        };
+        let (name, args) = match term {
+            Term::Compound(c) => (c.relation, c.params),
+            _ => return Self::err(
+                    "Expected arguments after the name of a template."
+                        .to_string())
+        };
+        if self.current != Some(Tok::Dot) {
+            return Self::err(
+                "Expected \".\" after an instantiation.".to_string());
+        }
+
+        let template = match self.templates.get(&name) {
+            Some(template) => template,
+            None => return Self::err(
+                    format!("No template \"{}\" found.", name))
+        };
+        if args.len() != template.params.len() {
+            return Self::err(format!(
+                "Template {} takes {} arguments, but was given {}.",
+                name, template.params.len(), args.len()));
+        }
+        let mut names = HashMap::new();
+        for (param, arg) in template.params.iter().zip(args) {
+            match arg {
+                AtomicTerm::Atom(Value::Symbol(relation)) =>
+                    names.insert(param.as_str(), relation),
+                other => return Self::err(format!(
+                        "Expected a relation name, not {}, as an argument \
+                         to template {}.", other, name))
+            };
+        }
+
+        let mut rules: VecDeque<Line> = template.rules.iter().map(|rule| {
+            Line::Rule(Rule {
+                head: substitute(&rule.head, &names),
+                body: rule.body.iter().map(|t| substitute(t, &names)).collect()
+            })
+        }).collect();
+        let first = rules.pop_front();
+        self.pending.extend(rules);
+        first.map(Ok)
+    }
+
+    fn err<T>(msg: String) -> Option<Result<T>> {
+        Some(Err(Error::Parser(msg)))
+    }
+}
+
+// Replace the parameters of a template in one of its terms with the relation
+// names they stand for, wherever they appear.
+fn substitute(term: &Term, names: &HashMap<&str, String>) -> Term {
+    let atomic = |param: &AtomicTerm| match param {
+        AtomicTerm::Variable(v) if names.contains_key(v.as_str()) =>
+            AtomicTerm::Atom(Value::Symbol(names[v.as_str()].clone())),
+        other => other.clone()
+    };
+    match term {
+        Term::Atomic(a) => Term::Atomic(atomic(a)),
+        Term::Compound(c) => Term::Compound(CompoundTerm {
+            relation: names.get(c.relation.as_str()).cloned()
+                           .unwrap_or_else(|| c.relation.clone()),
+            params: c.params.iter().map(atomic).collect()
+        }),
+        Term::Not(t) => Term::Not(Box::new(substitute(t, names)))
+    }
+}
+
+// Split the text of a meta-command into its name and arguments. Arguments are
+// separated by whitespace, except within double quotes.
+fn parse_command(text: String) -> Result<Command> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(word.split_off(0));
+                    in_word = false;
+                }
+            },
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quoted {
+        return Err(Error::Parser(
+                format!("Unterminated quote in command: .{}", text)));
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    let mut words = words.into_iter();
+    let name = words.next().ok_or_else(|| Error::Parser(
+            "Empty command.".to_string()))?;
+    Ok(Command { name, args: words.collect() })
+}
+
+/// Parse a string consisting of exactly one query.
+pub fn parse_query(text: &str) -> Result<Term> {
+    let toks = Lexer::new(text.chars()).collect::<Result<Vec<Tok>>>()?;
+    let mut lines = Parser::new(toks.into_iter());
+    match (lines.next(), lines.next()) {
+        (Some(Ok(Line::Query(t))), None) => Ok(t),
+        (Some(Err(e)), _) => Err(e),
+        _ => Err(Error::Parser(format!("Expected a single query: {}", text)))
+    }
+}
+
+/// Parse a string consisting of exactly one query, replacing each parameter
+/// `$n` with the nth (counting from 1) of `params`.
+///
+/// Parameters are substituted after parsing, and always as constants: numbers
+/// become numbers, and anything else becomes a symbol, so they can't change
+/// the structure of the query.
+pub fn parse_query_with_params(text: &str, params: &[String]) -> Result<Term> {
+    bind_params(parse_query(text)?, params)
+}
+
+/// Replace each parameter `$n` of a parsed query with the nth (counting from
+/// 1) of `params`, as `parse_query_with_params` does.
+pub fn bind_params(query: Term, params: &[String]) -> Result<Term> {
+    let bind = |param: AtomicTerm| -> Result<AtomicTerm> {
+        let name = match param {
+            AtomicTerm::Variable(ref v) if v.starts_with('$') => v.clone(),
+            other => return Ok(other)
+        };
+        let value = name[1..].parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| params.get(i))
+            .ok_or_else(|| Error::Parser(
+                    format!("no value given for parameter {}", name)))?;
+        Ok(AtomicTerm::Atom(param_value(value)))
+    };
+
+    Ok(match query {
+        Term::Atomic(a) => Term::Atomic(bind(a)?),
+        Term::Compound(c) => Term::Compound(CompoundTerm {
+            relation: c.relation,
+            params: c.params.into_iter().map(bind).collect::<Result<_>>()?
+        }),
+        negated @ Term::Not(_) => negated
+    })
+}
+
+/// The number of parameters (`$1`, `$2`, ...) a query takes. They must be
+/// numbered from `$1`, without gaps.
+pub fn parameter_count(query: &Term) -> Result<usize> {
+    let mut numbers = Vec::new();
+    for variable in query.variables() {
+        if !variable.starts_with('$') {
+            continue;
+        }
+        match variable[1..].parse::<usize>() {
+            Ok(n) if n > 0 => numbers.push(n),
+            _ => return Err(Error::Parser(
+                    format!("bad parameter name: {}", variable)))
+        }
+    }
+    numbers.sort();
+    match numbers.iter().enumerate().find(|(i, n)| **n != i + 1) {
+        Some((i, _)) => Err(Error::Parser(
+                format!("parameter ${} is missing", i + 1))),
+        None => Ok(numbers.len())
+    }
+}
+
+/// The constant a command-line parameter or meta-command argument stands for:
+/// a number if the lexer would read it as one, and otherwise a symbol.
+pub fn param_value(text: &str) -> Value {
+    if !lexer::is_number(text) {
+        Value::from(text)
+    } else if let Ok(i) = text.parse() {
+        Value::Int(i)
+    } else if let Ok(x) = text.parse() {
+        Value::Float(x)
+    } else {
+        Value::from(text)
+    }
+}
+
+impl<I: Iterator<Item = Tok>> Iterator for Parser<I> {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Result<Line>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
+
+        // Meta-commands are complete lines on their own.
+        let tok = self.next_token()?;
+        if let Tok::Command(text) = tok {
+            return Some(parse_command(text).map(Line::Command));
+        }
+
+        // `redefine` followed by a rule replaces a view's definition.
+        if tok == Tok::Atom("redefine".to_string()) {
+            let next = self.next_token()?;
+            if let Tok::Atom(name) = next {
+                let head = try_get!(self.term_from_atom(name));
+                if self.current != Some(Tok::Means) {
+                    return Self::err(
+                        "Expected \":-\" after the head of a redefinition."
+                            .to_string());
+                }
+                let body = try_get!(self.parse_body());
+                return Some(Ok(Line::Redefine(Rule { head, body })));
+            }
+
+            let first_term = try_get!(self.term_from_atom_followed_by(
+                    "redefine".to_string(), next));
+            return self.line_from_term(first_term);
+        }
+
+        // `count` followed by a query counts its answers, `count_derivations`
+        // counts how each answer can be derived, and `not` asks whether it has
+        // none.
+        if tok == Tok::Atom("count".to_string()) {
+            return self.keyword_query("count", Line::Count);
+        }
+        if tok == Tok::Atom("count_derivations".to_string()) {
+            return self.keyword_query("count_derivations",
+                                      Line::CountDerivations);
+        }
+        if tok == Tok::Atom("not".to_string()) {
+            return self.keyword_query("not", Line::Not);
+        }
+
+        // `with` followed by rules and a query defines views just for that
+        // query.
+        if tok == Tok::Atom("with".to_string()) {
+            return self.with_query();
+        }
+
+        // `ref` followed by `a(1) -> b(1).` declares a reference between
+        // relations.
+        if tok == Tok::Atom("ref".to_string()) {
+            return self.reference();
+        }
+
+        // `on` followed by `a(X) -> b(X).` declares a trigger.
+        if tok == Tok::Atom("on".to_string()) {
+            return self.trigger();
+        }
+
+        // `template` defines rules over relations given later, when it is used
+        // by `instantiate`.
+        if tok == Tok::Atom("template".to_string()) {
+            return self.template();
+        }
+        if tok == Tok::Atom("instantiate".to_string()) {
+            return self.instantiation();
+        }
+
+        // Otherwise, parse a term. Then, by examining the next token
+        // we know what kind of line we're looking at.
+        let first_term = try_get!(self.parse_term_from(tok));
+        self.line_from_term(first_term)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use ast::*;
     use tok::Tok;
-    use parser::Parser;
+    use lexer::Lexer;
+    use parser::{Parser, param_value, parameter_count, parse_query,
+                 parse_query_with_params};
+    use value::Value;
+    use std::time::Duration;
     use std::vec;
 
     fn parse_test(x: Vec<Tok>) -> Option<Vec<Line>> {
@@ -188,7 +800,7 @@ mod tests {
                      Tok::Dot)),
                 Some(vec!(
                     Line::Rule( Rule {
-                        head: Term::Atomic(AtomicTerm::Atom("abraham".to_string())),
+                        head: Term::Atomic(AtomicTerm::Atom(Value::from("abraham"))),
                         body: vec!()
                     } )))
                 );
@@ -211,7 +823,7 @@ mod tests {
                      Tok::Query)),
                 Some(vec!(
                         Line::Query(
-                            Term::Atomic(AtomicTerm::Atom("abraham".to_string())))))
+                            Term::Atomic(AtomicTerm::Atom(Value::from("abraham"))))))
                 );
 
         // > A?
@@ -232,8 +844,8 @@ mod tests {
         let head = Term::Compound(
             CompoundTerm { relation: "parent".to_string(),
                           params: vec!(
-                            AtomicTerm::Atom("abraham".to_string()),
-                            AtomicTerm::Atom("isaac".to_string())
+                            AtomicTerm::Atom(Value::from("abraham")),
+                            AtomicTerm::Atom(Value::from("isaac"))
                             ) });
         assert_eq!(parse_test(
                 vec!(Tok::Atom("parent".to_string()),
@@ -259,4 +871,502 @@ mod tests {
 
     }
 
+    #[test]
+    fn literals() {
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("salary".to_string()),
+                     Tok::OpenParen,
+                     Tok::Int(3),
+                     Tok::Comma,
+                     Tok::Float(2.5),
+                     Tok::Comma,
+                     Tok::Str("three".to_string()),
+                     Tok::CloseParen,
+                     Tok::Dot)),
+                Some(vec!(Line::Rule(Rule {
+                    head: Term::Compound(CompoundTerm {
+                        relation: "salary".to_string(),
+                        params: vec!(
+                            AtomicTerm::Atom(Value::Int(3)),
+                            AtomicTerm::Atom(Value::Float(2.5)),
+                            AtomicTerm::Atom(Value::String("three"
+                                                               .to_string())))
+                    }),
+                    body: vec!()
+                }))));
+    }
+
+    #[test]
+    fn redefine() {
+        let body = vec!(Term::Compound(CompoundTerm {
+            relation: "b".to_string(),
+            params: vec!(AtomicTerm::Variable("X".to_string()))
+        }));
+        let head = Term::Compound(CompoundTerm {
+            relation: "a".to_string(),
+            params: vec!(AtomicTerm::Variable("X".to_string()))
+        });
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("redefine".to_string()),
+                     Tok::Atom("a".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Means,
+                     Tok::Atom("b".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Dot)),
+                Some(vec!(Line::Redefine(Rule { head, body }))));
+
+        // "redefine" is reserved, so can only name a relation when quoted.
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("redefine".to_string()),
+                     Tok::OpenParen,
+                     Tok::Atom("a".to_string()),
+                     Tok::CloseParen,
+                     Tok::Dot)),
+                None);
+        assert_eq!(parse_test(
+                vec!(Tok::Str("redefine".to_string()),
+                     Tok::OpenParen,
+                     Tok::Atom("a".to_string()),
+                     Tok::CloseParen,
+                     Tok::Dot)),
+                Some(vec!(Line::Rule(Rule {
+                    head: Term::Compound(CompoundTerm {
+                        relation: "redefine".to_string(),
+                        params: vec!(AtomicTerm::Atom(Value::from("a")))
+                    }),
+                    body: vec!()
+                }))));
+
+        // A redefinition must have a body.
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("redefine".to_string()),
+                     Tok::Atom("a".to_string()),
+                     Tok::Dot)),
+                None);
+    }
+
+    #[test]
+    fn multiple_heads() {
+        let term = |relation: &str| Term::Compound(CompoundTerm {
+            relation: relation.to_string(),
+            params: vec!(AtomicTerm::Variable("X".to_string()))
+        });
+        let toks = |relation: &str| vec!(Tok::Atom(relation.to_string()),
+                                         Tok::OpenParen,
+                                         Tok::Variable("X".to_string()),
+                                         Tok::CloseParen);
+        let rule = |head| Line::Rule(Rule {
+            head: term(head),
+            body: vec!(term("c"), term("d"))
+        });
+
+        let mut line = toks("a");
+        line.push(Tok::Comma);
+        line.extend(toks("b"));
+        line.push(Tok::Means);
+        line.extend(toks("c"));
+        line.push(Tok::Comma);
+        line.extend(toks("d"));
+        line.push(Tok::Dot);
+        line.extend(toks("e"));
+        line.push(Tok::Query);
+        assert_eq!(parse_test(line),
+                   Some(vec!(rule("a"), rule("b"), Line::Query(term("e")))));
+
+        // Several heads are only allowed in a rule with a body.
+        let mut line = toks("a");
+        line.push(Tok::Comma);
+        line.extend(toks("b"));
+        line.push(Tok::Dot);
+        assert_eq!(parse_test(line), None);
+    }
+
+    #[test]
+    fn with() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+        let term = |relation: &str, param: AtomicTerm| {
+            Term::Compound(CompoundTerm {
+                relation: relation.to_string(),
+                params: vec!(param)
+            })
+        };
+        let x = || AtomicTerm::Variable("X".to_string());
+        let rule = |head, body| Rule {
+            head: term(head, x()),
+            body: vec!(term(body, x()))
+        };
+
+        assert_eq!(parse_test(lex("with a(X) :- b(X). c(X), d(X) :- a(X). \
+                                   c(y)?")),
+                   Some(vec!(Line::With(
+                       vec!(rule("a", "b"), rule("c", "a"), rule("d", "a")),
+                       term("c", AtomicTerm::Atom(Value::from("y")))))));
+
+        // There must be at least one rule.
+        assert_eq!(parse_test(lex("with a(X)?")), None);
+
+        // "with" is reserved, so can only name a relation when quoted.
+        assert_eq!(parse_test(lex("with(X)?")), None);
+        assert_eq!(parse_test(lex("\"with\"(X)?")),
+                   Some(vec!(Line::Query(term("with", x())))));
+    }
+
+    #[test]
+    fn reference() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+        let term = |relation: &str, columns: &[i64]| {
+            Term::Compound(CompoundTerm {
+                relation: relation.to_string(),
+                params: columns.iter().map(|c| {
+                    AtomicTerm::Atom(Value::Int(*c))
+                }).collect()
+            })
+        };
+
+        assert_eq!(parse_test(lex("ref works_in(1, 2) -> department(2, 1).")),
+                   Some(vec!(Line::Ref(term("works_in", &[1, 2]),
+                                       term("department", &[2, 1])))));
+        assert_eq!(parse_test(lex("ref works_in(1) department(1).")), None);
+        assert_eq!(parse_test(lex("ref works_in(1) -> department(1)?")),
+                   None);
+
+        // "ref" is reserved, so can only name a relation when quoted.
+        assert_eq!(parse_test(lex("ref(1).")), None);
+        assert_eq!(parse_test(lex("\"ref\"(1).")),
+                   Some(vec!(Line::Rule(Rule {
+                       head: term("ref", &[1]),
+                       body: vec!()
+                   }))));
+    }
+
+    #[test]
+    fn templates() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+        let closure = "template closure(R, C) {
+                           C(X, Y) :- R(X, Y).
+                           C(X, Z) :- C(X, Y), R(Y, Z), not R(Z, X).
+                       }\n";
+
+        assert_eq!(parse_test(lex(&format!("{}instantiate closure(edge, path).
+                                            path(a, b)?", closure))),
+                   parse_test(lex("path(X, Y) :- edge(X, Y).
+                                   path(X, Z) :- path(X, Y), edge(Y, Z),
+                                                 not edge(Z, X).
+                                   path(a, b)?")));
+
+        // Templates must be defined, and given every argument.
+        assert_eq!(parse_test(lex("instantiate closure(edge, path).")), None);
+        assert_eq!(parse_test(lex(&format!("{}instantiate closure(edge).",
+                                           closure))),
+                   None);
+        assert_eq!(parse_test(lex(&format!("{}instantiate closure(edge, P).",
+                                           closure))),
+                   None);
+        // Only parameters can stand for relations.
+        assert_eq!(parse_test(lex("template t(R) { S(X) :- R(X). }")), None);
+        assert_eq!(parse_test(lex("template t(R) { }")), None);
+    }
+
+    #[test]
+    fn grouped_bodies() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+
+        let flat = parse_test(lex("a(X) :- b(X), c(X), d(X), e(X)."));
+        assert!(flat.is_some());
+        let grouped = "a(X) :- (b(X), c(X)), (d(X), (e(X))).";
+        assert_eq!(parse_test(lex(grouped)), flat);
+        assert_eq!(parse_test(lex("a(X) :-    % the body:
+                                       b(X),  % one term,
+                                       (c(X), % a group,
+                                        d(X)),
+                                       e(X).  % and another term.
+                                   ")),
+                   flat);
+        assert_eq!(parse_test(lex("redefine a(X) :- (b(X), c(X)), d(X), e(X).
+                                   ")).map(|mut lines| match lines.pop() {
+                       Some(Line::Redefine(rule)) => vec!(Line::Rule(rule)),
+                       _ => panic!("expected a redefinition")
+                   }),
+                   flat);
+
+        assert_eq!(parse_test(lex("a(X) :- (b(X), c(X).")), None);
+        assert_eq!(parse_test(lex("a(X) :- ().")), None);
+    }
+
+    #[test]
+    fn reserved_words() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+
+        assert_eq!(parse_test(lex("is(a, b).")), None);
+        assert_eq!(parse_test(lex("a(X) :- b(X), is(X, c).")), None);
+        match parse_test(lex("a(X) :- b(X), \"is\"(X, c).")).unwrap().pop() {
+            Some(Line::Rule(rule)) =>
+                assert_eq!(rule.to_string(), "a(X) :- b(X), \"is\"(X, c)."),
+            _ => panic!("expected a rule")
+        }
+        // Reserved words are ordinary values, and quoted strings too.
+        assert!(parse_test(lex("a(is, select).")).is_some());
+        assert!(parse_test(lex("a(\"is\").")).is_some());
+        // Quoting only lets through names that could be relations.
+        assert_eq!(parse_test(lex("\"Is it\"(a).")), None);
+    }
+
+    #[test]
+    fn trigger() {
+        let lex = |text: &str| -> Vec<Tok> {
+            Lexer::new(text.chars()).map(|t| t.unwrap()).collect()
+        };
+        let term = |relation: &str, params: &[&str]| {
+            Term::Compound(CompoundTerm {
+                relation: relation.to_string(),
+                params: params.iter().map(|p| {
+                    AtomicTerm::Variable(p.to_string())
+                }).collect()
+            })
+        };
+
+        assert_eq!(parse_test(lex("on works_in(E, D) -> member(D, E), \
+                                                         staff(E).")),
+                   Some(vec!(Line::Trigger(
+                       term("works_in", &["E", "D"]),
+                       vec!(term("member", &["D", "E"]),
+                            term("staff", &["E"]))))));
+        assert_eq!(parse_test(lex("on works_in(E, D) member(D, E).")), None);
+        assert_eq!(parse_test(lex("on works_in(E, D) -> member(D, E)?")),
+                   None);
+    }
+
+    #[test]
+    fn select() {
+        let query = Term::Compound(CompoundTerm {
+            relation: "reports".to_string(),
+            params: vec!(AtomicTerm::Variable("E".to_string()),
+                         AtomicTerm::Variable("M".to_string()))
+        });
+        let tokens = |columns: &[&str]| vec!(
+            Tok::Atom("reports".to_string()),
+            Tok::OpenParen,
+            Tok::Variable("E".to_string()),
+            Tok::Comma,
+            Tok::Variable("M".to_string()),
+            Tok::CloseParen,
+            Tok::Select(columns.iter().map(|c| c.to_string()).collect()));
+
+        assert_eq!(parse_test(tokens(&["E"])),
+                   Some(vec!(Line::Select(query.clone(),
+                                          vec!("E".to_string())))));
+
+        // Only variables of the query can be selected.
+        assert_eq!(parse_test(tokens(&["E", "X"])), None);
+
+        let mut using = tokens(&[]);
+        let hints = |hints: &[&str]| {
+            Tok::Using(hints.iter().map(|h| h.to_string()).collect())
+        };
+        *using.last_mut().unwrap() = hints(&["seminaive", "no_cache"]);
+        let options = QueryOptions { semi_naive: true, no_cache: true };
+        assert_eq!(parse_test(using.clone()),
+                   Some(vec!(Line::Using(query.clone(), options))));
+        *using.last_mut().unwrap() = hints(&["topdown"]);
+        assert_eq!(parse_test(using.clone()), None);
+
+        let budget = Duration::from_secs(2);
+        *using.last_mut().unwrap() = Tok::Within(budget);
+        assert_eq!(parse_test(using),
+                   Some(vec!(Line::Within(query, budget))));
+    }
+
+    #[test]
+    fn count() {
+        let tokens = |keyword: &str| vec!(Tok::Atom(keyword.to_string()),
+                                          Tok::Atom("a".to_string()),
+                                          Tok::OpenParen,
+                                          Tok::Variable("X".to_string()),
+                                          Tok::CloseParen,
+                                          Tok::Query);
+        let query = Term::Compound(CompoundTerm {
+            relation: "a".to_string(),
+            params: vec!(AtomicTerm::Variable("X".to_string()))
+        });
+        assert_eq!(parse_test(tokens("count")),
+                   Some(vec!(Line::Count(query))));
+
+        // Unquoted, `count` can't name a relation.
+        assert_eq!(parse_test(vec!(Tok::Atom("count".to_string()),
+                                   Tok::OpenParen,
+                                   Tok::Atom("a".to_string()),
+                                   Tok::CloseParen,
+                                   Tok::Dot)),
+                   None);
+    }
+
+    #[test]
+    fn count_derivations() {
+        let query = Term::Compound(CompoundTerm {
+            relation: "a".to_string(),
+            params: vec!(AtomicTerm::Variable("X".to_string()))
+        });
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("count_derivations".to_string()),
+                     Tok::Atom("a".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Query)),
+                Some(vec!(Line::CountDerivations(query))));
+
+        // Only queries can be counted.
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("count_derivations".to_string()),
+                     Tok::Atom("a".to_string()),
+                     Tok::Dot)),
+                None);
+    }
+
+    #[test]
+    fn not() {
+        let query = Term::Compound(CompoundTerm {
+            relation: "a".to_string(),
+            params: vec!(AtomicTerm::Atom(Value::from("b")))
+        });
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("not".to_string()),
+                     Tok::Atom("a".to_string()),
+                     Tok::OpenParen,
+                     Tok::Atom("b".to_string()),
+                     Tok::CloseParen,
+                     Tok::Query)),
+                Some(vec!(Line::Not(query))));
+
+        // In a rule body, "not" negates the term after it.
+        let x = || vec!(AtomicTerm::Variable("X".to_string()));
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("c".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Means,
+                     Tok::Atom("d".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Comma,
+                     Tok::Atom("not".to_string()),
+                     Tok::Atom("e".to_string()),
+                     Tok::OpenParen,
+                     Tok::Variable("X".to_string()),
+                     Tok::CloseParen,
+                     Tok::Dot)),
+                Some(vec!(Line::Rule(Rule {
+                    head: Term::Compound(CompoundTerm {
+                        relation: "c".to_string(),
+                        params: x()
+                    }),
+                    body: vec!(
+                        Term::Compound(CompoundTerm {
+                            relation: "d".to_string(),
+                            params: x()
+                        }),
+                        Term::Not(Box::new(Term::Compound(CompoundTerm {
+                            relation: "e".to_string(),
+                            params: x()
+                        }))))
+                }))));
+
+        // "not" is reserved, so can only name a relation when quoted.
+        assert_eq!(parse_test(
+                vec!(Tok::Atom("not".to_string()),
+                     Tok::OpenParen,
+                     Tok::Atom("a".to_string()),
+                     Tok::CloseParen,
+                     Tok::Query)),
+                None);
+        assert_eq!(parse_test(
+                vec!(Tok::Str("not".to_string()),
+                     Tok::OpenParen,
+                     Tok::Atom("a".to_string()),
+                     Tok::CloseParen,
+                     Tok::Query)),
+                Some(vec!(Line::Query(Term::Compound(CompoundTerm {
+                    relation: "not".to_string(),
+                    params: vec!(AtomicTerm::Atom(Value::from("a")))
+                })))));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_test(vec!(Tok::Command("tables".to_string()))),
+                   Some(vec!(Line::Command(Command {
+                       name: "tables".to_string(),
+                       args: vec!()
+                   }))));
+        assert_eq!(
+            parse_test(vec!(Tok::Command(
+                "export_query \"parent(X, Y)?\"  out.parquet".to_string()))),
+            Some(vec!(Line::Command(Command {
+                name: "export_query".to_string(),
+                args: vec!("parent(X, Y)?".to_string(),
+                           "out.parquet".to_string())
+            }))));
+        assert_eq!(parse_test(vec!(Tok::Command("set \"a".to_string()))),
+                   None);
+    }
+
+    #[test]
+    fn single_query() {
+        assert_eq!(parse_query("parent(X, isaac)?").unwrap(),
+                   Term::Compound(CompoundTerm {
+                       relation: "parent".to_string(),
+                       params: vec!(AtomicTerm::Variable("X".to_string()),
+                                    AtomicTerm::Atom(Value::from("isaac")))
+                   }));
+        assert!(parse_query("parent(X, isaac).").is_err());
+        assert!(parse_query("a? b?").is_err());
+    }
+
+    #[test]
+    fn query_params() {
+        let params = vec!("id_10002".to_string(), "7".to_string(),
+                          "x), evil(Y".to_string());
+        assert_eq!(parse_query_with_params("r(E, $1, $2, $3)?", &params)
+                       .unwrap(),
+                   Term::Compound(CompoundTerm {
+                       relation: "r".to_string(),
+                       params: vec!(AtomicTerm::Variable("E".to_string()),
+                                    AtomicTerm::Atom(Value::from("id_10002")),
+                                    AtomicTerm::Atom(Value::Int(7)),
+                                    AtomicTerm::Atom(Value::from("x), evil(Y")))
+                   }));
+        assert!(parse_query_with_params("r($4)?", &params).is_err());
+        assert!(parse_query_with_params("r($0)?", &params).is_err());
+
+        let count = |text| parameter_count(&parse_query(text).unwrap());
+        assert_eq!(count("r(E, $2, $1, $2)?").unwrap(), 2);
+        assert_eq!(count("r(E)?").unwrap(), 0);
+        assert!(count("r($1, $3)?").is_err());
+        assert!(count("r($0)?").is_err());
+
+        // Only text the lexer reads as a number is one.
+        assert_eq!(param_value("-2.5"), Value::Float(-2.5));
+        assert_eq!(param_value("-3"), Value::Int(-3));
+        for symbol in &["inf", "NaN", "infinity", "1e5", "+1", ".5", "1."] {
+            assert_eq!(param_value(symbol), Value::from(*symbol));
+        }
+    }
+
 }
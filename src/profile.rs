@@ -0,0 +1,175 @@
+/// Recording what each node of a query plan did while it ran, for
+/// `.explain_analyze`.
+///
+/// Profiling is per thread. While a profile is being recorded, `eval` enters a
+/// node each time it plans a scan for a term, so scans planned while planning
+/// another (e.g. the terms in the body of a view) become its children.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// What one node of a query plan did while it ran.
+#[derive(Debug, Default)]
+pub struct Node {
+    /// The term the node scans.
+    pub label: String,
+    /// How the scan was answered, e.g. from a table or a view.
+    pub kind: &'static str,
+    /// Whether the scan was answered from the view cache, for views.
+    pub cache_hit: Option<bool>,
+    /// The number of times the term was planned. Terms in the bodies of
+    /// recursive rules are planned once per iteration.
+    pub plans: usize,
+    /// Frames produced, over all of the times the term was planned.
+    pub rows: usize,
+    /// Time spent planning the scan and producing its frames, including time
+    /// spent in its children.
+    pub time: Duration,
+    /// How many of the joins in the bodies of its rules were evaluated with
+    /// each strategy, e.g. `hash`; see `spill`.
+    pub joins: BTreeMap<String, usize>,
+    pub children: Vec<Rc<RefCell<Node>>>
+}
+
+struct Profiler {
+    roots: Vec<Rc<RefCell<Node>>>,
+    /// The nodes currently being planned, innermost last.
+    stack: Vec<Rc<RefCell<Node>>>
+}
+
+thread_local! {
+    static PROFILER: RefCell<Option<Profiler>> = RefCell::new(None);
+}
+
+/// Start recording a profile on this thread, discarding any unfinished one.
+pub fn start() {
+    PROFILER.with(|p| {
+        *p.borrow_mut() = Some(Profiler {
+            roots: Vec::new(),
+            stack: Vec::new()
+        });
+    });
+}
+
+/// Stop recording, returning the top-level nodes of the profile.
+pub fn finish() -> Vec<Rc<RefCell<Node>>> {
+    PROFILER.with(|p| {
+        p.borrow_mut().take().map(|p| p.roots).unwrap_or_default()
+    })
+}
+
+/// Begin planning a scan for `term`, returning its node if a profile is being
+/// recorded. Planning the same term again below the same parent reuses its
+/// node.
+///
+/// Must be matched by a call to `exit`.
+pub fn enter<T: fmt::Display>(term: &T) -> Option<Rc<RefCell<Node>>> {
+    PROFILER.with(|p| {
+        let mut guard = p.borrow_mut();
+        let profiler = guard.as_mut()?;
+        let label = term.to_string();
+        let node = {
+            let siblings = match profiler.stack.last() {
+                Some(parent) => &mut parent.borrow_mut().children,
+                None => &mut profiler.roots
+            };
+            let existing = siblings.iter()
+                                   .find(|n| n.borrow().label == label)
+                                   .cloned();
+            existing.unwrap_or_else(|| {
+                let node = Rc::new(RefCell::new(Node {
+                    label,
+                    .. Node::default()
+                }));
+                siblings.push(node.clone());
+                node
+            })
+        };
+        node.borrow_mut().plans += 1;
+        profiler.stack.push(node.clone());
+        Some(node)
+    })
+}
+
+/// Finish planning the innermost scan.
+pub fn exit() {
+    PROFILER.with(|p| {
+        if let Some(profiler) = p.borrow_mut().as_mut() {
+            profiler.stack.pop();
+        }
+    });
+}
+
+/// The node of the innermost scan being planned, if a profile is being
+/// recorded.
+pub fn current() -> Option<Rc<RefCell<Node>>> {
+    PROFILER.with(|p| p.borrow().as_ref().and_then(|p| p.stack.last().cloned()))
+}
+
+/// Record how the innermost scan is answered.
+pub fn set_kind(kind: &'static str, cache_hit: Option<bool>) {
+    PROFILER.with(|p| {
+        if let Some(node) = p.borrow().as_ref().and_then(|p| p.stack.last()) {
+            let mut node = node.borrow_mut();
+            node.kind = kind;
+            node.cache_hit = cache_hit;
+        }
+    });
+}
+
+impl Node {
+    fn write_indented(&self, f: &mut fmt::Formatter, depth: usize)
+            -> fmt::Result {
+        let millis = self.time.as_secs() as f64 * 1e3
+                   + self.time.subsec_nanos() as f64 / 1e6;
+        write!(f, "{:indent$}{} [{}] rows={} time={:.3}ms",
+               "", self.label, self.kind, self.rows, millis,
+               indent = depth * 2)?;
+        if let Some(hit) = self.cache_hit {
+            write!(f, " cache={}", if hit { "hit" } else { "miss" })?;
+        }
+        if self.plans > 1 {
+            write!(f, " plans={}", self.plans)?;
+        }
+        if !self.joins.is_empty() {
+            let joins: Vec<String> = self.joins.iter().map(|(strategy, n)| {
+                format!("{}:{}", strategy, n)
+            }).collect();
+            write!(f, " joins={}", joins.join(","))?;
+        }
+        writeln!(f)?;
+        for child in &self.children {
+            child.borrow().write_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// The shape of the plan below this node: the term each node scans and
+    /// how, one per line and indented as when displayed, but without rows,
+    /// times or anything else that varies from run to run. Queries planned
+    /// the same way have plans of the same shape.
+    pub fn shape(&self) -> String {
+        let mut text = String::new();
+        self.write_shape(&mut text, 0);
+        text
+    }
+
+    fn write_shape(&self, text: &mut String, depth: usize) {
+        text.push_str(&format!("{:indent$}{} [{}]\n", "", self.label,
+                               self.kind, indent = depth * 2));
+        for child in &self.children {
+            child.borrow().write_shape(text, depth + 1);
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
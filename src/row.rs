@@ -0,0 +1,248 @@
+/// Query results that own their values, with typed access to their columns,
+/// for using data-goblin as a library.
+///
+/// A `Row` is made from each frame of a query, e.g.
+/// `eval::query(&engine, &cache, query)?.map(Row::from)`. Where copying every
+/// value would be too slow, `query_refs` gives `RowRef`s instead, which borrow
+/// their values from the database.
+
+use ast;
+use cache::ViewCache;
+use error::*;
+use eval;
+use eval::{AstView, Frame};
+use storage::StorageEngine;
+use value::Value;
+
+use serde::{Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use serde::ser::SerializeMap;
+use serde_json;
+
+use std;
+
+/// One answer to a query: the value of each of its variables.
+///
+/// Unlike a frame, a row owns its values, so it can outlive the database it
+/// came from. It also keeps its variables in order: that of the query, for
+/// rows from `query_rows`, and otherwise alphabetical.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    values: Vec<(String, Value)>
+}
+
+/// Types that `Row::get` can convert values to.
+pub trait FromValue: Sized {
+    /// What values of this type are called in errors, e.g. "an integer".
+    const DESCRIPTION: &'static str;
+
+    /// The value as this type, or `None` if it is some other kind of value.
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for i64 {
+    const DESCRIPTION: &'static str = "an integer";
+
+    fn from_value(value: &Value) -> Option<i64> {
+        match value {
+            Value::Int(i) => Some(*i),
+            _ => None
+        }
+    }
+}
+
+/// Integers are converted to floats, too.
+impl FromValue for f64 {
+    const DESCRIPTION: &'static str = "a number";
+
+    fn from_value(value: &Value) -> Option<f64> {
+        match value {
+            Value::Float(x) => Some(*x),
+            Value::Int(i) => Some(*i as f64),
+            _ => None
+        }
+    }
+}
+
+impl FromValue for bool {
+    const DESCRIPTION: &'static str = "a boolean";
+
+    fn from_value(value: &Value) -> Option<bool> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+}
+
+/// Both identifiers and quoted strings are converted to strings.
+impl FromValue for String {
+    const DESCRIPTION: &'static str = "an identifier or string";
+
+    fn from_value(value: &Value) -> Option<String> {
+        match value {
+            Value::Symbol(s) | Value::String(s) => Some(s.clone()),
+            _ => None
+        }
+    }
+}
+
+impl FromValue for Value {
+    const DESCRIPTION: &'static str = "a value";
+
+    fn from_value(value: &Value) -> Option<Value> {
+        Some(value.clone())
+    }
+}
+
+impl Row {
+    /// The value of the variable `column`, as a `T`, e.g.
+    /// `row.get::<i64>("SALARY")`.
+    pub fn get<T: FromValue>(&self, column: &str) -> Result<T> {
+        let value = self.values.iter().find(|(c, _)| c == column)
+            .map(|(_, v)| v)
+            .ok_or_else(|| {
+                Error::Row(format!("no variable {} in the row", column))
+            })?;
+        T::from_value(value).ok_or_else(|| Error::Row(format!(
+            "{} is {}, not {}", column, value, T::DESCRIPTION)))
+    }
+
+    /// The variables of the row, in order.
+    pub fn columns(&self) -> Vec<&str> {
+        self.values.iter().map(|(c, _)| c.as_str()).collect()
+    }
+
+    /// Make a row of the given variables from a frame, in their order.
+    ///
+    /// Panics if the frame is missing one of them.
+    pub fn from_frame(frame: &Frame, columns: &[String]) -> Row {
+        Row {
+            values: columns.iter().map(|c| {
+                (c.clone(), frame[c].clone())
+            }).collect()
+        }
+    }
+
+    /// Convert the row to any type that can be deserialized from a map of
+    /// its variables to their values, such as a struct with
+    /// `#[derive(Deserialize)]` and a field named for each variable.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::to_value(self).and_then(serde_json::from_value)
+                                  .map_err(|e| Error::Row(e.to_string()))
+    }
+}
+
+/// Run a query to completion, returning a row for each of its frames, with
+/// the query's variables in order of their first appearance.
+///
+/// The rows don't borrow from the database, so e.g. a lock on it can be
+/// released before they are used.
+pub fn query_rows(engine: &StorageEngine<AstView>,
+                  cache: &ViewCache,
+                  query: ast::Term) -> Result<Vec<Row>> {
+    let columns = query.variables();
+    Ok(eval::query(engine, cache, query)?.map(|frame| {
+        Row::from_frame(&frame, &columns)
+    }).collect())
+}
+
+/// One answer to a query, like a `Row`, but borrowing its values from the
+/// database rather than owning them; see `query_refs`.
+///
+/// Values are only converted when asked for, and text can be read with
+/// `get_str` without being copied.
+pub struct RowRef<'s, 'c> {
+    columns: &'c [String],
+    values: Vec<&'s Value>
+}
+
+impl<'s, 'c> RowRef<'s, 'c> {
+    /// The value of the variable `column`.
+    pub fn value(&self, column: &str) -> Result<&'s Value> {
+        self.columns.iter().position(|c| c == column)
+            .map(|i| self.values[i])
+            .ok_or_else(|| {
+                Error::Row(format!("no variable {} in the row", column))
+            })
+    }
+
+    /// The value of the variable `column`, as a `T`; see `Row::get`.
+    pub fn get<T: FromValue>(&self, column: &str) -> Result<T> {
+        let value = self.value(column)?;
+        T::from_value(value).ok_or_else(|| Error::Row(format!(
+            "{} is {}, not {}", column, value, T::DESCRIPTION)))
+    }
+
+    /// The text of the variable `column`, if it is an identifier or string.
+    pub fn get_str(&self, column: &str) -> Result<&'s str> {
+        match self.value(column)? {
+            Value::Symbol(s) | Value::String(s) => Ok(s),
+            value => Err(Error::Row(format!(
+                "{} is {}, not {}", column, value, String::DESCRIPTION)))
+        }
+    }
+
+    /// The variables of the query, in order of their first appearance.
+    pub fn columns(&self) -> &'c [String] {
+        self.columns
+    }
+
+    /// The values of the variables, in the order of `columns`.
+    pub fn values(&self) -> &[&'s Value] {
+        &self.values
+    }
+
+    /// Copy the values into a `Row`, which can outlive the database.
+    pub fn to_row(&self) -> Row {
+        Row {
+            values: self.columns.iter().cloned()
+                        .zip(self.values.iter().map(|v| (*v).clone()))
+                        .collect()
+        }
+    }
+}
+
+/// Answer a query, passing `f` its answers as `RowRef`s.
+///
+/// Nothing is copied out of the database to make the answers, so they can
+/// only be used until `f` returns.
+pub fn query_refs<T, F>(engine: &StorageEngine<AstView>,
+                        cache: &ViewCache,
+                        query: ast::Term,
+                        f: F) -> Result<T>
+        where F: for<'s, 'c> FnOnce(&mut Iterator<Item = RowRef<'s, 'c>>)
+                                    -> T {
+    let columns = query.variables();
+    let frames = eval::query(engine, cache, query)?;
+    let mut rows = frames.map(|frame| RowRef {
+        columns: &columns,
+        values: columns.iter().map(|c| frame[c]).collect()
+    });
+    Ok(f(&mut rows))
+}
+
+impl<'a> From<Frame<'a>> for Row {
+    fn from(frame: Frame<'a>) -> Row {
+        Row {
+            values: frame.into_iter().map(|(k, v)| (k, v.clone())).collect()
+        }
+    }
+}
+
+// Rows serialize as maps from variables to values, in the row's order. Unlike
+// in relation files, strings are written just like identifiers, so that both
+// deserialize as strings.
+impl Serialize for Row {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.values.len()))?;
+        for (column, value) in &self.values {
+            match value {
+                Value::String(s) => map.serialize_entry(column, s)?,
+                other => map.serialize_entry(column, other)?
+            }
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,147 @@
+/// Running untrusted facts, rules and queries, e.g. ones submitted by the users
+/// of a shared database.
+///
+/// A `Sandbox` asserts and queries through `eval`, but first checks that the
+/// program only adds to relations it created itself, and only reads those and
+/// the relations it was allowed. Every query runs under limits (see `limits`),
+/// so a sandboxed program can't tie up the database either.
+
+use ast;
+use cache::ViewCache;
+use error::*;
+use eval;
+use eval::{AstView, Frames};
+use graph;
+use limits::Limits;
+use storage::StorageEngine;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+type Storage = StorageEngine<AstView>;
+
+/// The limits a sandbox enforces where none are given: enough for reasonable
+/// programs, but not for runaway ones.
+pub fn default_limits() -> Limits {
+    Limits {
+        max_rows: Some(100_000),
+        max_derived: Some(1_000_000),
+        timeout: Some(Duration::from_secs(10)),
+        budget: None
+    }
+}
+
+/// Checks each fact, rule and query of an untrusted program before running it.
+pub struct Sandbox {
+    /// Relations that existed before the program ran, which it may read.
+    allowed: HashSet<String>,
+    /// Relations the program created, which it may read and add to.
+    created: HashSet<String>,
+    limits: Limits
+}
+
+impl Sandbox {
+    /// A sandbox in which programs may read the `allowed` relations, and
+    /// whose queries are held to `limits`, or to `default_limits` where those
+    /// are unset.
+    pub fn new<I>(allowed: I, limits: Limits) -> Self
+            where I: IntoIterator<Item = String> {
+        let defaults = default_limits();
+        Sandbox {
+            allowed: allowed.into_iter().collect(),
+            created: HashSet::new(),
+            limits: Limits {
+                max_rows: limits.max_rows.or(defaults.max_rows),
+                max_derived: limits.max_derived.or(defaults.max_derived),
+                timeout: limits.timeout.or(defaults.timeout),
+                budget: limits.budget
+            }
+        }
+    }
+
+    /// The relations the program has created so far, in no particular order.
+    pub fn created(&self) -> impl Iterator<Item = &str> {
+        self.created.iter().map(String::as_str)
+    }
+
+    /// Assert a fact or rule with `eval::assert`, if its relation was created
+    /// by the program, or is new and used by no view but the program's, and a
+    /// rule only reads relations the program may read.
+    pub fn assert(&mut self,
+                  engine: &mut Storage,
+                  cache: &mut ViewCache,
+                  rule: ast::Rule) -> Result<()> {
+        let name = relation_of(&rule.head)?;
+        if !self.created.contains(&name) {
+            if exists(engine, &name) {
+                return Err(Error::Sandbox(format!(
+                    "{} already exists, so can't be changed", name)));
+            }
+            // Creating a relation a view already uses would change the view.
+            let dependents = cache.dependents(&name);
+            let outside = dependents.iter().find(|view| {
+                !self.created.contains(*view)
+            });
+            if let Some(view) = outside {
+                return Err(Error::Sandbox(format!(
+                    "{} is used by {}, so can't be created", name, view)));
+            }
+        }
+        for term in &rule.body {
+            self.check_read(term)?;
+        }
+        eval::assert(engine, cache, rule)?;
+        self.created.insert(name);
+        Ok(())
+    }
+
+    /// Answer a query with `eval::query_limited`, if it only reads a relation
+    /// the program may read.
+    pub fn query<T, F>(&self,
+                       engine: &Storage,
+                       cache: &ViewCache,
+                       query: ast::Term,
+                       f: F) -> Result<T>
+            where F: for<'s> FnOnce(Frames<'s, 's>) -> T {
+        self.check_read(&query)?;
+        eval::query_limited(engine, cache, query, &Default::default(),
+                            &self.limits, f)
+    }
+
+    // Fail unless the program may read the relation of a term. `holds` reads
+    // every table, so it's never allowed.
+    fn check_read(&self, term: &ast::Term) -> Result<()> {
+        if let ast::Term::Not(negated) = term {
+            return self.check_read(negated);
+        }
+        let mut name = relation_of(term)?;
+        // The path built-ins read the table of edges designated for them.
+        if graph::is_path_builtin(&name) {
+            name = graph::edges();
+        }
+        let readable = name != "holds"
+            && (eval::is_builtin(&name) || self.allowed.contains(&name)
+                || self.created.contains(&name));
+        if readable {
+            Ok(())
+        } else {
+            Err(Error::Sandbox(format!("{} can't be read here", name)))
+        }
+    }
+}
+
+// The name of the relation a term is about.
+fn relation_of(term: &ast::Term) -> Result<String> {
+    match term {
+        ast::Term::Compound(c) => Ok(c.relation.clone()),
+        ast::Term::Atomic(ast::AtomicTerm::Atom(a)) => Ok(a.to_string()),
+        other => Err(Error::MalformedLine(
+                format!("expected a relation, not {}", other)))
+    }
+}
+
+// Does a relation of this name exist, whether built in, stored or virtual?
+fn exists(engine: &Storage, name: &str) -> bool {
+    eval::is_builtin(name) || engine.virtual_table(name).is_some()
+        || engine.get_relation(name).is_some()
+}
@@ -0,0 +1,255 @@
+/// Joining more frames than fit in memory, by spilling them to disk.
+///
+/// Joins on shared variables are evaluated by `eval::Join`, which picks a
+/// strategy once it has seen how many frames its build side (the terms after
+/// the first) produces:
+///
+/// - up to `JoinConfig::nested_loop_max`, a block nested loop, which for so
+///   few frames is cheaper than hashing them;
+/// - up to `JoinConfig::memory_budget`, a hash join, holding the build side in
+///   a hash table on the shared variables and probing it with each frame of
+///   the other side;
+/// - beyond that, a Grace hash join: both sides are split by the hash of their
+///   shared variables into `JoinConfig::partitions` files in the system's
+///   temporary directory (see `Partitions`), and each pair of partitions is
+///   hash joined in turn. A build partition still over budget is joined a
+///   budget's worth of frames at a time, rescanning its probe partition for
+///   each.
+///
+/// If frames can't be spilled, e.g. for lack of disk space, the join falls
+/// back to a nested loop. If spilled frames can't be read back, the join stops
+/// early and the query fails with the error (see `check`). The thresholds are
+/// per thread, like limits.
+
+use error::*;
+use eval;
+use eval::Frame;
+use value::Value;
+
+use serde_json;
+
+use std;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// When joins switch from one strategy to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JoinConfig {
+    /// The most frames on the build side of a join to join with a nested
+    /// loop.
+    pub nested_loop_max: usize,
+    /// The most frames on the build side of a join to hold in memory at once.
+    /// This counts frames, not bytes, however many values each holds.
+    pub memory_budget: usize,
+    /// The number of partitions to split each side of a join into once its
+    /// build side is over budget.
+    pub partitions: usize
+}
+
+impl Default for JoinConfig {
+    fn default() -> Self {
+        JoinConfig {
+            nested_loop_max: eval::BATCH_SIZE,
+            memory_budget: 1_000_000,
+            partitions: 16
+        }
+    }
+}
+
+/// How a join was evaluated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    NestedLoop,
+    Hash,
+    Grace
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Strategy::NestedLoop => "nested_loop",
+            Strategy::Hash => "hash",
+            Strategy::Grace => "grace"
+        })
+    }
+}
+
+thread_local! {
+    static CONFIG: Cell<JoinConfig> = Cell::new(JoinConfig::default());
+    static FAILURE: RefCell<Option<Error>> = RefCell::new(None);
+}
+
+/// The thresholds joins on this thread use.
+pub fn config() -> JoinConfig {
+    CONFIG.with(Cell::get)
+}
+
+/// Change the thresholds joins on this thread use.
+pub fn configure(config: JoinConfig) {
+    CONFIG.with(|c| c.set(config));
+}
+
+/// Note that a join on this thread stopped early because its spilled frames
+/// couldn't be read back, if no join had failed since the last `check`.
+pub fn fail(error: Error) {
+    FAILURE.with(|f| {
+        f.borrow_mut().get_or_insert(error);
+    });
+}
+
+/// Has a join on this thread failed since the last `check`?
+pub fn failed() -> bool {
+    FAILURE.with(|f| f.borrow().is_some())
+}
+
+/// Fail if a join on this thread stopped early since the last check, clearing
+/// the failure.
+pub fn check() -> Result<()> {
+    match FAILURE.with(|f| f.borrow_mut().take()) {
+        Some(error) => Err(error),
+        None => Ok(())
+    }
+}
+
+/// The values of the shared variables of a join in a frame, by which it is
+/// hashed and partitioned.
+pub type Key<'s> = Vec<Option<&'s Value>>;
+
+/// The key of a frame, given the shared variables.
+pub fn key<'s>(shared: &[String], frame: &Frame<'s>) -> Key<'s> {
+    shared.iter().map(|v| frame.get(v).cloned()).collect()
+}
+
+// Distinguishes the files of different `Partitions` in this process.
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Frames split by key into files, which are deleted when dropped.
+pub struct Partitions {
+    paths: Vec<PathBuf>,
+    writers: Vec<BufWriter<fs::File>>
+}
+
+impl Partitions {
+    /// Create `count` empty partitions.
+    pub fn create(count: usize) -> Result<Partitions> {
+        let spill = SPILLS.fetch_add(1, Ordering::Relaxed);
+        let mut partitions = Partitions {
+            paths: Vec::new(),
+            writers: Vec::new()
+        };
+        for i in 0..count.max(1) {
+            let path = std::env::temp_dir().join(format!(
+                "data-goblin-{}-{}-{}.spill", std::process::id(), spill, i));
+            let file = fs::File::create(&path).map_err(spill_error)?;
+            // Pushed together, so that a file created is always removed.
+            partitions.paths.push(path);
+            partitions.writers.push(BufWriter::new(file));
+        }
+        Ok(partitions)
+    }
+
+    /// The number of partitions.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Add a frame to the partition for its key.
+    pub fn write(&mut self, key: &Key, frame: &Frame) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let partition = (hasher.finish() % self.len() as u64) as usize;
+        let writer = &mut self.writers[partition];
+        serde_json::to_writer(&mut *writer, frame).map_err(spill_error)?;
+        writer.write_all(b"\n").map_err(spill_error)
+    }
+
+    /// Read back the frames of a partition, in the order they were written.
+    ///
+    /// They were written by this process, so failing to read them means
+    /// something else removed or damaged the file.
+    pub fn read(&mut self, partition: usize) -> Result<PartitionReader> {
+        let path = &self.paths[partition];
+        let file = self.writers[partition].flush()
+            .and_then(|_| fs::File::open(path))
+            .map_err(|e| read_error(path, e))?;
+        Ok(PartitionReader {
+            path: path.clone(),
+            lines: BufReader::new(file).lines()
+        })
+    }
+}
+
+impl Drop for Partitions {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("cannot remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// The frames of one partition, with their values owned.
+pub struct PartitionReader {
+    path: PathBuf,
+    lines: std::io::Lines<BufReader<fs::File>>
+}
+
+impl Iterator for PartitionReader {
+    type Item = Result<BTreeMap<String, Value>>;
+
+    fn next(&mut self) -> Option<Result<BTreeMap<String, Value>>> {
+        let line = self.lines.next()?;
+        Some(line.map_err(|e| read_error(&self.path, e)).and_then(|line| {
+            serde_json::from_str(&line).map_err(|e| read_error(&self.path, e))
+        }))
+    }
+}
+
+fn spill_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::StorageError(Box::new(e))
+}
+
+fn read_error<E: fmt::Display>(path: &Path, e: E) -> Error {
+    Error::StorageError(format!("cannot read spilled frames from {}: {}",
+                                path.display(), e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use spill::*;
+
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn unreadable_partitions() {
+        let value = Value::from("a");
+        let mut frame = BTreeMap::new();
+        frame.insert("X".to_string(), &value);
+        let mut partitions = Partitions::create(1).unwrap();
+        partitions.write(&vec!(Some(&value)), &frame).unwrap();
+        let frames: Vec<_> = partitions.read(0).unwrap().collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap()["X"], value);
+
+        // A damaged partition gives an error rather than a frame, and a
+        // missing one can't be read at all.
+        fs::write(&partitions.paths[0], "{\"X\":\n").unwrap();
+        let frames: Vec<_> = partitions.read(0).unwrap().collect();
+        assert!(frames[0].is_err());
+        fs::remove_file(&partitions.paths[0]).unwrap();
+        assert!(partitions.read(0).is_err());
+
+        fail(partitions.read(0).err().unwrap());
+        assert!(failed());
+        assert!(check().is_err());
+        assert!(check().is_ok());
+    }
+}
@@ -2,22 +2,153 @@
 /// 
 /// Uses the `serde_json` library for deserialization; note that all types that
 /// own durable data are `Serialize` and `Deserialize`.
+///
+/// Each relation is stored in a file named after it (see `encode_name`), along
+/// with the version of the file format (see `FORMAT_VERSION`), a checksum of
+/// its contents and, for tables, the number of facts, which are checked
+/// whenever the file is read.
+/// Files in older formats are upgraded when they are read. Facts asserted with
+/// `StorageEngine::assert_fact` are instead appended to a journal next to it
+/// (see `JOURNAL_EXTENSION`), one JSON tuple per line, so that each write-back
+/// only writes the new facts. Journals are compacted into the main file once
+/// they grow past `MAX_JOURNAL_LEN` facts, or on `StorageEngine::vacuum`.
+/// Main files are written to a temporary file (see `TEMP_EXTENSION`) and
+/// renamed over the old one, so that a crash leaves either the old file or the
+/// new one. Each journal starts with its generation, and the main file records
+/// the generation of the last journal compacted into it, so that a journal
+/// left behind by a crash after it was compacted is not replayed twice.
+///
+/// Queries saved with `StorageEngine::save_query` are kept in one more file,
+/// `QUERIES_FILE`, mapping their names to their text, the baseline plans for
+/// them (see `StorageEngine::save_plan`) in `PLANS_FILE`, and the history of
+/// each view's rules (see `StorageEngine::record_version`) in `HISTORY_FILE`.
+///
+/// A database may instead keep all of its views in `RULES_FILE`, as the text
+/// of their rules, which is easier to review and to keep under version
+/// control; see `StorageEngine::set_rules_file`. Tables stay in their own
+/// files either way.
 
+use ast;
 use error::*;
 use error::Error::StorageError;
+use value::Value;
 
-use serde::{Serialize, Deserialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::SerializeSeq;
 use serde_json;
+use serde_json::Value as JsonValue;
 
 use std;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io;
+use std::io::{BufRead, Read, Write};
 use std::iter::IntoIterator;
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Appended to the name of a relation's file to get the name of its journal.
+pub const JOURNAL_EXTENSION: &'static str = ".facts";
+
+/// Appended to the name of a relation's file to get the name of the file its
+/// new contents are written to before replacing it. `encode_name` never writes
+/// a `.`, so this never clashes with a relation's file.
+pub const TEMP_EXTENSION: &'static str = ".tmp";
+
+/// The file in the data directory holding saved queries. Relation names can't
+/// begin with `.`, so this never clashes with a relation's file.
+pub const QUERIES_FILE: &'static str = ".queries";
+
+/// The file in the data directory holding the baseline plans for saved
+/// queries. Like `QUERIES_FILE`, it never clashes with a relation's file.
+pub const PLANS_FILE: &'static str = ".plans";
+
+/// The file in the data directory holding the history of each view's rules.
+/// Like `QUERIES_FILE`, it never clashes with a relation's file.
+pub const HISTORY_FILE: &'static str = ".history";
+
+/// The file in the data directory holding the rules of every view, for
+/// databases using it; see `StorageEngine::set_rules_file`. `encode_name`
+/// never writes a `.`, so this never clashes with a relation's file.
+pub const RULES_FILE: &'static str = "rules.dl";
+
+/// Appended to the name of a view's file to get the name of the file holding
+/// its materialization; see `StorageEngine::materialize`. `encode_name` never
+/// writes a `.`, so this never clashes with a relation's file.
+pub const MATERIALIZED_EXTENSION: &'static str = ".fixpoint";
+
+/// Journals longer than this many facts are compacted on the next write-back.
+pub const MAX_JOURNAL_LEN: usize = 1024;
+
+/// The longest allowed name of a new relation, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// The number of threads used to read relation files when opening a database.
+pub const LOAD_THREADS: usize = 4;
+
+/// The version of the file format written by this version of data-goblin.
+///
+/// - 0: the relation's contents, and the path it was written to.
+/// - 1: adds a checksum of the contents and, for tables, the number of facts.
+/// - 2: adds the version, and drops the path, which went stale whenever a
+///   data directory was moved.
+/// - 3: adds each table's optional limit on its number of facts.
+pub const FORMAT_VERSION: u64 = 3;
+
+// `MIGRATIONS[i]` upgrades a file, parsed as JSON, from version `i` to version
+// `i + 1`.
+const MIGRATIONS: &[fn(&mut JsonValue)] = &[
+    from_version_0,
+    from_version_1,
+    from_version_2
+];
+
+// Checksums and counts are only checked when present, so version 0 files need
+// no changes.
+fn from_version_0(_file: &mut JsonValue) {}
+
+fn from_version_1(file: &mut JsonValue) {
+    if let Some(fields) = file.as_object_mut() {
+        fields.remove("path");
+    }
+}
+
+// Tables without a limit on their number of facts are unchanged.
+fn from_version_2(_file: &mut JsonValue) {}
+
+// Get the version of a file, parsed as JSON. Versions 0 and 1 predate the
+// version field, and are told apart by whether they have a checksum.
+fn format_version(file: &JsonValue) -> Option<u64> {
+    match (file.get("version"), file.get("checksum")) {
+        (Some(version), _) => version.as_u64(),
+        (None, Some(_)) => Some(1),
+        (None, None) => Some(0)
+    }
+}
+
+// Get the version a relation file starts with, as `write_to` writes them,
+// without consuming any of it. Files that start any other way, like those
+// from before the version came first, have to be parsed whole to find it.
+fn leading_version<R: BufRead>(reader: &mut R) -> Result<Option<u64>> {
+    let start = b"{\"version\":";
+    let buffer = reader.fill_buf().map_err(err)?;
+    if !buffer.starts_with(start) {
+        return Ok(None);
+    }
+    let digits = buffer[start.len()..].iter()
+                                      .take_while(|b| b.is_ascii_digit())
+                                      .count();
+    let end = start.len() + digits;
+    if digits == 0 || end == buffer.len() {
+        return Ok(None);
+    }
+    Ok(std::str::from_utf8(&buffer[start.len()..end]).ok()
+           .and_then(|version| version.parse().ok()))
+}
 
 // Perhaps we want this to be generic in the future to allow swapping out
 // storage engines, since we're likely to make several storage engines. For now,
@@ -25,60 +156,594 @@ use std::sync::atomic::{AtomicBool, Ordering};
 // kind of interface works.
 
 /// A `Tuple` is simply an ordered collection of atoms.
-pub type Tuple<'a> = Vec<&'a str>;
+pub type Tuple<'a> = Vec<&'a Value>;
+
+/// Maps atoms to small integer ids and back, so that each distinct atom in a
+/// table is stored only once.
+#[derive(Debug, Default)]
+struct Interner {
+    symbols: Vec<Value>,
+    ids: HashMap<Value, usize>
+}
+
+impl Interner {
+    /// Get the id of the given atom, assigning it a new one if necessary.
+    fn intern(&mut self, atom: Value) -> usize {
+        if let Some(id) = self.ids.get(&atom) {
+            return *id;
+        }
+
+        let id = self.symbols.len();
+        self.symbols.push(atom.clone());
+        self.ids.insert(atom, id);
+        id
+    }
+
+    /// Get the id of the given atom, if it has been interned.
+    fn lookup(&self, atom: &Value) -> Option<usize> {
+        self.ids.get(atom).map(|id| *id)
+    }
+
+    fn resolve(&self, id: usize) -> &Value {
+        &self.symbols[id]
+    }
+}
+
+/// A reference from some columns of a table to columns of another relation,
+/// like a foreign key in SQL: each fact in the table must agree with some
+/// tuple of the other relation on those columns.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    /// The referring columns of the table, numbered from 0.
+    pub columns: Vec<usize>,
+    /// The relation referred to.
+    pub relation: String,
+    /// The columns of `relation` that `columns` must match, in order.
+    pub referenced: Vec<usize>
+}
+
+/// A trigger on a table: whenever a fact matching `pattern` is asserted into
+/// the table, each of `actions` is asserted too, with the variables of the
+/// pattern replaced by their values in the fact.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trigger {
+    pub pattern: Vec<ast::AtomicTerm>,
+    pub actions: Vec<ast::CompoundTerm>
+}
 
 /// A `Table` is an extensional relation in the database.
-#[derive(Debug, Serialize, Deserialize)]
+///
+/// Tables are stored column-major: each column is a vector of interned atom
+/// ids. On disk, they are still serialized as a flat, row-major list of atoms.
+#[derive(Debug)]
 pub struct Table {
-    contents: Vec<String>,
-    arity: usize
+    columns: Vec<Vec<usize>>,
+    len: usize,
+    arity: usize,
+    symbols: Interner,
+    /// The most facts to keep; see `expire`.
+    max_len: Option<usize>,
+    references: Vec<Reference>,
+    triggers: Vec<Trigger>,
+    /// The number of copies of each distinct fact, by the ids of its atoms,
+    /// kept up to date as facts are added and removed; see `distinct_len`.
+    copies: HashMap<Vec<usize>, usize>,
+    /// For each indexed column, the rows holding each atom (by id), in order;
+    /// see `add_index`.
+    indexes: BTreeMap<usize, HashMap<usize, Vec<usize>>>
+}
+
+/// The serialized form of a `Table`.
+#[derive(Serialize, Deserialize)]
+struct RowTable<C> {
+    contents: C,
+    arity: usize,
+    /// The number of facts in a table of arity 0, which have no atoms to be
+    /// counted by; absent if there are none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    len: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_len: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    references: Vec<Reference>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    triggers: Vec<Trigger>,
+    /// The indexed columns; the indexes themselves are rebuilt on loading.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    indexes: Vec<usize>
 }
 
 impl Table {
     pub fn new(arity: usize) -> Self {
         Table {
-            contents: Vec::new(),
-            arity
+            columns: (0..arity).map(|_| Vec::new()).collect(),
+            len: 0,
+            arity,
+            symbols: Interner::default(),
+            max_len: None,
+            references: Vec::new(),
+            triggers: Vec::new(),
+            copies: HashMap::new(),
+            indexes: BTreeMap::new()
+        }
+    }
+
+    // The ids of the atoms in a row, which identify its fact.
+    fn row_ids(&self, row: usize) -> Vec<usize> {
+        self.columns.iter().map(|c| c[row]).collect()
+    }
+
+    // Count another copy of the fact in a row just appended.
+    fn count_row(&mut self, row: usize) {
+        *self.copies.entry(self.row_ids(row)).or_insert(0) += 1;
+    }
+
+    // Count one copy fewer of the fact in a row about to be removed.
+    fn uncount_row(&mut self, row: usize) {
+        let ids = self.row_ids(row);
+        let last = match self.copies.get_mut(&ids) {
+            Some(copies) => {
+                *copies -= 1;
+                *copies == 0
+            },
+            None => panic!("row {} of a table was never counted", row)
+        };
+        if last {
+            self.copies.remove(&ids);
+        }
+    }
+
+    // Count every fact again, after changing the columns of every row.
+    fn recount(&mut self) {
+        self.copies.clear();
+        for row in 0..self.len {
+            self.count_row(row);
         }
     }
 
     /// Add a fact to this relation.
-    pub fn assert(&mut self, mut fact: Vec<String>) -> Result<()> {
+    pub fn assert(&mut self, fact: Vec<Value>) -> Result<()> {
         if fact.len() != self.arity {
             Err(Error::ArityMismatch {
                 expected: self.arity,
                 got: fact.len()
             })
         } else {
-            self.contents.append(&mut fact);
+            for (column, atom) in self.columns.iter_mut().zip(fact) {
+                column.push(self.symbols.intern(atom));
+            }
+            self.index_row(self.len);
+            self.count_row(self.len);
+            self.len += 1;
             Ok(())
         }
     }
+
+    /// Add many facts to this relation at once.
+    ///
+    /// Checks the arity of every fact before adding any, so either all of the
+    /// facts are added or none are.
+    pub fn assert_batch(&mut self, facts: Vec<Vec<Value>>) -> Result<()> {
+        if let Some(fact) = facts.iter().find(|f| f.len() != self.arity) {
+            return Err(Error::ArityMismatch {
+                expected: self.arity,
+                got: fact.len()
+            });
+        }
+
+        for column in &mut self.columns {
+            column.reserve(facts.len());
+        }
+        let first = self.len;
+        self.len += facts.len();
+        for fact in facts {
+            for (column, atom) in self.columns.iter_mut().zip(fact) {
+                column.push(self.symbols.intern(atom));
+            }
+        }
+        for row in first..self.len {
+            self.index_row(row);
+            self.count_row(row);
+        }
+        Ok(())
+    }
+
+    /// The number of columns in this relation.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// The number of tuples in this relation, counting any asserted more than
+    /// once each time.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of distinct tuples in this relation.
+    pub fn distinct_len(&self) -> usize {
+        self.copies.len()
+    }
+
+    /// Add a column to the end of this table, holding `fill` in every fact.
+    pub fn add_column(&mut self, fill: Value) {
+        let id = self.symbols.intern(fill);
+        self.columns.push(vec![id; self.len]);
+        self.arity += 1;
+        self.recount();
+    }
+
+    /// Remove every column past the first `arity`.
+    pub fn truncate_columns(&mut self, arity: usize) {
+        if arity < self.arity {
+            self.columns.truncate(arity);
+            self.arity = arity;
+            self.indexes.split_off(&arity);
+            self.recount();
+        }
+    }
+
+    /// The number of distinct atoms in a column.
+    pub fn distinct_in(&self, column: usize) -> usize {
+        self.columns[column].iter().collect::<HashSet<_>>().len()
+    }
+
+    /// The indexed columns of this table, in order.
+    pub fn indexes(&self) -> Vec<usize> {
+        self.indexes.keys().cloned().collect()
+    }
+
+    /// Whether a column is indexed.
+    pub fn is_indexed(&self, column: usize) -> bool {
+        self.indexes.contains_key(&column)
+    }
+
+    /// Index a column, so that scans binding it to an atom (see `select`)
+    /// only visit the rows holding that atom, rather than every row. The index
+    /// is kept up to date as facts are added and removed, at some cost to
+    /// both. Returns whether the column wasn't indexed already.
+    pub fn add_index(&mut self, column: usize) -> bool {
+        if self.is_indexed(column) {
+            return false;
+        }
+        let mut index: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (row, id) in self.columns[column].iter().enumerate() {
+            index.entry(*id).or_insert_with(Vec::new).push(row);
+        }
+        self.indexes.insert(column, index);
+        true
+    }
+
+    /// Remove the index on a column, returning whether there was one.
+    pub fn drop_index(&mut self, column: usize) -> bool {
+        self.indexes.remove(&column).is_some()
+    }
+
+    // Add a row just appended to every index.
+    fn index_row(&mut self, row: usize) {
+        for (column, index) in &mut self.indexes {
+            let id = self.columns[*column][row];
+            index.entry(id).or_insert_with(Vec::new).push(row);
+        }
+    }
+
+    // Rebuild every index, after removing rows shifts the rest.
+    fn reindex(&mut self) {
+        for column in self.indexes() {
+            self.indexes.remove(&column);
+            self.add_index(column);
+        }
+    }
+
+    /// The most facts this table keeps, if it is limited.
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+
+    /// Limit the number of facts this table keeps, or with `None`, remove the
+    /// limit. The limit is enforced by `expire`.
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+    }
+
+    /// The references from this table to other relations.
+    pub fn references(&self) -> &[Reference] {
+        &self.references
+    }
+
+    /// Declare a reference from this table to another relation. This table's
+    /// facts aren't checked against it.
+    pub fn add_reference(&mut self, reference: Reference) {
+        if !self.references.contains(&reference) {
+            self.references.push(reference);
+        }
+    }
+
+    /// The triggers on this table, in the order they were added.
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+
+    /// Add a trigger to this table. It doesn't fire for facts already in the
+    /// table.
+    pub fn add_trigger(&mut self, trigger: Trigger) {
+        if !self.triggers.contains(&trigger) {
+            self.triggers.push(trigger);
+        }
+    }
+
+    /// Remove the oldest facts from this table until it is within its limit,
+    /// returning the number removed.
+    pub fn expire(&mut self) -> usize {
+        let excess = match self.max_len {
+            Some(max) if self.len > max => self.len - max,
+            _ => return 0
+        };
+
+        for row in 0..excess {
+            self.uncount_row(row);
+        }
+        for column in &mut self.columns {
+            column.drain(..excess);
+        }
+        self.len -= excess;
+        self.reindex();
+        excess
+    }
+
+    /// Remove the most recently added copy of a fact from this table,
+    /// returning whether there was one.
+    pub fn retract(&mut self, fact: &[Value]) -> bool {
+        let ids: Option<Vec<usize>> =
+            fact.iter().map(|atom| self.symbols.lookup(atom)).collect();
+        let ids = match ids {
+            Some(ids) if ids.len() == self.arity => ids,
+            _ => return false
+        };
+        let row = (0..self.len).rev().find(|row| {
+            self.columns.iter().zip(&ids).all(|(c, id)| c[*row] == *id)
+        });
+        match row {
+            Some(row) => {
+                self.uncount_row(row);
+                for column in &mut self.columns {
+                    column.remove(row);
+                }
+                self.len -= 1;
+                self.reindex();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Remove every fact `keep` rejects, returning the number removed.
+    ///
+    /// Unlike retracting them one by one, the table is only rewritten once.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+            where F: FnMut(&Tuple) -> bool {
+        let (kept, dropped): (Vec<usize>, Vec<usize>) =
+            (0..self.len).partition(|row| keep(&self.row(*row)));
+        if dropped.is_empty() {
+            return 0;
+        }
+        for row in &dropped {
+            self.uncount_row(*row);
+        }
+        for column in &mut self.columns {
+            let rest: Vec<usize> =
+                kept.iter().map(|row| column[*row]).collect();
+            *column = rest;
+        }
+        self.len = kept.len();
+        self.reindex();
+        dropped.len()
+    }
+
+    /// Whether this table holds a fact.
+    pub fn contains(&self, fact: &[Value]) -> bool {
+        if fact.len() != self.arity {
+            return false;
+        }
+        let constants: Vec<(usize, &Value)> = fact.iter().enumerate().collect();
+        self.select(&constants).next().is_some()
+    }
+
+    /// Scan over only the tuples with the given atoms in the given columns.
+    ///
+    /// `constants` holds pairs of column indices and the atom that column must
+    /// contain.
+    pub fn select<'a>(&'a self, constants: &[(usize, &Value)])
+            -> TableScan<'a> {
+        self.select_equal(constants, &[])
+    }
+
+    /// Like `select`, but also skip tuples with different atoms in any pair of
+    /// columns in `equal`, e.g. for a term like `edge(X, X)`. The columns are
+    /// compared without reading their atoms.
+    ///
+    /// If any of the columns in `constants` is indexed, only the rows the
+    /// index gives for its atom are visited; with several, the fewest.
+    pub fn select_equal<'a>(&'a self,
+                            constants: &[(usize, &Value)],
+                            equal: &[(usize, usize)]) -> TableScan<'a> {
+        // An atom the table has never seen can't match anything.
+        let filter: Vec<_> = constants.iter().map(|(column, atom)| {
+            (*column, self.symbols.lookup(atom))
+        }).collect();
+        // So if there is one, visit no rows at all.
+        let rows = if filter.iter().any(|(_, id)| id.is_none()) {
+            Some(&[][..])
+        } else {
+            filter.iter().filter_map(|(column, id)| {
+                let index = self.indexes.get(column)?;
+                Some(id.and_then(|id| index.get(&id))
+                       .map_or(&[][..], Vec::as_slice))
+            }).min_by_key(|rows| rows.len())
+        };
+
+        TableScan {
+            table: self,
+            index: 0,
+            rows,
+            filter,
+            equal: equal.to_vec()
+        }
+    }
+
+    /// Iterate over the atoms in a single column.
+    pub fn column<'a>(&'a self, column: usize)
+            -> impl Iterator<Item = &'a Value> + 'a {
+        let symbols = &self.symbols;
+        self.columns[column].iter().map(move |id| symbols.resolve(*id))
+    }
+
+    // Does the row at the given index pass all of the filters, and have the
+    // same atom in each pair of columns in `equal`?
+    fn matches(&self,
+               row: usize,
+               filter: &[(usize, Option<usize>)],
+               equal: &[(usize, usize)]) -> bool {
+        filter.iter().all(|(column, id)| {
+            *id == Some(self.columns[*column][row])
+        }) && equal.iter().all(|(first, second)| {
+            self.columns[*first][row] == self.columns[*second][row]
+        })
+    }
+
+    fn row(&self, row: usize) -> Tuple {
+        self.columns.iter().map(|c| self.symbols.resolve(c[row])).collect()
+    }
+}
+
+// The atoms of a table in row-major order, serialized one at a time rather than
+// collected first.
+struct RowMajor<'a>(&'a Table);
+
+impl<'a> Serialize for RowMajor<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> std::result::Result<S::Ok, S::Error> {
+        let table = self.0;
+        let mut seq = serializer.serialize_seq(Some(table.len * table.arity))?;
+        for row in 0..table.len {
+            for column in &table.columns {
+                seq.serialize_element(table.symbols.resolve(column[row]))?;
+            }
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Table {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> std::result::Result<S::Ok, S::Error> {
+        let len = if self.arity == 0 && self.len > 0 {
+            Some(self.len)
+        } else {
+            None
+        };
+        RowTable { contents: RowMajor(self), arity: self.arity, len,
+                   max_len: self.max_len,
+                   references: self.references.clone(),
+                   triggers: self.triggers.clone(),
+                   indexes: self.indexes() }.serialize(serializer)
+    }
+}
+
+/// A flat list of atoms, interned as they are read, so that deserializing a
+/// large table never holds all of its atoms in memory at once.
+#[derive(Default)]
+struct InternedAtoms {
+    ids: Vec<usize>,
+    symbols: Interner
+}
+
+impl<'de> de::Visitor<'de> for InternedAtoms {
+    type Value = InternedAtoms;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a list of atoms")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(mut self, mut seq: A)
+            -> std::result::Result<InternedAtoms, A::Error> {
+        while let Some(atom) = seq.next_element::<Value>()? {
+            self.ids.push(self.symbols.intern(atom));
+        }
+        Ok(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for InternedAtoms {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_seq(InternedAtoms::default())
+    }
+}
+
+impl<'de> Deserialize<'de> for Table {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> std::result::Result<Self, D::Error> {
+        let rows: RowTable<InternedAtoms> =
+            RowTable::deserialize(deserializer)?;
+        let (atoms, arity) = (rows.contents.ids, rows.arity);
+        if atoms.len() % arity.max(1) != 0 || (arity == 0 && atoms.len() != 0) {
+            return Err(de::Error::custom(
+                    format!("{} atoms do not divide into tuples of arity {}",
+                            atoms.len(), arity)));
+        }
+
+        let mut table = Table::new(arity);
+        table.max_len = rows.max_len;
+        table.references = rows.references;
+        table.triggers = rows.triggers;
+        table.symbols = rows.contents.symbols;
+        table.len = if arity == 0 {
+            rows.len.unwrap_or(0)
+        } else {
+            atoms.len() / arity
+        };
+        for column in &mut table.columns {
+            column.reserve(table.len);
+        }
+        for (i, id) in atoms.into_iter().enumerate() {
+            table.columns[i % arity].push(id);
+        }
+        table.recount();
+        for column in rows.indexes.into_iter().filter(|c| *c < arity) {
+            table.add_index(column);
+        }
+        Ok(table)
+    }
 }
 
-/// A TableScan is an iterator over all of the tuples in an extensional
-/// relation.
+/// A TableScan is an iterator over the tuples in an extensional relation.
 #[derive(Debug)]
 pub struct TableScan<'a> {
     table: &'a Table,
-    index: usize
+    index: usize,
+    /// The only rows that can match, from an index, or `None` to visit every
+    /// row.
+    rows: Option<&'a [usize]>,
+    filter: Vec<(usize, Option<usize>)>,
+    equal: Vec<(usize, usize)>
 }
 
 impl<'a> Iterator for TableScan<'a> {
     type Item = Tuple<'a>;
 
     fn next(&mut self) -> Option<Tuple<'a>> {
-        if self.index >= self.table.contents.len() {
-            return None;
-        }
-
-        let slice = self.index..self.index + self.table.arity;
-        let result: Vec<_> =
-            self.table.contents[slice].into_iter().map(|s| s.as_str()).collect();
+        loop {
+            let row = match self.rows {
+                Some(rows) => *rows.get(self.index)?,
+                None if self.index < self.table.len => self.index,
+                None => return None
+            };
+            self.index += 1;
 
-        self.index += self.table.arity;
-
-        Some(result)
+            if self.table.matches(row, &self.filter, &self.equal) {
+                return Some(self.table.row(row));
+            }
+        }
     }
 }
 
@@ -88,17 +753,54 @@ impl<'i> IntoIterator for &'i Table {
     type IntoIter = TableScan<'i>;
 
     fn into_iter(self) -> TableScan<'i> {
-        TableScan {
-            table: self,
-            index: 0
-        }
+        self.select(&[])
     }
 }
 
+/// A relation whose facts come from outside the database, like a file or an
+/// iterator provided by a program embedding data-goblin.
+///
+/// Virtual tables are queried like tables, but can't be changed and are never
+/// written back; see `StorageEngine::attach`.
+pub trait VirtualTable: Send + Sync {
+    /// The number of columns of every fact.
+    fn arity(&self) -> usize;
+
+    /// Read every fact of the relation. This is called each time the relation
+    /// is scanned, so the facts may change from one query to the next.
+    fn facts(&self) -> Result<Vec<Vec<Value>>>;
+}
+
 pub trait View<'de>: Serialize + Deserialize<'de> {}
 
 impl<'de, T: Serialize + Deserialize<'de>> View<'de> for T {}
 
+/// One version of a view's rules, as recorded by
+/// `StorageEngine::record_version`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    /// When the rules changed, in seconds since the Unix epoch.
+    pub time: u64,
+    /// Who changed them.
+    pub user: String,
+    /// What the change was, e.g. the rule asserted.
+    pub change: String,
+    /// The text of each of the view's rules after the change, in order.
+    pub rules: Vec<String>
+}
+
+/// Views that can be kept in `RULES_FILE` as the text of their rules, rather
+/// than in files of their own.
+pub trait RulesText: Sized {
+    /// Lines of Datalog defining the named view, along with its description,
+    /// if it has one.
+    fn to_text(&self, name: &str, description: Option<&str>) -> String;
+
+    /// The views, with their names and descriptions, defined by text written
+    /// by `to_text`.
+    fn from_text(text: &str) -> Result<Vec<(String, Self, Option<String>)>>;
+}
+
 /// A `Relation` is either an extensional or an intensional relation.
 #[derive(Serialize, Deserialize)]
 pub enum Relation<V> {
@@ -111,17 +813,146 @@ impl<'de, V: View<'de>> Relation<V> {
         let out = io::BufWriter::new(fs::File::create(path).unwrap());
         serde_json::to_writer(out, self).unwrap();
     }
+
+    // The number of facts in this relation, if it is a table.
+    fn count(&self) -> Option<usize> {
+        match self {
+            Relation::Extension(t) => Some(t.len()),
+            Relation::Intension(_) => None
+        }
+    }
+}
+
+// Passes writes through to another writer, computing a checksum of everything
+// written, so that files can be checksummed as they are written or read without
+// holding them in memory. Uses FNV-1a, which is simple and fast, and good
+// enough to catch accidental corruption.
+struct Checksummed<W> {
+    inner: W,
+    hash: u64
+}
+
+impl<W: Write> Checksummed<W> {
+    fn new(inner: W) -> Self {
+        Checksummed { inner, hash: 0xcbf29ce484222325 }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        for byte in &buf[..written] {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Get the checksum of a relation's contents, as written by `write`.
+fn checksum<V: Serialize>(contents: &Relation<V>) -> Result<u64> {
+    let mut out = Checksummed::new(io::sink());
+    serde_json::to_writer(&mut out, contents).map_err(err)?;
+    Ok(out.hash)
+}
+
+// An error for a relation file that can't be read.
+fn corrupt(file: &Path, reason: String) -> Error {
+    Error::Corrupt { path: file.to_string_lossy().into_owned(), reason }
+}
+
+/// The on-disk form of a relation, once upgraded to the current version.
+///
+/// Files written before checksums were introduced have no `checksum` or
+/// `count`, and are read without checking them.
+#[derive(Deserialize)]
+struct RelationFile<V> {
+    #[serde(default)]
+    version: Option<u64>,
+    #[serde(default)]
+    checksum: Option<u64>,
+    #[serde(default)]
+    count: Option<usize>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    journal: Option<usize>,
+    contents: Relation<V>
 }
 
-#[derive(Serialize, Deserialize)]
 struct TaggedRelation<V> {
     contents: Relation<V>,
     path: String,
-    #[serde(default, skip)]
-    dirty: AtomicBool
+    /// What the relation holds, for people reading the database; see
+    /// `StorageEngine::describe`.
+    description: Option<String>,
+    dirty: AtomicBool,
+    /// Facts asserted since the last write-back, to append to the journal.
+    pending: Mutex<Vec<Vec<Value>>>,
+    /// The number of facts in the journal on disk.
+    journal_len: AtomicUsize,
+    /// The generation of the last journal compacted into the main file, or 0
+    /// if there hasn't been one. The next journal has the one after it.
+    compacted: AtomicUsize
 }
 
 impl<'de, V: View<'de>> TaggedRelation<V> {
+    fn new(contents: Relation<V>, path: String) -> Self {
+        TaggedRelation {
+            contents,
+            path,
+            description: None,
+            dirty: AtomicBool::new(false),
+            pending: Mutex::default(),
+            journal_len: AtomicUsize::new(0),
+            compacted: AtomicUsize::new(0)
+        }
+    }
+
+    // Write the whole relation to its file, with its count, description and
+    // last compacted journal ahead of the contents and its checksum after
+    // them. The file is only replaced once the new one is complete.
+    fn write(&self) -> Result<()> {
+        let temp = format!("{}{}", self.path, TEMP_EXTENSION);
+        self.write_to(&temp)?;
+        fs::rename(&temp, &self.path).map_err(err)
+    }
+
+    fn write_to(&self, path: &str) -> Result<()> {
+        let file = fs::File::create(path).map_err(err)?;
+        let mut out = io::BufWriter::new(file);
+        write!(out, "{{\"version\":{}", FORMAT_VERSION).map_err(err)?;
+        if let Some(count) = self.contents.count() {
+            write!(out, ",\"count\":{}", count).map_err(err)?;
+        }
+        if let Some(ref description) = self.description {
+            write!(out, ",\"description\":").map_err(err)?;
+            serde_json::to_writer(&mut out, description).map_err(err)?;
+        }
+        let compacted = self.compacted.load(Ordering::SeqCst);
+        if compacted > 0 {
+            write!(out, ",\"journal\":{}", compacted).map_err(err)?;
+        }
+        write!(out, ",\"contents\":").map_err(err)?;
+        let hash = {
+            let mut contents = Checksummed::new(&mut out);
+            serde_json::to_writer(&mut contents, &self.contents)
+                .map_err(err)?;
+            contents.hash
+        };
+        write!(out, ",\"checksum\":{}}}", hash).map_err(err)?;
+        out.flush().map_err(err)?;
+        out.get_ref().sync_all().map_err(err)
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
     /// Set the "dirty" flag, and return the previous dirty state.
     fn dirty(&self) -> bool {
         self.dirty.swap(true, Ordering::SeqCst)
@@ -132,14 +963,145 @@ impl<'de, V: View<'de>> TaggedRelation<V> {
         self.dirty.swap(false, Ordering::SeqCst)
     }
 
-    // On dropping the `RelViewMut`, any changes are written back.
+    // Whether the main file already includes the journal with the given
+    // generation. Journals without one are never compacted.
+    fn includes(&self, generation: usize) -> bool {
+        generation != 0 && generation <= self.compacted.load(Ordering::SeqCst)
+    }
+
+    fn journal_path(&self) -> String {
+        format!("{}{}", self.path, JOURNAL_EXTENSION)
+    }
+
+    // Write back any changes: either the whole relation, if it has been marked
+    // dirty or its journal has grown too long, or just the facts asserted
+    // since the last write-back.
     fn write_back(&self) {
+        let pending = mem::replace(&mut *self.pending.lock().unwrap(),
+                                   Vec::new());
+        let journal_len = self.journal_len.load(Ordering::SeqCst);
+        if journal_len + pending.len() > MAX_JOURNAL_LEN {
+            self.dirty();
+        }
+
+        let journal = self.journal_path();
+        let journaled = Path::new(&journal).exists();
         if self.clean() {
-            let out =
-                io::BufWriter::new(fs::File::create(self.path.as_str())
-                                       .unwrap());
-            serde_json::to_writer(out, self).unwrap();
+            debug!("writing back {}", self.path);
+            // Once the main file records the journal as compacted, a crash
+            // before it is removed leaves it to be skipped, not replayed.
+            if journaled {
+                self.compacted.fetch_add(1, Ordering::SeqCst);
+            }
+            self.write().unwrap();
+            if journaled {
+                fs::remove_file(&journal).unwrap();
+                self.journal_len.store(0, Ordering::SeqCst);
+            }
+        } else if !pending.is_empty() {
+            debug!("journaling {} facts to {}", pending.len(), self.path);
+            let file = fs::OpenOptions::new().create(true).append(true)
+                                             .open(&journal)
+                                             .unwrap();
+            let mut out = io::BufWriter::new(file);
+            if !journaled {
+                let generation = self.compacted.load(Ordering::SeqCst) + 1;
+                writeln!(out, "{{\"generation\":{}}}", generation).unwrap();
+            }
+            for fact in &pending {
+                serde_json::to_writer(&mut out, fact).unwrap();
+                out.write_all(b"\n").unwrap();
+            }
+            out.flush().unwrap();
+            self.journal_len.fetch_add(pending.len(), Ordering::SeqCst);
+        }
+    }
+}
+
+impl<V> TaggedRelation<V> where for<'de> V: View<'de> {
+    // Read a relation from the given file, upgrading it to the current format
+    // if necessary and checking its checksum and count. Upgraded relations
+    // are marked dirty, so that they are written back in the current format.
+    fn load(file: &Path) -> Result<Self> {
+        let mut reader = io::BufReader::new(fs::File::open(file).map_err(err)?);
+
+        // Files in the current format are read directly, streaming tables in
+        // without first parsing the whole file; only older ones are parsed
+        // and then upgraded. Either way, the file is read once.
+        let current = leading_version(&mut reader)? == Some(FORMAT_VERSION);
+        let (mut stored, version) = if current {
+            let stored = serde_json::from_reader(reader)
+                .map_err(|e| corrupt(file, e.to_string()))?;
+            (stored, FORMAT_VERSION)
+        } else {
+            Self::load_and_migrate(file, reader)?
+        };
+
+        if version != FORMAT_VERSION {
+            info!("upgrading {} from format version {}", file.display(),
+                  version);
+        }
+        if let Some(expected) = stored.checksum {
+            let actual = checksum(&stored.contents)?;
+            if actual != expected {
+                return Err(corrupt(file, format!(
+                    "checksum is {:x}, expected {:x}", actual, expected)));
+            }
+        }
+        // Tables of arity 0 used to be written without their number of facts,
+        // so their count is the only record of them.
+        let mut recovered = false;
+        if let (Some(expected), Relation::Extension(ref mut table)) =
+                (stored.count, &mut stored.contents) {
+            if table.arity() == 0 && table.len() == 0 && expected > 0 {
+                warn!("restoring {} facts of arity 0 in {}", expected,
+                      file.display());
+                for _ in 0..expected {
+                    table.assert(Vec::new())?;
+                }
+                recovered = true;
+            }
+        }
+        if let (Some(expected), Some(actual)) = (stored.count,
+                                                 stored.contents.count()) {
+            if actual != expected {
+                return Err(corrupt(file, format!(
+                    "has {} facts, expected {}", actual, expected)));
+            }
+        }
+
+        let path = file.to_string_lossy().into_owned();
+        let mut relation = Self::new(stored.contents, path);
+        relation.description = stored.description;
+        relation.compacted = AtomicUsize::new(stored.journal.unwrap_or(0));
+        if version < FORMAT_VERSION || recovered {
+            relation.dirty();
         }
+        Ok(relation)
+    }
+
+    // Read a relation file that may be in an older format, upgrading it to the
+    // current one. Returns the relation along with the version it was in.
+    fn load_and_migrate<R: Read>(file: &Path, reader: R)
+            -> Result<(RelationFile<V>, u64)> {
+        let mut json: JsonValue = serde_json::from_reader(reader)
+            .map_err(|e| corrupt(file, e.to_string()))?;
+
+        let version = format_version(&json).ok_or_else(|| {
+            corrupt(file, "invalid format version".to_string())
+        })?;
+        if version > FORMAT_VERSION {
+            return Err(corrupt(file, format!(
+                "format version {} is newer than this data-goblin supports \
+                 ({})", version, FORMAT_VERSION)));
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            migration(&mut json);
+        }
+
+        let stored = serde_json::from_value(json)
+            .map_err(|e| corrupt(file, e.to_string()))?;
+        Ok((stored, version))
     }
 }
 
@@ -149,7 +1111,38 @@ impl<'de, V: View<'de>> TaggedRelation<V> {
 /// relations, and ensure that modifications to relations are durable.
 pub struct StorageEngine<V> {
     data_dir: String,
-    relations: HashMap<String, TaggedRelation<V>>
+    relations: HashMap<String, TaggedRelation<V>>,
+    /// If set, changes are never written back; see `open_read_only`.
+    read_only: bool,
+    /// If set, changes aren't written back until `thaw`; see `freeze`.
+    frozen: bool,
+    /// Relations that have had facts expired since the last `take_expired`.
+    expired: Vec<String>,
+    /// The text of each saved query, by name.
+    queries: BTreeMap<String, String>,
+    /// The baseline plan for each saved query, by name.
+    plans: BTreeMap<String, String>,
+    /// The versions of each view's rules, oldest first, by name.
+    history: BTreeMap<String, Vec<Version>>,
+    /// Whether `history` has changed since it was last written back.
+    history_dirty: AtomicBool,
+    /// Relations defined for a single query, which are never written back;
+    /// see `define_scratch`.
+    scratch: HashMap<String, Relation<V>>,
+    /// Relations backed by something other than the database, which are
+    /// never written back; see `attach`.
+    virtual_tables: HashMap<String, Box<VirtualTable>>,
+    /// Whether views are kept in `RULES_FILE`; see `set_rules_file`.
+    rules_file: bool,
+    /// The text last written to `RULES_FILE`, so that it is only rewritten
+    /// when the rules change.
+    rules_written: Mutex<String>,
+    /// The stored contents of each materialized view, by name; see
+    /// `materialize`.
+    materialized: HashMap<String, TaggedRelation<V>>,
+    /// The files of materializations dropped since the last write-back, to
+    /// remove then.
+    dematerialized: Mutex<Vec<String>>
 }
 
 /// A mutable view on a `Relation`.
@@ -191,18 +1184,204 @@ impl<'i, 'de, V: View<'de>> DerefMut for RelViewMut<'i, 'de, V> {
 }
 
 // Lift some error into an `error::Error`.
-fn err<E: std::error::Error + 'static>(err: E) -> Error {
+fn err<E: std::error::Error + Send + Sync + 'static>(err: E) -> Error {
     StorageError(Box::new(err))
 }
 
-impl<V> StorageEngine<V> where for<'de> V: View<'de> {
-    /// Create a new StorageEngine.
-    /// 
+// Rename a relation file or journal named after its relation, as older
+// versions of data-goblin named them, to the name `encode_name` gives it,
+// returning its new path. Each file is renamed atomically, and files already
+// renamed are left alone, so if renaming is interrupted, it just carries on the
+// next time the database is opened.
+fn migrate_file_name(path: PathBuf) -> Result<PathBuf> {
+    let file_name = path.file_name().unwrap().to_os_string()
+                        .into_string().map_err(Error::BadFilename)?;
+    let (base, extension) = if file_name.ends_with(JOURNAL_EXTENSION) {
+        file_name.split_at(file_name.len() - JOURNAL_EXTENSION.len())
+    } else {
+        (file_name.as_str(), "")
+    };
+    if decode_name(base).is_some() {
+        return Ok(path);
+    }
+
+    let renamed = path.with_file_name(
+        format!("{}{}", encode_name(base), extension));
+    info!("renaming {} to {}", path.display(), renamed.display());
+    fs::rename(&path, &renamed).map_err(err)?;
+    Ok(renamed)
+}
+
+/// Check that `name` may name a new relation.
+///
+/// A name is one or more segments separated by `.`, each a lowercase ASCII
+/// letter followed by ASCII letters, digits and underscores, and is at most
+/// `MAX_NAME_LEN` bytes long. Every segment but the last is its namespace, so
+/// `hr.payroll.salary` is in the namespace `hr.payroll`.
+pub fn check_relation_name(name: &str) -> Result<()> {
+    let bad = |reason: &str| Err(Error::BadRelationName {
+        name: name.to_string(),
+        reason: reason.to_string()
+    });
+    if name.len() > MAX_NAME_LEN {
+        return bad(&format!("longer than {} bytes", MAX_NAME_LEN));
+    }
+    for segment in name.split('.') {
+        let mut chars = segment.chars();
+        match chars.next() {
+            None => return bad("empty name or namespace"),
+            Some(c) if !c.is_ascii_lowercase() =>
+                return bad("each part must begin with a lowercase letter"),
+            _ => ()
+        }
+        if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return bad("only letters, digits, underscores and dots are \
+                        allowed");
+        }
+    }
+    Ok(())
+}
+
+/// The namespace of the named relation: everything before its last `.`, or
+/// the empty string if it has none.
+pub fn namespace_of(name: &str) -> &str {
+    name.rfind('.').map_or("", |i| &name[..i])
+}
+
+// Windows won't create files with these names, whatever their case.
+const DEVICE_NAMES: &'static [&'static str] = &[
+    "aux", "con", "nul", "prn", "com1", "com2", "com3", "com4", "com5", "com6",
+    "com7", "com8", "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6",
+    "lpt7", "lpt8", "lpt9"
+];
+
+/// The name of the file holding the named relation, in its data directory.
+///
+/// Lowercase ASCII letters, digits and underscores stand for themselves; any
+/// other character is written as a `%` followed by each byte of its UTF-8
+/// encoding in uppercase hexadecimal, as is the first character of a name that
+/// is reserved on some filesystem, like `con`. So a name can never escape the
+/// data directory, clash with the journal of another or with `QUERIES_FILE`,
+/// or clash with another name on a case-insensitive filesystem.
+pub fn encode_name(name: &str) -> String {
+    let reserved = DEVICE_NAMES.contains(&name);
+    let mut result = String::new();
+    for (i, c) in name.chars().enumerate() {
+        let plain = c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+        if plain && !(reserved && i == 0) {
+            result.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for b in c.encode_utf8(&mut bytes).bytes() {
+                result.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    result
+}
+
+/// The name of the relation held in the named file, if the file name is one
+/// written by `encode_name`.
+pub fn decode_name(file_name: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chars = file_name.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            bytes.push(c as u8);
+            continue;
+        }
+        let hex: String = chars.by_ref().take(2).collect();
+        bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+    }
+    // Only accept the one way `encode_name` would have written the name.
+    String::from_utf8(bytes).ok().filter(|name| encode_name(name) == file_name)
+}
+
+// The name of the relation in a relation file or journal with the given file
+// name, extension removed. Files from before relation names were encoded are
+// just named after their relation.
+fn name_of_file(file_name: &str) -> String {
+    decode_name(file_name).unwrap_or_else(|| file_name.to_string())
+}
+
+// The first line of a journal, giving its generation. Journals written before
+// generations were introduced have none, and count as generation 0.
+#[derive(Deserialize)]
+struct JournalHeader {
+    generation: usize
+}
+
+// Read a journal's generation, the facts in it, and whether it ends in a torn
+// fact.
+fn read_journal(journal: &Path) -> Result<(usize, Vec<Vec<Value>>, bool)> {
+    let mut text = String::new();
+    fs::File::open(journal).map_err(err)?
+        .read_to_string(&mut text).map_err(err)?;
+    // Every complete fact ends in a newline; anything after the last one is
+    // left over from an interrupted write.
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    let torn = !lines.pop().unwrap_or("").is_empty();
+    let header = lines.first().and_then(|line| {
+        serde_json::from_str::<JournalHeader>(line).ok()
+    });
+    let generation = header.as_ref().map_or(0, |header| header.generation);
+    let facts = lines[if header.is_some() { 1 } else { 0 }..].iter()
+                     .map(|line| serde_json::from_str(line).map_err(err))
+                     .collect::<Result<Vec<Vec<Value>>>>()?;
+    Ok((generation, facts, torn))
+}
+
+// Skip a journal already compacted into its relation's file, removing it
+// unless the database is read-only, so that the next journal is started
+// afresh.
+fn skip_journal(journal: &Path, read_only: bool) -> Result<()> {
+    info!("skipping {}, which was already compacted", journal.display());
+    if read_only {
+        return Ok(());
+    }
+    fs::remove_file(journal).map_err(err)
+}
+
+// Read the relation files at the given paths, on up to `LOAD_THREADS` threads.
+fn load_relations<V>(paths: Vec<std::path::PathBuf>)
+        -> Result<HashMap<String, TaggedRelation<V>>>
+        where for<'de> V: View<'de>, V: Send + 'static {
+    let chunk_size = (paths.len() + LOAD_THREADS - 1) / LOAD_THREADS;
+    let workers: Vec<_> = paths.chunks(chunk_size.max(1)).map(|chunk| {
+        let chunk = chunk.to_vec();
+        std::thread::spawn(move || {
+            chunk.into_iter().map(|path| {
+                let name = path.file_name().unwrap().to_os_string()
+                               .into_string().map_err(Error::BadFilename)?;
+                Ok((name_of_file(&name), TaggedRelation::load(&path)?))
+            }).collect::<Result<Vec<_>>>()
+        })
+    }).collect();
+
+    let mut relations = HashMap::new();
+    for worker in workers {
+        relations.extend(worker.join().unwrap()?);
+    }
+    Ok(relations)
+}
+
+impl<V> StorageEngine<V>
+        where for<'de> V: View<'de>, V: RulesText + Send + 'static {
+    /// Create a new StorageEngine.
+    /// 
     /// Tables are stored in/retrieved from `data_dir`. If that directory does
     /// not exist, it will be created; if it does, its contents will be read
-    /// into the new `StorageEngine`.
+    /// into the new `StorageEngine`. Files named after their relation by
+    /// older versions of data-goblin are renamed as `encode_name` would name
+    /// them.
     pub fn new(data_dir: String) -> Result<Self> {
-        let mut relations = HashMap::new();
+        Self::open(data_dir, false)
+    }
+
+    // Open or create the database in `data_dir`, renaming old relation files
+    // unless `read_only` is set.
+    fn open(data_dir: String, read_only: bool) -> Result<Self> {
+        let relations = HashMap::new();
 
         match fs::read_dir(data_dir.clone()) {
             Err(e) =>
@@ -211,35 +1390,340 @@ impl<V> StorageEngine<V> where for<'de> V: View<'de> {
                         fs::create_dir(data_dir.as_str()).map_err(err)?;
                         Ok(StorageEngine {
                             data_dir,
-                            relations
+                            relations,
+                            read_only: false,
+                            frozen: false,
+                            expired: Vec::new(),
+                            queries: BTreeMap::new(),
+                            plans: BTreeMap::new(),
+                            history: BTreeMap::new(),
+                            history_dirty: AtomicBool::new(false),
+                            scratch: HashMap::new(),
+                            virtual_tables: HashMap::new(),
+                            rules_file: false,
+                            rules_written: Mutex::default(),
+                            materialized: HashMap::new(),
+                            dematerialized: Mutex::default()
                         })
                     },
                     _ => Err(err(e))
                 },
             Ok(files)  => {
+                let mut journals = Vec::new();
+                let mut paths = Vec::new();
+                let mut queries = BTreeMap::new();
+                let mut plans = BTreeMap::new();
+                let mut history = BTreeMap::new();
+                let mut rules = None;
+                let mut materialized = Vec::new();
                 for res_entry in files {
-                    let entry = res_entry.map_err(err)?;
-                    let fname = entry.path();
-                    let reader = fs::File::open(fname).map_err(err)?;
-                    let buffered = io::BufReader::new(reader);
-                    let table: TaggedRelation<V> =
-                        serde_json::from_reader(buffered).map_err(err)?;
-                    let name = entry.file_name().into_string().map_err(|e|
-                        Error::BadFilename(e)
-                    )?;
-                    relations.insert(name, table);
+                    let fname = res_entry.map_err(err)?.path();
+                    if fname.ends_with(RULES_FILE) {
+                        rules = Some(fname);
+                        continue;
+                    }
+                    if fname.ends_with(QUERIES_FILE) {
+                        let file = fs::File::open(&fname).map_err(err)?;
+                        queries = serde_json::from_reader(file)
+                            .map_err(|e| corrupt(&fname, e.to_string()))?;
+                        continue;
+                    }
+                    if fname.ends_with(PLANS_FILE) {
+                        let file = fs::File::open(&fname).map_err(err)?;
+                        plans = serde_json::from_reader(file)
+                            .map_err(|e| corrupt(&fname, e.to_string()))?;
+                        continue;
+                    }
+                    if fname.ends_with(HISTORY_FILE) {
+                        let file = fs::File::open(&fname).map_err(err)?;
+                        history = serde_json::from_reader(file)
+                            .map_err(|e| corrupt(&fname, e.to_string()))?;
+                        continue;
+                    }
+                    let text = fname.to_string_lossy().into_owned();
+                    // Left over from a crash before it replaced the old file.
+                    if text.ends_with(TEMP_EXTENSION) {
+                        if !read_only {
+                            fs::remove_file(&fname).map_err(err)?;
+                        }
+                        continue;
+                    }
+                    if text.ends_with(MATERIALIZED_EXTENSION) ||
+                            text.ends_with(&format!("{}{}",
+                                                    MATERIALIZED_EXTENSION,
+                                                    JOURNAL_EXTENSION)) {
+                        materialized.push(fname);
+                        continue;
+                    }
+                    let fname = if read_only {
+                        fname
+                    } else {
+                        migrate_file_name(fname)?
+                    };
+                    if fname.to_string_lossy().ends_with(JOURNAL_EXTENSION) {
+                        journals.push(fname);
+                    } else {
+                        paths.push(fname);
+                    }
+                }
+                // Sorting makes the first error reported, if any, predictable.
+                paths.sort();
+                let relations = load_relations(paths)?;
+                let mut engine = StorageEngine { data_dir, relations,
+                                                 read_only,
+                                                 frozen: false,
+                                                 expired: Vec::new(),
+                                                 queries,
+                                                 plans,
+                                                 history,
+                                                 history_dirty:
+                                                     AtomicBool::new(false),
+                                                 scratch: HashMap::new(),
+                                                 virtual_tables: HashMap::new(),
+                                                 rules_file: false,
+                                                 rules_written:
+                                                     Mutex::default(),
+                                                 materialized: HashMap::new(),
+                                                 dematerialized:
+                                                     Mutex::default() };
+                for journal in journals {
+                    engine.replay_journal(&journal)?;
+                }
+                if let Some(rules) = rules {
+                    engine.load_rules(&rules)?;
                 }
-                Ok(StorageEngine {
-                    data_dir,
-                    relations
-                })
+                // Journals after the materializations they belong to.
+                materialized.sort_by_key(|path| {
+                    path.to_string_lossy().ends_with(JOURNAL_EXTENSION)
+                });
+                for path in materialized {
+                    engine.load_materialized(&path)?;
+                }
+                info!("loaded {} relations from {}", engine.relations.len(),
+                      engine.data_dir);
+                Ok(engine)
+            }
+        }
+    }
+
+    /// Open the existing database in `data_dir` without ever writing to it.
+    ///
+    /// Unlike `new`, fails if `data_dir` does not exist.
+    pub fn open_read_only(data_dir: String) -> Result<Self> {
+        if !Path::new(data_dir.as_str()).is_dir() {
+            return Err(err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no database found at {}", data_dir))));
+        }
+
+        Self::open(data_dir, true)
+    }
+
+    // Read the views in `RULES_FILE`, and keep views there from now on.
+    fn load_rules(&mut self, file: &Path) -> Result<()> {
+        let text = fs::read_to_string(file).map_err(err)?;
+        let views = V::from_text(&text)
+            .map_err(|e| corrupt(file, e.to_string()))?;
+        for (name, view, description) in views {
+            if self.relations.contains_key(&name) {
+                return Err(corrupt(file, format!(
+                    "{} is also stored in a file of its own", name)));
             }
+            let path = self.path_of_table_name(&name);
+            let mut relation =
+                TaggedRelation::new(Relation::Intension(view), path);
+            relation.description = description;
+            self.relations.insert(name, relation);
         }
+        self.rules_file = true;
+        *self.rules_written.lock().unwrap() = self.rules_text();
+        Ok(())
+    }
+
+    // The text of every view's rules, as kept in `RULES_FILE`, in order of
+    // their names.
+    fn rules_text(&self) -> String {
+        let mut views: Vec<(&String, &TaggedRelation<V>)> =
+            self.relations.iter().filter(|(_, r)| match r.contents {
+                Relation::Intension(_) => true,
+                Relation::Extension(_) => false
+            }).collect();
+        views.sort_by_key(|(name, _)| *name);
+        let texts: Vec<String> = views.into_iter().filter_map(|(name, r)| {
+            match r.contents {
+                Relation::Intension(ref view) => Some(view.to_text(
+                        name, r.description.as_ref().map(String::as_str))),
+                Relation::Extension(_) => None
+            }
+        }).collect();
+        texts.join("\n")
+    }
+
+    // Rewrite `RULES_FILE` if any relation has changed since the last
+    // write-back and the rules in it are no longer up to date.
+    fn write_rules(&self) -> Result<()> {
+        if !self.relations.values().any(TaggedRelation::is_dirty) {
+            return Ok(());
+        }
+        let text = self.rules_text();
+        let mut written = self.rules_written.lock().unwrap();
+        if *written != text {
+            debug!("writing back {}", RULES_FILE);
+            fs::write(self.path_in_data_dir(RULES_FILE), &text)
+                .map_err(err)?;
+            *written = text;
+        }
+        Ok(())
+    }
+
+    /// Whether views are kept in `RULES_FILE`; see `set_rules_file`.
+    pub fn rules_file(&self) -> bool {
+        self.rules_file
+    }
+
+    /// With `on` set, keep every view in `RULES_FILE`, as the text of its
+    /// rules, rather than in a file of its own, from now on and whenever the
+    /// database is opened again. The file is rewritten whenever a view
+    /// changes, so any comments or formatting added by hand are lost then.
+    ///
+    /// With `on` unset, go back to keeping each view in a file of its own.
+    /// Either way, the views are moved to their new files straight away.
+    pub fn set_rules_file(&mut self, on: bool) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError(
+                    "can't change how a read-only database is stored".into()));
+        }
+        if self.frozen {
+            return Err(StorageError(
+                    "can't change how a frozen database is stored".into()));
+        }
+        if on == self.rules_file {
+            return Ok(());
+        }
+
+        let rules_path = self.path_in_data_dir(RULES_FILE);
+        let views = self.relations.values().filter(|r| match r.contents {
+            Relation::Intension(_) => true,
+            Relation::Extension(_) => false
+        });
+        if on {
+            let text = self.rules_text();
+            fs::write(&rules_path, &text).map_err(err)?;
+            *self.rules_written.lock().unwrap() = text;
+            for view in views {
+                view.clean();
+                if Path::new(&view.path).exists() {
+                    fs::remove_file(&view.path).map_err(err)?;
+                }
+            }
+        } else {
+            for view in views {
+                view.clean();
+                view.write()?;
+            }
+            fs::remove_file(&rules_path).map_err(err)?;
+        }
+        self.rules_file = on;
+        Ok(())
+    }
+
+    // Apply the facts in a journal to the relation it belongs to.
+    fn replay_journal(&mut self, journal: &Path) -> Result<()> {
+        let file_name = journal.file_name().unwrap().to_os_string();
+        let file_name = file_name.into_string().map_err(Error::BadFilename)?;
+        let name = name_of_file(
+            &file_name[..file_name.len() - JOURNAL_EXTENSION.len()]);
+
+        let (generation, facts, torn) = read_journal(journal)?;
+        let compacted = self.relations.get(&name).map_or(false, |relation| {
+            relation.includes(generation)
+        });
+        if compacted {
+            return skip_journal(journal, self.read_only);
+        }
+        let replayed = facts.len();
+        for fact in facts {
+            self.insert_fact(name.clone(), fact)?;
+        }
+        // The facts are now on disk in the journal, not waiting to be written.
+        let relation = self.relations.get_mut(&name);
+        if let Some(relation) = relation {
+            relation.pending.lock().unwrap().clear();
+            relation.journal_len.fetch_add(replayed, Ordering::SeqCst);
+            // Rewrite the relation rather than append after the torn fact.
+            if torn {
+                warn!("discarding a torn fact at the end of {}",
+                      journal.display());
+                relation.dirty();
+            }
+        }
+        Ok(())
+    }
+
+    // Read a view's materialization from its file, or apply the tuples in its
+    // journal to it.
+    fn load_materialized(&mut self, file: &Path) -> Result<()> {
+        let file_name = file.file_name().unwrap().to_os_string();
+        let file_name = file_name.into_string().map_err(Error::BadFilename)?;
+        let journal = file_name.ends_with(JOURNAL_EXTENSION);
+        let base = file_name.len() - MATERIALIZED_EXTENSION.len() -
+            if journal { JOURNAL_EXTENSION.len() } else { 0 };
+        let name = name_of_file(&file_name[..base]);
+        if !journal {
+            self.materialized.insert(name, TaggedRelation::load(file)?);
+            return Ok(());
+        }
+
+        let (generation, facts, torn) = read_journal(file)?;
+        let read_only = self.read_only;
+        let relation = self.materialized.get_mut(&name).ok_or_else(|| {
+            corrupt(file, format!("{} is not materialized", name))
+        })?;
+        if relation.includes(generation) {
+            return skip_journal(file, read_only);
+        }
+        relation.journal_len.fetch_add(facts.len(), Ordering::SeqCst);
+        if let Relation::Extension(ref mut table) = relation.contents {
+            table.assert_batch(facts)?;
+        }
+        if torn {
+            warn!("discarding a torn tuple at the end of {}", file.display());
+            relation.dirty();
+        }
+        Ok(())
+    }
+
+    // Assert a fact into the named table, creating it if necessary, and queue
+    // the fact to be appended to the table's journal.
+    fn insert_fact(&mut self, name: String, fact: Vec<Value>) -> Result<()> {
+        if !self.relations.contains_key(&name) {
+            let table = Relation::Extension(Table::new(fact.len()));
+            self.get_or_create_relation(name.clone(), table)?;
+        }
+
+        let relation = self.relations.get_mut(&name).unwrap();
+        match relation.contents {
+            Relation::Extension(ref t) if t.arity() != fact.len() =>
+                return Err(Error::RelationArity {
+                    relation: name,
+                    arity: t.arity(),
+                    got: fact.len()
+                }),
+            Relation::Extension(ref mut t) => t.assert(fact.clone())?,
+            Relation::Intension(_) => return Err(Error::NotExtensional(name))
+        }
+        relation.pending.lock().unwrap().push(fact);
+        Ok(())
     }
 
     // From the name of a table, get the path to that table.
     fn path_of_table_name(&self, table_name: &str) -> String {
-        let path_buf = Path::new(self.data_dir.as_str()).join(table_name);
+        self.path_in_data_dir(&encode_name(table_name))
+    }
+
+    // The path to the named file in the data directory.
+    fn path_in_data_dir(&self, file_name: &str) -> String {
+        let path_buf = Path::new(self.data_dir.as_str()).join(file_name);
         path_buf.as_path().as_os_str().to_str().unwrap().to_owned()
     }
 
@@ -248,6 +1732,7 @@ impl<V> StorageEngine<V> where for<'de> V: View<'de> {
     /// Returns `None` if it is not in the database.
     pub fn get_relation(&self, name: &str) -> Option<&Relation<V>> {
         self.relations.get(name).map(|r| &r.contents)
+            .or_else(|| self.scratch.get(name))
     }
 
     /// Get a mutable view on the named relation.
@@ -262,20 +1747,454 @@ impl<V> StorageEngine<V> where for<'de> V: View<'de> {
     /// 
     /// Must take ownership of the table name, because it needs to be stored in
     /// the database if it is not already there. See also `RelViewMut`.
+    ///
+    /// Fails without creating or modifying anything if the relation exists
+    /// but is of a different kind (extensional or intensional) than `rel`, is
+    /// a table of a different arity, or is a virtual table.
     pub fn get_or_create_relation(
             &mut self,
             name: String,
-            rel: Relation<V>) -> RelViewMut<V> {
+            rel: Relation<V>) -> Result<RelViewMut<V>> {
+        if self.virtual_tables.contains_key(&name) {
+            return Err(Error::Virtual(name));
+        }
+        if let Some(existing) = self.relations.get(&name) {
+            match (&existing.contents, &rel) {
+                (Relation::Extension(_), Relation::Intension(_)) =>
+                    return Err(Error::NotIntensional(name)),
+                (Relation::Intension(_), Relation::Extension(_)) =>
+                    return Err(Error::NotExtensional(name)),
+                (Relation::Extension(ours), Relation::Extension(theirs))
+                        if ours.arity() != theirs.arity() =>
+                    return Err(Error::RelationArity {
+                        relation: name,
+                        arity: ours.arity(),
+                        got: theirs.arity()
+                    }),
+                _ => ()
+            }
+        } else {
+            // Names loaded from disk predate the rules, so only check new ones.
+            check_relation_name(&name)?;
+        }
+
         let path = self.path_of_table_name(name.as_str());
-        let tagged = TaggedRelation { contents: rel,
-                                      path, dirty: AtomicBool::new(true) };
-        RelViewMut::new(self.relations.entry(name).or_insert(tagged))
+        let tagged = TaggedRelation::new(rel, path);
+        tagged.dirty();
+        Ok(RelViewMut::new(self.relations.entry(name).or_insert(tagged)))
+    }
+
+    /// Add a fact to the named extensional relation, creating the relation if
+    /// it doesn't exist.
+    ///
+    /// Unlike changes made through a `RelViewMut`, which rewrite the whole
+    /// relation on the next write-back, the fact is just appended to the
+    /// relation's journal.
+    pub fn assert_fact(&mut self, name: String, fact: Vec<Value>)
+            -> Result<()> {
+        self.insert_fact(name, fact)
+    }
+
+    /// Add many facts to the named extensional relation, creating it with the
+    /// given arity if it doesn't exist. See `Table::assert_batch`.
+    ///
+    /// Rather than being journaled, the relation is rewritten on the next
+    /// write-back, which is faster for large batches.
+    pub fn assert_facts(&mut self, name: String, arity: usize,
+                        facts: Vec<Vec<Value>>) -> Result<()> {
+        let table = Relation::Extension(Table::new(arity));
+        let mut relation = self.get_or_create_relation(name.clone(), table)?;
+        match *relation {
+            Relation::Extension(ref mut t) => t.assert_batch(facts),
+            Relation::Intension(_) => Err(Error::NotExtensional(name))
+        }
     }
 
     pub fn write_back(&self) {
+        if self.read_only || self.frozen {
+            return;
+        }
+        if self.rules_file {
+            self.write_rules().unwrap();
+        }
+        if self.history_dirty.swap(false, Ordering::SeqCst) {
+            debug!("writing back {}", HISTORY_FILE);
+            let path = self.path_in_data_dir(HISTORY_FILE);
+            let file = fs::File::create(path).unwrap();
+            serde_json::to_writer_pretty(file, &self.history).unwrap();
+        }
         for (_, relation) in &self.relations {
-            (&relation).write_back();
+            match relation.contents {
+                Relation::Intension(_) if self.rules_file => {
+                    relation.clean();
+                },
+                _ => relation.write_back()
+            }
+        }
+        for path in self.dematerialized.lock().unwrap().drain(..) {
+            debug!("removing {}", path);
+            let journal = format!("{}{}", path, JOURNAL_EXTENSION);
+            for file in &[journal, path] {
+                if Path::new(file).exists() {
+                    fs::remove_file(file).unwrap();
+                }
+            }
+        }
+        for relation in self.materialized.values() {
+            relation.write_back();
+        }
+    }
+
+    /// Stop writing to the data directory until `thaw`, so that it can be
+    /// copied safely, e.g. by a backup tool. Changes made meanwhile are kept
+    /// in memory, and written back once thawed.
+    ///
+    /// Since this takes `&mut self`, no write-back is under way once it
+    /// returns.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Go back to writing changes to the data directory; see `freeze`.
+    pub fn thaw(&mut self) {
+        self.frozen = false;
+    }
+
+    /// Whether writing to the data directory is stopped; see `freeze`.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// The directory the database is stored in.
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    /// Remove the oldest facts from every table with more than its limit (see
+    /// `Table::set_max_len`).
+    ///
+    /// The names of the tables changed can be retrieved with `take_expired`.
+    pub fn expire(&mut self) {
+        for (name, relation) in &mut self.relations {
+            if let Relation::Extension(ref mut table) = relation.contents {
+                let expired = table.expire();
+                if expired > 0 {
+                    debug!("expired {} facts from {}", expired, name);
+                    relation.dirty();
+                    if !self.expired.contains(name) {
+                        self.expired.push(name.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the names of the tables that have had facts expired since the last
+    /// call, e.g. to invalidate any views derived from them.
+    pub fn take_expired(&mut self) -> Vec<String> {
+        mem::replace(&mut self.expired, Vec::new())
+    }
+
+    /// Write back any changes, then re-read every relation's file and every
+    /// materialization's, checking its checksum and count, and `RULES_FILE`,
+    /// if the database uses it.
+    ///
+    /// Returns the errors found, if any, along with the relation they were
+    /// found in; for a materialization, the view's name followed by
+    /// `MATERIALIZED_EXTENSION`.
+    pub fn verify(&self) -> Vec<(String, Error)> {
+        self.write_back();
+
+        let mut errors = Vec::new();
+        let materialized = self.materialized.iter().map(|(name, relation)| {
+            (format!("{}{}", name, MATERIALIZED_EXTENSION), relation)
+        });
+        let relations = self.relations.iter()
+            .map(|(name, relation)| (name.clone(), relation))
+            .chain(materialized);
+        for (name, relation) in relations {
+            let path = Path::new(relation.path.as_str());
+            if path.exists() {
+                if let Err(e) = TaggedRelation::<V>::load(path) {
+                    errors.push((name, e));
+                }
+            }
+        }
+        if self.rules_file {
+            let path = self.path_in_data_dir(RULES_FILE);
+            let text = fs::read_to_string(&path).map_err(err);
+            if let Err(e) = text.and_then(|text| V::from_text(&text)) {
+                errors.push((RULES_FILE.to_string(), e));
+            }
+        }
+        errors.sort_by(|a, b| a.0.cmp(&b.0));
+        errors
+    }
+
+    /// Compact every journal into its relation's main file.
+    pub fn vacuum(&self) {
+        if self.read_only {
+            return;
+        }
+        let relations = self.relations.values()
+                                      .chain(self.materialized.values());
+        for relation in relations {
+            if relation.journal_len.load(Ordering::SeqCst) > 0 {
+                relation.dirty();
+            }
+        }
+        self.write_back();
+    }
+
+    /// Save the text of a query under `name`, replacing any query already
+    /// saved under it, and write the saved queries to disk.
+    pub fn save_query(&mut self, name: String, query: String) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError(
+                    "can't save queries to a read-only database".into()));
+        }
+        if self.frozen {
+            return Err(StorageError(
+                    "can't save queries while the database is frozen".into()));
+        }
+        self.queries.insert(name, query);
+        let path = self.path_in_data_dir(QUERIES_FILE);
+        let file = fs::File::create(path).map_err(err)?;
+        serde_json::to_writer_pretty(file, &self.queries).map_err(err)
+    }
+
+    /// Save the baseline plan for the saved query `name`, replacing any saved
+    /// before, and write the baselines to disk.
+    pub fn save_plan(&mut self, name: String, plan: String) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError(
+                    "can't save plans to a read-only database".into()));
+        }
+        if self.frozen {
+            return Err(StorageError(
+                    "can't save plans while the database is frozen".into()));
+        }
+        self.plans.insert(name, plan);
+        let path = self.path_in_data_dir(PLANS_FILE);
+        let file = fs::File::create(path).map_err(err)?;
+        serde_json::to_writer_pretty(file, &self.plans).map_err(err)
+    }
+
+    /// Get the baseline plan saved for the query `name`, if any.
+    pub fn saved_plan(&self, name: &str) -> Option<&str> {
+        self.plans.get(name).map(String::as_str)
+    }
+
+    /// The names of the queries with baseline plans, in order.
+    pub fn saved_plans(&self) -> Vec<&str> {
+        self.plans.keys().map(String::as_str).collect()
+    }
+
+    /// Attach a description of what the named relation holds, replacing any
+    /// it had. The description is stored in the relation's file.
+    pub fn describe(&mut self, name: &str, description: String) -> Result<()> {
+        let relation = self.relations.get_mut(name).ok_or_else(|| {
+            Error::MalformedLine(format!("No relation \"{}\" found.", name))
+        })?;
+        relation.description = Some(description);
+        relation.dirty();
+        Ok(())
+    }
+
+    /// Get the description of the named relation, if it has one.
+    pub fn description(&self, name: &str) -> Option<&str> {
+        self.relations.get(name).and_then(|r| r.description.as_ref())
+                                .map(String::as_str)
+    }
+
+    /// Get the text of the query saved under `name`, if any.
+    pub fn saved_query(&self, name: &str) -> Option<&str> {
+        self.queries.get(name).map(String::as_str)
+    }
+
+    /// Record a new version of the named view's rules, to be written back
+    /// with the relations.
+    pub fn record_version(&mut self, view: &str, version: Version) {
+        self.history.entry(view.to_string()).or_insert_with(Vec::new)
+                    .push(version);
+        self.history_dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// The recorded versions of the named view's rules, oldest first. Version
+    /// `n` is at index `n - 1`.
+    pub fn history(&self, view: &str) -> &[Version] {
+        self.history.get(view).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Store `table` as the materialization of the named view, replacing any
+    /// it had, to be written back with the relations in a file of its own.
+    ///
+    /// Nothing keeps it up to date as the relations the view depends on
+    /// change; that is up to the caller.
+    pub fn materialize(&mut self, view: &str, table: Table) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError(
+                    "can't materialize views in a read-only database".into()));
+        }
+        match self.relations.get(view).map(|r| &r.contents) {
+            Some(Relation::Intension(_)) => (),
+            Some(Relation::Extension(_)) =>
+                return Err(Error::NotIntensional(view.to_string())),
+            None => return Err(Error::MalformedLine(
+                    format!("No relation \"{}\" found.", view)))
+        }
+        // Any old file is removed before the new one is written.
+        self.dematerialize(view);
+        let path = format!("{}{}", self.path_of_table_name(view),
+                           MATERIALIZED_EXTENSION);
+        let relation = TaggedRelation::new(Relation::Extension(table), path);
+        relation.dirty();
+        self.materialized.insert(view.to_string(), relation);
+        Ok(())
+    }
+
+    /// Drop the materialization of the named view, removing its file on the
+    /// next write-back. Returns whether it had one.
+    pub fn dematerialize(&mut self, view: &str) -> bool {
+        match self.materialized.remove(view) {
+            Some(relation) => {
+                self.dematerialized.get_mut().unwrap().push(relation.path);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The materialization of the named view, if it has one.
+    pub fn materialization(&self, view: &str) -> Option<&Table> {
+        match self.materialized.get(view).map(|r| &r.contents) {
+            Some(Relation::Extension(table)) => Some(table),
+            _ => None
+        }
+    }
+
+    /// The names of the materialized views, in order.
+    pub fn materialized(&self) -> Vec<&str> {
+        let mut names: Vec<&str> =
+            self.materialized.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// Add tuples to the materialization of the named view, appending them to
+    /// its journal on the next write-back.
+    pub fn extend_materialization(&mut self, view: &str,
+                                  tuples: Vec<Vec<Value>>) -> Result<()> {
+        let relation = match self.materialized.get_mut(view) {
+            Some(relation) => relation,
+            None => return Ok(())
+        };
+        if let Relation::Extension(ref mut table) = relation.contents {
+            table.assert_batch(tuples.clone())?;
         }
+        relation.pending.lock().unwrap().extend(tuples);
+        Ok(())
+    }
+
+    /// Remove every tuple `keep` rejects from the materialization of the named
+    /// view, returning the number removed. The whole materialization is
+    /// rewritten on the next write-back.
+    pub fn retain_materialized<F>(&mut self, view: &str, keep: F) -> usize
+            where F: FnMut(&Tuple) -> bool {
+        let relation = match self.materialized.get_mut(view) {
+            Some(relation) => relation,
+            None => return 0
+        };
+        let removed = match relation.contents {
+            Relation::Extension(ref mut table) => table.retain(keep),
+            Relation::Intension(_) => 0
+        };
+        if removed > 0 {
+            relation.dirty();
+        }
+        removed
+    }
+
+    /// The names and texts of all of the saved queries, in order of name.
+    pub fn saved_queries(&self) -> Vec<(&str, &str)> {
+        self.queries.iter().map(|(name, query)| {
+            (name.as_str(), query.as_str())
+        }).collect()
+    }
+
+    /// Get the named scratch relation, creating it from `rel` if it doesn't
+    /// exist yet. Scratch relations can be read like any other, but are never
+    /// written back, and last until removed by `remove_scratch`.
+    ///
+    /// Returns `None` if a durable relation already has that name.
+    pub fn define_scratch(&mut self, name: String, rel: Relation<V>)
+            -> Option<&mut Relation<V>> {
+        if self.relations.contains_key(&name)
+                || self.virtual_tables.contains_key(&name) {
+            return None;
+        }
+        Some(self.scratch.entry(name).or_insert(rel))
+    }
+
+    /// Make `rel` the named scratch relation, replacing any scratch relation
+    /// with that name already; see `define_scratch`.
+    ///
+    /// Returns `false`, changing nothing, if a durable relation already has
+    /// that name.
+    pub fn replace_scratch(&mut self, name: String, rel: Relation<V>) -> bool {
+        if self.relations.contains_key(&name)
+                || self.virtual_tables.contains_key(&name) {
+            return false;
+        }
+        self.scratch.insert(name, rel);
+        true
+    }
+
+    /// Is the named relation a scratch relation?
+    pub fn is_scratch(&self, name: &str) -> bool {
+        !self.relations.contains_key(name) && self.scratch.contains_key(name)
+    }
+
+    /// Remove the named scratch relation, if there is one.
+    pub fn remove_scratch(&mut self, name: &str) {
+        self.scratch.remove(name);
+    }
+
+    /// Make `table` the named virtual table. Virtual tables can be read like
+    /// any other relation, but not changed, and last until removed by
+    /// `detach`.
+    ///
+    /// Fails if any other relation already has that name.
+    pub fn attach(&mut self, name: String, table: Box<VirtualTable>)
+            -> Result<()> {
+        if self.get_relation(&name).is_some() {
+            return Err(Error::MalformedLine(format!(
+                "can't attach {}: a relation of that name already exists",
+                name)));
+        }
+        if !self.virtual_tables.contains_key(&name) {
+            check_relation_name(&name)?;
+        }
+        self.virtual_tables.insert(name, table);
+        Ok(())
+    }
+
+    /// Remove the named virtual table, returning whether there was one.
+    pub fn detach(&mut self, name: &str) -> bool {
+        self.virtual_tables.remove(name).is_some()
+    }
+
+    /// Get the named virtual table, if there is one.
+    pub fn virtual_table(&self, name: &str) -> Option<&VirtualTable> {
+        self.virtual_tables.get(name).map(|t| &**t)
+    }
+
+    /// The names of the virtual tables, sorted.
+    pub fn virtual_tables(&self) -> Vec<&str> {
+        let mut result: Vec<&str> =
+            self.virtual_tables.keys().map(String::as_str).collect();
+        result.sort();
+        result
     }
 
     pub fn get_relations<'a>(&'a self) -> Vec<&'a str> {
@@ -283,6 +2202,31 @@ impl<V> StorageEngine<V> where for<'de> V: View<'de> {
         for (k, _) in self.relations.iter() {
             result.push(k.as_str());
         }
+        for (k, _) in self.scratch.iter() {
+            result.push(k.as_str());
+        }
+        result
+    }
+
+    /// The namespaces with relations in them, sorted, not including the
+    /// empty namespace.
+    pub fn namespaces(&self) -> Vec<&str> {
+        let mut result: Vec<&str> = self.get_relations().into_iter()
+            .map(namespace_of)
+            .filter(|namespace| !namespace.is_empty())
+            .collect();
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    /// The relations directly in the given namespace, sorted. The empty
+    /// namespace holds the relations without a `.` in their names.
+    pub fn relations_in<'a>(&'a self, namespace: &str) -> Vec<&'a str> {
+        let mut result: Vec<&str> = self.get_relations().into_iter()
+            .filter(|name| namespace_of(name) == namespace)
+            .collect();
+        result.sort();
         result
     }
 }
@@ -296,21 +2240,120 @@ mod tests {
     fn test_table(v: &[Vec<&str>]) -> Table {
         let mut t = Table::new(v[0].len());
         for tuple in v {
-            t.assert(tuple.into_iter().map(|r| r.to_string()).collect())
+            t.assert(tuple.into_iter().map(|r| Value::from(*r)).collect())
              .unwrap();
         }
         t
     }
 
-    fn table_as_vec(t: &Table) -> Vec<Tuple> {
-        t.into_iter().collect()
+    fn table_as_vec(t: &Table) -> Vec<Vec<String>> {
+        t.into_iter().map(|tuple| {
+            tuple.into_iter().map(Value::to_string).collect()
+        }).collect()
+    }
+
+    fn strings(v: &[Vec<&str>]) -> Vec<Vec<String>> {
+        v.iter().map(|tuple| {
+            tuple.iter().map(|s| s.to_string()).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn table_select() {
+        let t = test_table(&[vec!("a", "b"), vec!("c", "b"), vec!("a", "d")]);
+        let (a, d, z) = (Value::from("a"), Value::from("d"), Value::from("z"));
+        let select = |constants: &[(usize, &Value)]| -> Vec<Vec<String>> {
+            t.select(constants).map(|tuple| {
+                tuple.into_iter().map(Value::to_string).collect()
+            }).collect()
+        };
+
+        assert_eq!(select(&[(0, &a)]),
+                   strings(&[vec!("a", "b"), vec!("a", "d")]));
+        assert_eq!(select(&[(0, &a), (1, &d)]), strings(&[vec!("a", "d")]));
+        assert_eq!(select(&[(1, &z)]), strings(&[]));
+        assert_eq!(t.column(1).map(Value::to_string).collect::<Vec<_>>(),
+                   vec!("b", "b", "d"));
+        assert_eq!(t.len(), 3);
+
+        let t = test_table(&[vec!("a", "a", "b"), vec!("a", "b", "b"),
+                             vec!("c", "c", "c")]);
+        let select_equal = |constants: &[(usize, &Value)],
+                            equal: &[(usize, usize)]| -> Vec<Vec<String>> {
+            t.select_equal(constants, equal).map(|tuple| {
+                tuple.into_iter().map(Value::to_string).collect()
+            }).collect()
+        };
+        assert_eq!(select_equal(&[], &[(0, 1)]),
+                   strings(&[vec!("a", "a", "b"), vec!("c", "c", "c")]));
+        assert_eq!(select_equal(&[], &[(0, 1), (1, 2)]),
+                   strings(&[vec!("c", "c", "c")]));
+        assert_eq!(select_equal(&[(0, &a)], &[(1, 2)]),
+                   strings(&[vec!("a", "b", "b")]));
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut t = Table::new(2);
+        t.assert(vec!(Value::Int(3), Value::String("three".to_string())))
+         .unwrap();
+        t.assert(vec!(Value::Float(0.5), Value::Bool(true))).unwrap();
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json,
+                   r#"{"contents":[3,{"String":"three"},0.5,true],"arity":2}"#);
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(table_as_vec(&back),
+                   strings(&[vec!("3", "\"three\""), vec!("0.5", "true")]));
+    }
+
+    #[test]
+    fn table_serialization() {
+        let t = test_table(&[vec!("a", "b"), vec!("c", "b")]);
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, r#"{"contents":["a","b","c","b"],"arity":2}"#);
+
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(table_as_vec(&back), table_as_vec(&t));
+
+        let bad: std::result::Result<Table, _> =
+            serde_json::from_str(r#"{"contents":["a","b","c"],"arity":2}"#);
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn table_round_trip() {
+        let t = test_table(&[vec!("a", "b"), vec!("b", "a"), vec!("a", "a")]);
+        let json = serde_json::to_string(&t).unwrap();
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(table_as_vec(&back), table_as_vec(&t));
+        assert_eq!(back.symbols.symbols.len(), 2);
+        assert!(back.contains(&[Value::from("b"), Value::from("a")]));
+        assert!(!back.contains(&[Value::from("b"), Value::from("b")]));
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+
+        let mut t = Table::new(0);
+        let json = serde_json::to_string(&t).unwrap();
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!((back.arity(), back.len()), (0, 0));
+        t.assert(vec!()).unwrap();
+        t.assert(vec!()).unwrap();
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, r#"{"contents":[],"arity":0,"len":2}"#);
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!((back.len(), back.distinct_len()), (2, 1));
+        let bad: std::result::Result<Table, _> =
+            serde_json::from_str(r#"{"contents":["a"],"arity":0}"#);
+        assert!(bad.is_err());
+        let bad: std::result::Result<Table, _> =
+            serde_json::from_str(r#"{"contents":["a","a","a"],"arity":2}"#);
+        assert!(bad.is_err());
     }
 
     #[test]
     fn empty_table() {
         let t = Table::new(10);
-        let expected: Vec<Tuple> = vec!();
-        assert_eq!(table_as_vec(&t), expected);
+        assert_eq!(table_as_vec(&t), strings(&[]));
     }
 
     #[test]
@@ -318,35 +2361,776 @@ mod tests {
         let expected_contents = vec!(vec!("a", "b", "c"),
                                      vec!("d", "e", "f"));
         let t = test_table(&expected_contents);
-        let mut expected: Vec<&[&str]> = Vec::new();
-        
-        for tuple in &expected_contents {
-            expected.push(tuple)
-        }
 
-        assert_eq!(table_as_vec(&t), expected);
+        assert_eq!(table_as_vec(&t), strings(&expected_contents));
     }
 
-    fn clear_test_dir() {
-        if std::fs::read_dir(TEST_DIR).is_ok() {
-            std::fs::remove_dir_all(TEST_DIR).unwrap();
+    // Each test that touches the disk gets its own directory, since tests run
+    // in parallel.
+    fn clear_test_dir(dir: &str) {
+        if std::fs::read_dir(dir).is_ok() {
+            std::fs::remove_dir_all(dir).unwrap();
         }
     }
 
-    fn test_engine() -> StorageEngine<()> {
-        clear_test_dir();
-        StorageEngine::new(TEST_DIR.to_string()).unwrap()
+    // Views in these tests hold nothing, so the rules file just lists their
+    // names.
+    impl RulesText for () {
+        fn to_text(&self, name: &str, _description: Option<&str>) -> String {
+            format!("{}\n", name)
+        }
+
+        fn from_text(text: &str) -> Result<Vec<(String, (), Option<String>)>> {
+            Ok(text.lines().filter(|line| !line.is_empty())
+                   .map(|name| (name.to_string(), (), None)).collect())
+        }
+    }
+
+    fn test_engine(dir: &str) -> StorageEngine<()> {
+        clear_test_dir(dir);
+        StorageEngine::new(dir.to_string()).unwrap()
     }
 
-    fn cleanup(engine: StorageEngine<()>) {
+    fn cleanup(engine: StorageEngine<()>, dir: &str) {
         std::mem::drop(engine);
-        clear_test_dir();
+        clear_test_dir(dir);
+    }
+
+    fn relation_len(engine: &StorageEngine<()>, name: &str) -> usize {
+        match engine.get_relation(name) {
+            Some(Relation::Extension(t)) => t.len(),
+            _ => panic!("no table {}", name)
+        }
     }
 
     #[test]
     fn initially_empty() {
-        let engine = test_engine();
+        let engine = test_engine(TEST_DIR);
         assert!(engine.get_relation("test relation").is_none());
-        cleanup(engine);
+        cleanup(engine, TEST_DIR);
+    }
+
+    #[test]
+    fn parallel_load() {
+        let dir = "_test_parallel_load_dir";
+        let mut engine = test_engine(dir);
+        for i in 0..(LOAD_THREADS * 3 + 1) {
+            engine.assert_fact(format!("r{}", i), vec!(Value::Int(i as i64)))
+                  .unwrap();
+        }
+        engine.write_back();
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(engine.get_relations().len(), LOAD_THREADS * 3 + 1);
+        assert_eq!(relation_len(&engine, "r0"), 1);
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn rules_file() {
+        let dir = "_test_storage_rules_file_dir";
+        let mut engine = test_engine(dir);
+        engine.assert_fact("edge".to_string(),
+                           vec!(Value::from("a"), Value::from("b")))
+              .unwrap();
+        for name in &["reach", "path"] {
+            engine.get_or_create_relation(name.to_string(),
+                                          Relation::Intension(())).unwrap();
+        }
+        engine.write_back();
+        let exists = |file: &str| Path::new(dir).join(file).exists();
+        assert!(exists("path") && exists("reach"));
+
+        // Turning the rules file on moves every view into it.
+        engine.set_rules_file(true).unwrap();
+        assert!(!exists("path") && !exists("reach"));
+        let rules = || fs::read_to_string(Path::new(dir).join(RULES_FILE))
+                          .unwrap();
+        assert_eq!(rules(), "path\n\nreach\n");
+
+        // New views go there too, and it is read when the database is opened.
+        engine.get_or_create_relation("far".to_string(),
+                                      Relation::Intension(())).unwrap();
+        engine.write_back();
+        assert!(!exists("far"));
+        assert_eq!(rules(), "far\n\npath\n\nreach\n");
+        std::mem::drop(engine);
+        let mut engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert!(engine.rules_file());
+        let mut names = engine.get_relations();
+        names.sort();
+        assert_eq!(names, vec!("edge", "far", "path", "reach"));
+        assert!(engine.verify().is_empty());
+
+        // Turning it off moves them back.
+        engine.set_rules_file(false).unwrap();
+        assert!(exists("far") && exists("path") && !exists(RULES_FILE));
+        std::mem::drop(engine);
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert!(!engine.rules_file());
+        assert_eq!(engine.get_relations().len(), 4);
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn freeze() {
+        let dir = "_test_freeze_dir";
+        let mut engine = test_engine(dir);
+        engine.freeze();
+        engine.assert_fact("edge".to_string(),
+                           vec!(Value::from("a"), Value::from("b")))
+              .unwrap();
+        engine.write_back();
+        engine.vacuum();
+        assert!(std::fs::read_dir(dir).unwrap().next().is_none());
+        assert!(engine.save_query("q".to_string(), "edge(X, Y)?".to_string())
+                      .is_err());
+
+        // Changes made while frozen are written back once thawed.
+        engine.thaw();
+        engine.write_back();
+        std::mem::drop(engine);
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "edge"), 1);
+        cleanup(engine, dir);
+    }
+
+    struct Squares(i64);
+
+    impl VirtualTable for Squares {
+        fn arity(&self) -> usize {
+            2
+        }
+
+        fn facts(&self) -> Result<Vec<Vec<Value>>> {
+            Ok((0..self.0).map(|i| vec!(Value::Int(i), Value::Int(i * i)))
+                          .collect())
+        }
+    }
+
+    #[test]
+    fn virtual_tables() {
+        let dir = "_test_virtual_tables_dir";
+        let mut engine = test_engine(dir);
+        engine.attach("square".to_string(), Box::new(Squares(3))).unwrap();
+        assert_eq!(engine.virtual_table("square").unwrap().facts().unwrap()
+                         .len(), 3);
+        assert_eq!(engine.virtual_tables(), vec!("square"));
+
+        // Virtual tables can't be changed, or share a name with a relation.
+        assert!(engine.assert_fact("square".to_string(),
+                                   vec!(Value::Int(3), Value::Int(9)))
+                      .is_err());
+        engine.assert_fact("edge".to_string(),
+                           vec!(Value::from("a"), Value::from("b")))
+              .unwrap();
+        assert!(engine.attach("edge".to_string(), Box::new(Squares(1)))
+                      .is_err());
+        assert!(engine.define_scratch("square".to_string(),
+                                      Relation::Extension(Table::new(2)))
+                      .is_none());
+
+        // Nor are they ever written back.
+        engine.write_back();
+        std::mem::drop(engine);
+        let mut engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert!(engine.virtual_table("square").is_none());
+        assert_eq!(engine.get_relations(), vec!("edge"));
+        engine.attach("square".to_string(), Box::new(Squares(1))).unwrap();
+        assert!(engine.detach("square"));
+        assert!(!engine.detach("square"));
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn saved_queries() {
+        let dir = "_test_saved_queries_dir";
+        let mut engine = test_engine(dir);
+        engine.save_query("all".to_string(), "r(X)?".to_string()).unwrap();
+        engine.save_query("one".to_string(), "r(a)?".to_string()).unwrap();
+        engine.save_query("all".to_string(), "r(Y)?".to_string()).unwrap();
+        engine.save_plan("one".to_string(), "r(a) [table]\n".to_string())
+            .unwrap();
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(engine.saved_query("all"), Some("r(Y)?"));
+        assert_eq!(engine.saved_query("one"), Some("r(a)?"));
+        assert_eq!(engine.saved_query("r"), None);
+        assert_eq!(engine.saved_queries(), vec!(("all", "r(Y)?"),
+                                                ("one", "r(a)?")));
+        assert_eq!(engine.saved_plans(), vec!("one"));
+        assert_eq!(engine.saved_plan("one"), Some("r(a) [table]\n"));
+        assert_eq!(engine.saved_plan("all"), None);
+        assert!(engine.get_relations().is_empty());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn rule_history() {
+        let dir = "_test_history_dir";
+        let mut engine = test_engine(dir);
+        let version = |change: &str, rules: &[&str]| Version {
+            time: 1,
+            user: "al".to_string(),
+            change: change.to_string(),
+            rules: rules.iter().map(|r| r.to_string()).collect()
+        };
+        engine.record_version("v", version("assert", &["v(X) :- r(X)."]));
+        engine.record_version("v", version("redefine", &["v(X) :- s(X)."]));
+        engine.write_back();
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(engine.history("v"),
+                   &[version("assert", &["v(X) :- r(X)."]),
+                     version("redefine", &["v(X) :- s(X)."])][..]);
+        assert!(engine.history("r").is_empty());
+        assert!(engine.get_relations().is_empty());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn descriptions() {
+        let dir = "_test_descriptions_dir";
+        let mut engine = test_engine(dir);
+        engine.assert_fact("r".to_string(), vec!(Value::from("a"))).unwrap();
+        assert!(engine.describe("s", "nothing".to_string()).is_err());
+        engine.describe("r", "HR feed, \"nightly\"".to_string()).unwrap();
+        engine.write_back();
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(engine.description("r"), Some("HR feed, \"nightly\""));
+        assert_eq!(engine.description("s"), None);
+        assert!(engine.verify().is_empty());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn checksums() {
+        let dir = "_test_checksum_dir";
+        let path = Path::new(dir).join("r");
+        let mut engine = test_engine(dir);
+        engine.assert_fact("r".to_string(), vec!(Value::from("abc"))).unwrap();
+        engine.assert_fact("r".to_string(), vec!(Value::from("def"))).unwrap();
+        assert!(engine.verify().is_empty());
+        std::mem::drop(engine);
+
+        let good = std::fs::read_to_string(&path).unwrap();
+        assert!(good.contains("\"checksum\":"));
+        assert!(good.contains("\"count\":2"));
+        let damage = |from: &str, to: &str| {
+            std::fs::write(&path, good.replace(from, to)).unwrap();
+            StorageEngine::<()>::new(dir.to_string())
+        };
+
+        match damage("abc", "abd") {
+            Err(Error::Corrupt { .. }) => (),
+            _ => panic!("changed contents not detected")
+        }
+        match damage(",\"def\"", "") {
+            Err(Error::Corrupt { .. }) => (),
+            _ => panic!("missing fact not detected")
+        }
+        match damage("]", "") {
+            Err(Error::Corrupt { .. }) => (),
+            _ => panic!("truncated file not detected")
+        }
+
+        clear_test_dir(dir);
+    }
+
+    #[test]
+    fn nullary_relations() {
+        let dir = "_test_nullary_relations_dir";
+        let path = Path::new(dir).join("rain");
+        let mut engine = test_engine(dir);
+        engine.assert_fact("rain".to_string(), vec!()).unwrap();
+        engine.vacuum();
+        std::mem::drop(engine);
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "rain"), 1);
+        std::mem::drop(engine);
+
+        // Files from before tables of arity 0 recorded their number of facts
+        // still open, with the facts their count says they had.
+        let empty: Relation<()> = Relation::Extension(Table::new(0));
+        std::fs::write(&path, format!(
+            "{{\"version\":{},\"count\":2,\"contents\":{},\"checksum\":{}}}",
+            FORMAT_VERSION, serde_json::to_string(&empty).unwrap(),
+            checksum(&empty).unwrap())).unwrap();
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "rain"), 2);
+        engine.write_back();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"len\":2"));
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn migration() {
+        let dir = "_test_migration_dir";
+        let path = Path::new(dir).join("r");
+        let engine = test_engine(dir);
+        std::mem::drop(engine);
+
+        // A version 0 file, with a path from wherever it was written.
+        std::fs::write(&path, r#"{"contents":{"Extension":{"contents":["a"],
+                                 "arity":1}},"path":"./data/r"}"#).unwrap();
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 1);
+        engine.write_back();
+        let upgraded = std::fs::read_to_string(&path).unwrap();
+        assert!(upgraded.starts_with(
+                &format!("{{\"version\":{},", FORMAT_VERSION)));
+        assert!(!upgraded.contains("path"));
+        std::mem::drop(engine);
+
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 1);
+        std::mem::drop(engine);
+
+        // The version is found without reading the rest of the file, unless
+        // the file doesn't start with it, e.g. after being reformatted.
+        let mut reader = std::io::Cursor::new(upgraded.as_bytes());
+        assert_eq!(leading_version(&mut reader).unwrap(),
+                   Some(FORMAT_VERSION));
+        assert_eq!(reader.position(), 0);
+        std::fs::write(&path, upgraded.replacen("{", "{ ", 1)).unwrap();
+        let engine = StorageEngine::<()>::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 1);
+        std::mem::drop(engine);
+
+        std::fs::write(&path, upgraded.replacen(
+                &FORMAT_VERSION.to_string(), "99", 1)).unwrap();
+        match StorageEngine::<()>::new(dir.to_string()) {
+            Err(Error::Corrupt { .. }) => (),
+            _ => panic!("file from a newer version was read")
+        }
+        clear_test_dir(dir);
+    }
+
+    #[test]
+    fn distinct_len() {
+        let mut t = test_table(&[vec!("a", "b"), vec!("a", "c"),
+                                 vec!("a", "b")]);
+        assert_eq!((t.len(), t.distinct_len()), (3, 2));
+        t.retract(&[Value::from("a"), Value::from("b")]);
+        assert_eq!((t.len(), t.distinct_len()), (2, 2));
+        t.truncate_columns(1);
+        assert_eq!((t.len(), t.distinct_len()), (2, 1));
+        t.assert(vec!(Value::from("d"))).unwrap();
+        assert_eq!(t.distinct_len(), 2);
+        t.assert_batch(vec!(vec!(Value::from("d")), vec!(Value::from("e"))))
+         .unwrap();
+        assert_eq!((t.len(), t.distinct_len()), (5, 3));
+        assert_eq!(t.retain(|tuple| *tuple[0] != Value::from("a")), 2);
+        assert_eq!((t.len(), t.distinct_len()), (3, 2));
+        t.set_max_len(Some(1));
+        assert_eq!(t.expire(), 2);
+        assert_eq!((t.len(), t.distinct_len()), (1, 1));
+        t.add_column(Value::from("x"));
+        assert_eq!((t.len(), t.distinct_len()), (1, 1));
+    }
+
+    #[test]
+    fn table_assert_batch() {
+        let mut t = test_table(&[vec!("a", "b")]);
+        let batch = |v: &[&str]| v.iter().map(|s| Value::from(*s)).collect();
+        assert!(t.assert_batch(vec!(batch(&["c", "d"]), batch(&["e"])))
+                 .is_err());
+        assert_eq!(t.len(), 1);
+        t.assert_batch(vec!(batch(&["c", "d"]), batch(&["e", "f"]))).unwrap();
+        assert_eq!(table_as_vec(&t), strings(&[vec!("a", "b"),
+                                               vec!("c", "d"),
+                                               vec!("e", "f")]));
+    }
+
+    #[test]
+    fn table_retract() {
+        let mut t = test_table(&[vec!("a", "b"), vec!("c", "d"),
+                                 vec!("a", "b"), vec!("e", "f")]);
+        let fact = |v: &[&str]| -> Vec<Value> {
+            v.iter().map(|s| Value::from(*s)).collect()
+        };
+        assert!(t.retract(&fact(&["a", "b"])));
+        assert_eq!(table_as_vec(&t), strings(&[vec!("a", "b"),
+                                               vec!("c", "d"),
+                                               vec!("e", "f")]));
+        assert!(!t.retract(&fact(&["a", "d"])));
+        assert!(!t.retract(&fact(&["z", "b"])));
+        assert!(!t.retract(&fact(&["a"])));
+        assert_eq!(t.len(), 3);
+
+        assert!(t.contains(&fact(&["c", "d"])));
+        assert!(!t.contains(&fact(&["c", "b"])));
+        assert!(!t.contains(&fact(&["c"])));
+        t.add_index(1);
+        assert_eq!(t.retain(|tuple| tuple[0] != &Value::from("c")), 1);
+        assert_eq!(table_as_vec(&t), strings(&[vec!("a", "b"),
+                                               vec!("e", "f")]));
+        let f = Value::from("f");
+        // The index is rebuilt for the rows left.
+        assert_eq!(t.select(&[(1, &f)]).collect::<Vec<_>>(),
+                   vec!(vec!(&Value::from("e"), &f)));
+        assert_eq!(t.retain(|_| true), 0);
+    }
+
+    #[test]
+    fn materializations() {
+        let dir = "_test_materializations_dir";
+        let mut engine = test_engine(dir);
+        let view = || Relation::Intension(());
+        engine.get_or_create_relation("v".to_string(), view()).unwrap();
+        assert!(engine.materialize("t", Table::new(2)).is_err());
+        engine.materialize("v", test_table(&[vec!("a", "b")])).unwrap();
+        engine.write_back();
+        engine.extend_materialization("v", vec!(
+            vec!(Value::from("b"), Value::from("c")))).unwrap();
+        engine.write_back();
+        std::mem::drop(engine);
+
+        // Tuples added since the materialization was written are journaled,
+        // and read back with it.
+        let mut engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(engine.materialized(), vec!("v"));
+        assert!(engine.get_relation("v").is_some());
+        let contents = |engine: &StorageEngine<()>| {
+            table_as_vec(engine.materialization("v").unwrap())
+        };
+        assert_eq!(contents(&engine),
+                   strings(&[vec!("a", "b"), vec!("b", "c")]));
+        assert_eq!(engine.retain_materialized("v", |t| {
+            t[0] != &Value::from("a")
+        }), 1);
+        assert!(engine.verify().is_empty());
+        std::mem::drop(engine);
+
+        let mut engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(contents(&engine), strings(&[vec!("b", "c")]));
+        assert!(engine.dematerialize("v"));
+        assert!(!engine.dematerialize("v"));
+        engine.write_back();
+        assert!(!Path::new(dir).join(format!("v{}", MATERIALIZED_EXTENSION))
+                               .exists());
+        std::mem::drop(engine);
+        let engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert!(engine.materialized().is_empty());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn table_references() {
+        let mut t = test_table(&[vec!("a", "b")]);
+        let reference = Reference {
+            columns: vec!(1),
+            relation: "s".to_string(),
+            referenced: vec!(0)
+        };
+        t.add_reference(reference.clone());
+        t.add_reference(reference.clone());
+        assert_eq!(t.references(), &[reference.clone()]);
+
+        let json = serde_json::to_string(&t).unwrap();
+        let t: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(t.references(), &[reference]);
+        assert_eq!(table_as_vec(&t), strings(&[vec!("a", "b")]));
+    }
+
+    #[test]
+    fn table_triggers() {
+        let mut t = test_table(&[vec!("a", "b")]);
+        let x = || ast::AtomicTerm::Variable("X".to_string());
+        let trigger = Trigger {
+            pattern: vec!(x(), ast::AtomicTerm::Atom(Value::from("b"))),
+            actions: vec!(ast::CompoundTerm {
+                relation: "s".to_string(),
+                params: vec!(x())
+            })
+        };
+        t.add_trigger(trigger.clone());
+        t.add_trigger(trigger.clone());
+        assert_eq!(t.triggers(), &[trigger.clone()]);
+
+        let json = serde_json::to_string(&t).unwrap();
+        let t: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(t.triggers(), &[trigger]);
+        assert_eq!(table_as_vec(&t), strings(&[vec!("a", "b")]));
+    }
+
+    #[test]
+    fn table_columns() {
+        let mut t = test_table(&[vec!("a", "b"), vec!("c", "d")]);
+        t.add_column(Value::from("x"));
+        assert_eq!(t.arity(), 3);
+        assert_eq!(table_as_vec(&t),
+                   strings(&[vec!("a", "b", "x"), vec!("c", "d", "x")]));
+        t.assert(vec!(Value::from("e"), Value::from("f"), Value::from("g")))
+            .unwrap();
+        t.truncate_columns(1);
+        assert_eq!(t.arity(), 1);
+        assert_eq!(table_as_vec(&t),
+                   strings(&[vec!("a"), vec!("c"), vec!("e")]));
+        assert!(t.assert(vec!(Value::from("a"), Value::from("b"))).is_err());
+    }
+
+    #[test]
+    fn table_indexes() {
+        let mut t = test_table(&[vec!("a", "b"), vec!("c", "b"),
+                                 vec!("a", "d")]);
+        let select = |t: &Table, constants: &[(usize, &str)]| {
+            let values: Vec<(usize, Value)> = constants.iter().map(|(c, a)| {
+                (*c, Value::from(*a))
+            }).collect();
+            let constants: Vec<(usize, &Value)> =
+                values.iter().map(|(c, v)| (*c, v)).collect();
+            t.select(&constants).map(|tuple| {
+                tuple.into_iter().map(Value::to_string).collect()
+            }).collect::<Vec<Vec<String>>>()
+        };
+        assert!(t.add_index(0));
+        assert!(!t.add_index(0));
+        assert_eq!(t.indexes(), vec!(0));
+        assert_eq!(t.distinct_in(1), 2);
+        assert_eq!(select(&t, &[(0, "a")]),
+                   strings(&[vec!("a", "b"), vec!("a", "d")]));
+        assert_eq!(select(&t, &[(0, "a"), (1, "d")]),
+                   strings(&[vec!("a", "d")]));
+        assert_eq!(select(&t, &[(0, "b")]), strings(&[]));
+        assert_eq!(select(&t, &[(0, "z")]), strings(&[]));
+
+        // Indexes keep up with facts added and removed.
+        t.assert(vec!(Value::from("c"), Value::from("e"))).unwrap();
+        assert!(t.retract(&[Value::from("a"), Value::from("b")]));
+        assert_eq!(select(&t, &[(0, "c")]),
+                   strings(&[vec!("c", "b"), vec!("c", "e")]));
+        assert_eq!(select(&t, &[(0, "a")]), strings(&[vec!("a", "d")]));
+
+        // Indexed columns are saved with the table, and rebuilt on loading.
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, concat!(r#"{"contents":["c","b","a","d","c","e"],"#,
+                                 r#""arity":2,"indexes":[0]}"#));
+        let back: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.indexes(), vec!(0));
+        assert_eq!(select(&back, &[(0, "c")]), select(&t, &[(0, "c")]));
+
+        assert!(t.drop_index(0));
+        assert!(!t.drop_index(0));
+        assert_eq!(select(&t, &[(0, "a")]), strings(&[vec!("a", "d")]));
+    }
+
+    #[test]
+    fn arity_conflicts() {
+        let dir = "_test_arity_conflicts_dir";
+        let mut engine = test_engine(dir);
+        let fact = |atoms: &[&str]| -> Vec<Value> {
+            atoms.iter().map(|a| Value::from(*a)).collect()
+        };
+        engine.assert_fact("r".to_string(), fact(&["a", "b"])).unwrap();
+        match engine.assert_fact("r".to_string(), fact(&["a", "b", "c"])) {
+            Err(Error::RelationArity { relation, arity: 2, got: 3 }) =>
+                assert_eq!(relation, "r"),
+            _ => panic!("expected an arity conflict")
+        }
+        assert!(engine.assert_facts("r".to_string(), 1,
+                                    vec!(fact(&["a"]))).is_err());
+        assert_eq!(relation_len(&engine, "r"), 1);
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn expiration() {
+        let mut t = test_table(&[vec!("a"), vec!("b"), vec!("c")]);
+        assert_eq!(t.expire(), 0);
+        t.set_max_len(Some(2));
+        assert_eq!(t.expire(), 1);
+        assert_eq!(table_as_vec(&t), strings(&[vec!("b"), vec!("c")]));
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, r#"{"contents":["b","c"],"arity":1,"max_len":2}"#);
+        let t: Table = serde_json::from_str(&json).unwrap();
+        assert_eq!(t.max_len(), Some(2));
+
+        let dir = "_test_expiration_dir";
+        let mut engine = test_engine(dir);
+        for s in &["a", "b", "c"] {
+            engine.assert_fact("r".to_string(), vec!(Value::from(*s)))
+                  .unwrap();
+        }
+        if let Some(mut r) = engine.get_relation_mut("r") {
+            if let Relation::Extension(ref mut t) = *r {
+                t.set_max_len(Some(1));
+            }
+        }
+        engine.expire();
+        assert_eq!(relation_len(&engine, "r"), 1);
+        assert_eq!(engine.take_expired(), vec!("r".to_string()));
+        assert!(engine.take_expired().is_empty());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn journal() {
+        let dir = "_test_journal_dir";
+        let main = Path::new(dir).join("r");
+        let journal = Path::new(dir).join("r.facts");
+        let fact = |s: &str| vec!(Value::from(s), Value::Int(1));
+
+        let mut engine = test_engine(dir);
+        engine.assert_fact("r".to_string(), fact("a")).unwrap();
+        engine.write_back();
+        assert!(main.exists() && !journal.exists());
+
+        engine.assert_fact("r".to_string(), fact("b")).unwrap();
+        engine.assert_fact("r".to_string(), fact("c")).unwrap();
+        engine.write_back();
+        let lines = std::fs::read_to_string(&journal).unwrap();
+        assert_eq!(lines, "{\"generation\":1}\n[\"b\",1]\n[\"c\",1]\n");
+        assert!(engine.assert_fact("r".to_string(), vec!()).is_err());
+
+        // A torn write at the end of the journal is ignored.
+        std::fs::OpenOptions::new().append(true).open(&journal).unwrap()
+            .write_all(b"[\"d\"").unwrap();
+        std::mem::drop(engine);
+        let mut engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 3);
+        engine.assert_fact("r".to_string(), fact("e")).unwrap();
+        engine.write_back();
+        assert!(!journal.exists());
+        engine.assert_fact("r".to_string(), fact("f")).unwrap();
+        engine.write_back();
+        assert!(journal.exists());
+
+        engine.vacuum();
+        assert!(!journal.exists());
+        std::mem::drop(engine);
+        let engine = StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 5);
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn crash_while_compacting() {
+        let dir = "_test_crash_while_compacting_dir";
+        let main = Path::new(dir).join("r");
+        let journal = Path::new(dir).join("r.facts");
+        let temp = Path::new(dir).join("r.tmp");
+        let fact = |s: &str| vec!(Value::from(s), Value::Int(1));
+
+        let mut engine = test_engine(dir);
+        engine.assert_fact("r".to_string(), fact("a")).unwrap();
+        engine.write_back();
+        engine.assert_fact("r".to_string(), fact("b")).unwrap();
+        engine.write_back();
+        let journaled = std::fs::read(&journal).unwrap();
+
+        // Crash after the main file is replaced, before the journal it
+        // includes is removed.
+        engine.vacuum();
+        std::mem::drop(engine);
+        std::fs::write(&journal, &journaled).unwrap();
+        let mut engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 2);
+        assert!(!journal.exists());
+
+        // The next journal is replayed as usual.
+        engine.assert_fact("r".to_string(), fact("c")).unwrap();
+        engine.write_back();
+        assert!(journal.exists());
+
+        // Crash while writing the new main file.
+        std::mem::drop(engine);
+        std::fs::write(&temp, "{\"version\":").unwrap();
+        let engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "r"), 3);
+        assert!(main.exists() && !temp.exists());
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn relation_names() {
+        for (name, file) in &[("edge", "edge"), ("is_a2", "is_a2"),
+                              ("eDGE", "e%44%47%45"), ("../x", "%2E%2E%2Fx"),
+                              ("r.facts", "r%2Efacts"), ("con", "%63on"),
+                              ("console", "console"), ("café", "caf%C3%A9"),
+                              ("100%", "100%25")] {
+            assert_eq!(&encode_name(name), file);
+            assert_eq!(decode_name(file).as_ref().map(String::as_str),
+                       Some(*name));
+        }
+        // Only names as `encode_name` writes them are decoded.
+        for file in &["Edge", "%2e", "%2", "%ZZ", "%C3", "con", "%65dge"] {
+            assert_eq!(decode_name(file), None);
+        }
+    }
+
+    #[test]
+    fn name_rules() {
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        for name in &["edge", "is_a2", "eDGE", "hr.employee", "a.b_c.d9"] {
+            assert!(check_relation_name(name).is_ok(), "{}", name);
+        }
+        for name in &["", "Edge", "_x", "2x", "café", "a-b", "hr.", ".x",
+                      "a..b", "hr.Employee", long.as_str()] {
+            assert!(check_relation_name(name).is_err(), "{}", name);
+        }
+
+        let dir = "_test_name_rules_dir";
+        let mut engine = test_engine(dir);
+        for name in &["edge", "hr.employee", "hr.salary", "hr.pay.rate"] {
+            engine.assert_fact(name.to_string(), vec!(Value::from("a")))
+                  .unwrap();
+        }
+        match engine.assert_fact("hr.Bad".to_string(), vec!()) {
+            Err(Error::BadRelationName { name, reason: _ }) =>
+                assert_eq!(name, "hr.Bad"),
+            other => panic!("expected a bad name, got {:?}", other)
+        }
+        assert_eq!(engine.namespaces(), vec!("hr", "hr.pay"));
+        assert_eq!(engine.relations_in("hr"), vec!("hr.employee", "hr.salary"));
+        assert_eq!(engine.relations_in(""), vec!("edge"));
+        cleanup(engine, dir);
+    }
+
+    #[test]
+    fn encoded_files() {
+        let dir = "_test_encoded_files_dir";
+        let encoded = Path::new(dir).join("e%44%47%45");
+        let journal = Path::new(dir).join("e%44%47%45.facts");
+        let fact = |s: &str| vec!(Value::from(s));
+
+        let mut engine = test_engine(dir);
+        engine.assert_fact("eDGE".to_string(), fact("a")).unwrap();
+        engine.write_back();
+        engine.assert_fact("eDGE".to_string(), fact("b")).unwrap();
+        engine.write_back();
+        assert!(encoded.exists() && journal.exists());
+        std::mem::drop(engine);
+
+        // Files named after their relation are renamed when the database is
+        // next opened, but not if it is opened read-only.
+        let legacy = Path::new(dir).join("eDGE");
+        let legacy_journal = Path::new(dir).join("eDGE.facts");
+        std::fs::rename(&encoded, &legacy).unwrap();
+        std::fs::rename(&journal, &legacy_journal).unwrap();
+        let engine: StorageEngine<()> =
+            StorageEngine::open_read_only(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "eDGE"), 2);
+        assert!(legacy.exists() && legacy_journal.exists());
+        std::mem::drop(engine);
+
+        let engine: StorageEngine<()> =
+            StorageEngine::new(dir.to_string()).unwrap();
+        assert_eq!(relation_len(&engine, "eDGE"), 2);
+        assert!(encoded.exists() && journal.exists());
+        assert!(!legacy.exists() && !legacy_journal.exists());
+        cleanup(engine, dir);
     }
 }
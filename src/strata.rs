@@ -0,0 +1,259 @@
+/// The order in which views are evaluated.
+///
+/// Views are split into strata: the strongly connected components of the
+/// graph of references from each view to the relations its rules use. A
+/// stratum's views refer only to views in it or in earlier strata, so taking
+/// the strata in order, everything a stratum uses outside itself is complete
+/// before it is evaluated. A stratum with a cycle of references in it is
+/// recursive, and its views are evaluated together to a fixpoint, however
+/// many there are; one that isn't has a single view, evaluated in one pass.
+///
+/// The strata are worked out once for each query planned (see
+/// `eval::evaluation_order`), and also decide which programs are stratified:
+/// a view may only negate views in earlier strata, or take extrema over them,
+/// or it would have no single well-defined meaning.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The relations each view refers to, with how each is used.
+pub type References = BTreeMap<String, Vec<(String, Dependence)>>;
+
+/// How a rule uses a relation in its body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dependence {
+    Positive,
+    /// In a negated term.
+    Negated,
+    /// In a rule with `min_by` or `max_by`, which keeps only the best frames
+    /// of the rest of the body and so, like negation, can lose answers as the
+    /// relation gains facts.
+    Extremum
+}
+
+/// Views evaluated together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stratum {
+    /// The views in this stratum, in alphabetical order.
+    pub views: Vec<String>,
+    /// Whether some view here refers to a view here, perhaps itself.
+    pub recursive: bool
+}
+
+/// The strata of a set of views, each after those it refers to.
+pub struct Strata {
+    strata: Vec<Stratum>,
+    // The index in `strata` of each view's stratum.
+    index: HashMap<String, usize>,
+    references: References
+}
+
+impl Strata {
+    /// Work out the strata of the views in `references`. Other relations, like
+    /// tables, have no rules to evaluate and so belong to no stratum.
+    pub fn new(references: References) -> Strata {
+        let components = Components::find(&references);
+        let mut strata = Vec::new();
+        let mut index = HashMap::new();
+        for mut views in components {
+            views.sort();
+            let recursive = views.len() > 1 || references[&views[0]].iter()
+                .any(|(relation, _)| *relation == views[0]);
+            for view in &views {
+                index.insert(view.clone(), strata.len());
+            }
+            strata.push(Stratum { views, recursive });
+        }
+        Strata { strata, index, references }
+    }
+
+    /// Every stratum, in the order they are evaluated.
+    pub fn strata(&self) -> &[Stratum] {
+        &self.strata
+    }
+
+    /// The references the strata were worked out from.
+    pub fn references(&self) -> &References {
+        &self.references
+    }
+
+    /// The stratum of the named view, if it has any rules.
+    pub fn stratum(&self, view: &str) -> Option<&Stratum> {
+        self.index.get(view).map(|i| &self.strata[*i])
+    }
+
+    /// Is the named view evaluated to a fixpoint with others or itself?
+    pub fn is_recursive(&self, view: &str) -> bool {
+        self.stratum(view).map_or(false, |s| s.recursive)
+    }
+
+    /// The strata the named relation depends on, directly or through other
+    /// views, including its own, in the order they are evaluated.
+    pub fn needed_by(&self, relation: &str) -> Vec<&Stratum> {
+        let mut needed = BTreeSet::new();
+        let mut stack = vec!(relation);
+        while let Some(relation) = stack.pop() {
+            let i = match self.index.get(relation) {
+                Some(i) => *i,
+                None => continue
+            };
+            if needed.insert(i) {
+                for view in &self.strata[i].views {
+                    for (target, _) in &self.references[view] {
+                        stack.push(target);
+                    }
+                }
+            }
+        }
+        needed.into_iter().map(|i| &self.strata[i]).collect()
+    }
+
+    /// A view negating a view in its own stratum or taking an extremum over
+    /// one, and the view it uses that way, if there is one: the first in
+    /// alphabetical order.
+    pub fn non_monotone_within(&self) -> Option<(&str, &str)> {
+        self.references.iter().flat_map(|(view, targets)| {
+            targets.iter()
+                   .filter(|(_, dependence)| {
+                       *dependence != Dependence::Positive
+                   })
+                   .map(move |(target, _)| (view.as_str(), target.as_str()))
+        }).find(|(view, target)| {
+            self.index.contains_key(*target) &&
+                self.index.get(*target) == self.index.get(*view)
+        })
+    }
+}
+
+// Tarjan's algorithm for strongly connected components, which finds each
+// component only once it has found every component its views refer to.
+struct Components<'r> {
+    references: &'r References,
+    // The order each view was first visited in, and the earliest view known
+    // to be reachable from it that is still on the stack.
+    visited: HashMap<&'r str, usize>,
+    lowest: HashMap<&'r str, usize>,
+    stack: Vec<&'r str>,
+    components: Vec<Vec<String>>
+}
+
+impl<'r> Components<'r> {
+    fn find(references: &'r References) -> Vec<Vec<String>> {
+        let mut components = Components {
+            references,
+            visited: HashMap::new(),
+            lowest: HashMap::new(),
+            stack: Vec::new(),
+            components: Vec::new()
+        };
+        for view in references.keys() {
+            if !components.visited.contains_key(view.as_str()) {
+                components.visit(view);
+            }
+        }
+        components.components
+    }
+
+    fn visit(&mut self, view: &'r str) {
+        let order = self.visited.len();
+        self.visited.insert(view, order);
+        self.lowest.insert(view, order);
+        self.stack.push(view);
+
+        let references = self.references;
+        for (target, _) in &references[view] {
+            let target = target.as_str();
+            if !references.contains_key(target) {
+                continue;
+            }
+            if !self.visited.contains_key(target) {
+                self.visit(target);
+                let lowest = cmp::min(self.lowest[view], self.lowest[target]);
+                self.lowest.insert(view, lowest);
+            } else if self.stack.contains(&target) {
+                let lowest = cmp::min(self.lowest[view], self.visited[target]);
+                self.lowest.insert(view, lowest);
+            }
+        }
+
+        if self.lowest[view] == order {
+            let start = self.stack.iter().position(|v| *v == view)
+                            .expect("visited view not on the stack");
+            let component = self.stack.split_off(start);
+            self.components.push(component.into_iter()
+                                          .map(str::to_string)
+                                          .collect());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strata::{Dependence, References, Strata, Stratum};
+    use strata::Dependence::*;
+
+    fn references(edges: &[(&str, &str, Dependence)]) -> References {
+        let mut references = References::new();
+        for (view, target, dependence) in edges {
+            references.entry(view.to_string()).or_insert(Vec::new())
+                      .push((target.to_string(), *dependence));
+        }
+        references
+    }
+
+    fn stratum(views: &[&str], recursive: bool) -> Stratum {
+        Stratum {
+            views: views.iter().map(|v| v.to_string()).collect(),
+            recursive
+        }
+    }
+
+    #[test]
+    fn evaluation_order() {
+        let strata = Strata::new(references(&[
+            ("ancestor", "parent", Positive),
+            ("ancestor", "ancestor", Positive),
+            ("even", "zero", Positive),
+            ("even", "odd", Positive),
+            ("odd", "even", Positive),
+            ("orphan", "person", Positive),
+            ("orphan", "ancestor", Negated),
+            ("unrelated", "odd", Positive),
+            ("unrelated", "orphan", Extremum)
+        ]));
+        assert_eq!(strata.strata(), &[stratum(&["ancestor"], true),
+                                      stratum(&["even", "odd"], true),
+                                      stratum(&["orphan"], false),
+                                      stratum(&["unrelated"], false)][..]);
+        assert!(strata.is_recursive("odd"));
+        assert!(!strata.is_recursive("orphan"));
+        assert!(!strata.is_recursive("parent"));
+        assert_eq!(strata.stratum("parent"), None);
+        assert_eq!(strata.needed_by("orphan"),
+                   vec!(&stratum(&["ancestor"], true),
+                        &stratum(&["orphan"], false)));
+        assert_eq!(strata.needed_by("unrelated").len(), 4);
+        assert!(strata.needed_by("parent").is_empty());
+        assert_eq!(strata.non_monotone_within(), None);
+    }
+
+    #[test]
+    fn negation_within_a_stratum() {
+        let strata = Strata::new(references(&[
+            ("free", "node", Positive),
+            ("free", "taken", Negated),
+            ("taken", "free", Positive)
+        ]));
+        assert_eq!(strata.strata(), &[stratum(&["free", "taken"], true)][..]);
+        assert_eq!(strata.non_monotone_within(), Some(("free", "taken")));
+
+        let strata = Strata::new(references(&[("odd", "odd", Negated)]));
+        assert_eq!(strata.non_monotone_within(), Some(("odd", "odd")));
+
+        let strata = Strata::new(references(&[
+            ("best", "path", Extremum),
+            ("path", "best", Positive)
+        ]));
+        assert_eq!(strata.non_monotone_within(), Some(("best", "path")));
+    }
+}
@@ -0,0 +1,154 @@
+/// Regression tests for rule programs, comparing the answers to queries with
+/// answers written down before.
+///
+/// A suite is a TOML file naming the program files to load and, for each
+/// query, a file of its expected answers:
+///
+/// ```toml
+/// program = ["family.dl"]
+///
+/// [[query]]
+/// query = "ancestor(helen, X)?"
+/// expected = "ancestors_of_helen.tsv"
+/// ```
+///
+/// Expected answers are in the format `--script` prints them in: a header
+/// line of the query's variables, then one tab-separated line per answer, in
+/// any order. Paths are relative to the suite's directory.
+
+use ast;
+use cache::ViewCache;
+use error::*;
+use eval;
+use lexer::Lexer;
+use parser;
+use parser::Parser;
+use storage::StorageEngine;
+
+use toml;
+
+use std;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The number of suites run so far by this process, to give each its own
+// database.
+static SUITES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Suite {
+    program: Vec<String>,
+    #[serde(default, rename = "query")]
+    queries: Vec<Case>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    query: String,
+    expected: String
+}
+
+/// How the answers to one query differed from those expected.
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    pub query: String,
+    /// Expected answers that weren't given, as printed.
+    pub missing: Vec<String>,
+    /// Answers given that weren't expected, as printed.
+    pub unexpected: Vec<String>
+}
+
+fn suite_error<E: ToString>(path: &Path, e: E) -> Error {
+    Error::Suite(format!("{}: {}", path.display(), e.to_string()))
+}
+
+/// Run the suite at `path`, returning the number of queries that passed and
+/// how each of the others failed.
+pub fn run(path: &str) -> Result<(usize, Vec<Failure>)> {
+    let path = Path::new(path);
+    let text = fs::read_to_string(path).map_err(|e| suite_error(path, e))?;
+    let suite: Suite = toml::from_str(&text)
+        .map_err(|e| suite_error(path, e))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    // The program is loaded into an empty database that is never written.
+    let data_dir = std::env::temp_dir().join(format!(
+        "data-goblin-suite-{}-{}", std::process::id(),
+        SUITES.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&data_dir);
+    let result = StorageEngine::new(data_dir.to_string_lossy().into_owned())
+        .and_then(|mut engine| {
+            let mut cache = ViewCache::new();
+            for program in &suite.program {
+                load(&mut engine, &mut cache, &dir.join(program))?;
+            }
+            let mut passed = 0;
+            let mut failures = Vec::new();
+            for case in &suite.queries {
+                match check(&engine, &cache, dir, case)? {
+                    None => passed += 1,
+                    Some(failure) => failures.push(failure)
+                }
+            }
+            Ok((passed, failures))
+        });
+    let _ = fs::remove_dir_all(&data_dir);
+    result
+}
+
+// Assert the facts and rules in a program file.
+fn load(engine: &mut StorageEngine<eval::AstView>,
+        cache: &mut ViewCache,
+        path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path).map_err(|e| suite_error(path, e))?;
+    let tokens = Lexer::new(text.chars()).collect::<Result<Vec<_>>>()
+        .map_err(|e| suite_error(path, e))?;
+    for line in Parser::new(tokens.into_iter()) {
+        match line.map_err(|e| suite_error(path, e))? {
+            ast::Line::Rule(rule) => eval::assert(engine, cache, rule)?,
+            ast::Line::Redefine(rule) => eval::redefine(engine, cache, rule)?,
+            _ => return Err(suite_error(
+                    path, "programs may only hold facts and rules"))
+        }
+    }
+    Ok(())
+}
+
+// Compare the answers to a query with those expected.
+fn check(engine: &StorageEngine<eval::AstView>,
+         cache: &ViewCache,
+         dir: &Path,
+         case: &Case) -> Result<Option<Failure>> {
+    let path = dir.join(&case.expected);
+    let text = fs::read_to_string(&path).map_err(|e| suite_error(&path, e))?;
+    let mut lines = text.lines();
+    let query = parser::parse_query(&case.query)?;
+    let columns = query.variables();
+    if lines.next().unwrap_or("") != columns.join("\t") {
+        return Err(suite_error(&path, format!(
+            "expected a header line of {}'s variables, {}",
+            case.query, columns.join(", "))));
+    }
+    let expected: BTreeSet<String> = lines.map(str::to_string).collect();
+
+    let answers: BTreeSet<String> =
+        eval::query_owned(engine, cache, query)?.into_iter().map(|frame| {
+            let values: Vec<&str> = columns.iter().map(|c| {
+                frame[c].as_str()
+            }).collect();
+            values.join("\t")
+        }).collect();
+
+    if answers == expected {
+        return Ok(None);
+    }
+    Ok(Some(Failure {
+        query: case.query.clone(),
+        missing: expected.difference(&answers).cloned().collect(),
+        unexpected: answers.difference(&expected).cloned().collect()
+    }))
+}
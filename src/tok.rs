@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(Debug, PartialEq, Clone)]
 
 /// Datalog lexical tokens.
@@ -5,12 +7,58 @@ pub enum Tok {
     Atom(String),
     Comma,
     CloseParen,
+    /// A meta-command: a line beginning with "." (e.g. ".tables"), holding
+    /// the text of the line after the ".".
+    Command(String),
     /// "."
     Dot,
     /// ":-"
     Means,
+    /// "->"
+    Arrow,
     /// "?"
     Query,
+    /// "?" followed on the same line by `select` and a comma-separated list of
+    /// variables, holding the variables.
+    Select(Vec<String>),
+    /// "?" followed on the same line by `using` and a comma-separated list of
+    /// hints on how to evaluate the query, holding the hints.
+    Using(Vec<String>),
+    /// "?" followed on the same line by `within` and a length of time, like
+    /// `2s` or `500ms`, holding the time.
+    Within(Duration),
+    /// "?->" followed on the same line by the name of a relation to store the
+    /// results of the query in, holding the name.
+    Capture(String),
     OpenParen,
-    Variable(String)
+    /// "{"
+    OpenBrace,
+    /// "}"
+    CloseBrace,
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    /// A double-quoted string literal, with escapes already processed.
+    Str(String),
+    /// "<"
+    Less,
+    /// ">"
+    Greater,
+    /// "=<", as in Prolog, so as not to look like an arrow.
+    LessOrEqual,
+    /// ">="
+    GreaterOrEqual,
+    /// "="
+    Equal,
+    /// "!="
+    NotEqual,
+    /// "+"
+    Plus,
+    /// "-". A "-" directly followed by a digit is part of a negative number
+    /// instead, so subtraction of a literal needs a space, as in `X - 1`.
+    Minus,
+    /// "*"
+    Times,
+    /// "/"
+    Divide
 }
@@ -0,0 +1,264 @@
+/// The values stored in relations.
+///
+/// On disk, symbols are stored as plain JSON strings (so existing data files
+/// remain readable), numbers and booleans as the corresponding JSON
+/// primitives, and strings as `{"String": "..."}`.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde::ser::SerializeMap;
+
+use std;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A single value in a tuple.
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// A bare identifier, like `isaac`.
+    Symbol(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// A quoted string, like `"Isaac"`.
+    String(String)
+}
+
+impl Value {
+    /// Get the name of this value if it is a symbol.
+    pub fn as_symbol(&self) -> Option<&str> {
+        match self {
+            Value::Symbol(s) => Some(s.as_str()),
+            _ => None
+        }
+    }
+
+    // Values of different types are ordered first by this rank. Integers and
+    // floats share a rank so that they sort numerically.
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Bool(_) => 0,
+            Value::Int(_) | Value::Float(_) => 1,
+            Value::Symbol(_) => 2,
+            Value::String(_) => 3
+        }
+    }
+}
+
+// A key for floats whose ordering is total and consistent with bitwise
+// equality: negative floats have their non-sign bits flipped so that they sort
+// in reverse.
+fn float_key(f: f64) -> i64 {
+    let bits = f.to_bits() as i64;
+    if bits < 0 {
+        bits ^ std::i64::MAX
+    } else {
+        bits
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Symbol(a), Value::Symbol(b)) => a.cmp(b),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) =>
+                float_key(*a).cmp(&float_key(*b)),
+            // Compare mixed numbers numerically, breaking ties (e.g. between 1
+            // and 1.0) by putting integers first.
+            (Value::Int(a), Value::Float(b)) =>
+                (*a as f64).partial_cmp(b).unwrap_or(Ordering::Less)
+                           .then(Ordering::Less),
+            (Value::Float(a), Value::Int(b)) =>
+                a.partial_cmp(&(*b as f64)).unwrap_or(Ordering::Greater)
+                 .then(Ordering::Greater),
+            (a, b) => a.rank().cmp(&b.rank())
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Symbol(s) => { 0.hash(state); s.hash(state) },
+            Value::Int(i) => { 1.hash(state); i.hash(state) },
+            Value::Float(f) => { 2.hash(state); f.to_bits().hash(state) },
+            Value::Bool(b) => { 3.hash(state); b.hash(state) },
+            Value::String(s) => { 4.hash(state); s.hash(state) }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Symbol(s) => write!(f, "{}", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            // Written as a literal the lexer reads back, which knows only
+            // these escapes and takes any other character as it is.
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{}", c)?
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Symbol(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Symbol(s)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S)
+            -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Symbol(s) => serializer.serialize_str(s),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Float(x) => serializer.serialize_f64(*x),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::String(s) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("String", s)?;
+                map.end()
+            }
+        }
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a symbol, number, boolean or {{\"String\": ...}}")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::Symbol(s.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, s: String) -> Result<Value, E> {
+        Ok(Value::Symbol(s))
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<Value, E> {
+        Ok(Value::Int(i))
+    }
+
+    fn visit_u64<E: de::Error>(self, i: u64) -> Result<Value, E> {
+        if i > std::i64::MAX as u64 {
+            Err(E::custom(format!("integer out of range: {}", i)))
+        } else {
+            Ok(Value::Int(i as i64))
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, x: f64) -> Result<Value, E> {
+        Ok(Value::Float(x))
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A)
+            -> Result<Value, A::Error> {
+        match map.next_entry::<String, String>()? {
+            Some((ref key, s)) if key == "String" => Ok(Value::String(s)),
+            _ => Err(de::Error::custom("expected {\"String\": ...}"))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> std::result::Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lexer::Lexer;
+    use tok::Tok;
+    use value::Value;
+    use serde_json;
+
+    #[test]
+    fn numeric_order() {
+        let mut values = vec!(Value::Int(10), Value::Float(2.5), Value::Int(2),
+                              Value::Float(-1.0), Value::Int(-3));
+        values.sort();
+        assert_eq!(values, vec!(Value::Int(-3), Value::Float(-1.0),
+                                Value::Int(2), Value::Float(2.5),
+                                Value::Int(10)));
+        assert!(Value::Int(1) < Value::Float(1.0));
+        assert!(Value::Int(1) != Value::Float(1.0));
+    }
+
+    #[test]
+    fn order_across_types() {
+        assert!(Value::Bool(true) < Value::Int(0));
+        assert!(Value::Int(100) < Value::from("a"));
+        assert!(Value::from("z") < Value::String("a".to_string()));
+        assert!(Value::from("abc") < Value::from("abd"));
+    }
+
+    #[test]
+    fn serialization() {
+        let values = vec!(Value::from("isaac"), Value::Int(-4),
+                          Value::Float(0.25), Value::Bool(false),
+                          Value::String("Isaac".to_string()));
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(json, r#"["isaac",-4,0.25,false,{"String":"Isaac"}]"#);
+        let back: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, values);
+    }
+
+    #[test]
+    fn string_literals() {
+        for s in &["plain", "a \"quote\"", "back\\slash", "line\nbreak\t",
+                   "carriage\rreturn", "bell\u{7}", "\u{200b}"] {
+            let text = Value::String(s.to_string()).to_string();
+            let tokens: Vec<Tok> = Lexer::new(text.chars())
+                .collect::<Result<_, _>>().unwrap();
+            assert_eq!(tokens, vec!(Tok::Str(s.to_string())));
+        }
+    }
+}